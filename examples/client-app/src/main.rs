@@ -1,6 +1,9 @@
 // Import necessary modules
 use topiary_config::Configuration;
-use topiary_core::{formatter, Language, Operation, TopiaryQuery};
+use topiary_core::{
+    formatter, CommentAttachment, FinalNewline, Language, LineEnding, Operation,
+    ParsingErrorTolerance, TopiaryQuery,
+};
 
 #[tokio::main]
 async fn main() {
@@ -28,6 +31,10 @@ async fn main() {
         formatting_query: TopiaryQuery::new(&grammar, query).unwrap(),
         grammar,
         indent: None,
+        max_blank_lines: None,
+        formatting_markers: None,
+        comment_attachment: CommentAttachment::default(),
+        tab_width: None,
         injection_query: None,
     };
 
@@ -38,7 +45,11 @@ async fn main() {
         &language,
         Operation::Format {
             skip_idempotence: false,
-            tolerate_parsing_errors: false,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
         },
         None,
     )
@@ -1,50 +1,303 @@
-use std::io::BufReader;
+use std::{
+    io::{BufReader, IsTerminal},
+    sync::Arc,
+};
 
+use clap::ValueEnum;
 use rootcause::report;
-use topiary_core::{Language, LanguageResolver, Operation, formatter};
+use similar::TextDiff;
+use topiary_core::{
+    FinalNewline, Formatter, FormatterBuilder, Language, LanguageResolver, LineEnding, Operation,
+    formatter,
+};
 
 use crate::{
-    error::{CLIResult, TopiaryError},
-    io::{InputFile, read_input},
+    error::{CLIResult, TopiaryError, strict_failure},
+    io::{InputFile, InputSource, read_input},
 };
 
-/// Run the formatter on an input and compare the result to the original.
-/// Returns `Ok(())` if the input is already formatted, or a `CheckFailed` error
-/// containing the original and formatted strings if it is not.
-pub fn check_input(
-    input: InputFile,
-    language: &Language,
+/// Whether to skip the idempotence check for a language: the `--skip-idempotence` flag, when
+/// given explicitly, takes precedence over the language's configured default
+pub fn skip_idempotence(flag: Option<bool>, language: &topiary_config::language::Language) -> bool {
+    flag.unwrap_or_else(|| language.skip_idempotence())
+}
+
+/// The operation with which the `format` subcommand formats an input, from its flags and the
+/// input's line ending
+pub fn format_operation(
+    skip_idempotence: bool,
+    tolerate_parsing_errors: bool,
+    strict: bool,
+    line_ending: LineEnding,
+) -> Operation {
+    Operation::Format {
+        skip_idempotence,
+        tolerate_parsing_errors: tolerate_parsing_errors.into(),
+        final_newline: FinalNewline::Single,
+        line_ending,
+        max_line_width: None,
+        strict,
+    }
+}
+
+/// A formatter that formats an input as [`format_operation`] describes, for the options that only
+/// a [`Formatter`] supports
+pub fn formatter_builder(
+    language: Arc<Language>,
     skip_idempotence: bool,
     tolerate_parsing_errors: bool,
+    strict: bool,
+    line_ending: LineEnding,
+) -> FormatterBuilder {
+    Formatter::builder(language)
+        .skip_idempotence(skip_idempotence)
+        .tolerate_parsing_errors(tolerate_parsing_errors.into())
+        .strict(strict)
+        .line_ending(line_ending)
+}
+
+/// Run the formatter on an input, without writing the result anywhere, returning the original
+/// input along with its formatted form
+fn format_input(
+    input: InputFile,
+    language: &Language,
+    operation: Operation,
     resolve: Option<&LanguageResolver<'_>>,
-) -> CLIResult<()> {
+) -> CLIResult<(String, String)> {
     let source_name = input.source().to_string();
-
     let mut buf_input = BufReader::new(input);
     let original = read_input(&mut buf_input)?;
 
     let mut formatted_bytes: Vec<u8> = Vec::new();
-    formatter(
+    let warnings = formatter(
         &mut original.as_bytes(),
         &mut formatted_bytes,
         language,
-        Operation::Format {
-            skip_idempotence,
-            tolerate_parsing_errors,
-        },
+        operation,
         resolve,
-    )?;
+    )
+    .map_err(strict_failure)?;
+    for warning in warnings {
+        log::warn!("{source_name}: {warning}");
+    }
 
     let formatted = String::from_utf8_lossy(&formatted_bytes).into_owned();
+    Ok((original, formatted))
+}
+
+/// Run the formatter on an input and compare the result to the original.
+/// Returns `Ok(())` if the input is already formatted, or a `CheckFailed` error
+/// (with a diff of the changes, if requested, and coloured if `color` is set) if it is not.
+pub fn check_input(
+    input: InputFile,
+    language: &Language,
+    operation: Operation,
+    show_diff: bool,
+    color: bool,
+    resolve: Option<&LanguageResolver<'_>>,
+) -> CLIResult<()> {
+    let source_name = input.source().to_string();
+    let diff_name = diff_name(input.source());
+    let (original, formatted) = format_input(input, language, operation, resolve)?;
 
     if original != formatted {
         return Err(report!(TopiaryError::CheckFailed {
             source_name,
-            original,
-            formatted,
+            diff: show_diff.then(|| {
+                let diff = unified_diff(&diff_name, &original, &formatted);
+                if color { color_diff(&diff) } else { diff }
+            }),
         })
         .into_dynamic());
     }
 
     Ok(())
 }
+
+/// Run the formatter on an input and return the unified diff between the original and the
+/// formatted output, or `None` if formatting would not change the input. The input itself is left
+/// untouched.
+pub fn diff_input(
+    input: InputFile,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+) -> CLIResult<Option<String>> {
+    let diff_name = diff_name(input.source());
+    let (original, formatted) = format_input(input, language, operation, resolve)?;
+
+    Ok((original != formatted).then(|| unified_diff(&diff_name, &original, &formatted)))
+}
+
+/// The name of an input, as it appears in the headers of its diff
+fn diff_name(source: &InputSource) -> String {
+    match source.location().to_path() {
+        Some(path) => path.display().to_string(),
+        None => "stdin".to_string(),
+    }
+}
+
+/// A unified diff from `original` to `formatted`, with `--- a/NAME` and `+++ b/NAME` headers, as
+/// accepted by `patch -p1` and `git apply`
+pub fn unified_diff(name: &str, original: &str, formatted: &str) -> String {
+    TextDiff::from_lines(original, formatted)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("a/{name}"), &format!("b/{name}"))
+        .to_string()
+}
+
+/// When to colour diffs
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Color {
+    /// Only when writing to a terminal and NO_COLOR is not set
+    Auto,
+
+    /// Always, even when NO_COLOR is set
+    Always,
+
+    /// Never
+    Never,
+}
+
+impl Color {
+    /// Whether to colour diffs written to `stream`
+    pub fn enabled(self, stream: &impl IsTerminal) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            // See https://no-color.org: NO_COLOR only counts if it is not empty
+            Self::Auto => {
+                stream.is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+            }
+        }
+    }
+}
+
+const ANSI_RESET: &str = "\x1b[0m";
+const ANSI_BOLD: &str = "\x1b[1m";
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_CYAN: &str = "\x1b[36m";
+
+/// Colours a unified diff with ANSI escape codes: file headers in bold, hunk headers in cyan,
+/// additions in green and deletions in red. Context lines are left as they are.
+pub fn color_diff(diff: &str) -> String {
+    let mut colored = String::with_capacity(diff.len());
+    // Until the first hunk, lines starting with "---" or "+++" are file headers, rather than a
+    // deletion or addition
+    let mut in_hunk = false;
+
+    for line in diff.split_inclusive('\n') {
+        let color = if line.starts_with("@@") {
+            in_hunk = true;
+            ANSI_CYAN
+        } else if !in_hunk {
+            ANSI_BOLD
+        } else if line.starts_with('+') {
+            ANSI_GREEN
+        } else if line.starts_with('-') {
+            ANSI_RED
+        } else {
+            colored.push_str(line);
+            continue;
+        };
+
+        // Reset before the newline, so that a pager never carries the colour over
+        let (content, newline) = line
+            .strip_suffix('\n')
+            .map_or((line, ""), |content| (content, "\n"));
+        colored.push_str(&format!("{color}{content}{ANSI_RESET}{newline}"));
+    }
+
+    colored
+}
+
+/// The closing line of `format --check`, e.g., "3 files checked, 1 would be reformatted"
+pub fn summary(checked: usize, reformatted: usize, failed: usize) -> String {
+    let files = if checked == 1 { "file" } else { "files" };
+    let mut summary = format!("{checked} {files} checked, {reformatted} would be reformatted");
+    if failed > 0 {
+        summary.push_str(&format!(", {failed} could not be checked"));
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use topiary_config::Configuration;
+
+    use super::{color_diff, skip_idempotence, summary, unified_diff};
+    use crate::cli::{Cli, Commands};
+
+    fn skip_idempotence_flag(args: &[&str]) -> Option<bool> {
+        let cli = Cli::try_parse_from(["topiary", "format"].iter().chain(args)).unwrap();
+        match cli.command {
+            Commands::Format {
+                skip_idempotence, ..
+            } => skip_idempotence,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn skip_idempotence_flag_overrides_language_default() {
+        let config = Configuration::default();
+        let checked = config.get_language("json").unwrap().clone();
+        let mut skipped = checked.clone();
+        skipped.config.skip_idempotence = true;
+
+        let unset = skip_idempotence_flag(&["file.json"]);
+        assert_eq!(unset, None);
+        assert!(!skip_idempotence(unset, &checked));
+        assert!(skip_idempotence(unset, &skipped));
+
+        let set = skip_idempotence_flag(&["--skip-idempotence", "file.json"]);
+        assert_eq!(set, Some(true));
+        assert!(skip_idempotence(set, &checked));
+
+        let unset_explicitly = skip_idempotence_flag(&["--skip-idempotence=false", "file.json"]);
+        assert_eq!(unset_explicitly, Some(false));
+        assert!(!skip_idempotence(unset_explicitly, &skipped));
+    }
+
+    #[test]
+    fn summary_counts_each_outcome() {
+        assert_eq!(summary(1, 0, 0), "1 file checked, 0 would be reformatted");
+        assert_eq!(
+            summary(3, 1, 1),
+            "3 files checked, 1 would be reformatted, 1 could not be checked"
+        );
+    }
+
+    #[test]
+    fn unified_diff_has_file_and_hunk_headers() {
+        let original = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let formatted = "a\nB\nc\nd\ne\nf\ng\nh\ni\nJ\n";
+
+        assert_eq!(
+            unified_diff("src/file.txt", original, formatted),
+            "--- a/src/file.txt\n\
+             +++ b/src/file.txt\n\
+             @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -7,4 +7,4 @@\n g\n h\n i\n-j\n+J\n"
+        );
+    }
+
+    #[test]
+    fn color_diff_colors_each_kind_of_line() {
+        let diff = unified_diff("file.txt", "a\nb\n", "a\nB\n");
+
+        assert_eq!(
+            color_diff(&diff),
+            "\x1b[1m--- a/file.txt\x1b[0m\n\
+             \x1b[1m+++ b/file.txt\x1b[0m\n\
+             \x1b[36m@@ -1,2 +1,2 @@\x1b[0m\n a\n\
+             \x1b[31m-b\x1b[0m\n\
+             \x1b[32m+B\x1b[0m\n"
+        );
+    }
+}
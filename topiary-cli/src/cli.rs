@@ -3,11 +3,11 @@
 use clap::{ArgAction, ArgGroup, Args, CommandFactory, Parser, Subcommand};
 use clap_complete::{generate, shells::Shell};
 use rootcause::{report, report_collection::ReportCollection};
-use std::{io::stdout, path::PathBuf};
+use std::{io::stdout, num::NonZeroUsize, path::PathBuf};
 
 use log::LevelFilter;
 
-use crate::{error::CLIResult, fs, visualisation};
+use crate::{check, error::CLIResult, fs, visualisation};
 
 #[derive(Debug, Parser)]
 // NOTE Don't use infer_subcommands, as that could fossilise the interface. We define explicit
@@ -57,25 +57,29 @@ pub struct GlobalArgs {
 // NOTE This abstraction is largely to workaround clap-rs/clap#4707
 #[derive(Args, Debug)]
 pub struct FromStdin {
-    /// Topiary language identifier (when formatting stdin)
+    /// Topiary language identifier (when formatting stdin, or to override detection from file
+    /// extensions)
     #[arg(short, long)]
-    pub language: String,
+    pub language: Option<String>,
 
-    /// Topiary query file override (when formatting stdin)
-    #[arg(short, long, requires = "language")]
+    /// Topiary query file override (for the given or detected language)
+    #[arg(short, long)]
     pub query: Option<PathBuf>,
 }
 
 // Subtype for exactly one input:
-// * FILE       => Read input from disk, visualisation output to stdout
-// * --language => Read input from stdin, visualisation output to stdout
+// * FILE                 => Read input from disk, visualisation output to stdout
+// * --language           => Read input from stdin, visualisation output to stdout
+// * FILE with --language => Read input from disk, as the given language
+// * --query              => Format with the given query, rather than the language's
+// * --stdin-filename     => Read input from stdin, as though it were the given file
 #[derive(Args, Debug)]
 #[command(
-    // Require exactly one of --language, or FILES...
+    // Require at least one of --language, --stdin-filename, or FILE
     group = ArgGroup::new("source")
-        .multiple(false)
+        .multiple(true)
         .required(true)
-        .args(&["language", "file"])
+        .args(&["language", "stdin_filename", "file"])
 )]
 pub struct ExactlyOneInput {
     #[command(flatten)]
@@ -86,18 +90,28 @@ pub struct ExactlyOneInput {
     /// Language detection and query selection is automatic, mapped from file extensions defined in
     /// the Topiary configuration.
     pub file: Option<PathBuf>,
+
+    /// Filename of the input read from stdin (used in diagnostics and, unless --language is given,
+    /// for language detection)
+    #[arg(long, conflicts_with = "file")]
+    pub stdin_filename: Option<PathBuf>,
 }
 
 // Subtype for at least one input
-// * FILES...   => Read input(s) from disk, format in place
-// * --language => Read input from stdin, output to stdout
+// * FILES...                 => Read input(s) from disk, format in place
+// * --language               => Read input from stdin, output to stdout
+// * FILES... with --language => Read input(s) from disk, as the given language, format in place
+// * --query                  => Format with the given query, rather than the language's
+// * --stdin-filename         => Read input from stdin, as though it were the given file, output to
+//                               stdout
+// * --files-from             => Read a list of input files from disk or stdin, format in place
 #[derive(Args, Debug)]
 #[command(
-    // Require exactly one of --language, --query, or FILES...
+    // Require at least one of --language, --stdin-filename, --files-from, or FILES...
     group = ArgGroup::new("source")
-        .multiple(false)
+        .multiple(true)
         .required(true)
-        .args(&["language", "files"])
+        .args(&["language", "stdin_filename", "files_from", "files"])
 )]
 pub struct AtLeastOneInput {
     #[command(flatten)]
@@ -109,9 +123,32 @@ pub struct AtLeastOneInput {
     /// the Topiary configuration.
     pub files: Vec<PathBuf>,
 
+    /// Filename of the input read from stdin (used in diagnostics and, unless --language is given,
+    /// for language detection)
+    #[arg(long, conflicts_with_all = ["files", "files_from"])]
+    pub stdin_filename: Option<PathBuf>,
+
+    /// Read input files and directories, one per line, from this file ("-" for stdin), in
+    /// addition to FILES...
+    #[arg(long, value_name = "LIST")]
+    pub files_from: Option<PathBuf>,
+
     /// Follow symlinks (when formatting files)
     #[arg(short = 'L', long)]
     pub follow_symlinks: bool,
+
+    /// Maximum number of files to process concurrently (defaults to the number of CPUs)
+    #[arg(short, long)]
+    pub jobs: Option<NonZeroUsize>,
+
+    /// Stop at the first input that fails, rather than processing all of them and reporting every
+    /// failure
+    #[arg(long, overrides_with = "no_fail_fast")]
+    pub fail_fast: bool,
+
+    /// Process all inputs, even after one fails (the default; overrides --fail-fast)
+    #[arg(long, overrides_with = "fail_fast")]
+    pub no_fail_fast: bool,
 }
 
 // NOTE When changing the subcommands, please update verify-documented-usage.sh respectively.
@@ -120,17 +157,90 @@ pub enum Commands {
     /// Format inputs
     #[command(alias = "fmt", display_order = 1)]
     Format {
-        /// Verify inputs are already formatted (exit non-zero with diff if not)
+        /// Verify inputs are already formatted (exit non-zero, listing those that are not)
         #[arg(short = 'c', long)]
         check: bool,
 
+        /// Print a unified diff of the changes, rather than writing them (with --check, show
+        /// how each unformatted input would change)
+        #[arg(long)]
+        diff: bool,
+
+        /// When to colour diffs
+        #[arg(long, value_name = "WHEN", default_value = "auto")]
+        color: check::Color,
+
         /// Consume as much as possible in the presence of parsing errors
         #[arg(short, long)]
         tolerate_parsing_errors: bool,
 
-        /// Do not check that formatting twice gives the same output
-        #[arg(short, long)]
-        skip_idempotence: bool,
+        /// Do not check that formatting twice gives the same output (by default, as configured for
+        /// each language; pass `--skip-idempotence=false` to check regardless)
+        #[arg(
+            short,
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "true"
+        )]
+        skip_idempotence: Option<bool>,
+
+        /// Fail on warnings, such as a query pattern that matches nothing in an input or a
+        /// capture name that Topiary does not recognise, rather than logging them
+        #[arg(long)]
+        strict: bool,
+
+        /// Write the formatted input to this file ("-" for stdout), rather than in place, leaving
+        /// the input untouched (only with a single input)
+        #[arg(short, long, value_name = "PATH", conflicts_with_all = ["check", "diff"])]
+        output: Option<PathBuf>,
+
+        /// Take the indentation and line endings of inputs from the nearest .editorconfig files
+        /// (the indentation only where the Topiary configuration does not set it)
+        #[arg(long)]
+        editorconfig: bool,
+
+        /// When formatting an input is not idempotent, shrink it to a small part that still is
+        /// not, and report that part along with the error
+        #[arg(long)]
+        minimize: bool,
+
+        /// Print how long each stage of formatting took, for each input and in total, to stderr
+        #[arg(long, conflicts_with_all = ["check", "diff"])]
+        time: bool,
+
+        /// Only format the nodes on the lines of each input that changed since a git revision
+        /// (see --since), leaving the rest as it is; inputs that git does not track are formatted
+        /// in full
+        #[arg(long, conflicts_with_all = ["check", "diff", "minimize", "time"])]
+        changed_only: bool,
+
+        /// The git revision against which --changed-only finds the changed lines
+        #[arg(
+            long,
+            value_name = "REF",
+            default_value = "HEAD",
+            requires = "changed_only"
+        )]
+        since: String,
+
+        /// Only format the nodes of these kinds (e.g., function_definition), along with everything
+        /// within them, leaving the rest of each input as it is
+        #[arg(
+            long,
+            value_name = "KIND",
+            value_delimiter = ',',
+            conflicts_with_all = ["check", "diff", "minimize", "time", "changed_only"]
+        )]
+        only_kinds: Vec<String>,
+
+        /// Keep the whitespace of inputs wherever the formatting differs from it only in amount,
+        /// such as the spaces that align code (experimental)
+        #[arg(
+            long,
+            conflicts_with_all = ["check", "diff", "minimize", "time", "changed_only"]
+        )]
+        preserve_whitespace: bool,
 
         #[command(flatten)]
         inputs: AtLeastOneInput,
@@ -147,6 +257,15 @@ pub enum Commands {
         #[arg(short, long, default_value = "dot")]
         format: visualisation::Format,
 
+        /// Omit anonymous nodes (e.g., punctuation and keywords) from the tree
+        #[arg(long)]
+        named_only: bool,
+
+        /// Annotate the nodes at which the formatting query begins or ends a scope with whether
+        /// that scope is single-line or multi-line
+        #[arg(long)]
+        scopes: bool,
+
         #[command(flatten)]
         input: ExactlyOneInput,
     },
@@ -195,6 +314,16 @@ pub enum Commands {
 pub enum ConfigCommand {
     /// Display config sources that Topiary looks through
     ShowSources,
+
+    /// Display the effective settings of a language, along with the config source that set each
+    Explain {
+        /// Topiary language identifier
+        language: String,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 /// Parse CLI arguments and normalise them for the caller
@@ -236,6 +365,7 @@ pub fn get_args() -> CLIResult<Cli> {
             inputs:
                 AtLeastOneInput {
                     files,
+                    files_from,
                     follow_symlinks,
                     ..
                 },
@@ -245,11 +375,16 @@ pub fn get_args() -> CLIResult<Cli> {
             inputs:
                 AtLeastOneInput {
                     files,
+                    files_from,
                     follow_symlinks,
                     ..
                 },
             ..
         } => {
+            if let Some(list) = files_from {
+                files.extend(fs::read_file_list(list)?);
+            }
+
             let mut errs = ReportCollection::new();
             // If we're given a list of FILES... then we assume them to all be on disk, even if "-"
             // is passed as an argument (i.e., interpret this as a valid filename, rather than as
@@ -297,6 +432,36 @@ pub fn get_args() -> CLIResult<Cli> {
         _ => {}
     }
 
+    // There is nowhere to write the formatting of more than one input to a single --output
+    if let Commands::Format {
+        output: Some(_),
+        inputs: AtLeastOneInput { files, .. },
+        ..
+    } = &args.command
+        && files.len() > 1
+    {
+        return Err(
+            report!("Cannot write {} inputs to a single output", files.len()).attach(
+                "please provide a single input with --output, or omit it to format in place.",
+            ),
+        );
+    }
+
+    // Only the files on disk have lines that git can tell have changed
+    if let Commands::Format {
+        changed_only: true,
+        inputs: AtLeastOneInput {
+            files, files_from, ..
+        },
+        ..
+    } = &args.command
+        && files.is_empty()
+        && files_from.is_none()
+    {
+        return Err(report!("Cannot find the changed lines of standard input")
+            .attach("please provide the input files with --changed-only."));
+    }
+
     Ok(args)
 }
 
@@ -0,0 +1,148 @@
+//! Indentation and line endings from `.editorconfig` files (see <https://editorconfig.org>).
+
+use std::path::Path;
+
+use ec4rs::property::{EndOfLine, IndentSize, IndentStyle, TabWidth};
+use topiary_core::LineEnding;
+
+/// The settings of an input that are taken from `.editorconfig` files
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EditorConfig {
+    /// The indentation string, from `indent_style` and `indent_size`
+    pub indent: Option<String>,
+
+    /// The line ending, from `end_of_line`
+    pub line_ending: Option<LineEnding>,
+}
+
+impl EditorConfig {
+    /// Reads the settings that apply to the file at `path` from the `.editorconfig` files in its
+    /// directory and those above it, up to the first that declares `root = true`. Properties from
+    /// nearer files take precedence, as do those of later sections within a file. Should the
+    /// files not be read, no settings are taken from them, with a warning.
+    pub fn for_file(path: &Path) -> Self {
+        let Ok(absolute) = std::path::absolute(path) else {
+            return Self::default();
+        };
+
+        let properties = match ec4rs::properties_of(&absolute) {
+            Ok(properties) => properties,
+            Err(error) => {
+                log::warn!(
+                    "Could not read the .editorconfig files of {}: {error}",
+                    path.display()
+                );
+                return Self::default();
+            }
+        };
+
+        Self::from_properties(&properties)
+    }
+
+    fn from_properties(properties: &ec4rs::Properties) -> Self {
+        let indent = match properties.get::<IndentStyle>() {
+            Ok(IndentStyle::Tabs) => Some("\t".to_string()),
+            Ok(IndentStyle::Spaces) => match properties.get::<IndentSize>() {
+                Ok(IndentSize::Value(size)) => Some(size),
+                Ok(IndentSize::UseTabWidth) => match properties.get::<TabWidth>() {
+                    Ok(TabWidth::Value(size)) => Some(size),
+                    _ => None,
+                },
+                _ => None,
+            }
+            .map(|size| " ".repeat(size)),
+            _ => None,
+        };
+
+        let line_ending = match properties.get::<EndOfLine>() {
+            Ok(EndOfLine::Lf) => Some(LineEnding::Lf),
+            Ok(EndOfLine::CrLf) => Some(LineEnding::CrLf),
+            // Topiary cannot end lines with a lone carriage return
+            _ => None,
+        };
+
+        Self {
+            indent,
+            line_ending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn nearer_files_and_later_sections_take_precedence() {
+        let root = TempDir::new().unwrap();
+        let project = root.path().join("project");
+        fs::create_dir(&project).unwrap();
+        fs::write(
+            root.path().join(".editorconfig"),
+            "[*]\nindent_style = tab\nend_of_line = crlf\n",
+        )
+        .unwrap();
+        fs::write(
+            project.join(".editorconfig"),
+            "[*]\nindent_style = space\nindent_size = 4\n\n[*.json]\nindent_size = 2\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            EditorConfig::for_file(&project.join("a.json")),
+            EditorConfig {
+                indent: Some("  ".into()),
+                line_ending: Some(LineEnding::CrLf),
+            }
+        );
+        assert_eq!(
+            EditorConfig::for_file(&project.join("a.toml")).indent,
+            Some("    ".into())
+        );
+        assert_eq!(
+            EditorConfig::for_file(&root.path().join("a.json")).indent,
+            Some("\t".into())
+        );
+
+        // Files above the root are not read
+        fs::write(
+            project.join(".editorconfig"),
+            "root = true\n[*]\nindent_style = space\nindent_size = 2\n",
+        )
+        .unwrap();
+        assert_eq!(
+            EditorConfig::for_file(&project.join("a.json")),
+            EditorConfig {
+                indent: Some("  ".into()),
+                line_ending: None,
+            }
+        );
+    }
+
+    #[test]
+    fn unset_properties_are_removed() {
+        let settings = |properties: &str| {
+            let dir = TempDir::new().unwrap();
+            fs::write(
+                dir.path().join(".editorconfig"),
+                format!("root = true\n[*]\n{properties}"),
+            )
+            .unwrap();
+            EditorConfig::for_file(&dir.path().join("a.json"))
+        };
+
+        assert_eq!(
+            settings("indent_style = space\nindent_size = unset\n").indent,
+            None
+        );
+        assert_eq!(
+            settings("indent_style = space\nindent_size = tab\ntab_width = 3\n").indent,
+            Some("   ".into())
+        );
+        assert_eq!(settings("end_of_line = cr\n").line_ending, None);
+    }
+}
@@ -10,7 +10,6 @@ use topiary_config::error::{TopiaryConfigError, TopiaryConfigFetchingError as Fe
 
 use nickel_lang_core::error::report::{ColorOpt, report_as_str};
 
-use similar::TextDiff;
 use topiary_core::FormatterError;
 
 /// A convenience wrapper around `std::result::Result<T, TopiaryError>`.
@@ -27,9 +26,11 @@ pub enum TopiaryError {
     /// Formatting check failed: input is not already formatted
     CheckFailed {
         source_name: String,
-        original: String,
-        formatted: String,
+        /// How the input would change, if requested
+        diff: Option<String>,
     },
+    /// Formatting with --strict found a query pattern that matches nothing
+    Strict,
 }
 
 impl fmt::Display for TopiaryError {
@@ -39,21 +40,14 @@ impl fmt::Display for TopiaryError {
             Self::UnsupportedLanguage(name) => {
                 write!(f, "The specified language is unsupported: {name}")
             }
-            Self::CheckFailed {
-                source_name,
-                original,
-                formatted,
-            } => {
-                writeln!(f, "{source_name} is not formatted")?;
-                let diff = TextDiff::from_lines(original, formatted);
-                write!(
-                    f,
-                    "Diff in {source_name}:\n{}",
-                    diff.unified_diff()
-                        .context_radius(3)
-                        .header("original", "formatted")
-                )
+            Self::CheckFailed { source_name, diff } => {
+                write!(f, "{source_name} would be reformatted")?;
+                match diff {
+                    Some(diff) => write!(f, "\nDiff in {source_name}:\n{diff}"),
+                    None => Ok(()),
+                }
             }
+            Self::Strict => write!(f, "Formatting failed with --strict"),
             Self::Io => {
                 write!(f, "I/O Error")
             }
@@ -87,9 +81,17 @@ where
     let mut code = 10;
     for rep in r.iter_reports() {
         if let Some(collection) = rep.downcast_current_context::<ReportCollection>() {
-            // Multiple errors: Exit 9
             if collection.len() > 1 {
-                code = 9;
+                // Check mode detected several unformatted files, and nothing else: Exit 1
+                // Multiple errors: Exit 9
+                code = if collection
+                    .iter()
+                    .all(|r| is_check_failure(r.into_uncloneable()))
+                {
+                    1
+                } else {
+                    9
+                };
                 break;
             }
             if let Some(only) = collection.iter().next() {
@@ -100,13 +102,13 @@ where
         if let Some(e) = rep.downcast_current_context::<FormatterError>() {
             code = match e {
                 // I/O errors: Exit 3
-                FormatterError::Io => 3,
+                FormatterError::Io | FormatterError::GrammarLoad(_) => 3,
                 // Query errors: Exit 4
                 FormatterError::Query(_) => 4,
                 // Parsing errors: Exit 5
-                FormatterError::Parsing => 5,
+                FormatterError::Parsing(_) => 5,
                 // Idempotency errors: Exit 7
-                FormatterError::Idempotence => 7,
+                FormatterError::Idempotence(_) => 7,
                 // Idempotency parsing errors: Exit 8
                 FormatterError::IdempotenceParsing => 8,
                 _ => 10,
@@ -126,6 +128,8 @@ where
                 TopiaryError::CheckFailed { .. } => 1,
                 // I/O errors: Exit 3
                 TopiaryError::Io => 3,
+                // Patterns that match nothing with --strict are query errors: Exit 4
+                TopiaryError::Strict => 4,
                 // Anything else: Exit 10
                 _ => 10,
             };
@@ -155,11 +159,17 @@ fn benign_inner<C>(r: ReportRef<'_, C, Uncloneable>) -> bool
 where
     C: ?Sized,
 {
+    // A pattern that does not match is only the expected result of a coverage check
+    if iter_downcast_reports::<TopiaryError, _>(r).any(|e| matches!(e, TopiaryError::Strict)) {
+        return false;
+    }
+
     let serious_err_in_collections = iter_downcast_reports::<ReportCollection, _>(r)
         .flat_map(|c| c.iter())
         .any(|r| {
             r.downcast_current_context::<FormatterError>()
                 != Some(&FormatterError::PatternDoesNotMatch)
+                && !is_quiet_check_failure(r.into_uncloneable())
         });
     if serious_err_in_collections {
         return false;
@@ -167,6 +177,66 @@ where
 
     iter_downcast_reports::<FormatterError, _>(r)
         .any(|fmt_err| *fmt_err == FormatterError::PatternDoesNotMatch)
+        || is_quiet_check_failure(r)
+        || iter_downcast_reports::<ReportCollection, _>(r)
+            .flat_map(|c| c.iter())
+            .any(|r| is_quiet_check_failure(r.into_uncloneable()))
+}
+
+/// The `CheckFailed` error of a report, if it is about an input that `format --check` found to be
+/// unformatted
+fn check_failure<C>(r: ReportRef<'_, C, Uncloneable>) -> Option<&TopiaryError>
+where
+    C: ?Sized,
+{
+    iter_downcast_reports::<TopiaryError, _>(r)
+        .find(|e| matches!(e, TopiaryError::CheckFailed { .. }))
+}
+
+fn is_check_failure<C>(r: ReportRef<'_, C, Uncloneable>) -> bool
+where
+    C: ?Sized,
+{
+    check_failure(r).is_some()
+}
+
+// Unformatted inputs are already listed by the check summary, so they only warrant a message of
+// their own when they come with a diff
+fn is_quiet_check_failure<C>(r: ReportRef<'_, C, Uncloneable>) -> bool
+where
+    C: ?Sized,
+{
+    matches!(
+        check_failure(r),
+        Some(TopiaryError::CheckFailed { diff: None, .. })
+    )
+}
+
+/// Splits the failures of `format --check` into the names of the inputs that would be
+/// reformatted, and the number of inputs that could not be checked at all (e.g., because they
+/// failed to parse)
+pub(crate) fn check_outcomes(r: &Report) -> (Vec<&str>, usize) {
+    let r = r.as_ref().into_uncloneable();
+    let failures: Vec<_> = match r.downcast_current_context::<ReportCollection>() {
+        Some(collection) => collection
+            .iter()
+            .map(|r| check_failure(r.into_uncloneable()))
+            .collect(),
+        None => vec![check_failure(r)],
+    };
+
+    let mut reformatted = Vec::new();
+    let mut failed = 0;
+    for failure in failures {
+        match failure {
+            Some(TopiaryError::CheckFailed { source_name, .. }) => {
+                reformatted.push(source_name.as_str())
+            }
+            _ => failed += 1,
+        }
+    }
+
+    (reformatted, failed)
 }
 
 impl From<&TopiaryConfigError> for TopiaryError {
@@ -223,6 +293,17 @@ where
     }
 }
 
+/// Marks the failure of an input formatted with --strict because a query pattern matched nothing
+/// in it, so that it is reported as an error, rather than passing for the negative result of a
+/// coverage check. Other reports are returned as they are.
+pub(crate) fn strict_failure(report: Report<FormatterError>) -> Report<Dynamic> {
+    if *report.current_context() == FormatterError::PatternDoesNotMatch {
+        return report.context(TopiaryError::Strict).into_dynamic();
+    }
+
+    report.into_dynamic()
+}
+
 fn iter_downcast_reports<T: 'static, C>(
     report: ReportRef<'_, C, Uncloneable>,
 ) -> impl Iterator<Item = &T>
@@ -244,14 +325,48 @@ mod tests {
     fn check_exits_1() {
         let report = report!(TopiaryError::CheckFailed {
             source_name: "source".to_string(),
-            original: "original".to_string(),
-            formatted: "formatted".to_string()
+            diff: Some("diff".to_string()),
         })
         .into_dynamic();
         // check failed error -> exit code 1
         assert_eq!(exit_code(&report), 1.into());
     }
 
+    #[test]
+    fn check_outcomes_distinguish_parse_failures() {
+        let check_failed = |source_name: &str| {
+            report!(TopiaryError::CheckFailed {
+                source_name: source_name.to_string(),
+                diff: None,
+            })
+            .into_dynamic()
+            .into_cloneable()
+        };
+        let collection = report!(ReportCollection::from_iter(vec![
+            check_failed("a"),
+            check_failed("b")
+        ]))
+        .into_dynamic();
+
+        // only unformatted inputs -> listed, not printed, exit code 1
+        assert!(collection.benign());
+        assert_eq!(check_outcomes(&collection), (vec!["a", "b"], 0));
+        assert_eq!(exit_code(&collection), 1.into());
+
+        // unformatted inputs and a parsing error -> exit code 9
+        let collection = report!(ReportCollection::from_iter(vec![
+            check_failed("a"),
+            check_failed("b"),
+            report!(FormatterError::Parsing(None))
+                .into_dynamic()
+                .into_cloneable(),
+        ]))
+        .into_dynamic();
+        assert!(!collection.benign());
+        assert_eq!(check_outcomes(&collection), (vec!["a", "b"], 1));
+        assert_eq!(exit_code(&collection), 9.into());
+    }
+
     #[test]
     fn preformat_context_nested_io_exits_3() {
         let err: Result<(), TopiaryConfigError> = Err(TopiaryConfigError::Fetching(
@@ -261,6 +376,12 @@ mod tests {
         assert_eq!(exit_code(&report), 3.into());
     }
 
+    #[test]
+    fn grammar_load_exits_3() {
+        let report = report!(FormatterError::GrammarLoad("no grammar".to_string())).into_dynamic();
+        assert_eq!(exit_code(&report), 3.into());
+    }
+
     #[test]
     fn preformat_context_exit_code_10() {
         let err: Result<(), TopiaryConfigError> = Err(TopiaryConfigError::UnknownLanguage(
@@ -270,6 +391,19 @@ mod tests {
         assert_eq!(exit_code(&report), 10.into());
     }
 
+    #[test]
+    fn strict_failure_is_not_benign() {
+        let coverage_err = report!(FormatterError::PatternDoesNotMatch).into_dynamic();
+        assert!(coverage_err.benign());
+
+        let strict_err = strict_failure(report!(FormatterError::PatternDoesNotMatch));
+        assert!(!strict_err.benign());
+        assert_eq!(exit_code(&strict_err), 4.into());
+
+        let parsing_err = strict_failure(report!(FormatterError::Parsing(None)));
+        assert_eq!(exit_code(&parsing_err), 5.into());
+    }
+
     #[test]
     fn iter_downcast_exit_code() {
         let benign_err = report!(FormatterError::PatternDoesNotMatch).into_dynamic();
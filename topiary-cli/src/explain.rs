@@ -0,0 +1,65 @@
+//! Explanation of a language's effective configuration, with the provenance of each setting.
+
+use std::path::PathBuf;
+
+use serde_json::{Value, json};
+use tabled::{Table, settings::Style};
+use topiary_config::{Configuration, Provenance, source::Source};
+
+use crate::error::{CLIResult, ResultPreformat};
+
+/// Print each setting of `language` in the effective configuration, along with the source that
+/// set it, as a table or (with `json`) as a JSON array.
+///
+/// The values are those of `config`, which is what Topiary formats with. Their sources are found
+/// by layering the configuration sources in effect, given `merge` and the explicit configuration
+/// `file`; settings that no source sets are attributed to `<default>`.
+pub fn explain(
+    config: &Configuration,
+    merge: bool,
+    file: &Option<PathBuf>,
+    language: &str,
+    json: bool,
+) -> CLIResult<()> {
+    let values = serde_json::to_value(&config.get_language(language).preformat_context()?.config)
+        .expect("Language configurations should always serialise");
+    let (_, provenance) =
+        Configuration::from_layers(&Source::layers(merge, file)).preformat_context()?;
+
+    let settings: Vec<(&str, &Value, String)> = Provenance::SETTINGS
+        .iter()
+        .filter_map(|setting| {
+            // Nested settings, such as `grammar.source`, are named by their path
+            let value = setting
+                .split('.')
+                .try_fold(&values, |value, key| value.get(key))?;
+            let source = provenance
+                .source_of(language, setting)
+                .map_or_else(|| "<default>".to_string(), ToString::to_string);
+
+            Some((*setting, value, source))
+        })
+        .collect();
+
+    if json {
+        let explained: Vec<Value> = settings
+            .into_iter()
+            .map(|(setting, value, source)| {
+                json!({ "setting": setting, "value": value, "source": source })
+            })
+            .collect();
+
+        println!("{}", Value::Array(explained));
+    } else {
+        let rows = settings
+            .into_iter()
+            .map(|(setting, value, source)| (setting, value.to_string(), source));
+
+        let mut table = Table::builder(rows);
+        table.remove_record(0);
+        table.insert_record(0, ["setting", "value", "source"]);
+        println!("{}", table.build().with(Style::modern_rounded()));
+    }
+
+    Ok(())
+}
@@ -2,7 +2,7 @@ use rootcause::{report, report_collection::ReportCollection};
 
 use crate::error::CLIResult;
 use std::{
-    fs,
+    fs, io,
     path::{Path, PathBuf},
 };
 
@@ -100,6 +100,26 @@ impl FileMeta {
     }
 }
 
+/// Read a list of paths, one per line, from the given file, or from stdin if it is "-". Blank lines
+/// are ignored; relative paths are left as they are, to be resolved against the working directory.
+pub fn read_file_list(list: &Path) -> CLIResult<Vec<PathBuf>> {
+    let content = if list == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(list).map_err(|e| {
+            report!(e)
+                .attach(format!("{}", list.display()))
+                .into_dynamic()
+        })?
+    };
+
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
 /// Given a vector of paths, recursively expand those that identify as directories, in place.
 /// Follow symlinks, if specified, and skip over files with multiple links. Ultimately, we'll
 /// finish with a vector of canonical paths to real files with a single link.
@@ -0,0 +1,97 @@
+//! The lines of inputs that changed since a git revision, for `format --changed-only`.
+
+use std::{ops::Range, path::Path, process::Command};
+
+use rootcause::report;
+
+use crate::error::CLIResult;
+
+/// The lines of the file at `path` that changed since the git revision `since`, numbered from
+/// zero, as found by `git diff` in the file's directory. Uncommitted changes, whether staged or
+/// not, are included. Git does not track a new file, all of whose lines have changed, which is
+/// `None`.
+pub fn changed_lines(path: &Path, since: &str) -> CLIResult<Option<Vec<Range<usize>>>> {
+    let path = std::path::absolute(path)?;
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(report!(
+            "Cannot find the changed lines of {}",
+            path.display()
+        ));
+    };
+
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .arg("--")
+            .arg(name)
+            .output()
+            .map_err(|e| report!(e).attach("Failed to run git").into_dynamic())
+    };
+
+    let diff = git(&["diff", "--no-ext-diff", "--no-color", "--unified=0", since])?;
+    if !diff.status.success() {
+        return Err(report!(
+            "Cannot find the changed lines of {} since {since}",
+            path.display()
+        )
+        .attach(String::from_utf8_lossy(&diff.stderr).trim().to_string()));
+    }
+
+    let lines = hunk_lines(&String::from_utf8_lossy(&diff.stdout));
+    if lines.is_empty() && !git(&["ls-files", "--error-unmatch"])?.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(lines))
+}
+
+/// The lines that the hunks of a unified diff (without context) add to the new file, numbered from
+/// zero. Hunks that only remove lines add none.
+fn hunk_lines(diff: &str) -> Vec<Range<usize>> {
+    diff.lines()
+        .filter_map(|line| {
+            // A hunk header reads "@@ -start[,count] +start[,count] @@ ...", with a count of one
+            // if omitted
+            let new = line
+                .strip_prefix("@@ -")?
+                .split(' ')
+                .nth(1)?
+                .strip_prefix('+')?;
+            let (start, count) = match new.split_once(',') {
+                Some((start, count)) => (start.parse::<usize>().ok()?, count.parse().ok()?),
+                None => (new.parse().ok()?, 1),
+            };
+
+            // A hunk that adds no lines is numbered after the line it follows
+            (count > 0).then(|| start - 1..start - 1 + count)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::hunk_lines;
+
+    #[test]
+    fn hunk_lines_are_the_added_lines() {
+        let diff = "\
+diff --git a/input.json b/input.json
+index 1234567..89abcde 100644
+--- a/input.json
++++ b/input.json
+@@ -2 +2 @@
+-  \"a\": 1,
++  \"a\":1,
+@@ -5,0 +6,2 @@ {
++  \"c\":3,
++  \"d\":4,
+@@ -9,2 +10,0 @@ {
+-  \"e\": 5,
+-  \"f\": 6,
+";
+
+        assert_eq!(hunk_lines(diff), vec![1..2, 5..7]);
+    }
+}
@@ -1,10 +1,13 @@
 use std::{
-    ffi::OsString,
     fmt::{self, Display},
-    fs::File,
-    io::{self, BufWriter, Read, Seek, Write},
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
 use nickel_lang_core::{
@@ -20,15 +23,17 @@ use rootcause::{
     report_collection::ReportCollection,
 };
 use rootcause_preformat::PreformatReportExt;
-use tempfile::tempfile;
+use tempfile::NamedTempFile;
+use tokio::sync::Semaphore;
 use topiary_config::Configuration;
 use topiary_core::{
-    ErrorSpan, FormatterError, InjectionQuery, Language, Operation, SpanAttachment, TopiaryQuery,
-    formatter,
+    ErrorSpan, FinalNewline, FormatterError, FormattingMarkers, InjectionQuery, Language,
+    LineEnding, Operation, ParsingErrorTolerance, SpanAttachment, TopiaryQuery, formatter,
 };
 
 use crate::{
     cli::{AtLeastOneInput, ExactlyOneInput, FromStdin},
+    editorconfig::EditorConfig,
     error::{CLIResult, ResultPreformat, TopiaryError},
     language::LanguageDefinitionCache,
 };
@@ -92,29 +97,45 @@ impl QuerySource {
 }
 
 /// Unified interface for input sources. We either have input from:
-/// * Standard input, in which case we need to specify the language and, optionally, query override
-/// * A sequence of files
+/// * Standard input, in which case we need to specify the language or a filename from which the
+///   language is detected
+/// * A sequence of files, optionally with a language override, in which case the language is not
+///   detected from the files' extensions
+///
+/// Either may have a query override, which replaces the query of the given or detected language.
 ///
 /// These are captured by the CLI parser, with `cli::AtLeastOneInput` and `cli::ExactlyOneInput`.
 /// We use this struct to normalise the interface for downstream (using `From` implementations).
 pub enum InputFrom {
-    Stdin(String, Option<QuerySource>),
-    Files(Vec<PathBuf>),
+    Stdin(Overrides, Option<PathBuf>),
+    Files(Vec<PathBuf>, Overrides),
+}
+
+/// The language and query overrides given by `--language` and `--query`, if any
+pub type Overrides = (Option<String>, Option<QuerySource>);
+
+fn language_override(stdin: &Option<FromStdin>) -> Overrides {
+    stdin
+        .as_ref()
+        .map_or((None, None), |FromStdin { language, query }| {
+            (language.to_owned(), query.as_ref().map(|p| p.into()))
+        })
 }
 
 impl From<&ExactlyOneInput> for InputFrom {
     fn from(input: &ExactlyOneInput) -> Self {
         match input {
             ExactlyOneInput {
-                stdin: Some(FromStdin { language, query }),
+                stdin,
+                file: Some(path),
                 ..
-            } => InputFrom::Stdin(language.to_owned(), query.as_ref().map(|p| p.into())),
+            } => InputFrom::Files(vec![path.to_owned()], language_override(stdin)),
 
             ExactlyOneInput {
-                file: Some(path), ..
-            } => InputFrom::Files(vec![path.to_owned()]),
-
-            _ => unreachable!("Clap guarantees input is always one of the above"),
+                stdin,
+                stdin_filename,
+                ..
+            } => InputFrom::Stdin(language_override(stdin), stdin_filename.to_owned()),
         }
     }
 }
@@ -123,11 +144,18 @@ impl From<&AtLeastOneInput> for InputFrom {
     fn from(input: &AtLeastOneInput) -> Self {
         match input {
             AtLeastOneInput {
-                stdin: Some(FromStdin { language, query }),
+                stdin,
+                files,
+                files_from: None,
+                stdin_filename,
                 ..
-            } => InputFrom::Stdin(language.to_owned(), query.as_ref().map(|p| p.into())),
+            } if files.is_empty() => {
+                InputFrom::Stdin(language_override(stdin), stdin_filename.to_owned())
+            }
 
-            AtLeastOneInput { files, .. } => InputFrom::Files(files.to_owned()),
+            AtLeastOneInput { stdin, files, .. } => {
+                InputFrom::Files(files.to_owned(), language_override(stdin))
+            }
         }
     }
 }
@@ -136,21 +164,22 @@ impl From<&AtLeastOneInput> for InputFrom {
 /// implementation can do the right thing.
 #[derive(Debug)]
 pub enum InputSource {
-    Stdin,
+    /// Standard input, optionally with the filename given by `--stdin-filename`
+    Stdin(Option<Arc<PathBuf>>),
     Disk(Arc<PathBuf>, Option<File>),
 }
 
 impl InputSource {
     pub fn location(&self) -> InputLocation {
         match self {
-            InputSource::Stdin => InputLocation(None),
+            InputSource::Stdin(path) => InputLocation(path.clone()),
             InputSource::Disk(path, _) => InputLocation(Some(path.clone())),
         }
     }
 
     fn filepath(&self) -> Option<&Path> {
         match self {
-            InputSource::Stdin => None,
+            InputSource::Stdin(path) => path.as_deref().map(PathBuf::as_path),
             InputSource::Disk(path, _) => Some(path.as_ref()),
         }
     }
@@ -159,7 +188,8 @@ impl InputSource {
 impl fmt::Display for InputSource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Stdin => write!(f, "standard input"),
+            Self::Stdin(None) => write!(f, "standard input"),
+            Self::Stdin(Some(path)) => write!(f, "{} (standard input)", path.display()),
             Self::Disk(path, _) => write!(f, "{}", path.display()),
         }
     }
@@ -192,6 +222,7 @@ pub struct InputFile<'cfg> {
     language: &'cfg topiary_config::language::Language,
     pub(crate) formatting_query: QuerySource,
     pub(crate) injection_query: Option<QuerySource>,
+    editorconfig: EditorConfig,
 }
 
 impl InputFile<'_> {
@@ -206,19 +237,43 @@ impl InputFile<'_> {
             }
             None => None,
         };
-        let formatting_query = TopiaryQuery::new(&grammar, &query_contents)
-            .attach_filepath(self.formatting_query.filepath())
-            .context(FormatterError::Parsing)?;
+        let formatting_query = TopiaryQuery::with_node_aliases(
+            &grammar,
+            &query_contents,
+            self.language().node_aliases(),
+        )
+        .attach_filepath(self.formatting_query.filepath())
+        .context(FormatterError::Parsing(None))?;
 
         Ok(Language {
             name: self.language.name.clone(),
             formatting_query,
             injection_query,
             grammar,
-            indent: self.language().indent(),
+            indent: self.indent(),
+            max_blank_lines: self.language().max_blank_lines(),
+            tab_width: self.language().tab_width(),
+            formatting_markers: self
+                .language()
+                .formatting_markers()
+                .map(FormattingMarkers::from),
+            comment_attachment: self.language().into(),
         })
     }
 
+    /// The indentation of the input: that of its language's configuration or, failing that, of
+    /// its `.editorconfig`
+    pub(crate) fn indent(&self) -> Option<String> {
+        self.language()
+            .indent()
+            .or_else(|| self.editorconfig.indent.clone())
+    }
+
+    /// The line ending of the formatted input: that of its `.editorconfig`, if any
+    pub(crate) fn line_ending(&self) -> LineEnding {
+        self.editorconfig.line_ending.unwrap_or(LineEnding::Lf)
+    }
+
     /// Expose input source
     pub fn source(&self) -> &InputSource {
         &self.source
@@ -252,9 +307,10 @@ pub(crate) async fn to_language_from_config<T: AsRef<str>>(
     let grammar = config_language.grammar()?;
     let query_source = to_query_from_language(config_language)?;
     let query_content = query_source.get_content().await?;
-    let formatting_query = TopiaryQuery::new(&grammar, &query_content)
-        .attach_filepath(query_source.filepath())
-        .context(FormatterError::Parsing)?;
+    let formatting_query =
+        TopiaryQuery::with_node_aliases(&grammar, &query_content, config_language.node_aliases())
+            .attach_filepath(query_source.filepath())
+            .context(FormatterError::Parsing(None))?;
     let injection_query = match to_injection_query_from_language(config_language) {
         Some(source) => {
             let contents = source.get_content().await?;
@@ -269,6 +325,12 @@ pub(crate) async fn to_language_from_config<T: AsRef<str>>(
         injection_query,
         grammar,
         indent: config_language.indent(),
+        max_blank_lines: config_language.max_blank_lines(),
+        tab_width: config_language.tab_width(),
+        formatting_markers: config_language
+            .formatting_markers()
+            .map(FormattingMarkers::from),
+        comment_attachment: config_language.into(),
     })
 }
 
@@ -280,9 +342,10 @@ pub(crate) fn to_language_from_config_sync<T: AsRef<str> + fmt::Display>(
     let grammar = config_language.grammar()?;
     let query_source = to_query_from_language(config_language)?;
     let query_content = query_source.get_content_sync()?;
-    let formatting_query = TopiaryQuery::new(&grammar, &query_content)
-        .attach_filepath(query_source.filepath())
-        .context(FormatterError::Parsing)?;
+    let formatting_query =
+        TopiaryQuery::with_node_aliases(&grammar, &query_content, config_language.node_aliases())
+            .attach_filepath(query_source.filepath())
+            .context(FormatterError::Parsing(None))?;
     let injection_query = match to_injection_query_from_language(config_language) {
         Some(source) => {
             let contents = source.get_content_sync()?;
@@ -297,8 +360,15 @@ pub(crate) fn to_language_from_config_sync<T: AsRef<str> + fmt::Display>(
         injection_query,
         grammar,
         indent: config_language.indent(),
+        max_blank_lines: config_language.max_blank_lines(),
+        tab_width: config_language.tab_width(),
+        formatting_markers: config_language
+            .formatting_markers()
+            .map(FormattingMarkers::from),
+        comment_attachment: config_language.into(),
     })
 }
+
 /// Simple helper function to read the full content of an io Read stream
 pub(crate) fn read_input(input: &mut dyn io::Read) -> CLIResult<String> {
     let mut content = String::new();
@@ -309,7 +379,7 @@ pub(crate) fn read_input(input: &mut dyn io::Read) -> CLIResult<String> {
 impl Read for InputFile<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match &mut self.source {
-            InputSource::Stdin => io::stdin().lock().read(buf),
+            InputSource::Stdin(_) => io::stdin().lock().read(buf),
 
             InputSource::Disk(path, fd) => {
                 if fd.is_none() {
@@ -332,12 +402,17 @@ impl<'cfg, 'i> Inputs<'cfg> {
         &'i T: Into<InputFrom>,
     {
         let inputs = match inputs.into() {
-            InputFrom::Stdin(language_name, query) => {
+            InputFrom::Stdin(language_override, filename) => {
                 vec![(|| {
-                    let language = config
-                        .get_language(&language_name)
-                        .map_err(|e| report!(e).preformat())
-                        .context(TopiaryError::Config)?;
+                    let (language_name, query) = language_override;
+                    let language = match language_name {
+                        Some(language_name) => get_language(config, &language_name)?,
+                        // Clap guarantees a filename, when no language is given
+                        None => {
+                            let filename = filename.as_ref().expect("stdin filename");
+                            config.detect(filename).preformat_context()?
+                        }
+                    };
                     let query_source: QuerySource = match query {
                         // The user specified a query file
                         Some(p) => p,
@@ -346,19 +421,31 @@ impl<'cfg, 'i> Inputs<'cfg> {
                     };
                     let injection_query = to_injection_query_from_language(language);
                     Ok(InputFile {
-                        source: InputSource::Stdin,
+                        source: InputSource::Stdin(filename.map(Arc::new)),
                         language,
                         formatting_query: query_source,
                         injection_query,
+                        editorconfig: EditorConfig::default(),
                     })
                 })()]
             }
 
-            InputFrom::Files(files) => files
+            InputFrom::Files(files, language_override) => files
                 .into_iter()
                 .map(|path| {
-                    let language = config.detect(&path).preformat_context()?;
-                    let query: QuerySource = to_query_from_language(language)?;
+                    let (language_name, query) = &language_override;
+                    let language = match language_name {
+                        // The user specified the language
+                        Some(language_name) => get_language(config, language_name)?,
+                        // Otherwise, detect the language from the file extension
+                        None => config.detect(&path).preformat_context()?,
+                    };
+                    let query = match query {
+                        // The user specified a query file
+                        Some(p) => p.clone(),
+                        // The user did not specify a file, try the default locations
+                        None => to_query_from_language(language)?,
+                    };
                     let injection_query = to_injection_query_from_language(language);
 
                     Ok(InputFile {
@@ -366,6 +453,7 @@ impl<'cfg, 'i> Inputs<'cfg> {
                         language,
                         formatting_query: query,
                         injection_query,
+                        editorconfig: EditorConfig::default(),
                     })
                 })
                 .collect(),
@@ -373,6 +461,33 @@ impl<'cfg, 'i> Inputs<'cfg> {
 
         Self(inputs)
     }
+
+    /// Take the settings of each input that has a path from its `.editorconfig` files, if
+    /// `enabled`
+    pub fn with_editorconfig(mut self, enabled: bool) -> Self {
+        if enabled {
+            for input in self.0.iter_mut().flatten() {
+                if let Some(path) = input.filepath() {
+                    input.editorconfig = EditorConfig::for_file(path);
+                }
+            }
+        }
+
+        self
+    }
+}
+
+/// Looks up a language by the name given on the command line
+fn get_language<'cfg>(
+    config: &'cfg Configuration,
+    name: &str,
+) -> CLIResult<&'cfg topiary_config::language::Language> {
+    let language = config
+        .get_language(name)
+        .map_err(|e| report!(e).preformat())
+        .context(TopiaryError::Config)?;
+
+    Ok(language)
 }
 
 pub(crate) fn to_query_from_language(
@@ -428,21 +543,28 @@ impl<'cfg> Iterator for Inputs<'cfg> {
     fn next(&mut self) -> Option<Self::Item> {
         self.0.pop()
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
 }
 
+impl ExactSizeIterator for Inputs<'_> {}
+
 /// An `OutputFile` is the unit of output for Topiary, differentiating between standard output and
 /// disk (which uses temporary files to perform atomic updates in place). It implements
 /// `io::Write`, so it can be passed directly to the Topiary API.
 ///
 /// NOTE When writing to disk, the `persist` function must be called to perform the in place write.
+/// If an `OutputFile` is dropped without being persisted, the output on disk is left untouched.
 #[derive(Debug)]
 pub enum OutputFile {
     Stdout,
     Disk {
-        // NOTE We stage to a file, rather than writing
-        // to memory (e.g., Vec<u8>), to ensure atomicity
-        staged: File,
-        output: OsString,
+        // NOTE We stage to a file in the same directory as the output, rather than writing to
+        // memory (e.g., Vec<u8>), so that it can be renamed over the output atomically
+        staged: NamedTempFile,
+        output: PathBuf,
     },
 }
 
@@ -450,25 +572,60 @@ impl OutputFile {
     pub fn new(path: &str) -> CLIResult<Self> {
         match path {
             "-" => Ok(Self::Stdout),
-            file => Ok(Self::Disk {
-                staged: tempfile().context(TopiaryError::Config)?,
-                output: file.into(),
-            }),
+            file => {
+                // Write through symlinks, rather than replacing them with the formatted file
+                let output = fs::canonicalize(file).unwrap_or_else(|_| file.into());
+                let directory = match output.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+
+                // A new output is given the permissions of any new file (0666, less the umask),
+                // rather than the owner-only ones of a temporary file. An existing one keeps its
+                // own, which are carried over when it is persisted.
+                let mut builder = tempfile::Builder::new();
+                #[cfg(unix)]
+                if !output.exists() {
+                    use std::os::unix::fs::PermissionsExt;
+
+                    builder.permissions(fs::Permissions::from_mode(0o666));
+                }
+
+                Ok(Self::Disk {
+                    staged: builder
+                        .tempfile_in(directory)
+                        .context(TopiaryError::Config)?,
+                    output,
+                })
+            }
         }
     }
 
     // This function must be called to persist the output to disk
     pub fn persist(self) -> CLIResult<()> {
         if let Self::Disk { mut staged, output } = self {
-            // Rewind to the beginning of the staged output
             staged.flush()?;
-            staged.rewind()?;
 
-            // Open the actual output for writing and copy the staged contents
-            let mut writer = File::create(&output)?;
-            let bytes = io::copy(&mut staged, &mut writer)?;
+            // Carry the original's permissions (and, where possible, ownership) over to the
+            // staged output, before it replaces the original
+            if let Ok(metadata) = fs::metadata(&output) {
+                staged.as_file().set_permissions(metadata.permissions())?;
+
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::{MetadataExt, fchown};
 
-            log::debug!("Wrote {bytes} bytes to {}", &output.display());
+                    if let Err(e) =
+                        fchown(staged.as_file(), Some(metadata.uid()), Some(metadata.gid()))
+                    {
+                        log::debug!("Could not preserve ownership of {}: {e}", output.display());
+                    }
+                }
+            }
+
+            staged.persist(&output).map_err(|e| e.error)?;
+
+            log::debug!("Wrote {}", output.display());
         }
 
         Ok(())
@@ -508,7 +665,7 @@ impl TryFrom<&InputFile<'_>> for OutputFile {
 
     fn try_from(input: &InputFile) -> CLIResult<Self> {
         match &input.source {
-            InputSource::Stdin => Ok(Self::Stdout),
+            InputSource::Stdin(_) => Ok(Self::Stdout),
             InputSource::Disk(path, _) => Self::new(path.to_string_lossy().as_ref()),
         }
     }
@@ -627,7 +784,11 @@ pub(crate) async fn format_config(
         &language,
         Operation::Format {
             skip_idempotence: true,
-            tolerate_parsing_errors: false,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
         },
         None,
     )?;
@@ -636,10 +797,14 @@ pub(crate) async fn format_config(
 }
 
 // meant to be used in scenarios where multiple inputs are possible
+// At most `jobs` inputs (by default, one per CPU) are processed at once. Rather than aborting on
+// the first failure, all errors are collected and reported together, in the order of the inputs.
 pub(crate) async fn process_inputs<F>(
     inputs: Inputs<'_>,
     process_fn: F,
     cache: Arc<LanguageDefinitionCache>,
+    jobs: Option<NonZeroUsize>,
+    fail_fast: bool,
 ) -> CLIResult<()>
 where
     F: Fn(InputFile, Arc<Language>, Arc<LanguageDefinitionCache>) -> Result<(), Report>
@@ -648,20 +813,51 @@ where
         + 'static,
     ErrorSpan: ObjectMarkerFor<SendSync>,
 {
+    let jobs = jobs
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get);
+    let semaphore = Semaphore::new(jobs);
+    let failed = AtomicBool::new(false);
+    let several = inputs.len() > 1;
+
     let (_, mut results) = async_scoped::TokioScope::scope_and_block(|scope| {
         for input in inputs {
             let cache = cache.clone();
             let process_fn = &process_fn;
+            let semaphore = &semaphore;
+            let failed = &failed;
             scope.spawn(async move {
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .expect("job semaphore is never closed");
+
+                // With --fail-fast, inputs that are yet to start are skipped once one has failed
+                if fail_fast && failed.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+
                 // This happens when the input resolver cannot establish an input
                 // source, language or query file.
-                let input = input?;
-                let location = input.source().location();
-                tokio::task::block_in_place(|| {
-                    let language = cache.fetch_input(&input)?;
-                    process_fn(input, language, cache)
-                        .map_err(|e| e.attach_filepath(location.to_path()))
-                })
+                let result = input.and_then(|input| {
+                    let source_name = input.source().to_string();
+                    let location = input.source().location();
+                    tokio::task::block_in_place(|| {
+                        let language = cache.fetch_input(&input)?;
+                        process_fn(input, language, cache)
+                    })
+                    .map_err(|e| {
+                        let e = e.attach_filepath(location.to_path());
+                        // Name the input in its error, so as to tell the failures of a
+                        // multi-file run apart
+                        if several { e.attach(source_name) } else { e }
+                    })
+                });
+
+                if result.is_err() {
+                    failed.store(true, Ordering::Release);
+                }
+                result
             });
         }
     });
@@ -682,3 +878,84 @@ where
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn output_in(dir: &TempDir, name: &str, original: &str) -> (PathBuf, OutputFile) {
+        let path = dir.path().join(name);
+        fs::write(&path, original).unwrap();
+        let output = OutputFile::new(path.to_str().unwrap()).unwrap();
+
+        (path, output)
+    }
+
+    fn directory_entries(dir: &TempDir) -> usize {
+        fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[test]
+    fn persist_replaces_output() {
+        let dir = TempDir::new().unwrap();
+        let (path, mut output) = output_in(&dir, "file.json", "{ }");
+
+        writeln!(output, "{{}}").unwrap();
+        output.persist().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}\n");
+        assert_eq!(directory_entries(&dir), 1);
+    }
+
+    #[test]
+    fn failed_write_leaves_output_intact() {
+        let dir = TempDir::new().unwrap();
+        let (path, output) = output_in(&dir, "file.json", "{ }");
+
+        // Formatting fails part way through writing, so the output is dropped without being
+        // persisted
+        {
+            let mut output = output;
+            write!(output, "{{").unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{ }");
+        assert_eq!(directory_entries(&dir), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn persist_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let (path, mut output) = output_in(&dir, "script.sh", "echo  hello");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+
+        writeln!(output, "echo hello").unwrap();
+        output.persist().unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn persist_writes_through_symlinks() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().join("target.json");
+        let link = dir.path().join("link.json");
+        fs::write(&target, "{ }").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let mut output = OutputFile::new(link.to_str().unwrap()).unwrap();
+        writeln!(output, "{{}}").unwrap();
+        output.persist().unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "{}\n");
+    }
+}
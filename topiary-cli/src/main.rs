@@ -1,7 +1,10 @@
 mod check;
 mod cli;
+mod editorconfig;
 mod error;
+mod explain;
 mod fs;
+mod git;
 mod io;
 mod language;
 mod visualisation;
@@ -9,21 +12,23 @@ mod visualisation;
 use std::{
     io::{BufReader, BufWriter, Write},
     process::ExitCode,
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use error::Benign;
+use rootcause::Report;
 use tabled::{Table, settings::Style};
 use topiary_config::{Configuration, error::TopiaryConfigError, source::Source};
 use topiary_core::{
-    FormatterError, FormatterResult, Language, Operation, SpanAttachment, check_query_coverage,
-    formatter,
+    FormatStats, FormatterError, FormatterResult, Language, LanguageResolver, Operation,
+    SpanAttachment, check_query_coverage, format_lines, formatter, formatter_str,
+    formatter_str_with_stats, minimize_idempotence_failure,
 };
 
 use crate::{
     cli::Commands,
-    error::{CLIResult, ResultPreformat, exit_code},
-    io::{Inputs, OutputFile, process_inputs, read_input},
+    error::{CLIResult, ResultPreformat, check_outcomes, exit_code, strict_failure},
+    io::{InputSource, Inputs, OutputFile, process_inputs, read_input},
     language::LanguageDefinitionCache,
 };
 
@@ -49,6 +54,64 @@ fn resolve_injected_language(
     }
 }
 
+/// The most candidate inputs formatted when minimizing an idempotence failure
+const MAX_MINIMIZE_RUNS: usize = 1000;
+
+/// Attaches, to the report of an input whose formatting is not idempotent, a
+/// small part of that input whose formatting is not idempotent either. Other
+/// reports are returned as they are.
+fn attach_reproducer(
+    report: Report<FormatterError>,
+    content: &str,
+    language: &Language,
+    operation: Operation,
+    resolve: &LanguageResolver<'_>,
+) -> Report<FormatterError> {
+    if !matches!(
+        report.current_context(),
+        FormatterError::Idempotence(_) | FormatterError::IdempotenceParsing
+    ) {
+        return report;
+    }
+
+    match minimize_idempotence_failure(
+        content,
+        language,
+        operation,
+        Some(resolve),
+        MAX_MINIMIZE_RUNS,
+    ) {
+        Ok(Some(reproducer)) => report.attach(format!("Minimized reproducer:\n{reproducer}")),
+        Ok(None) | Err(_) => {
+            log::warn!("Could not minimize the input that is not formatted idempotently");
+            report
+        }
+    }
+}
+
+/// Prints, to stderr, how long each stage of formatting took for each input, in a stable order,
+/// and in total
+fn print_timings(timings: &mut [(String, FormatStats)]) {
+    let line = |stats: &FormatStats| {
+        format!(
+            "parse {:?}, query {:?}, post-process {:?}, render {:?}",
+            stats.parse_time, stats.query_apply_time, stats.post_process_time, stats.render_time
+        )
+    };
+
+    // Inputs are processed concurrently, so print them in a stable order
+    timings.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut total = FormatStats::default();
+    for (source_name, stats) in timings.iter() {
+        eprintln!("{source_name}: {}", line(stats));
+        total.parse_time += stats.parse_time;
+        total.query_apply_time += stats.query_apply_time;
+        total.post_process_time += stats.post_process_time;
+        total.render_time += stats.render_time;
+    }
+    eprintln!("Total: {}", line(&total));
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
     if let Err(e) = run().await {
@@ -73,14 +136,23 @@ async fn run() -> CLIResult<()> {
     match args.command {
         Commands::Format {
             check: true,
+            diff,
+            color,
             tolerate_parsing_errors,
             skip_idempotence,
+            strict,
+            editorconfig,
             inputs,
+            ..
         } => {
-            let inputs = Inputs::new(&config, &inputs);
+            let (jobs, fail_fast) = (inputs.jobs, inputs.fail_fast);
+            let inputs = Inputs::new(&config, &inputs).with_editorconfig(editorconfig);
+            let checked = inputs.len();
+            // Diffs of unformatted inputs are part of their errors, which are printed to stderr
+            let color = diff && color.enabled(&std::io::stderr());
             let cache = Arc::new(LanguageDefinitionCache::new());
             let config = config.clone();
-            process_inputs(
+            let result = process_inputs(
                 inputs,
                 move |input, language, cache| {
                     log::info!(
@@ -90,73 +162,276 @@ async fn run() -> CLIResult<()> {
                         input.formatting_query(),
                     );
 
+                    let skip_idempotence =
+                        check::skip_idempotence(skip_idempotence, input.language());
+                    let line_ending = input.line_ending();
                     check::check_input(
                         input,
                         &language,
-                        skip_idempotence,
-                        tolerate_parsing_errors,
+                        check::format_operation(
+                            skip_idempotence,
+                            tolerate_parsing_errors,
+                            strict,
+                            line_ending,
+                        ),
+                        diff,
+                        color,
                         Some(&|name| resolve_injected_language(&cache, &config, name)),
                     )
                 },
                 cache,
+                jobs,
+                fail_fast,
             )
-            .await?;
+            .await;
+
+            let (reformatted, failed) = result.as_ref().err().map_or((vec![], 0), check_outcomes);
+            for source_name in &reformatted {
+                println!("Would reformat: {source_name}");
+            }
+            println!("{}", check::summary(checked, reformatted.len(), failed));
+
+            result?;
         }
         Commands::Format {
+            diff: true,
+            color,
             tolerate_parsing_errors,
             skip_idempotence,
+            strict,
+            editorconfig,
             inputs,
             ..
         } => {
-            let inputs = Inputs::new(&config, &inputs);
+            let (jobs, fail_fast) = (inputs.jobs, inputs.fail_fast);
+            let inputs = Inputs::new(&config, &inputs).with_editorconfig(editorconfig);
             let cache = Arc::new(LanguageDefinitionCache::new());
             let config = config.clone();
+            let diffs = Arc::new(Mutex::new(Vec::new()));
 
-            process_inputs(
+            let result = process_inputs(
                 inputs,
-                move |input, language, cache| {
-                    let output = OutputFile::try_from(&input)?;
-
-                    log::info!(
-                        "Formatting {}, as {} using {}, to {}",
-                        input.source(),
-                        input.language().name,
-                        input.formatting_query(),
-                        output
-                    );
-
-                    let mut buf_output = BufWriter::new(output);
-
-                    {
-                        // NOTE This newly opened scope is important! `buf_input` takes
-                        // ownership of `input`, which -- upon reading -- contains an
-                        // open file handle. We need to close this file, by dropping
-                        // `buf_input`, before we attempt to persist our output.
-                        // Otherwise, we get an exclusive lock problem on Windows.
-                        let mut buf_input = BufReader::new(input);
-
-                        formatter(
-                            &mut buf_input,
-                            &mut buf_output,
+                {
+                    let diffs = diffs.clone();
+                    move |input, language, cache| {
+                        log::info!(
+                            "Diffing {}, as {} using {}",
+                            input.source(),
+                            input.language().name,
+                            input.formatting_query(),
+                        );
+
+                        let source_name = input.source().to_string();
+                        let skip_idempotence =
+                            check::skip_idempotence(skip_idempotence, input.language());
+                        let line_ending = input.line_ending();
+                        if let Some(diff) = check::diff_input(
+                            input,
                             &language,
-                            Operation::Format {
+                            check::format_operation(
                                 skip_idempotence,
                                 tolerate_parsing_errors,
-                            },
+                                strict,
+                                line_ending,
+                            ),
                             Some(&|name| resolve_injected_language(&cache, &config, name)),
-                        )?;
+                        )? {
+                            diffs.lock().unwrap().push((source_name, diff));
+                        }
+
+                        CLIResult::Ok(())
                     }
+                },
+                cache,
+                jobs,
+                fail_fast,
+            )
+            .await;
+
+            // Inputs are processed concurrently, so print their diffs in a stable order
+            let mut diffs = diffs.lock().unwrap();
+            diffs.sort();
+            let color = color.enabled(&std::io::stdout());
+            let mut stdout = std::io::stdout().lock();
+            for (_, diff) in diffs.iter() {
+                if color {
+                    stdout.write_all(check::color_diff(diff).as_bytes())?;
+                } else {
+                    stdout.write_all(diff.as_bytes())?;
+                }
+            }
 
-                    buf_output.into_inner()?.persist()?;
+            result?;
+        }
+        Commands::Format {
+            tolerate_parsing_errors,
+            skip_idempotence,
+            strict,
+            minimize,
+            time,
+            editorconfig,
+            output: output_path,
+            changed_only,
+            since,
+            only_kinds,
+            preserve_whitespace,
+            inputs,
+            ..
+        } => {
+            let (jobs, fail_fast) = (inputs.jobs, inputs.fail_fast);
+            let inputs = Inputs::new(&config, &inputs).with_editorconfig(editorconfig);
+            let cache = Arc::new(LanguageDefinitionCache::new());
+            let config = config.clone();
+            let timings = Arc::new(Mutex::new(Vec::new()));
 
-                    CLIResult::Ok(())
+            let result = process_inputs(
+                inputs,
+                {
+                    let timings = timings.clone();
+                    move |input, language, cache| {
+                        let output = match &output_path {
+                            Some(path) => OutputFile::new(&path.to_string_lossy())?,
+                            None => OutputFile::try_from(&input)?,
+                        };
+                        let skip_idempotence =
+                            check::skip_idempotence(skip_idempotence, input.language());
+                        // The lines to format, if not all of them
+                        let changed_lines = match input.source() {
+                            InputSource::Disk(path, _) if changed_only => {
+                                git::changed_lines(path, &since)?
+                            }
+                            _ => None,
+                        };
+
+                        log::info!(
+                            "Formatting {}, as {} using {}, to {}",
+                            input.source(),
+                            input.language().name,
+                            input.formatting_query(),
+                            output
+                        );
+
+                        let source_name = input.source().to_string();
+                        let line_ending = input.line_ending();
+                        let mut buf_output = BufWriter::new(output);
+
+                        {
+                            // NOTE This newly opened scope is important! `buf_input` takes
+                            // ownership of `input`, which -- upon reading -- contains an
+                            // open file handle. We need to close this file, by dropping
+                            // `buf_input`, before we attempt to persist our output.
+                            // Otherwise, we get an exclusive lock problem on Windows.
+                            let mut buf_input = BufReader::new(input);
+                            let operation = check::format_operation(
+                                skip_idempotence,
+                                tolerate_parsing_errors,
+                                strict,
+                                line_ending,
+                            );
+                            let resolve =
+                                |name: &str| resolve_injected_language(&cache, &config, name);
+
+                            let warnings = if let Some(lines) = &changed_lines {
+                                let content = read_input(&mut buf_input)?;
+                                format_lines(
+                                    &content,
+                                    lines,
+                                    &mut buf_output,
+                                    &language,
+                                    operation,
+                                    Some(&resolve),
+                                )
+                                .map_err(strict_failure)?
+                            } else if !only_kinds.is_empty() || preserve_whitespace {
+                                let content = read_input(&mut buf_input)?;
+                                let (cache, config) = (cache.clone(), config.clone());
+                                let (formatted, warnings) = check::formatter_builder(
+                                    language.clone(),
+                                    skip_idempotence,
+                                    tolerate_parsing_errors,
+                                    strict,
+                                    line_ending,
+                                )
+                                .only_kinds(only_kinds.clone())
+                                .preserve_whitespace(preserve_whitespace)
+                                .resolve(move |name| {
+                                    resolve_injected_language(&cache, &config, name)
+                                })
+                                .build()?
+                                .format_with_warnings(&content)
+                                .map_err(strict_failure)?;
+                                buf_output.write_all(formatted.as_bytes())?;
+                                warnings
+                            } else if minimize || time {
+                                // The input is needed again to minimize it, and is formatted from
+                                // memory to time it, so read it up front
+                                let content = read_input(&mut buf_input)?;
+                                let fail = |report| {
+                                    strict_failure(if minimize {
+                                        attach_reproducer(
+                                            report, &content, &language, operation, &resolve,
+                                        )
+                                    } else {
+                                        report
+                                    })
+                                };
+
+                                if time {
+                                    let (stats, warnings) = formatter_str_with_stats(
+                                        &content,
+                                        &mut buf_output,
+                                        &language,
+                                        operation,
+                                        Some(&resolve),
+                                    )
+                                    .map_err(fail)?;
+                                    timings.lock().unwrap().push((source_name.clone(), stats));
+                                    warnings
+                                } else {
+                                    formatter_str(
+                                        &content,
+                                        &mut buf_output,
+                                        &language,
+                                        operation,
+                                        Some(&resolve),
+                                    )
+                                    .map_err(fail)?
+                                }
+                            } else {
+                                formatter(
+                                    &mut buf_input,
+                                    &mut buf_output,
+                                    &language,
+                                    operation,
+                                    Some(&resolve),
+                                )
+                                .map_err(strict_failure)?
+                            };
+                            for warning in warnings {
+                                log::warn!("{source_name}: {warning}");
+                            }
+                        }
+
+                        buf_output.into_inner()?.persist()?;
+
+                        CLIResult::Ok(())
+                    }
                 },
                 cache,
+                jobs,
+                fail_fast,
             )
-            .await?;
+            .await;
+
+            if time {
+                print_timings(&mut timings.lock().unwrap());
+            }
+
+            result?;
         }
 
         Commands::CheckGrammar { inputs } => {
+            let (jobs, fail_fast) = (inputs.jobs, inputs.fail_fast);
             let inputs = Inputs::new(&config, &inputs);
 
             process_inputs(
@@ -174,11 +449,18 @@ async fn run() -> CLIResult<()> {
                     Ok(())
                 },
                 Arc::new(LanguageDefinitionCache::new()),
+                jobs,
+                fail_fast,
             )
             .await?;
         }
 
-        Commands::Visualise { format, input } => {
+        Commands::Visualise {
+            format,
+            named_only,
+            scopes,
+            input,
+        } => {
             // We are guaranteed (by clap) to have exactly one input, so it's safe to unwrap
             let input = Inputs::new(&config, &input).next().unwrap()?;
             let output = OutputFile::Stdout;
@@ -202,6 +484,8 @@ async fn run() -> CLIResult<()> {
                 &language,
                 Operation::Visualise {
                     output_format: format.into(),
+                    named_only,
+                    scopes,
                 },
                 None,
             )
@@ -233,6 +517,18 @@ async fn run() -> CLIResult<()> {
             println!("{}", table.build().with(Style::modern_rounded()));
         }
 
+        Commands::Config {
+            command: Some(cli::ConfigCommand::Explain { language, json }),
+        } => {
+            explain::explain(
+                &config,
+                args.global.merge_configuration,
+                file_config,
+                &language,
+                json,
+            )?;
+        }
+
         Commands::Config { command: None } => {
             // Output the collated nickel configuration.
             // Don't fail on error but merely log the event since the original `nickel_config` is
@@ -2,6 +2,7 @@ use std::{fmt, sync::Once};
 #[cfg(any(
     feature = "json",
     feature = "toml",
+    feature = "rust",
     all(feature = "ocamllex", feature = "ocaml")
 ))]
 use {
@@ -120,6 +121,43 @@ fn test_fmt_stdin_query() {
         .stdout(JSON_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_stdin_filename() {
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // The language is detected from the filename's extension
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--stdin-filename")
+        .arg("some/path/input.json")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_stdin_filename_in_diagnostic() {
+    use predicates::str::contains;
+
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--stdin-filename")
+        .arg("some/path/input.json")
+        .write_stdin(r#"{ "test": }"#)
+        .assert()
+        .failure()
+        .stderr(contains("some/path/input.json"));
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_fmt_stdin_query_fallback() {
@@ -139,6 +177,56 @@ fn test_fmt_stdin_query_fallback() {
         .stdout(JSON_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_query_override_for_detected_language() {
+    initialize();
+    let json = State::new(r#"{"a":1,"b":2}"#, "json");
+    let query = json.0.path().join("formatting.scm");
+    fs::write(&query, "\",\" @append_space\n\":\" @append_space\n").unwrap();
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // The query replaces that of the language detected from the file extension
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--query")
+        .arg(&query)
+        .arg(json.path())
+        .assert()
+        .success();
+
+    assert_eq!(json.read(), "{\"a\": 1, \"b\": 2}\n");
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_invalid_query_override() {
+    use predicates::str::contains;
+
+    initialize();
+    let json = State::new(JSON_INPUT, "json");
+    let query = json.0.path().join("formatting.scm");
+    fs::write(&query, "\n(no_such_node) @append_space\n").unwrap();
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // The error names the query file and where in it compilation failed
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--query")
+        .arg(&query)
+        .arg(json.path())
+        .assert()
+        .failure()
+        .stderr(contains("formatting.scm"))
+        .stderr(contains("no_such_node"));
+
+    assert_eq!(json.read(), JSON_INPUT);
+}
+
 #[test]
 #[cfg(all(feature = "json", feature = "toml"))]
 fn test_fmt_files() {
@@ -160,6 +248,150 @@ fn test_fmt_files() {
     assert_eq!(toml.read(), TOML_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_output() {
+    initialize();
+    let json = State::new(JSON_INPUT, "json");
+    let output = json.0.path().join("formatted.json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg(json.path())
+        .arg("--output")
+        .arg(&output)
+        .assert()
+        .success();
+
+    // The input is left untouched
+    assert_eq!(json.read(), JSON_INPUT);
+    assert_eq!(fs::read_to_string(&output).unwrap(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(all(unix, feature = "json"))]
+fn test_fmt_output_new_file_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    initialize();
+    let json = State::new(JSON_INPUT, "json");
+    let output = json.0.path().join("formatted.json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg(json.path())
+        .arg("--output")
+        .arg(&output)
+        .assert()
+        .success();
+
+    // The output has the permissions of any other new file, which are subject to the umask
+    let reference = json.0.path().join("reference.json");
+    fs::write(&reference, "").unwrap();
+    let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&output), mode(&reference));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_output_multiple_inputs() {
+    use predicates::str::contains;
+
+    initialize();
+    let json = State::new(JSON_INPUT, "json");
+    let other = State::new(JSON_INPUT, "json");
+    let output = json.0.path().join("formatted.json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg(json.path())
+        .arg(other.path())
+        .arg("--output")
+        .arg(&output)
+        .assert()
+        .failure()
+        .stderr(contains("Cannot write 2 inputs to a single output"));
+
+    assert_eq!(json.read(), JSON_INPUT);
+    assert_eq!(other.read(), JSON_INPUT);
+    assert!(!output.exists());
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_changed_only() {
+    initialize();
+    let json = State::new("{\n  \"a\"  :  1,\n  \"b\"  :  2\n}\n", "json");
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(json.0.path())
+            .args([
+                "-c",
+                "user.name=Topiary",
+                "-c",
+                "user.email=topiary@example.com",
+            ])
+            .args(["-c", "commit.gpgsign=false"])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    };
+    git(&["init", "--quiet"]);
+    git(&["add", "."]);
+    git(&["commit", "--quiet", "--message", "Unformatted input"]);
+
+    // Only the second pair changes, and an untracked input is added
+    fs::write(json.path(), "{\n  \"a\"  :  1,\n  \"b\"  :3\n}\n").unwrap();
+    let untracked = json.0.path().join("untracked.json");
+    fs::write(&untracked, JSON_INPUT).unwrap();
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--changed-only")
+        .arg(json.path())
+        .arg(&untracked)
+        .assert()
+        .success();
+
+    // The unchanged line is left as it is, but the untracked input is formatted in full
+    assert_eq!(json.read(), "{\n  \"a\"  :  1,\n  \"b\": 3\n}\n");
+    assert_eq!(fs::read_to_string(&untracked).unwrap(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_changed_only_stdin() {
+    use predicates::str::contains;
+
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--changed-only")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .failure()
+        .stderr(contains("Cannot find the changed lines of standard input"));
+}
+
 #[test]
 #[cfg(all(feature = "json", feature = "toml"))]
 fn test_fmt_files_query_fallback() {
@@ -183,6 +415,185 @@ fn test_fmt_files_query_fallback() {
     assert_eq!(toml.read(), TOML_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_files_language_override() {
+    initialize();
+    let json = State::new(JSON_INPUT, "txt");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--language")
+        .arg("json")
+        .arg(json.path())
+        .assert()
+        .success();
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_files_extension_mapped_by_config() {
+    initialize();
+    let json = State::new(JSON_INPUT, "data");
+    let config = State::new(r#"{ extensions = { data = "json" } }"#, "ncl");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--configuration")
+        .arg(config.path())
+        .arg(json.path())
+        .assert()
+        .success();
+
+    assert_eq!(json.read(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_editorconfig() {
+    initialize();
+    let project = TempDir::new().unwrap();
+    let nested = project.path().join("nested");
+    fs::create_dir(&nested).unwrap();
+    fs::write(
+        project.path().join(".editorconfig"),
+        "root = true\n\n[*]\nindent_style = tab\n\n[*.json]\nindent_style = space\nindent_size = 2\nend_of_line = crlf\n",
+    )
+    .unwrap();
+    fs::write(
+        nested.join(".editorconfig"),
+        "[*.json]\nindent_style = tab\n",
+    )
+    .unwrap();
+
+    let input = "{\n\"a\": 1\n}";
+    let json = project.path().join("a.json");
+    let nested_json = nested.join("b.json");
+    fs::write(&json, input).unwrap();
+    fs::write(&nested_json, input).unwrap();
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--editorconfig")
+        .arg(&json)
+        .arg(&nested_json)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fs::read_to_string(&json).unwrap(),
+        "{\r\n  \"a\": 1\r\n}\r\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&nested_json).unwrap(),
+        "{\r\n\t\"a\": 1\r\n}\r\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "rust")]
+fn test_fmt_editorconfig_over_query_indent() {
+    initialize();
+    let project = TempDir::new().unwrap();
+    let nested = project.path().join("nested");
+    fs::create_dir(&nested).unwrap();
+    fs::write(
+        nested.join(".editorconfig"),
+        "root = true\n\n[*]\nindent_style = tab\n",
+    )
+    .unwrap();
+
+    // The Rust query indents with four spaces, unless .editorconfig says otherwise
+    let input = "fn f() {\nlet x = 1;\n}\n";
+    let rust = project.path().join("a.rs");
+    let nested_rust = nested.join("b.rs");
+    fs::write(&rust, input).unwrap();
+    fs::write(&nested_rust, input).unwrap();
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--editorconfig")
+        .arg(&rust)
+        .arg(&nested_rust)
+        .assert()
+        .success();
+
+    assert_eq!(
+        fs::read_to_string(&rust).unwrap(),
+        "fn f() {\n    let x = 1;\n}\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&nested_rust).unwrap(),
+        "fn f() {\n\tlet x = 1;\n}\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_config_explain_layers() {
+    let project = TempDir::new().unwrap();
+    // Keep the search for workspace configuration within the project
+    fs::create_dir(project.path().join(".git")).unwrap();
+    fs::create_dir(project.path().join(".topiary")).unwrap();
+    fs::write(
+        project.path().join(".topiary/languages.ncl"),
+        "{ languages.json.tab_width = 4 }",
+    )
+    .unwrap();
+    let config = State::new(r#"{ languages.json.indent | force = "    " }"#, "ncl");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    let output = topiary
+        .current_dir(project.path())
+        .arg("--merge-configuration")
+        .arg("--configuration")
+        .arg(config.path())
+        .arg("config")
+        .arg("explain")
+        .arg("json")
+        .arg("--json")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let explained: serde_json::Value = serde_json::from_slice(&output).unwrap();
+    let setting = |name: &str| {
+        explained
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|setting| setting["setting"] == name)
+            .unwrap()
+            .clone()
+    };
+    let source = |name: &str| setting(name)["source"].as_str().unwrap().to_owned();
+
+    assert_eq!(setting("indent")["value"], "    ");
+    assert!(source("indent").ends_with(".ncl"));
+    assert!(!source("indent").contains(".topiary"));
+    assert_eq!(setting("tab_width")["value"], 4);
+    assert!(source("tab_width").contains(".topiary"));
+    assert!(source("tab_width").ends_with("languages.ncl"));
+    assert_eq!(source("extensions"), "<built-in>");
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_fmt_dir() {
@@ -201,6 +612,182 @@ fn test_fmt_dir() {
     assert_eq!(json.read(), JSON_EXPECTED);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_files_continue_after_failure() {
+    use predicates::str::contains;
+
+    initialize();
+    let good = State::new(JSON_INPUT, "json");
+    let bad_a = State::new(r#"{ "test": }"#, "json");
+    let bad_b = State::new(r#"{ "test": }"#, "json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // Every input is processed and every failure is reported, naming its file
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg(bad_a.path())
+        .arg(good.path())
+        .arg(bad_b.path())
+        .assert()
+        .code(9)
+        .stderr(contains(bad_a.path().display().to_string()))
+        .stderr(contains(bad_b.path().display().to_string()));
+
+    assert_eq!(good.read(), JSON_EXPECTED);
+
+    // --no-fail-fast overrides an earlier --fail-fast
+    let good = State::new(JSON_INPUT, "json");
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--fail-fast")
+        .arg("--no-fail-fast")
+        .arg("--jobs=1")
+        .arg(bad_a.path())
+        .arg(good.path())
+        .arg(bad_b.path())
+        .assert()
+        .code(9);
+
+    assert_eq!(good.read(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_files_fail_fast() {
+    use predicates::function::function;
+
+    initialize();
+    let good = State::new(JSON_INPUT, "json");
+    let bad_a = State::new(r#"{ "test": }"#, "json");
+    let bad_b = State::new(r#"{ "test": }"#, "json");
+    let bad_paths = [
+        bad_a.path().display().to_string(),
+        bad_b.path().display().to_string(),
+    ];
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // With a single job, nothing is started after the first failure, so only one of the failing
+    // inputs is reported
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--fail-fast")
+        .arg("--jobs=1")
+        .arg(bad_a.path())
+        .arg(good.path())
+        .arg(bad_b.path())
+        .assert()
+        .failure()
+        .stderr(function(|stderr: &str| {
+            bad_paths
+                .iter()
+                .filter(|path| stderr.contains(*path))
+                .count()
+                == 1
+        }));
+
+    // Whether the good input was formatted depends on whether it came before the failure, but
+    // it is never left half-written
+    let good = good.read();
+    assert!(good == JSON_INPUT || good == JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_files_from_stdin() {
+    use predicates::str::contains;
+
+    initialize();
+    let json_a = State::new(JSON_INPUT, "json");
+    let json_b = State::new(JSON_INPUT, "json");
+    let missing = json_a.path().with_file_name("missing.json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // Blank lines are ignored, and the missing file does not stop the others from being formatted
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--files-from")
+        .arg("-")
+        .write_stdin(format!(
+            "{}\n\n{}\n{}\n",
+            json_a.path().display(),
+            missing.display(),
+            json_b.path().display()
+        ))
+        .assert()
+        .success()
+        .stderr(contains(missing.display().to_string()));
+
+    assert_eq!(json_a.read(), JSON_EXPECTED);
+    assert_eq!(json_b.read(), JSON_EXPECTED);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_strict() {
+    use predicates::str::contains;
+
+    initialize();
+    // The second pattern never matches the input
+    let query = State::new("(number) @append_space\n\n(true) @prepend_space\n", "scm");
+
+    let topiary = || {
+        let mut topiary = cargo_bin_cmd!("topiary");
+        topiary
+            .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+            .arg("fmt")
+            .arg("--language")
+            .arg("json")
+            .arg("--query")
+            .arg(query.path())
+            .arg("--skip-idempotence")
+            .write_stdin("[1]");
+        topiary
+    };
+
+    topiary().assert().success();
+
+    topiary()
+        .arg("--strict")
+        .assert()
+        .failure()
+        .stderr(contains(
+            "The query pattern at (3,1) does not match the input",
+        ));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_fmt_time() {
+    use predicates::str::contains;
+
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // Timings go to stderr, leaving the formatted output on stdout untouched
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--language")
+        .arg("json")
+        .arg("--time")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(JSON_EXPECTED)
+        .stderr(contains("standard input: parse "))
+        .stderr(contains("Total: parse "));
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_check_stdin_clean() {
@@ -273,6 +860,137 @@ fn test_check_file_clean() {
         .success();
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_check_lists_unformatted_files() {
+    use predicates::str::contains;
+
+    initialize();
+    let clean = State::new(JSON_EXPECTED, "json");
+    let dirty = State::new(JSON_INPUT, "json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--check")
+        .arg(clean.path())
+        .arg(dirty.path())
+        .assert()
+        .code(1)
+        .stdout(contains(format!(
+            "Would reformat: {}",
+            dirty.path().display()
+        )))
+        .stdout(contains("2 files checked, 1 would be reformatted"));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_diff_color() {
+    use predicates::{prelude::PredicateBooleanExt, str::contains};
+
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diff")
+        .arg("--color=never")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(contains("+{ \"test\": 123 }\n"))
+        .stdout(contains("\x1b[").not());
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // NO_COLOR does not override an explicit request for colour
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .env("NO_COLOR", "1")
+        .arg("fmt")
+        .arg("--diff")
+        .arg("--color=always")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(contains("\x1b[32m+{ \"test\": 123 }\x1b[0m\n"));
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    // Colour is off by default when the output is not a terminal
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diff")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(contains("\x1b[").not());
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_diff_stdin() {
+    use predicates::str::contains;
+
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diff")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(JSON_INPUT)
+        .assert()
+        .success()
+        .stdout(contains("--- a/stdin\n+++ b/stdin\n@@ -1 +1 @@\n"))
+        .stdout(contains("+{ \"test\": 123 }\n"));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_diff_file_no_modify() {
+    initialize();
+    let dirty = State::new(JSON_INPUT, "json");
+    let clean = State::new(JSON_EXPECTED, "json");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diff")
+        .arg(clean.path())
+        .assert()
+        .success()
+        .stdout("");
+
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("fmt")
+        .arg("--diff")
+        .arg(dirty.path())
+        .assert()
+        .success();
+
+    // The file must NOT be modified by --diff
+    assert_eq!(dirty.read(), JSON_INPUT);
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_fmt_invalid() {
@@ -379,6 +1097,91 @@ fn test_vis() {
         .stdout(is_graph);
 }
 
+#[test]
+#[cfg(feature = "json")]
+fn test_vis_dot_golden() {
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    let expected = r#"graph {
+  0 [label="document", shape=ellipse];
+  0 -- 1;
+  1 [label="object", shape=ellipse];
+  1 -- 2;
+  2 [label="{", shape=box];
+  1 -- 3;
+  3 [label="pair", shape=ellipse];
+  3 -- 4 [label="key"];
+  4 [label="string", shape=ellipse];
+  4 -- 5;
+  5 [label="\"", shape=box];
+  4 -- 6;
+  6 [label="string_content", shape=ellipse];
+  4 -- 7;
+  7 [label="\"", shape=box];
+  3 -- 8;
+  8 [label=":", shape=box];
+  3 -- 9 [label="value"];
+  9 [label="number", shape=ellipse];
+  1 -- 10;
+  10 [label="}", shape=box];
+}
+"#;
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("vis")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(r#"{"a":1}"#)
+        .assert()
+        .success()
+        .stdout(expected);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn test_vis_json_golden_named_only() {
+    initialize();
+    let mut topiary = cargo_bin_cmd!("topiary");
+
+    let node = |kind: &str, field_name: &str, (start, end): (u32, u32), children: &str| {
+        format!(
+            r#"{{"kind":"{kind}","field_name":{field_name},"is_named":true,"is_extra":false,"is_error":false,"is_missing":false,"start":{{"row":1,"column":{}}},"end":{{"row":1,"column":{}}},"start_byte":{start},"end_byte":{end},"children":[{children}]}}"#,
+            start + 1,
+            end + 1
+        )
+    };
+
+    let key = node(
+        "string",
+        r#""key""#,
+        (1, 4),
+        &node("string_content", "null", (2, 3), ""),
+    );
+    let value = node("number", r#""value""#, (5, 6), "");
+    let pair = node("pair", "null", (1, 6), &format!("{key},{value}"));
+    let expected = node(
+        "document",
+        "null",
+        (0, 7),
+        &node("object", "null", (0, 7), &pair),
+    );
+
+    topiary
+        .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries")
+        .arg("vis")
+        .arg("--format")
+        .arg("json")
+        .arg("--named-only")
+        .arg("--language")
+        .arg("json")
+        .write_stdin(r#"{"a":1}"#)
+        .assert()
+        .success()
+        .stdout(expected);
+}
+
 #[test]
 #[cfg(feature = "json")]
 fn test_vis_invalid() {
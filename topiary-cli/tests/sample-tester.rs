@@ -139,6 +139,53 @@ mod test_fmt {
     }
 }
 
+#[cfg(test)]
+mod test_fixtures {
+    use super::*;
+
+    // Each fixture is a directory with an input, its expected formatting and a formatting query of
+    // its own, which demonstrates a capture, predicate or option apart from the bundled queries.
+    // The input is named `input`, with the extension of its language, and the expected output
+    // `expected`, likewise. Any further arguments to `topiary fmt` are given in an `args` file,
+    // one per line.
+    #[test]
+    #[cfg(all(feature = "json", feature = "ocaml", feature = "toml"))]
+    fn fmt_fixtures() {
+        for fixture in fs::read_dir("tests/samples/fixtures").unwrap() {
+            let fixture = fixture.unwrap().path();
+
+            let input = fs::read_dir(&fixture)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .find(|path| path.file_stem().is_some_and(|stem| stem == "input"))
+                .expect("Fixture without an input");
+            let extension = input.extension().unwrap().to_str().unwrap();
+            let expected = fixture.join(format!("expected.{extension}"));
+            let expected_output = fs::read_to_string(expected).unwrap();
+            let args = fs::read_to_string(fixture.join("args")).unwrap_or_default();
+
+            // Stage the input to a temporary directory
+            let tmp = TempDir::new().unwrap();
+            let staged = tmp.path().join(input.file_name().unwrap());
+            fs::copy(&input, &staged).unwrap();
+
+            let mut topiary = cargo_bin_cmd!("topiary");
+            topiary
+                .env("TOPIARY_LANGUAGE_DIR", "../topiary-queries/queries/")
+                .arg("fmt")
+                .arg("--query")
+                .arg(fixture.join("query.scm"))
+                .args(args.lines())
+                .arg(&staged)
+                .assert()
+                .success();
+
+            let formatted = fs::read_to_string(&staged).unwrap();
+            assert_eq!(expected_output, formatted, "fixture {}", fixture.display());
+        }
+    }
+}
+
 #[cfg(test)]
 mod test_check {
     use super::*;
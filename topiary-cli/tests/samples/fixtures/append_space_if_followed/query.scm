@@ -0,0 +1,13 @@
+; The comma is followed by a space only when a comment trails it on its line
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(object
+  (pair) @prepend_hardline
+)
+
+":" @append_space
+
+"," @append_space_if_followed
@@ -0,0 +1,12 @@
+; By default, a comment on the line of the preceding node is attached to the
+; following node, so it is put on a line of its own, as is a leading comment
+(comment) @prepend_hardline @append_hardline
+
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
@@ -0,0 +1,13 @@
+; Configured with comment_attachment = 'preceding, a comment on the line of
+; the preceding node stays on that line, whereas a leading comment is put on a
+; line of its own
+(comment) @prepend_hardline @append_hardline
+
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
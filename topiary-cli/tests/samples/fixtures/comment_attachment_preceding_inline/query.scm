@@ -0,0 +1,13 @@
+; Configured with comment_attachment = 'preceding, a comment that is followed
+; by code on its line does not trail the preceding node, but leads the
+; following one, so it is put on a line of its own
+(comment) @prepend_hardline @append_hardline
+
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
@@ -0,0 +1,13 @@
+; Configured with comment_attachment = 'preceding, each comment keeps its
+; position relative to the code: those on the line of the preceding node stay
+; on that line, whereas those on a line of their own stay there
+(comment) @prepend_hardline @append_hardline
+
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
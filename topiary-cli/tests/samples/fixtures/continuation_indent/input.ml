@@ -0,0 +1,4 @@
+let x = aaa
++ bbb
++ ccc
+let y = a + b
@@ -0,0 +1,9 @@
+; The wrapped binary expression is indented under its definition, while the
+; single-line definition is left as is
+(value_definition) @continuation_indent @append_hardline
+
+"let" @append_space
+
+"=" @prepend_space @append_space
+
+(add_operator) @prepend_input_softline @append_space
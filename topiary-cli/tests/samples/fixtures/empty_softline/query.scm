@@ -0,0 +1,7 @@
+; An empty softline never yields a space, whether its node is single-line or
+; multi-line
+(array
+  (array
+    "," @append_empty_softline
+  )
+)
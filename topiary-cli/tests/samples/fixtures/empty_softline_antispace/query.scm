@@ -0,0 +1,14 @@
+; An antispace in single-line nodes removes the space next to an empty
+; softline
+(array
+  (array
+    "," @append_space @append_empty_softline
+  )
+)
+
+(array
+  (array
+    "," @append_antispace
+  )
+  (#single_line_only!)
+)
@@ -0,0 +1,7 @@
+; A space next to an empty softline is the space capture's, which the line
+; break absorbs in multi-line nodes
+(array
+  (array
+    "," @append_space @append_empty_softline
+  )
+)
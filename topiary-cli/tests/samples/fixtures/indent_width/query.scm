@@ -0,0 +1,23 @@
+; The array's blocks are indented by two indentation strings, so that the
+; object's blocks, indented by one, take half as much, and widths add up
+; across nested blocks, whichever their kind
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(
+  (array
+    "[" @append_indent_start
+    "]" @prepend_hardline @prepend_indent_end
+  )
+  (#indent_width! "2")
+)
+
+(array
+  (_) @prepend_hardline
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
@@ -0,0 +1,22 @@
+; Configured to indent with tabs, whose width in the input is unrelated, the
+; object's blocks are indented by two tabs
+(
+  (object
+    "{" @append_indent_start
+    "}" @prepend_hardline @prepend_indent_end
+  )
+  (#indent_width! "2")
+)
+
+(array
+  "[" @append_indent_start
+  "]" @prepend_hardline @prepend_indent_end
+)
+
+(array
+  (_) @prepend_hardline
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
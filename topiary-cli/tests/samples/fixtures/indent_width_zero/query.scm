@@ -0,0 +1,12 @@
+; A block of no width is not indented
+(
+  (array
+    "[" @append_indent_start
+    "]" @prepend_hardline @prepend_indent_end
+  )
+  (#indent_width! "0")
+)
+
+(array
+  (_) @prepend_hardline
+)
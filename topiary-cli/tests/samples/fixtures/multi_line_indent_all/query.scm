@@ -0,0 +1,17 @@
+; The lines of a block comment that line up with it follow it, whereas those
+; indented relative to its line follow the indentation of the line, wherever
+; the comment is placed
+(comment) @multi_line_indent_all @append_space
+
+":" @append_space
+
+(object
+  .
+  "{" @append_hardline @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+  .
+)
+
+(object
+  "," @append_hardline
+)
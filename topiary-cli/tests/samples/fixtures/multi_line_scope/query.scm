@@ -0,0 +1,25 @@
+; The array is a scope, whose elements are put on lines of their own when it
+; is multi-line, which it is, however it is written
+(
+  (array) @prepend_begin_scope @append_end_scope
+  (#scope_id! "array")
+)
+
+(
+  (array
+    "[" @append_empty_scoped_softline
+    "]" @prepend_empty_scoped_softline
+  )
+  (#scope_id! "array")
+)
+
+(
+  (array
+    "," @append_empty_scoped_softline
+  )
+  (#scope_id! "array")
+)
+
+(document
+  (array) @multi_line
+)
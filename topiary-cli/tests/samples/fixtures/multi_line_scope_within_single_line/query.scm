@@ -0,0 +1,30 @@
+; Each array is a scope, whose elements are put on lines of their own when it
+; is multi-line. The outer array is single-line, however it is written, but
+; the array within it is multi-line.
+(
+  (array) @prepend_begin_scope @append_end_scope
+  (#scope_id! "array")
+)
+
+(
+  (array
+    "[" @append_empty_scoped_softline
+    "]" @prepend_empty_scoped_softline
+  )
+  (#scope_id! "array")
+)
+
+(
+  (array
+    "," @append_spaced_scoped_softline
+  )
+  (#scope_id! "array")
+)
+
+(document
+  (array) @single_line
+)
+
+(array
+  (array) @multi_line
+)
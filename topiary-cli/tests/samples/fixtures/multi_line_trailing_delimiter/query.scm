@@ -0,0 +1,29 @@
+; The last element of a multi-line array is given a trailing comma, unless it
+; already has one, whereas that of a single-line array loses it
+(document
+  (pair) @append_hardline
+)
+
+"=" @prepend_space @append_space
+
+(
+  "," @append_spaced_softline
+  .
+  "]"? @do_nothing
+)
+
+(array
+  ("," @delete . "]")
+  (#single_line_only!)
+)
+
+(array
+  ((integer) @append_delimiter . ","* @do_nothing . "]")
+  (#delimiter! ",")
+  (#multi_line_only!)
+)
+
+(array
+  "[" @append_empty_softline @append_indent_start
+  "]" @prepend_empty_softline @prepend_indent_end
+)
@@ -0,0 +1,39 @@
+; The softlines of a list within a list follow the scope of the inner list,
+; whereas those of an object within the list follow the scope of the outer
+; list, so that they are broken whenever the outer list is multi-line
+(document
+  (array
+    "[" @append_begin_scope
+    "]" @prepend_end_scope
+  )
+  (#scope_id! "outer")
+)
+
+(document
+  (array
+    "," @append_empty_scoped_softline
+  )
+  (#scope_id! "outer")
+)
+
+(array
+  (array
+    "[" @append_begin_scope
+    "]" @prepend_end_scope
+  )
+  (#scope_id! "inner")
+)
+
+(array
+  (array
+    "," @append_empty_scoped_softline
+  )
+  (#scope_id! "inner")
+)
+
+(array
+  (object
+    "," @append_empty_scoped_softline
+  )
+  (#scope_id! "outer")
+)
@@ -0,0 +1,14 @@
+; Only arrays are formatted, including the array nested in another, while the
+; objects are left as they are
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
+
+(array
+  "," @append_space
+)
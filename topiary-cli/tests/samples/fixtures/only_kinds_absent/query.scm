@@ -0,0 +1,13 @@
+; Without any array, nothing is formatted
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+":" @append_space
+
+(array
+  "," @append_space
+)
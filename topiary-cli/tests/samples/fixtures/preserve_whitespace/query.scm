@@ -0,0 +1,14 @@
+; With --preserve-whitespace, the alignment after the colons and the blank line
+; are kept, while the missing space and line break are added
+(object
+  "{" @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+)
+
+(pair) @prepend_hardline
+
+(pair) @allow_blank_line_before
+
+":" @append_space
+
+"," @append_space
@@ -0,0 +1,49 @@
+; The scope predicates consult the scope that they name, rather than the
+; nearest one: the array's scope is single-line, but the object's scope that
+; encloses it is multi-line. The delimiters are not JSON, so idempotence is not
+; checked.
+(
+  (object) @prepend_begin_scope @append_end_scope
+  (#scope_id! "object")
+)
+
+(
+  (array) @prepend_begin_scope @append_end_scope
+  (#scope_id! "array")
+)
+
+(object
+  "," @append_hardline
+)
+
+(array
+  "," @append_space
+)
+
+(pair
+  ":" @append_space
+)
+
+(array
+  "[" @append_delimiter
+  (#delimiter! "M")
+  (#multi_line_scope_only! "object")
+)
+
+(array
+  "[" @append_delimiter
+  (#delimiter! "m")
+  (#multi_line_scope_only! "array")
+)
+
+(array
+  "]" @prepend_delimiter
+  (#delimiter! "S")
+  (#single_line_scope_only! "object")
+)
+
+(array
+  "]" @prepend_delimiter
+  (#delimiter! "s")
+  (#single_line_scope_only! "array")
+)
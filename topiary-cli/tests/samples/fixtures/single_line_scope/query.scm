@@ -0,0 +1,19 @@
+; Each array is a scope, whose commas are followed by a line break when it is
+; multi-line. The inner arrays are single-line, however they are written.
+(
+  (array) @prepend_begin_scope @append_end_scope
+  (#scope_id! "array")
+)
+
+(
+  (array
+    "," @append_spaced_scoped_softline
+  )
+  (#scope_id! "array")
+)
+
+(document
+  (array
+    (array) @single_line
+  )
+)
@@ -138,6 +138,14 @@ This shouldn't be indented ${foo}
 EOF
 }
 
+heredoc_in_function()   {
+cat <<EOF
+    keep this indentation
+	and this tab
+  as well as this
+EOF
+}
+
 readonly a="$(foo | bar || baz --quux 2>&1)"
 foo <(bar||baz --something) | tee >(quux)
 
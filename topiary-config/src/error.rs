@@ -9,6 +9,11 @@ pub type TopiaryConfigResult<T> = result::Result<T, TopiaryConfigError>;
 pub enum TopiaryConfigError {
     FileNotFound(path::PathBuf),
     UnknownLanguage(String),
+    /// A language that does not have a required setting in any configuration layer
+    IncompleteLanguage {
+        language: String,
+        setting: &'static str,
+    },
     UnknownExtension(String),
     NoExtension(path::PathBuf),
     #[cfg(not(target_arch = "wasm32"))]
@@ -42,6 +47,7 @@ pub enum TopiaryConfigFetchingError {
     Io(io::Error),
     LibLoading(libloading::Error),
     GrammarFileNotFound(path::PathBuf),
+    IncompatibleGrammar { path: path::PathBuf, version: u32 },
 }
 
 impl std::error::Error for TopiaryConfigFetchingError {}
@@ -58,6 +64,10 @@ impl fmt::Display for TopiaryConfigError {
                 f,
                 "You were looking for language \"{lang}\", but we do not know that language."
             ),
+            TopiaryConfigError::IncompleteLanguage { language, setting } => write!(
+                f,
+                "The configuration of language \"{language}\" does not set \"{setting}\" in any of its sources."
+            ),
             TopiaryConfigError::UnknownExtension(ext) => write!(
                 f,
                 "You tried to format a file with extension: \"{ext}\", but we do not know that extension. Make sure the extension is in your configuration file!"
@@ -109,6 +119,13 @@ impl fmt::Display for TopiaryConfigFetchingError {
                 "Attempted to load grammar at `{}`, but no file found",
                 path.display()
             ),
+            TopiaryConfigFetchingError::IncompatibleGrammar { path, version } => write!(
+                f,
+                "The grammar at `{}` has ABI version {version}, but only versions {} to {} are supported. Rebuild it with a compatible version of the Tree-sitter CLI.",
+                path.display(),
+                topiary_tree_sitter_facade::MIN_COMPATIBLE_LANGUAGE_VERSION,
+                topiary_tree_sitter_facade::LANGUAGE_VERSION,
+            ),
         }
     }
 }
@@ -11,7 +11,7 @@ use gix::{
     remote::{self, Direction, fetch, fetch::refmap},
     worktree::state::checkout,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 #[cfg(not(target_arch = "wasm32"))]
 use std::num::NonZero;
 #[cfg(not(target_arch = "wasm32"))]
@@ -40,15 +40,70 @@ pub struct LanguageConfiguration {
     /// switch to the right language based on the input filename.
     pub extensions: HashSet<String>,
 
+    /// A set of interpreter names, such as `bash`, associated with this language. This enables
+    /// Topiary to switch to the right language based on the input's shebang line, when there is
+    /// no filename to go by. A name also matches interpreters that suffix it with a version (e.g.,
+    /// `python` matches `python3.12`).
+    #[serde(default)]
+    pub shebangs: HashSet<String>,
+
     /// The indentation string used for this language; defaults to "  " (i.e., two spaces). Any
     /// string can be provided, but in most instances it will be some whitespace (e.g., "    ",
     /// "\t", etc.)
     pub indent: Option<String>,
 
+    /// The maximum number of consecutive blank lines kept from the input; defaults to 1. Setting
+    /// it to 0 removes all blank lines.
+    pub max_blank_lines: Option<usize>,
+
+    /// The width, in columns, of a tab in the input, with which the original indentation of
+    /// multi-line leaves is measured; defaults to 8. This is unrelated to the `indent` string.
+    pub tab_width: Option<usize>,
+
+    /// The markers that, when found in comments, disable and re-enable formatting; the source
+    /// between a pair of such comments is kept verbatim. Formatting cannot be disabled if unset.
+    pub formatting_markers: Option<FormattingMarkers>,
+
+    /// The node to which a comment that starts on the line on which the preceding node ends is
+    /// attached; defaults to the following node. Attaching it to the preceding node keeps it on
+    /// that line, even if the query puts a line break before it.
+    pub comment_attachment: Option<CommentAttachment>,
+
+    /// Stable names for node kinds, mapped to the kinds of the grammar that they stand for. A
+    /// formatting query can use these names in place of the kinds, so that it need only be
+    /// updated here when a grammar renames a node kind.
+    #[serde(default)]
+    pub node_aliases: HashMap<String, String>,
+
+    /// Whether to skip the idempotence check when formatting this language, unless overridden on
+    /// the command line; defaults to `false`. This is meant for languages whose queries are still
+    /// maturing.
+    #[serde(default)]
+    pub skip_idempotence: bool,
+
     /// The tree-sitter source of the language, contains all that is needed to pull and compile the tree-sitter grammar
     pub grammar: Grammar,
 }
 
+/// The pair of markers that disable and re-enable formatting, when found in comments.
+#[derive(Debug, serde::Deserialize, PartialEq, serde::Serialize, Clone)]
+pub struct FormattingMarkers {
+    /// The marker of the comment after which formatting is disabled (e.g., "topiary: off")
+    pub off: String,
+    /// The marker of the comment before which formatting is re-enabled (e.g., "topiary: on")
+    pub on: String,
+}
+
+/// The node to which a trailing comment is attached.
+#[derive(Debug, serde::Deserialize, PartialEq, Eq, serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentAttachment {
+    /// The comment is placed wherever the query puts it (e.g., on a line of its own)
+    Following,
+    /// The comment stays on the line of the node that precedes it (e.g., `foo, // note`)
+    Preceding,
+}
+
 #[derive(Debug, serde::Deserialize, PartialEq, serde::Serialize, Clone)]
 pub struct Grammar {
     #[cfg(not(target_arch = "wasm32"))]
@@ -89,6 +144,38 @@ impl Language {
         self.config.indent.clone()
     }
 
+    pub fn max_blank_lines(&self) -> Option<usize> {
+        self.config.max_blank_lines
+    }
+
+    pub fn tab_width(&self) -> Option<usize> {
+        self.config.tab_width
+    }
+
+    pub fn formatting_markers(&self) -> Option<&FormattingMarkers> {
+        self.config.formatting_markers.as_ref()
+    }
+
+    pub fn comment_attachment(&self) -> Option<CommentAttachment> {
+        self.config.comment_attachment
+    }
+
+    pub fn node_aliases(&self) -> &HashMap<String, String> {
+        &self.config.node_aliases
+    }
+
+    pub fn skip_idempotence(&self) -> bool {
+        self.config.skip_idempotence
+    }
+
+    /// Whether the given interpreter name, as found in a shebang line, is associated with this
+    /// language.
+    pub fn matches_interpreter(&self, interpreter: &str) -> bool {
+        let unversioned = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+
+        self.config.shebangs.contains(interpreter) || self.config.shebangs.contains(unversioned)
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     #[allow(clippy::result_large_err)]
     pub fn find_query_file(&self) -> TopiaryConfigResult<PathBuf> {
@@ -203,7 +290,10 @@ impl Language {
             tree_sitter_language::LanguageFn::from_raw(*language_fn)
         };
         std::mem::forget(library);
-        Ok(topiary_tree_sitter_facade::Language::from(language))
+
+        let language = topiary_tree_sitter_facade::Language::from(language);
+        check_abi_version(&library_path, language.version())?;
+        Ok(language)
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -231,6 +321,22 @@ impl Language {
 
 type Result<T, E = TopiaryConfigFetchingError> = std::result::Result<T, E>;
 
+/// Checks that a grammar loaded from the given library has an ABI version that Tree-sitter
+/// supports, so that a mismatch is reported when loading it, rather than when first parsing
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn check_abi_version(library_path: &std::path::Path, version: u32) -> Result<()> {
+    use topiary_tree_sitter_facade::{LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
+
+    if (MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(TopiaryConfigFetchingError::IncompatibleGrammar {
+            path: library_path.to_path_buf(),
+            version,
+        })
+    }
+}
+
 trait GitResult<T> {
     fn wrap_err(self) -> Result<T>;
 }
@@ -363,3 +469,22 @@ fn source_object_id(source: &refmap::Source) -> Result<ObjectId> {
         }
     }
 }
+
+/// Extracts the name of the interpreter from the shebang line at the start of `input`, if there is
+/// one. Only the first line is inspected. The `env` indirection (e.g., `#!/usr/bin/env bash`) is
+/// followed, skipping over any options and variable assignments passed to `env`.
+pub(crate) fn shebang_interpreter(input: &str) -> Option<&str> {
+    fn basename(path: &str) -> &str {
+        path.rsplit('/').next().unwrap_or(path)
+    }
+
+    let line = input.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut interpreter = basename(words.next()?);
+
+    if interpreter == "env" {
+        interpreter = basename(words.find(|word| !word.starts_with('-') && !word.contains('='))?);
+    }
+
+    Some(interpreter)
+}
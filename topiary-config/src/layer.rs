@@ -0,0 +1,207 @@
+//! Layering of configuration sources, where each source overrides the individual settings of the
+//! sources before it, rather than being merged by Nickel.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::language::GrammarSource;
+use crate::{
+    error::{TopiaryConfigError, TopiaryConfigResult},
+    language::{CommentAttachment, FormattingMarkers, Grammar, LanguageConfiguration},
+    source::Source,
+};
+
+/// Which configuration source determined each setting of a layered configuration, as returned by
+/// [`Configuration::from_layers`](crate::Configuration::from_layers).
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+    settings: HashMap<String, HashMap<&'static str, Source>>,
+}
+
+impl Provenance {
+    /// The settings of a language whose provenance is recorded, named as in the configuration
+    /// file
+    pub const SETTINGS: [&'static str; 11] = [
+        "extensions",
+        "shebangs",
+        "indent",
+        "max_blank_lines",
+        "tab_width",
+        "formatting_markers",
+        "comment_attachment",
+        "node_aliases",
+        "skip_idempotence",
+        "grammar.source",
+        "grammar.symbol",
+    ];
+
+    /// The source whose value is in effect for the given setting of a language, if any source set
+    /// it. Settings are named as in the configuration file: see [`Self::SETTINGS`].
+    pub fn source_of(&self, language: &str, setting: &str) -> Option<&Source> {
+        self.settings.get(language)?.get(setting)
+    }
+
+    fn record(&mut self, language: &str, setting: &'static str, source: &Source) {
+        self.settings
+            .entry(language.to_owned())
+            .or_default()
+            .insert(setting, source.clone());
+    }
+}
+
+/// A configuration source, of which every setting is optional
+#[derive(Debug, Deserialize)]
+pub(crate) struct PartialConfiguration {
+    #[serde(default)]
+    languages: HashMap<String, PartialLanguage>,
+    #[serde(default)]
+    extensions: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PartialLanguage {
+    extensions: Option<HashSet<String>>,
+    shebangs: Option<HashSet<String>>,
+    indent: Option<String>,
+    max_blank_lines: Option<usize>,
+    tab_width: Option<usize>,
+    formatting_markers: Option<FormattingMarkers>,
+    comment_attachment: Option<CommentAttachment>,
+    node_aliases: Option<HashMap<String, String>>,
+    skip_idempotence: Option<bool>,
+    #[serde(default)]
+    grammar: PartialGrammar,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PartialGrammar {
+    #[cfg(not(target_arch = "wasm32"))]
+    source: Option<GrammarSource>,
+    symbol: Option<String>,
+}
+
+/// Overrides `target` with `value`, if the latter is set, returning whether it was
+fn override_setting<T: Clone>(target: &mut Option<T>, value: &Option<T>) -> bool {
+    if value.is_some() {
+        target.clone_from(value);
+    }
+
+    value.is_some()
+}
+
+/// Layers the given sources, lowest priority first, so that each setting of each language takes
+/// its value from the last source that sets it. Likewise, each additional file extension maps to
+/// the language given by the last source that maps it. Returns the resulting language
+/// configurations and extension mapping, along with the provenance of the languages' settings.
+///
+/// # Errors
+///
+/// If a language lacks a required setting in every source, a `TopiaryConfigError` is returned.
+#[allow(clippy::result_large_err)]
+pub(crate) fn layer(
+    layers: Vec<(Source, PartialConfiguration)>,
+) -> TopiaryConfigResult<(
+    HashMap<String, LanguageConfiguration>,
+    HashMap<String, String>,
+    Provenance,
+)> {
+    let mut provenance = Provenance::default();
+    let mut merged: HashMap<String, PartialLanguage> = HashMap::new();
+    let mut extensions: HashMap<String, String> = HashMap::new();
+
+    for (source, configuration) in &layers {
+        extensions.extend(configuration.extensions.clone());
+
+        for (name, language) in &configuration.languages {
+            let target = merged.entry(name.clone()).or_default();
+            #[cfg_attr(target_arch = "wasm32", allow(unused_mut))]
+            let mut overridden = vec![
+                (
+                    "extensions",
+                    override_setting(&mut target.extensions, &language.extensions),
+                ),
+                (
+                    "shebangs",
+                    override_setting(&mut target.shebangs, &language.shebangs),
+                ),
+                (
+                    "indent",
+                    override_setting(&mut target.indent, &language.indent),
+                ),
+                (
+                    "max_blank_lines",
+                    override_setting(&mut target.max_blank_lines, &language.max_blank_lines),
+                ),
+                (
+                    "tab_width",
+                    override_setting(&mut target.tab_width, &language.tab_width),
+                ),
+                (
+                    "formatting_markers",
+                    override_setting(&mut target.formatting_markers, &language.formatting_markers),
+                ),
+                (
+                    "comment_attachment",
+                    override_setting(&mut target.comment_attachment, &language.comment_attachment),
+                ),
+                (
+                    "node_aliases",
+                    override_setting(&mut target.node_aliases, &language.node_aliases),
+                ),
+                (
+                    "skip_idempotence",
+                    override_setting(&mut target.skip_idempotence, &language.skip_idempotence),
+                ),
+                (
+                    "grammar.symbol",
+                    override_setting(&mut target.grammar.symbol, &language.grammar.symbol),
+                ),
+            ];
+            #[cfg(not(target_arch = "wasm32"))]
+            overridden.push((
+                "grammar.source",
+                override_setting(&mut target.grammar.source, &language.grammar.source),
+            ));
+
+            for (setting, _) in overridden.into_iter().filter(|(_, overridden)| *overridden) {
+                provenance.record(name, setting, source);
+            }
+        }
+    }
+
+    let languages = merged
+        .into_iter()
+        .map(|(name, language)| {
+            let missing = |setting| TopiaryConfigError::IncompleteLanguage {
+                language: name.clone(),
+                setting,
+            };
+
+            let configuration = LanguageConfiguration {
+                extensions: language.extensions.ok_or_else(|| missing("extensions"))?,
+                shebangs: language.shebangs.unwrap_or_default(),
+                indent: language.indent,
+                max_blank_lines: language.max_blank_lines,
+                tab_width: language.tab_width,
+                formatting_markers: language.formatting_markers,
+                comment_attachment: language.comment_attachment,
+                node_aliases: language.node_aliases.unwrap_or_default(),
+                skip_idempotence: language.skip_idempotence.unwrap_or_default(),
+                grammar: Grammar {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    source: language
+                        .grammar
+                        .source
+                        .ok_or_else(|| missing("grammar.source"))?,
+                    symbol: language.grammar.symbol,
+                },
+            };
+
+            Ok((name, configuration))
+        })
+        .collect::<TopiaryConfigResult<_>>()?;
+
+    Ok((languages, extensions, provenance))
+}
@@ -3,6 +3,7 @@
 //! Additional configuration has to be provided by the user of the library.
 pub mod error;
 pub mod language;
+mod layer;
 pub mod source;
 
 use std::{
@@ -24,6 +25,7 @@ use tempfile::tempdir;
 
 use crate::error::{TopiaryConfigError, TopiaryConfigResult};
 
+pub use layer::Provenance;
 pub use source::Source;
 
 /// The configuration of the Topiary.
@@ -34,12 +36,17 @@ pub use source::Source;
 #[derive(Debug, Clone)]
 pub struct Configuration {
     languages: Vec<Language>,
+    /// Additional file extensions (without leading dot), mapped to the name of their language.
+    /// These take precedence over the extensions of each language.
+    extensions: HashMap<String, String>,
 }
 
 /// Internal struct to help with deserialisation, converted to the actual Configuration in deserialization
 #[derive(Debug, serde::Deserialize, PartialEq, serde::Serialize, Clone)]
 struct SerdeConfiguration {
     languages: HashMap<String, LanguageConfiguration>,
+    #[serde(default)]
+    extensions: HashMap<String, String>,
 }
 
 impl Configuration {
@@ -93,6 +100,47 @@ impl Configuration {
             .ok_or(TopiaryConfigError::UnknownLanguage(name.to_string()))
     }
 
+    /// Lists the names of the configured languages, sorted, each with the file extensions that
+    /// are detected as that language (also sorted). This accounts for the top-level `extensions`
+    /// setting, so an extension that it maps to another language is not listed.
+    pub fn available_languages(&self) -> Vec<(&str, Vec<&str>)> {
+        let mut languages: Vec<(&str, Vec<&str>)> = self
+            .languages
+            .iter()
+            .map(|language| {
+                let mapped = self
+                    .extensions
+                    .iter()
+                    .filter(|(_, name)| **name == language.name)
+                    .map(|(extension, _)| extension);
+
+                let mut extensions: Vec<&str> = language
+                    .config
+                    .extensions
+                    .iter()
+                    .filter(|extension| !self.extensions.contains_key(*extension))
+                    .chain(mapped)
+                    .map(String::as_str)
+                    .collect();
+
+                extensions.sort_unstable();
+                (language.name.as_str(), extensions)
+            })
+            .collect();
+
+        languages.sort_unstable_by_key(|(name, _)| *name);
+        languages
+    }
+
+    /// Returns the formatting query that is bundled with Topiary for the named language, through
+    /// `topiary-queries`. Only the queries of the languages whose feature is enabled are bundled.
+    ///
+    /// Returns `None` if the language is not configured, or if its query is not bundled.
+    pub fn default_query_for(&self, name: &str) -> Option<&'static str> {
+        self.get_language(name).ok()?;
+        topiary_queries::formatting(name)
+    }
+
     /// Prefetch a language per its configuration
     ///
     /// # Errors
@@ -198,15 +246,22 @@ impl Configuration {
         Ok(())
     }
 
-    /// Convenience alias to detect the Language from a Path-like value's extension.
+    /// Convenience alias to detect the Language from a Path-like value's extension. Extensions
+    /// mapped to a language by the top-level `extensions` setting take precedence over those of
+    /// each language.
     ///
     /// # Errors
     ///
-    /// If the file extension is not supported, a `FormatterError` will be returned.
+    /// If the file extension is not supported, a `FormatterError` will be returned. If it is
+    /// mapped to a language that is not configured, a `TopiaryConfigError::UnknownLanguage` will
+    /// be returned.
     #[allow(clippy::result_large_err)]
     pub fn detect<P: AsRef<Path>>(&self, path: P) -> TopiaryConfigResult<&Language> {
         let pb = &path.as_ref().to_path_buf();
         if let Some(extension) = pb.extension().and_then(|ext| ext.to_str()) {
+            if let Some(name) = self.extensions.get(extension) {
+                return self.get_language(name);
+            }
             for lang in &self.languages {
                 if lang.config.extensions.contains(extension) {
                     return Ok(lang);
@@ -217,6 +272,58 @@ impl Configuration {
         Err(TopiaryConfigError::NoExtension(pb.clone()))
     }
 
+    /// Detects the Language from the shebang line (e.g., `#!/usr/bin/env bash`) at the start of
+    /// some input, per the `shebangs` of each language's configuration. This is useful when
+    /// there is no file extension to go by, such as when formatting stdin. Only the first line of
+    /// `input` is inspected.
+    ///
+    /// Returns `None` if the input has no shebang line, or if its interpreter is not associated
+    /// with any language.
+    pub fn detect_from_shebang(&self, input: &str) -> Option<&Language> {
+        let interpreter = language::shebang_interpreter(input)?;
+
+        self.languages
+            .iter()
+            .find(|language| language.matches_interpreter(interpreter))
+    }
+
+    /// Layers the given configuration sources, ordered from lowest to highest priority (e.g.,
+    /// built-in, then user, then project). Rather than being merged by Nickel, each source is
+    /// evaluated on its own and then overrides, per language and per setting, the sources before
+    /// it. A source can therefore override just one language's `indent`, or add a language,
+    /// inheriting everything else. Conflicting settings are resolved in favour of the last source;
+    /// the returned [`Provenance`] says which source each setting came from.
+    ///
+    /// # Errors
+    ///
+    /// If a source cannot be evaluated or deserialised, or if a language lacks a required setting
+    /// (such as its `extensions`) in every source, this function will return a
+    /// `TopiaryConfigError`.
+    #[allow(clippy::result_large_err)]
+    pub fn from_layers(sources: &[Source]) -> TopiaryConfigResult<(Self, Provenance)> {
+        let layers = sources
+            .iter()
+            .map(|source| {
+                let term = Self::evaluate(source.clone())?;
+                Ok((
+                    source.clone(),
+                    layer::PartialConfiguration::deserialize(term)?,
+                ))
+            })
+            .collect::<TopiaryConfigResult<_>>()?;
+
+        let (languages, extensions, provenance) = layer::layer(layers)?;
+
+        Ok((
+            SerdeConfiguration {
+                languages,
+                extensions,
+            }
+            .into(),
+            provenance,
+        ))
+    }
+
     #[allow(clippy::result_large_err)]
     fn parse_and_merge(sources: &[Source]) -> TopiaryConfigResult<(Self, NickelValue)> {
         let mut builder = ProgramBuilder::new()
@@ -241,6 +348,14 @@ impl Configuration {
 
     #[allow(clippy::result_large_err)]
     fn parse(source: Source) -> TopiaryConfigResult<(Self, NickelValue)> {
+        let term = Self::evaluate(source)?;
+        let serde_config = SerdeConfiguration::deserialize(term.clone())?;
+
+        Ok((serde_config.into(), term))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn evaluate(source: Source) -> TopiaryConfigResult<NickelValue> {
         let mut program = source
             .add_to(
                 ProgramBuilder::new()
@@ -249,16 +364,12 @@ impl Configuration {
             )
             .build::<CacheImpl>()?;
 
-        let term = program
+        program
             .eval_full_for_export()
             .map_err(|error| TopiaryConfigError::Nickel {
                 error: Box::new(error),
                 files: Box::new(program.files()),
-            })?;
-
-        let serde_config = SerdeConfiguration::deserialize(term.clone())?;
-
-        Ok((serde_config.into(), term))
+            })
     }
 }
 
@@ -302,7 +413,7 @@ impl PartialEq for Configuration {
         let lhs: HashMap<String, Language> = self.into();
         let rhs: HashMap<String, Language> = other.into();
 
-        lhs == rhs
+        lhs == rhs && self.extensions == other.extensions
     }
 }
 
@@ -314,7 +425,10 @@ impl From<SerdeConfiguration> for Configuration {
             .map(|(name, config)| Language::new(name, config))
             .collect();
 
-        Self { languages }
+        Self {
+            languages,
+            extensions: value.extensions,
+        }
     }
 }
 
@@ -322,3 +436,398 @@ pub(crate) fn project_dirs() -> directories::ProjectDirs {
     directories::ProjectDirs::from("", "", "topiary")
         .expect("Could not access the OS's Home directory")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::{Configuration, Source};
+    use crate::{
+        error::{TopiaryConfigError, TopiaryConfigFetchingError},
+        language::{CommentAttachment, FormattingMarkers, check_abi_version},
+        source::workspace_config_dir_from,
+    };
+
+    fn detected_language(input: &str) -> Option<String> {
+        Configuration::default()
+            .detect_from_shebang(input)
+            .map(|language| language.name.clone())
+    }
+
+    #[test]
+    fn detect_from_absolute_shebang() {
+        assert_eq!(
+            detected_language("#!/bin/bash\necho hello\n"),
+            Some("bash".into())
+        );
+    }
+
+    #[test]
+    fn detect_from_env_shebang() {
+        assert_eq!(
+            detected_language("#!/usr/bin/env bash\necho hello\n"),
+            Some("bash".into())
+        );
+        assert_eq!(
+            detected_language("#!/usr/bin/env -S LC_ALL=C sh -e\n"),
+            Some("bash".into())
+        );
+    }
+
+    #[test]
+    fn detect_from_versioned_shebang() {
+        assert_eq!(
+            detected_language("#!/usr/bin/env ocaml5.1\n"),
+            Some("ocaml".into())
+        );
+    }
+
+    #[test]
+    fn no_shebang_detects_nothing() {
+        assert_eq!(detected_language("echo hello\n#!/bin/bash\n"), None);
+        assert_eq!(detected_language("#!/usr/bin/env\n"), None);
+        assert_eq!(detected_language("#!/usr/bin/env perl\n"), None);
+        assert_eq!(detected_language(""), None);
+    }
+
+    fn layer_file(dir: &TempDir, name: &str, content: &str) -> Source {
+        let path: PathBuf = dir.path().join(name);
+        fs::write(&path, content).unwrap();
+        Source::File(path)
+    }
+
+    #[test]
+    fn project_layer_overrides_single_settings() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{
+              languages = {
+                json.indent = "    ",
+                foo = {
+                  extensions = ["foo"],
+                  grammar.source.path = "/path/to/foo.so",
+                },
+              },
+            }"#,
+        );
+
+        let (config, provenance) =
+            Configuration::from_layers(&[Source::Builtin, project.clone()]).unwrap();
+        let defaults = Configuration::default();
+
+        let json = config.get_language("json").unwrap();
+        let default_json = defaults.get_language("json").unwrap();
+        assert_eq!(json.indent(), Some("    ".into()));
+        assert_eq!(json.config.extensions, default_json.config.extensions);
+        assert_eq!(json.config.grammar, default_json.config.grammar);
+
+        assert!(config.get_language("foo").is_ok());
+        assert!(config.get_language("ocaml").is_ok());
+
+        assert!(matches!(
+            provenance.source_of("json", "indent"),
+            Some(Source::File(path)) if path.ends_with("project.ncl")
+        ));
+        assert!(matches!(
+            provenance.source_of("json", "extensions"),
+            Some(Source::Builtin)
+        ));
+        assert!(provenance.source_of("foo", "grammar.symbol").is_none());
+    }
+
+    #[test]
+    fn last_layer_wins_conflicts() {
+        let dir = TempDir::new().unwrap();
+        let user = layer_file(&dir, "user.ncl", r#"{ languages.json.indent = "\t" }"#);
+        let project = layer_file(&dir, "project.ncl", r#"{ languages.json.indent = " " }"#);
+
+        let (config, provenance) =
+            Configuration::from_layers(&[Source::Builtin, user, project]).unwrap();
+
+        assert_eq!(
+            config.get_language("json").unwrap().indent(),
+            Some(" ".into())
+        );
+        assert!(matches!(
+            provenance.source_of("json", "indent"),
+            Some(Source::File(path)) if path.ends_with("project.ncl")
+        ));
+    }
+
+    #[test]
+    fn workspace_configuration_is_sought_up_to_the_repository_root() {
+        let root = TempDir::new().unwrap();
+        let repo = root.path().join("repo");
+        let sub = repo.join("sub");
+        let start = sub.join("deeper");
+        fs::create_dir_all(&start).unwrap();
+        fs::create_dir(repo.join(".git")).unwrap();
+
+        let config_at = |dir: &std::path::Path, indent: &str| {
+            fs::create_dir(dir.join(".topiary")).unwrap();
+            fs::write(
+                dir.join(".topiary/languages.ncl"),
+                format!(r#"{{ languages.json.indent = "{indent}" }}"#),
+            )
+            .unwrap();
+        };
+        config_at(root.path(), "\t");
+        config_at(&repo, "   ");
+        config_at(&sub, " ");
+
+        let effective_indent = || {
+            let found = workspace_config_dir_from(&start);
+            let project = Source::File(found.join("languages.ncl"));
+            let (config, _) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+            config.get_language("json").unwrap().indent()
+        };
+
+        // The nearest configuration wins
+        assert_eq!(workspace_config_dir_from(&start), sub.join(".topiary"));
+        assert_eq!(effective_indent(), Some(" ".into()));
+
+        fs::remove_dir_all(sub.join(".topiary")).unwrap();
+        assert_eq!(workspace_config_dir_from(&start), repo.join(".topiary"));
+        assert_eq!(effective_indent(), Some("   ".into()));
+
+        // The configuration above the repository root is not considered
+        fs::remove_dir_all(repo.join(".topiary")).unwrap();
+        assert_eq!(workspace_config_dir_from(&start), start.join(".topiary"));
+        assert!(!start.join(".topiary").exists());
+    }
+
+    #[test]
+    fn max_blank_lines_is_optional() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{ languages.json.max_blank_lines = 2 }"#,
+        );
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+
+        assert_eq!(
+            config.get_language("json").unwrap().max_blank_lines(),
+            Some(2)
+        );
+        assert_eq!(
+            config.get_language("ocaml").unwrap().max_blank_lines(),
+            None
+        );
+    }
+
+    #[test]
+    fn formatting_markers_are_optional() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{ languages.json.formatting_markers = { off = "fmt: off", on = "fmt: on" } }"#,
+        );
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+
+        assert_eq!(
+            config.get_language("json").unwrap().formatting_markers(),
+            Some(&FormattingMarkers {
+                off: "fmt: off".into(),
+                on: "fmt: on".into(),
+            })
+        );
+        assert_eq!(
+            config.get_language("ocaml").unwrap().formatting_markers(),
+            None
+        );
+    }
+
+    #[test]
+    fn comment_attachment_is_optional() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{ languages.json.comment_attachment = 'preceding }"#,
+        );
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+
+        assert_eq!(
+            config.get_language("json").unwrap().comment_attachment(),
+            Some(CommentAttachment::Preceding)
+        );
+        assert_eq!(
+            config.get_language("ocaml").unwrap().comment_attachment(),
+            None
+        );
+    }
+
+    #[test]
+    fn tab_width_is_optional() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(&dir, "project.ncl", r#"{ languages.json.tab_width = 4 }"#);
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+
+        assert_eq!(config.get_language("json").unwrap().tab_width(), Some(4));
+        assert_eq!(config.get_language("ocaml").unwrap().tab_width(), None);
+    }
+
+    #[test]
+    fn node_aliases_default_to_empty() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{ languages.json.node_aliases = { dictionary = "object" } }"#,
+        );
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+
+        assert_eq!(
+            config.get_language("json").unwrap().node_aliases(),
+            &HashMap::from([("dictionary".to_owned(), "object".to_owned())])
+        );
+        assert!(
+            config
+                .get_language("ocaml")
+                .unwrap()
+                .node_aliases()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn skip_idempotence_defaults_to_false() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{ languages.json.skip_idempotence = true }"#,
+        );
+
+        let (config, provenance) = Configuration::from_layers(&[Source::Builtin, project]).unwrap();
+
+        assert!(config.get_language("json").unwrap().skip_idempotence());
+        assert!(!config.get_language("ocaml").unwrap().skip_idempotence());
+        assert!(matches!(
+            provenance.source_of("json", "skip_idempotence"),
+            Some(Source::File(path)) if path.ends_with("project.ncl")
+        ));
+    }
+
+    #[test]
+    fn extensions_map_to_languages() {
+        let dir = TempDir::new().unwrap();
+        let user = layer_file(&dir, "user.ncl", r#"{ extensions = { tf = "toml" } }"#);
+        let project = layer_file(
+            &dir,
+            "project.ncl",
+            r#"{ extensions = { jsonc = "json", json = "toml" } }"#,
+        );
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, user, project]).unwrap();
+        let detected = |path| config.detect(path).unwrap().name.clone();
+
+        assert_eq!(detected("main.tf"), "toml");
+        assert_eq!(detected("settings.jsonc"), "json");
+        // The mapping takes precedence over the extensions of each language
+        assert_eq!(detected("package.json"), "toml");
+        assert_eq!(detected("lib.ml"), "ocaml");
+    }
+
+    #[test]
+    fn extensions_merge_with_builtin_configuration() {
+        let dir = TempDir::new().unwrap();
+        let user = layer_file(
+            &dir,
+            "user.ncl",
+            r#"{ extensions = { tf = "toml", unknown = "klingon" } }"#,
+        );
+
+        let (config, _) = Configuration::parse_and_merge(&[user, Source::Builtin]).unwrap();
+
+        assert_eq!(config.detect("main.tf").unwrap().name, "toml");
+        assert_eq!(config.detect("lib.ml").unwrap().name, "ocaml");
+        assert!(matches!(
+            config.detect("file.unknown"),
+            Err(TopiaryConfigError::UnknownLanguage(ref name)) if name == "klingon"
+        ));
+    }
+
+    #[test]
+    fn every_bundled_query_is_discoverable() {
+        let config = Configuration::default();
+        let languages = config.available_languages();
+        let queries = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../topiary-queries/queries");
+
+        for entry in fs::read_dir(queries).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let query = fs::read_to_string(path.join(topiary_queries::FORMATTING_QUERY)).unwrap();
+
+            assert!(
+                languages.iter().any(|(language, _)| *language == name),
+                "{name} is not listed"
+            );
+            assert_eq!(config.default_query_for(name), Some(query.as_str()));
+        }
+
+        assert_eq!(config.default_query_for("klingon"), None);
+    }
+
+    #[test]
+    fn available_languages_account_for_extension_mapping() {
+        let dir = TempDir::new().unwrap();
+        let user = layer_file(
+            &dir,
+            "user.ncl",
+            r#"{ extensions = { jsonc = "json", json = "toml" } }"#,
+        );
+
+        let (config, _) = Configuration::from_layers(&[Source::Builtin, user]).unwrap();
+        let languages: HashMap<&str, Vec<&str>> =
+            config.available_languages().into_iter().collect();
+
+        assert!(languages["json"].contains(&"jsonc"));
+        assert!(!languages["json"].contains(&"json"));
+        assert!(languages["toml"].contains(&"json"));
+    }
+
+    #[test]
+    fn grammar_abi_version_is_checked() {
+        let path = PathBuf::from("/path/to/grammar.so");
+        let supported = topiary_tree_sitter_facade::LANGUAGE_VERSION;
+        assert!(check_abi_version(&path, supported).is_ok());
+
+        let unsupported = supported + 1;
+        let err = check_abi_version(&path, unsupported).unwrap_err();
+        assert!(matches!(
+            err,
+            TopiaryConfigFetchingError::IncompatibleGrammar { ref path, version }
+                if path.ends_with("grammar.so") && version == unsupported
+        ));
+        assert!(
+            err.to_string()
+                .contains(&format!("ABI version {unsupported}"))
+        );
+    }
+
+    #[test]
+    fn incomplete_layered_language_fails() {
+        let dir = TempDir::new().unwrap();
+        let project = layer_file(&dir, "project.ncl", r#"{ languages.foo.indent = " " }"#);
+
+        let result = Configuration::from_layers(&[Source::Builtin, project]);
+
+        assert!(matches!(
+            result,
+            Err(TopiaryConfigError::IncompleteLanguage { ref language, setting })
+                if language == "foo" && setting == "extensions"
+        ));
+    }
+}
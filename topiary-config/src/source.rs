@@ -38,8 +38,8 @@ impl Source {
     /// Iterate through valid sources of configuration, in priority order (highest to lowest):
     ///
     /// 1. `path`, passed as a CLI argument/environment variable
-    /// 2. `.topiary/languages.ncl` (or equivalent)
-    /// 3. `~/.config/topiary/languages.ncl`
+    /// 2. `.topiary/languages.ncl` (or equivalent), up to the repository root
+    /// 3. `$XDG_CONFIG_HOME/topiary/languages.ncl` (macOS; `~/.config` by default)
     /// 4. OS configuration directory (if different from #3)
     /// 5. Built-in configuration: [`Self::builtin_nickel`]
     pub fn config_sources(path: &Option<PathBuf>) -> impl Iterator<Item = (&'static str, Self)> {
@@ -117,6 +117,24 @@ impl Source {
             .collect()
     }
 
+    /// Return the configuration sources in effect, ordered from lowest to highest priority, as
+    /// expected by [`Configuration::from_layers`](crate::Configuration::from_layers). When
+    /// merging, these are all valid sources; otherwise, they are the built-in configuration and
+    /// the valid source with the highest priority. See [`Self::config_sources`].
+    pub fn layers(merge: bool, file: &Option<PathBuf>) -> Vec<Self> {
+        let mut sources = if merge {
+            Self::fetch_all(file)
+        } else {
+            match Self::fetch_one(file) {
+                Self::Builtin => vec![Self::Builtin],
+                source => vec![source, Self::Builtin],
+            }
+        };
+
+        sources.reverse();
+        sources
+    }
+
     /// Checks if a given [`Self`] variant can be found as a path or value
     pub fn languages_exists(&self) -> bool {
         match self {
@@ -188,28 +206,50 @@ fn os_config_dir() -> Source {
 }
 
 /// Ascend the directory hierarchy, starting from the current working directory, in search of the
-/// nearest `.topiary` configuration directory.
+/// nearest `.topiary` configuration directory. See [`workspace_config_dir_from`].
 /// Directory is not guaranteed to exist.
 fn workspace_config_dir() -> Source {
     let pwd = current_dir().expect("Could not get current working directory");
-    let dir = pwd
-        .ancestors()
-        .map(|path| path.join(".topiary"))
-        .find(|path| path.exists())
-        .unwrap_or_else(|| pwd.join(".topiary"));
+    Source::Directory(workspace_config_dir_from(&pwd))
+}
 
-    Source::Directory(dir)
+/// Entries that mark the root of a repository, above which no workspace configuration is sought
+const REPOSITORY_ROOT_MARKERS: [&str; 3] = [".git", ".hg", ".jj"];
+
+/// Ascend the directory hierarchy, starting from `start`, in search of the nearest `.topiary`
+/// configuration directory. The search stops at the root of the repository containing `start`,
+/// if any, so that a `.topiary` directory outside the project is not picked up. When none is
+/// found, `start/.topiary` is returned.
+/// Directory is not guaranteed to exist.
+pub(crate) fn workspace_config_dir_from(start: &Path) -> PathBuf {
+    for dir in start.ancestors() {
+        let candidate = dir.join(".topiary");
+        if candidate.exists() {
+            return candidate;
+        }
+
+        if REPOSITORY_ROOT_MARKERS
+            .iter()
+            .any(|marker| dir.join(marker).exists())
+        {
+            break;
+        }
+    }
+
+    start.join(".topiary")
 }
 
 /// Certain platforms have alternate config directories (macOS)
-/// polyfill for linux-like `os_config_dir()`
+/// polyfill for linux-like `os_config_dir()`, which honours `$XDG_CONFIG_HOME`
 /// https://docs.rs/directories/latest/src/directories/lib.rs.html#38-43
 /// Directory is not guaranteed to exist.
 #[cfg(target_os = "macos")]
 fn unix_home_config_dir() -> Source {
-    let dir = std::env::home_dir()
-        .unwrap_or_default()
-        .join(".config/topiary");
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| std::env::home_dir().unwrap_or_default().join(".config"))
+        .join("topiary");
 
     Source::Directory(dir)
 }
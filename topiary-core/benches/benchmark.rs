@@ -1,7 +1,10 @@
 use criterion::{Criterion, criterion_group, criterion_main};
 use std::fs;
 use std::io;
-use topiary_core::{Language, Operation, TopiaryQuery, formatter};
+use topiary_core::{
+    CommentAttachment, FinalNewline, Language, LineEnding, Operation, ParsingErrorTolerance,
+    TopiaryQuery, formatter,
+};
 
 fn setup() -> (String, Language) {
     let input = fs::read_to_string("../topiary-cli/tests/samples/input/nickel.ncl").unwrap();
@@ -16,6 +19,10 @@ fn setup() -> (String, Language) {
         formatting_query: TopiaryQuery::new(&grammar, topiary_queries::nickel()).unwrap(),
         grammar,
         indent: None,
+        max_blank_lines: None,
+        formatting_markers: None,
+        comment_attachment: CommentAttachment::default(),
+        tab_width: None,
         injection_query: None,
     };
 
@@ -35,7 +42,11 @@ fn criterion_benchmark(c: &mut Criterion) {
                 &language,
                 Operation::Format {
                     skip_idempotence: true,
-                    tolerate_parsing_errors: false,
+                    tolerate_parsing_errors: ParsingErrorTolerance::None,
+                    final_newline: FinalNewline::Single,
+                    line_ending: LineEnding::Lf,
+                    max_line_width: None,
+                    strict: false,
                 },
                 None,
             )
@@ -2,7 +2,8 @@ use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
 use std::{io, sync::Arc};
 use topiary_config::Configuration;
 use topiary_core::{
-    InjectionQuery, Language, LanguageResolver, Operation, TopiaryQuery, formatter_str,
+    CommentAttachment, FinalNewline, InjectionQuery, Language, LanguageResolver, LineEnding,
+    Operation, ParsingErrorTolerance, TopiaryQuery, formatter_str,
 };
 
 const OCAMLLEX_FORMATTING_QUERY: &str =
@@ -28,6 +29,10 @@ fn language_from_config(
             .map(|query_content| InjectionQuery::new(&grammar, query_content).unwrap()),
         grammar,
         indent: config_language.indent(),
+        max_blank_lines: config_language.max_blank_lines(),
+        formatting_markers: None,
+        comment_attachment: CommentAttachment::default(),
+        tab_width: None,
     }
 }
 
@@ -59,7 +64,11 @@ fn format_ocamllex(input: &str, language: &Language, resolve: Option<&LanguageRe
         language,
         Operation::Format {
             skip_idempotence: true,
-            tolerate_parsing_errors: false,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
         },
         resolve,
     )
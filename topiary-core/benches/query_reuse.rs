@@ -0,0 +1,82 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use topiary_config::Configuration;
+use topiary_core::{
+    CommentAttachment, FinalNewline, Language, LineEnding, Operation, ParsingErrorTolerance,
+    TopiaryQuery, formatter_str,
+};
+use topiary_tree_sitter_facade::Language as Grammar;
+
+// The number of small inputs in a run, as in a directory of hundreds of files
+const FILE_COUNT: usize = 300;
+
+fn inputs() -> Vec<String> {
+    (0..FILE_COUNT)
+        .map(|i| format!("let x{i} = {{ a = {i}, b = [1,2,3] }} in x{i}.a\n"))
+        .collect()
+}
+
+fn language(grammar: &Grammar) -> Language {
+    Language {
+        name: "nickel".to_owned(),
+        formatting_query: TopiaryQuery::new(grammar, topiary_queries::nickel()).unwrap(),
+        grammar: grammar.clone(),
+        indent: None,
+        max_blank_lines: None,
+        formatting_markers: None,
+        comment_attachment: CommentAttachment::default(),
+        tab_width: None,
+        injection_query: None,
+    }
+}
+
+fn format(input: &str, language: &Language) {
+    let mut output = Vec::new();
+    formatter_str(
+        input,
+        &mut output,
+        language,
+        Operation::Format {
+            skip_idempotence: true,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
+        },
+        None,
+    )
+    .unwrap();
+}
+
+// Compares compiling the query for every input, as the CLI did before it cached its language
+// definitions, against compiling it once per run
+fn criterion_benchmark(c: &mut Criterion) {
+    let config = Configuration::default();
+    let grammar = config.get_language("nickel").unwrap().grammar().unwrap();
+    let inputs = inputs();
+
+    let mut group = c.benchmark_group("query_reuse");
+    group.sample_size(10);
+
+    group.bench_function("compile_per_file", |b| {
+        b.iter(|| {
+            for input in &inputs {
+                format(input, &language(&grammar));
+            }
+        });
+    });
+
+    group.bench_function("compile_once", |b| {
+        b.iter(|| {
+            let language = language(&grammar);
+            for input in &inputs {
+                format(input, &language);
+            }
+        });
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,31 +1,57 @@
 use std::{
     borrow::Cow,
+    cmp::Reverse,
     collections::{HashMap, HashSet},
-    mem,
-    ops::Deref,
+    iter, mem,
+    ops::{Deref, Range},
 };
 
 use rootcause::prelude::ResultExt;
 use topiary_tree_sitter_facade::Node;
+use unicode_width::UnicodeWidthStr;
 
 use crate::{
-    Atom, Capitalisation, FormatterError, FormatterResult, ScopeCondition, ScopeInformation,
-    tree_sitter::NodeExt,
+    Atom, Capitalisation, CommentAttachment, FormatterError, FormatterResult, FormattingMarkers,
+    ScopeCondition, ScopeInformation, Warning,
+    pretty::column_width,
+    tree_sitter::{InjectionSpan, NodeExt, Position, ScopeAnnotation, ScopeBoundary},
 };
 
-/// A struct that holds sets of node IDs that have line breaks before or after them.
+/// A struct that holds maps of node IDs that have line breaks before or after them.
 ///
 /// This struct is used by the `detect_line_breaks` function to return the node IDs that
 /// have at least `minimum_line_breaks` number of line breaks before or after them in the
-/// flattened vector of nodes.
+/// flattened vector of nodes, along with their number of line breaks.
 struct NodesWithLinebreaks {
-    /// A set of node IDs that have line breaks before them.
-    before: HashSet<usize>,
-    /// A set of node IDs that have line breaks after them.
-    after: HashSet<usize>,
+    /// The node IDs that have line breaks before them, with their number.
+    before: HashMap<usize, u32>,
+    /// The node IDs that have line breaks after them, with their number.
+    after: HashMap<usize, u32>,
 }
 
-/// Mutable references to the three boolean "flag" fields of an [`Atom::Leaf`],
+/// The parts of the input that [`AtomCollection::collect_leaves`] passes
+/// through verbatim, rather than formatting them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Verbatim<'a> {
+    /// Whether ERROR nodes are passed through verbatim
+    pub errors: bool,
+    /// If given, the source between the comments that contain these markers
+    /// is passed through verbatim
+    pub formatting_markers: Option<&'a FormattingMarkers>,
+    /// If not empty, only the nodes of these kinds, along with their
+    /// descendants, are formatted; everything else is passed through verbatim
+    pub only_kinds: &'a [String],
+    /// If given, only the nodes on these ranges of lines (zero-based and
+    /// end-exclusive), along with the whitespace between them, are formatted,
+    /// as are the nodes selected by `only_kinds`; everything else is passed
+    /// through verbatim
+    pub only_lines: Option<&'a [Range<usize>]>,
+    /// Whether the whitespace between the leaves of the input is recorded, so
+    /// that [`AtomCollection::preserve_original_whitespace`] can keep it
+    pub whitespace: bool,
+}
+
+/// Mutable references to the boolean "flag" fields of an [`Atom::Leaf`],
 /// exposed together so leaf-flag directives can flip a single flag without
 /// repeating the leaf-id search loop.
 struct LeafFlagsMut<'a> {
@@ -34,6 +60,65 @@ struct LeafFlagsMut<'a> {
     keep_whitespace: &'a mut bool,
 }
 
+/// The capture names that Topiary understands: `do_nothing`, which is handled
+/// when the query is applied, and those understood by
+/// [`AtomCollection::resolve_capture`].
+pub(crate) const CAPTURE_NAMES: &[&str] = &[
+    "do_nothing",
+    "allow_blank_line_before",
+    "append_delimiter",
+    "append_empty_softline",
+    "append_hardline",
+    "append_indent_start",
+    "append_indent_end",
+    "append_input_softline",
+    "append_empty_input_softline",
+    "append_blank_input_softline",
+    "append_space",
+    "append_space_if_followed",
+    "append_antispace",
+    "append_spaced_softline",
+    "prepend_delimiter",
+    "prepend_empty_softline",
+    "prepend_hardline",
+    "prepend_hardline_unless_first",
+    "prepend_indent_start",
+    "prepend_indent_end",
+    "prepend_input_softline",
+    "prepend_empty_input_softline",
+    "prepend_blank_input_softline",
+    "prepend_space",
+    "prepend_space_unless_bol",
+    "prepend_antispace",
+    "prepend_spaced_softline",
+    "align",
+    "leaf",
+    "delete",
+    "delete_with_surrounding_space",
+    "upper_case",
+    "lower_case",
+    "prepend_begin_scope",
+    "append_begin_scope",
+    "prepend_end_scope",
+    "append_end_scope",
+    "prepend_begin_measuring_scope",
+    "append_begin_measuring_scope",
+    "prepend_end_measuring_scope",
+    "append_end_measuring_scope",
+    "append_empty_scoped_softline",
+    "append_spaced_scoped_softline",
+    "prepend_empty_scoped_softline",
+    "prepend_spaced_scoped_softline",
+    "single_line_no_indent",
+    "single_line_scope_no_indent",
+    "multi_line_indent_all",
+    "continuation_indent",
+    "keep_whitespace",
+    "sort_children",
+    "single_line",
+    "multi_line",
+];
+
 /// Contains Topiary's internal representation parsed document.
 #[derive(Debug)]
 pub struct AtomCollection {
@@ -60,9 +145,18 @@ pub struct AtomCollection {
     /// multiple lines. During initial collection all such nodes are added to this
     /// HashSet for easy checking if a node spans multiple lines.
     multi_line_nodes: HashSet<usize>,
-    /// During initial Atom collection, any node that has a blank lines above
-    /// the node is added to this HashSet.
-    blank_lines_before: HashSet<usize>,
+    /// The ids of the nodes captured by `@single_line`, keyed by the id of
+    /// each node within them, themselves included
+    single_line_roots: HashMap<usize, Vec<usize>>,
+    /// The ids of the nodes captured by `@multi_line`, keyed by the id of each
+    /// of their children and of themselves
+    multi_line_roots: HashMap<usize, Vec<usize>>,
+    /// During initial Atom collection, any node that has blank lines above
+    /// the node is added to this HashMap, along with their number.
+    blank_lines_before: HashMap<usize, usize>,
+    /// During initial Atom collection, any node that has blank lines below
+    /// the node is added to this HashMap, along with their number.
+    blank_lines_after: HashMap<usize, usize>,
     /// During initial Atom collection, any node that has a linebreak directly
     /// before it is added to this HashSet.
     line_break_before: HashSet<usize>,
@@ -71,6 +165,68 @@ pub struct AtomCollection {
     line_break_after: HashSet<usize>,
     /// Used to generate unique IDs
     counter: usize,
+    /// The leaves marked by `@align`, keyed by their id, along with the name
+    /// of their alignment group.
+    aligned_leaves: HashMap<usize, String>,
+    /// If set, the maximum line width and the width of one indentation level:
+    /// scopes whose single-line rendering would exceed the former are forced
+    /// into multi-line mode.
+    line_width_limit: Option<(usize, usize)>,
+    /// The maximum number of consecutive blank lines, set by
+    /// [`AtomCollection::limit_blank_lines`].
+    blank_line_limit: usize,
+    /// The node to which trailing comments are attached, set by
+    /// [`AtomCollection::attach_comments`].
+    comment_attachment: CommentAttachment,
+    /// The ids of the comment leaves that start on the line on which the
+    /// preceding node ends.
+    trailing_comments: HashSet<usize>,
+    /// The ids of the leaves, originating from ERROR nodes or from nodes
+    /// captured in a pattern with `#verbatim!`, that are to be passed through
+    /// verbatim.
+    frozen_leaves: HashSet<usize>,
+    /// The byte ranges in which formatting is disabled, by the language's
+    /// formatting markers or because they lie outside the kinds of node that
+    /// are formatted.
+    disabled_ranges: Vec<Range<usize>>,
+    /// The byte ranges of the outermost nodes of the kinds that are formatted,
+    /// if only some kinds are; captures outside of them are ignored.
+    selected_ranges: Option<Vec<Range<usize>>>,
+    /// The leaves that hold the source of the ranges in which formatting is
+    /// disabled, keyed by their id, along with the byte range of their content.
+    /// These leaves are also frozen.
+    disabled_leaves: HashMap<usize, Range<usize>>,
+    /// The boundaries of the indentation ranges named by `#indent_id!`, keyed
+    /// by that name. Each boundary is the byte offset at which the indentation
+    /// starts or ends, paired with `true` for a start and `false` for an end.
+    indent_ranges: HashMap<String, Vec<(usize, bool)>>,
+    /// The runs of children to be sorted, recorded by `@sort_children`
+    sorted_children: Vec<SortRun>,
+    /// The ids of the nodes whose children were recorded by `@sort_children`
+    sorted_parents: HashSet<usize>,
+    /// The number of spaces, set by `#space_count!`, that `@append_space`
+    /// puts after each leaf, keyed by its id, where it is more than one.
+    space_counts_after: HashMap<usize, usize>,
+    /// The number of spaces, set by `#space_count!`, that `@prepend_space`
+    /// puts before each leaf, keyed by its id, where it is more than one.
+    space_counts_before: HashMap<usize, usize>,
+    /// The whitespace that precedes each leaf in the input, keyed by its id,
+    /// along with the id of the leaf that precedes it there
+    original_gaps: HashMap<usize, (usize, String)>,
+    /// Whether to keep the input's whitespace where it differs from the
+    /// formatted whitespace only in amount, set by
+    /// [`AtomCollection::preserve_original_whitespace`].
+    preserve_whitespace: bool,
+    /// The boundaries of the scopes resolved during post-processing, keyed by
+    /// the id of the node at which each was captured
+    resolved_scopes: HashMap<usize, Vec<ScopeAnnotation>>,
+    /// The width, in columns, of a tab in the input, with which the original
+    /// column and indentation of each leaf are measured
+    tab_width: usize,
+    /// The number of query matches that were applied to this collection
+    query_match_count: usize,
+    /// The non-fatal issues found while applying the query
+    warnings: Vec<Warning>,
 }
 
 impl AtomCollection {
@@ -86,18 +242,108 @@ impl AtomCollection {
             specified_leaf_nodes: HashSet::new(),
             parent_leaf_nodes: HashMap::new(),
             multi_line_nodes: HashSet::new(),
-            blank_lines_before: HashSet::new(),
+            single_line_roots: HashMap::new(),
+            multi_line_roots: HashMap::new(),
+            blank_lines_before: HashMap::new(),
+            blank_lines_after: HashMap::new(),
             line_break_before: HashSet::new(),
             line_break_after: HashSet::new(),
             counter: 0,
+            aligned_leaves: HashMap::new(),
+            line_width_limit: None,
+            blank_line_limit: 1,
+            comment_attachment: CommentAttachment::default(),
+            trailing_comments: HashSet::new(),
+            frozen_leaves: HashSet::new(),
+            disabled_ranges: Vec::new(),
+            selected_ranges: None,
+            disabled_leaves: HashMap::new(),
+            indent_ranges: HashMap::new(),
+            sorted_children: Vec::new(),
+            sorted_parents: HashSet::new(),
+            space_counts_after: HashMap::new(),
+            space_counts_before: HashMap::new(),
+            original_gaps: HashMap::new(),
+            preserve_whitespace: false,
+            resolved_scopes: HashMap::new(),
+            tab_width: crate::language::DEFAULT_TAB_WIDTH,
+            query_match_count: 0,
+            warnings: Vec::new(),
         }
     }
 
-    /// Use this to create an initial `AtomCollection`
+    /// Forces scopes whose single-line rendering would be wider than
+    /// `max_line_width` columns into multi-line mode, during post-processing.
+    /// `indent` is the string with which one level of indentation will be
+    /// rendered.
+    pub fn limit_line_width(&mut self, max_line_width: usize, indent: &str) {
+        self.line_width_limit = Some((max_line_width, indent.chars().count()));
+    }
+
+    /// Caps the number of consecutive blank lines at `max_blank_lines`, during
+    /// post-processing. If it is 0, blank lines are removed altogether. The
+    /// default is 1.
+    pub fn limit_blank_lines(&mut self, max_blank_lines: usize) {
+        self.blank_line_limit = max_blank_lines;
+    }
+
+    /// Keeps the input's whitespace between consecutive leaves that were
+    /// adjacent in the input, where it differs from the formatted whitespace
+    /// only in amount: the spaces between leaves on the same line, and the
+    /// number of blank lines (up to the limit) between leaves on different
+    /// lines. This happens at the end of post-processing, so that formatting
+    /// changes the whitespace of the input no more than it must. The leaves
+    /// must have been collected with [`Verbatim::whitespace`] set.
+    pub fn preserve_original_whitespace(&mut self) {
+        self.preserve_whitespace = true;
+    }
+
+    /// Sets the node to which trailing comments are attached, during
+    /// post-processing. The default is the following node, which leaves their
+    /// placement to the query.
+    pub fn attach_comments(&mut self, comment_attachment: CommentAttachment) {
+        self.comment_attachment = comment_attachment;
+    }
+
+    /// Consumes the collection, returning its atoms.
+    pub fn into_atoms(self) -> Vec<Atom> {
+        self.atoms
+    }
+
+    /// The number of query matches that were applied to this collection
+    pub(crate) fn query_match_count(&self) -> usize {
+        self.query_match_count
+    }
+
+    pub(crate) fn set_query_match_count(&mut self, query_match_count: usize) {
+        self.query_match_count = query_match_count;
+    }
+
+    /// Takes the boundaries of the scopes resolved during post-processing,
+    /// keyed by the id of the node at which each was captured
+    pub(crate) fn take_resolved_scopes(&mut self) -> HashMap<usize, Vec<ScopeAnnotation>> {
+        mem::take(&mut self.resolved_scopes)
+    }
+
+    /// Takes the non-fatal issues found while applying the query, leaving
+    /// none in their place
+    pub(crate) fn take_warnings(&mut self) -> Vec<Warning> {
+        mem::take(&mut self.warnings)
+    }
+
+    pub(crate) fn set_warnings(&mut self, warnings: Vec<Warning>) {
+        self.warnings = warnings;
+    }
+
+    /// Use this to create an initial `AtomCollection`, passing the parts of
+    /// the source set by `verbatim` through as they are. Tabs in the source
+    /// are taken to be `tab_width` columns wide.
     pub fn collect_leaves(
         root: &Node,
         source: &[u8],
         specified_leaf_nodes: HashSet<usize>,
+        verbatim: Verbatim,
+        tab_width: usize,
     ) -> FormatterResult<Self> {
         // Flatten the tree, from the root node, in a depth-first traversal
         let dfs_nodes = dfs_flatten(root);
@@ -114,13 +360,65 @@ impl AtomCollection {
             specified_leaf_nodes,
             parent_leaf_nodes: HashMap::new(),
             multi_line_nodes,
-            blank_lines_before: blank_line_nodes.before,
-            line_break_before: line_break_nodes.before,
-            line_break_after: line_break_nodes.after,
+            single_line_roots: HashMap::new(),
+            multi_line_roots: HashMap::new(),
+            blank_lines_before: blank_lines(blank_line_nodes.before),
+            blank_lines_after: blank_lines(blank_line_nodes.after),
+            line_break_before: line_break_nodes.before.into_keys().collect(),
+            line_break_after: line_break_nodes.after.into_keys().collect(),
             counter: 0,
+            aligned_leaves: HashMap::new(),
+            line_width_limit: None,
+            blank_line_limit: 1,
+            comment_attachment: CommentAttachment::default(),
+            trailing_comments: detect_trailing_comments(&dfs_nodes),
+            frozen_leaves: HashSet::new(),
+            disabled_ranges: Vec::new(),
+            selected_ranges: None,
+            disabled_leaves: HashMap::new(),
+            indent_ranges: HashMap::new(),
+            sorted_children: Vec::new(),
+            sorted_parents: HashSet::new(),
+            space_counts_after: HashMap::new(),
+            space_counts_before: HashMap::new(),
+            original_gaps: HashMap::new(),
+            preserve_whitespace: false,
+            resolved_scopes: HashMap::new(),
+            tab_width,
+            query_match_count: 0,
+            warnings: Vec::new(),
         };
 
-        atoms.collect_leaves_inner(root, source, 0)?;
+        if let Some(markers) = verbatim.formatting_markers {
+            atoms.disabled_ranges = disabled_ranges(&dfs_nodes, source, markers);
+        }
+        if !verbatim.only_kinds.is_empty() || verbatim.only_lines.is_some() {
+            let mut selected = selected_ranges(&dfs_nodes, verbatim.only_kinds);
+            if let Some(lines) = verbatim.only_lines {
+                selected.extend(selected_line_ranges(&dfs_nodes, source, lines));
+            }
+            let selected = merge_ranges(selected);
+            atoms
+                .disabled_ranges
+                .extend(complement(&selected, source.len()));
+            atoms.selected_ranges = Some(selected);
+        }
+
+        atoms.collect_leaves_inner(root, source, 0, verbatim.errors)?;
+
+        let disabled_leaves: Vec<(usize, Range<usize>)> = atoms
+            .disabled_leaves
+            .iter()
+            .map(|(id, range)| (*id, range.clone()))
+            .collect();
+        for (id, range) in disabled_leaves {
+            atoms.freeze_range(id, range, source);
+        }
+
+        if verbatim.whitespace {
+            atoms.original_gaps =
+                original_gaps(&atoms.atoms, &dfs_nodes, &atoms.disabled_leaves, source);
+        }
 
         Ok(atoms)
     }
@@ -136,7 +434,8 @@ impl AtomCollection {
             if let Atom::Leaf {
                 id,
                 content,
-                original_position,
+                original_column,
+                original_line_indent,
                 ..
             } = atom
                 && *id == node_id
@@ -144,14 +443,72 @@ impl AtomCollection {
                 *content = new_content;
                 // Injected formatters return column-zero text; let the host
                 // leaf indentation account for the current render column.
-                original_position.column = 1;
+                *original_column = 0;
+                *original_line_indent = 0;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Splice formatted inner-language text into the [`Atom::Leaf`] that
+    /// encloses the injected node of `span`, in place of the node's text.
+    /// This is the case of injections within a host leaf, such as a string.
+    /// Returns whether the enclosing leaf was found, with the node's text
+    /// where expected.
+    ///
+    /// The lines after the first are indented like the host input line on
+    /// which the injected node starts, as are the other lines of the leaf.
+    pub fn splice_injected_content(&mut self, span: &InjectionSpan, new_content: &str) -> bool {
+        let Some(&leaf_id) = self.parent_leaf_nodes.get(&span.node_id) else {
+            return false;
+        };
+
+        for atom in &mut self.atoms {
+            if let Atom::Leaf {
+                id,
+                content,
+                original_position,
+                ..
+            } = atom
+                && *id == leaf_id
+            {
+                // Tree-sitter columns count bytes, so the node's offset in the
+                // leaf follows from their positions
+                let rows = (span.position.row - original_position.row) as usize;
+                let line_offset: usize =
+                    content.split_inclusive('\n').take(rows).map(str::len).sum();
+                let column_offset = if rows == 0 {
+                    span.position.column - original_position.column
+                } else {
+                    span.position.column - 1
+                } as usize;
+                let start = line_offset + column_offset;
+                let end = start + span.content.len();
+
+                if content.get(start..end) != Some(span.content) {
+                    return false;
+                }
+
+                let indented = new_content
+                    .split('\n')
+                    .enumerate()
+                    .map(|(index, line)| match (index, line) {
+                        (0, _) | (_, "") => Cow::Borrowed(line),
+                        _ => Cow::Owned(format!("{}{line}", span.indentation)),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                content.replace_range(start..end, &indented);
+
                 return true;
             }
         }
+
         false
     }
 
-    /// Apply `f` to the three boolean flags of every [`Atom::Leaf`] in
+    /// Apply `f` to the boolean flags of every [`Atom::Leaf`] in
     /// `self.atoms` whose tree-sitter `id` equals `node_id`. Used by the
     /// leaf-flag directives (`@single_line_no_indent`,
     /// `@multi_line_indent_all`, `@keep_whitespace`).
@@ -174,6 +531,160 @@ impl AtomCollection {
             }
         }
     }
+    /// Rewrite the content of the [`Atom::Leaf`] whose tree-sitter `id`
+    /// equals `node_id` to the given case. Used by the `#lower_case!` and
+    /// `#upper_case!` predicates; as case folding is idempotent, it is safe to
+    /// apply more than once to the same leaf.
+    fn rewrite_leaf_case(&mut self, node_id: usize, capitalisation: &Capitalisation) {
+        if self.frozen_leaves.contains(&node_id) {
+            return;
+        }
+
+        for atom in &mut self.atoms {
+            if let Atom::Leaf { id, content, .. } = atom
+                && *id == node_id
+            {
+                *content = match capitalisation {
+                    Capitalisation::UpperCase => content.to_uppercase(),
+                    Capitalisation::LowerCase => content.to_lowercase(),
+                    Capitalisation::Pass => return,
+                };
+            }
+        }
+    }
+
+    /// Rewrite the content of the [`Atom::Leaf`] whose tree-sitter `id`
+    /// equals `node_id`, a line comment starting with `prefix`, such that
+    /// exactly one space separates its body from the prefix. Used by the
+    /// `#comment_prefix!` predicate. Comments in which the prefix is directly
+    /// followed by punctuation, such as `//-` separators, `///` doc comments or
+    /// `#!` shebang lines, are left as they are, as are empty comments. Like
+    /// case folding, this is idempotent.
+    fn rewrite_comment_prefix(&mut self, node_id: usize, prefix: &str) {
+        if self.frozen_leaves.contains(&node_id) {
+            return;
+        }
+
+        for atom in &mut self.atoms {
+            if let Atom::Leaf { id, content, .. } = atom
+                && *id == node_id
+                && let Some(body) = content.strip_prefix(prefix)
+            {
+                if body.starts_with(|c: char| c.is_ascii_punctuation()) {
+                    continue;
+                }
+
+                let body = body.trim_start_matches([' ', '\t']);
+                if !body.is_empty() {
+                    *content = format!("{prefix} {body}");
+                }
+            }
+        }
+    }
+
+    /// Remember where an indentation range named by `#indent_id!` starts (or
+    /// ends), so that [`AtomCollection::check_indent_ranges`] can later verify
+    /// that the range is balanced. Indentation captures without an
+    /// `#indent_id!` predicate are not tracked.
+    fn record_indent_boundary(&mut self, offset: usize, start: bool, predicates: &QueryPredicates) {
+        if let Some(indent_id) = &predicates.indent_id {
+            self.indent_ranges
+                .entry(indent_id.clone())
+                .or_default()
+                .push((offset, start));
+        }
+    }
+
+    /// Checks that every indentation range named by `#indent_id!` is opened
+    /// before it is closed, and that every start has a matching end.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FormatterError::Query` naming the first unbalanced range.
+    pub(crate) fn check_indent_ranges(&self) -> FormatterResult<()> {
+        let mut indent_ids: Vec<&String> = self.indent_ranges.keys().collect();
+        indent_ids.sort();
+
+        for indent_id in indent_ids {
+            let mut boundaries = self.indent_ranges[indent_id].clone();
+            // At the same offset, a range may close only once it has opened
+            boundaries.sort_by_key(|&(offset, start)| (offset, !start));
+
+            let mut open = 0usize;
+            for (_, start) in boundaries {
+                if start {
+                    open += 1;
+                } else if let Some(still_open) = open.checked_sub(1) {
+                    open = still_open;
+                } else {
+                    rootcause::bail!(FormatterError::Query(format!(
+                        "Indentation range {indent_id:?} is ended before it is started"
+                    )));
+                }
+            }
+
+            if open > 0 {
+                rootcause::bail!(FormatterError::Query(format!(
+                    "Indentation range {indent_id:?} is started but never ended"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that, over the whole document, every indentation block is ended
+    /// and none is ended before it is started. Nested blocks are allowed. This
+    /// must be called after post-processing, once scoped atoms have been
+    /// resolved and deleted atoms removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FormatterError::Query` locating an unbalanced block by the
+    /// leaf that precedes it: the first block that is ended without being
+    /// started or, failing that, the innermost block that is never ended.
+    pub(crate) fn check_indent_balance(&self) -> FormatterResult<()> {
+        fn location(leaf: Option<(&str, Position)>) -> String {
+            match leaf {
+                Some((content, position)) => format!("after {content:?} at {position}"),
+                None => "at the start of the document".into(),
+            }
+        }
+
+        // The leaf preceding each indentation block that is still open
+        let mut open: Vec<Option<(&str, Position)>> = Vec::new();
+        let mut last_leaf = None;
+
+        for atom in &self.atoms {
+            match atom {
+                Atom::Leaf {
+                    content,
+                    original_position,
+                    ..
+                } => last_leaf = Some((content.as_str(), *original_position)),
+                Atom::IndentStart | Atom::IndentStartWidth(_) => open.push(last_leaf),
+                Atom::IndentEnd if open.pop().is_none() => {
+                    rootcause::bail!(FormatterError::Query(format!(
+                        "Indentation block ended {} is never started",
+                        location(last_leaf)
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        // Report the innermost block that is never ended, which is the last
+        // one started
+        if let Some(&unended) = open.last() {
+            rootcause::bail!(FormatterError::Query(format!(
+                "Indentation block started {} is never ended",
+                location(unended)
+            )));
+        }
+
+        Ok(())
+    }
+
     // wrap inside a conditional atom if #single/multi_line_scope_only! is set
     fn wrap(&mut self, atom: Atom, predicates: &QueryPredicates) -> Atom {
         if let Some(scope_id) = &predicates.single_line_scope_only {
@@ -210,6 +721,7 @@ impl AtomCollection {
     ///
     /// This function returns an error if the capture name requires a predicate that is not present.
     /// It also returns an error if the capture name is not recognized by Topiary.
+    /// When adding a capture name, also add it to [`CAPTURE_NAMES`].
     // NOTE: During processing Topiary applies the function below on every match
     // of every query. As such, this function may get called multiple times
     // per node. This means that any performance loss in this function has a
@@ -229,6 +741,11 @@ impl AtomCollection {
                 FormatterError::Query(format!("@{name} requires a #delimiter! predicate"))
             })
         };
+        let requires_align_group = || {
+            predicates.align_group.as_deref().ok_or_else(|| {
+                FormatterError::Query(format!("@{name} requires an #align_group! predicate"))
+            })
+        };
         let requires_scope_id = || {
             predicates.scope_id.as_deref().ok_or_else(|| {
                 FormatterError::Query(format!("@{name} requires a #scope_id! predicate"))
@@ -241,12 +758,14 @@ impl AtomCollection {
             Ok(ScopeInformation {
                 line_number: node.start_position().row(),
                 scope_id: requires_scope_id()?.to_owned(),
+                node_id: node.id(),
             })
         };
         let scope_information_append = || -> FormatterResult<ScopeInformation> {
             Ok(ScopeInformation {
                 line_number: node.end_position().row(),
                 scope_id: requires_scope_id()?.to_owned(),
+                node_id: node.id(),
             })
         };
 
@@ -265,6 +784,21 @@ impl AtomCollection {
             log::debug!("Skipping because context is single-line and #multi_line_only! is set");
             return Ok(());
         }
+        if predicates.delete_if_empty
+            && node
+                .parent()
+                .is_some_and(|parent| parent.named_child_count() == 0)
+        {
+            log::debug!("Skipping because context is empty and #delete_if_empty! is set");
+            return Ok(());
+        }
+        if self.formatting_disabled(node.id()) || !self.is_selected(node) {
+            log::debug!(
+                "Skipping because formatting is disabled: {}",
+                node.display_one_based()
+            );
+            return Ok(());
+        }
         if let Some(parent_id) = self.parent_leaf_nodes.get(&node.id())
             && *parent_id != node.id()
         {
@@ -275,23 +809,36 @@ impl AtomCollection {
             return Ok(());
         }
 
+        if predicates.capitalisation != Capitalisation::Pass {
+            self.rewrite_leaf_case(node.id(), &predicates.capitalisation);
+        }
+        if let Some(prefix) = &predicates.comment_prefix {
+            self.rewrite_comment_prefix(node.id(), prefix);
+        }
+
         match name {
             "allow_blank_line_before" => {
-                if self.blank_lines_before.contains(&node.id()) {
-                    self.prepend(Atom::Blankline, node, predicates);
+                if let Some(count) = self.blank_lines_before.get(&node.id()) {
+                    self.prepend(Atom::Blankline(*count), node, predicates);
+                }
+            }
+            "append_delimiter" => {
+                for atom in delimiter_atoms(requires_delimiter()?) {
+                    self.append(atom, node, predicates);
                 }
             }
-            "append_delimiter" => self.append(
-                Atom::Literal(requires_delimiter()?.to_string()),
-                node,
-                predicates,
-            ),
             "append_empty_softline" => {
                 self.append(Atom::Softline { spaced: false }, node, predicates);
             }
             "append_hardline" => self.append(Atom::Hardline, node, predicates),
-            "append_indent_start" => self.append(Atom::IndentStart, node, predicates),
-            "append_indent_end" => self.append(Atom::IndentEnd, node, predicates),
+            "append_indent_start" => {
+                self.record_indent_boundary(node.end_byte() as usize, true, predicates);
+                self.append(indent_start(predicates), node, predicates);
+            }
+            "append_indent_end" => {
+                self.record_indent_boundary(node.end_byte() as usize, false, predicates);
+                self.append(Atom::IndentEnd, node, predicates);
+            }
             "append_input_softline" => {
                 let space = if self.line_break_after.contains(&node.id()) {
                     Atom::Hardline
@@ -310,22 +857,52 @@ impl AtomCollection {
 
                 self.append(space, node, predicates);
             }
-            "append_space" => self.append(Atom::Space, node, predicates),
+            "append_blank_input_softline" => {
+                let space = if let Some(count) = self.blank_lines_after.get(&node.id()) {
+                    Atom::Blankline(*count)
+                } else if self.line_break_after.contains(&node.id()) {
+                    Atom::Hardline
+                } else {
+                    Atom::Space
+                };
+
+                self.append(space, node, predicates);
+            }
+            "append_space" => {
+                if let Some(count) = predicates.space_count {
+                    let leaf = self.last_leaf(node).id();
+                    let counted = self.space_counts_after.entry(leaf).or_default();
+                    *counted = count.max(*counted);
+                }
+                self.append(Atom::Space, node, predicates);
+            }
+            "append_space_if_followed" => self.append(Atom::SpaceIfFollowed, node, predicates),
             "append_antispace" => self.append(Atom::Antispace, node, predicates),
             "append_spaced_softline" => {
                 self.append(Atom::Softline { spaced: true }, node, predicates);
             }
-            "prepend_delimiter" => self.prepend(
-                Atom::Literal(requires_delimiter()?.to_string()),
-                node,
-                predicates,
-            ),
+            "prepend_delimiter" => {
+                for atom in delimiter_atoms(requires_delimiter()?) {
+                    self.prepend(atom, node, predicates);
+                }
+            }
             "prepend_empty_softline" => {
                 self.prepend(Atom::Softline { spaced: false }, node, predicates);
             }
             "prepend_hardline" => self.prepend(Atom::Hardline, node, predicates),
-            "prepend_indent_start" => self.prepend(Atom::IndentStart, node, predicates),
-            "prepend_indent_end" => self.prepend(Atom::IndentEnd, node, predicates),
+            "prepend_hardline_unless_first" => {
+                if has_preceding_named_sibling(node) {
+                    self.prepend(Atom::Hardline, node, predicates);
+                }
+            }
+            "prepend_indent_start" => {
+                self.record_indent_boundary(node.start_byte() as usize, true, predicates);
+                self.prepend(indent_start(predicates), node, predicates);
+            }
+            "prepend_indent_end" => {
+                self.record_indent_boundary(node.start_byte() as usize, false, predicates);
+                self.prepend(Atom::IndentEnd, node, predicates);
+            }
             "prepend_input_softline" => {
                 let space = if self.line_break_before.contains(&node.id()) {
                     Atom::Hardline
@@ -344,11 +921,38 @@ impl AtomCollection {
 
                 self.prepend(space, node, predicates);
             }
-            "prepend_space" => self.prepend(Atom::Space, node, predicates),
+            "prepend_blank_input_softline" => {
+                let space = if let Some(count) = self.blank_lines_before.get(&node.id()) {
+                    Atom::Blankline(*count)
+                } else if self.line_break_before.contains(&node.id()) {
+                    Atom::Hardline
+                } else {
+                    Atom::Space
+                };
+
+                self.prepend(space, node, predicates);
+            }
+            "prepend_space" => {
+                if let Some(count) = predicates.space_count {
+                    let leaf = self.first_leaf(node).id();
+                    let counted = self.space_counts_before.entry(leaf).or_default();
+                    *counted = count.max(*counted);
+                }
+                self.prepend(Atom::Space, node, predicates);
+            }
+            "prepend_space_unless_bol" => self.prepend(Atom::SpaceUnlessBol, node, predicates),
             "prepend_antispace" => self.prepend(Atom::Antispace, node, predicates),
             "prepend_spaced_softline" => {
                 self.prepend(Atom::Softline { spaced: true }, node, predicates);
             }
+            // Alignment is only sensible for rows that fit on a single line
+            "align" => {
+                let group = requires_align_group()?;
+                if !is_multi_line {
+                    self.aligned_leaves
+                        .insert(self.first_leaf(node).id(), group.to_owned());
+                }
+            }
             // Skip over leaves
             "leaf" => {
                 self.prepend(Atom::CaseBegin(Capitalisation::Pass), node, predicates);
@@ -359,6 +963,12 @@ impl AtomCollection {
                 self.prepend(Atom::DeleteBegin, node, predicates);
                 self.append(Atom::DeleteEnd, node, predicates);
             }
+            "delete_with_surrounding_space" => {
+                self.prepend(Atom::DeleteBoundary, node, predicates);
+                self.prepend(Atom::DeleteBegin, node, predicates);
+                self.append(Atom::DeleteEnd, node, predicates);
+                self.append(Atom::DeleteBoundary, node, predicates);
+            }
             "upper_case" => {
                 self.prepend(Atom::CaseBegin(Capitalisation::UpperCase), node, predicates);
                 self.append(Atom::CaseEnd, node, predicates);
@@ -480,6 +1090,35 @@ impl AtomCollection {
                 });
                 self.append(Atom::Hardline, node, predicates);
             }
+            // Suspend the enclosing indentation of a node, if its scope is single-line
+            "single_line_scope_no_indent" => {
+                let scope_id = requires_scope_id()?;
+                let suspend = Atom::ScopedConditional {
+                    id: self.next_id(),
+                    scope_id: scope_id.to_owned(),
+                    condition: ScopeCondition::SingleLineOnly,
+                    atom: Box::new(Atom::IndentSuspend),
+                };
+                self.prepend(suspend, node, predicates);
+
+                let resume = Atom::ScopedConditional {
+                    id: self.next_id(),
+                    scope_id: scope_id.to_owned(),
+                    condition: ScopeCondition::SingleLineOnly,
+                    atom: Box::new(Atom::IndentResume),
+                };
+                self.append(resume, node, predicates);
+            }
+            // Indent the lines of a multi-line node after its first
+            "continuation_indent" => {
+                if self.multi_line_nodes.contains(&node.id()) {
+                    let first_leaf = self.first_leaf(node).into_owned();
+                    self.record_indent_boundary(first_leaf.end_byte() as usize, true, predicates);
+                    self.append(indent_start(predicates), &first_leaf, predicates);
+                    self.record_indent_boundary(node.end_byte() as usize, false, predicates);
+                    self.append(Atom::IndentEnd, node, predicates);
+                }
+            }
             // Mark a leaf to have all its lines be indented
             "multi_line_indent_all" => {
                 self.mutate_leaf_flags(node.id(), |flags| {
@@ -492,6 +1131,10 @@ impl AtomCollection {
                     *flags.keep_whitespace = true;
                 });
             }
+            "sort_children" => self.record_sorted_children(node, predicates.sort_key.as_deref()),
+            // Applied by `AtomCollection::force_single_line` and
+            // `AtomCollection::force_multi_line`, before any capture is resolved
+            "single_line" | "multi_line" => {}
             // Return a query parsing error on unknown capture names
             unknown => {
                 rootcause::bail!(FormatterError::Query(format!(
@@ -503,6 +1146,261 @@ impl AtomCollection {
         Ok(())
     }
 
+    /// Treats the nodes captured by `@single_line`, and all of their
+    /// descendants, as though they were written on a single line, so that
+    /// their softlines collapse. This must happen before any capture is
+    /// resolved, as softlines are expanded when they are added. The scopes
+    /// that lie within these nodes are made single-line in
+    /// [`AtomCollection::post_process_scopes`].
+    ///
+    /// A node that contains a comment with more of the node on a later line
+    /// is left as it is: putting that on the comment's line would swallow it
+    /// into a line comment.
+    pub(crate) fn force_single_line(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            let last_row = node.end_position().row();
+            let dfs_nodes = dfs_flatten(node);
+
+            if let Some(comment) = dfs_nodes
+                .iter()
+                .find(|n| is_comment(n) && n.start_position().row() < last_row)
+            {
+                log::warn!(
+                    "Keeping {} multi-line for @single_line, because of the comment {}",
+                    node.display_one_based(),
+                    comment.display_one_based()
+                );
+                continue;
+            }
+
+            for n in &dfs_nodes {
+                self.multi_line_nodes.remove(&n.id());
+                self.single_line_roots
+                    .entry(n.id())
+                    .or_default()
+                    .push(node.id());
+            }
+        }
+    }
+
+    /// Treats the nodes captured by `@multi_line` as though they were written
+    /// over several lines, so that the softlines of their children expand to
+    /// line breaks. Unlike [`AtomCollection::force_single_line`], this does not
+    /// extend to their descendants, which keep their own mode. As it is
+    /// applied after `@single_line`, a node captured by `@multi_line` within
+    /// one captured by `@single_line` is multi-line nonetheless. Likewise,
+    /// the scopes that span the children of these nodes are made multi-line
+    /// in [`AtomCollection::post_process_scopes`].
+    pub(crate) fn force_multi_line(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.multi_line_nodes.insert(node.id());
+            for n in iter::once(*node).chain(node.children(&mut node.walk())) {
+                self.multi_line_roots
+                    .entry(n.id())
+                    .or_default()
+                    .push(node.id());
+            }
+        }
+    }
+
+    /// Whether a scope that begins at one node and ends at another is made
+    /// multi-line (`Some(true)`) or single-line (`Some(false)`) by
+    /// `@multi_line` or `@single_line`, rather than by the input. As with
+    /// softlines, a scope is multi-line if it spans the children of a node
+    /// captured by `@multi_line`, or that node itself, and single-line if it
+    /// lies within a node captured by `@single_line` otherwise.
+    fn forced_scope_mode(&self, begin: usize, end: usize) -> Option<bool> {
+        let share_root =
+            |roots: &HashMap<usize, Vec<usize>>| match (roots.get(&begin), roots.get(&end)) {
+                (Some(begin_roots), Some(end_roots)) => {
+                    begin_roots.iter().any(|root| end_roots.contains(root))
+                }
+                _ => false,
+            };
+
+        // A scope around a single child does not span the children of its
+        // parent
+        let spans_children = if begin == end {
+            self.multi_line_roots
+                .get(&begin)
+                .is_some_and(|roots| roots.contains(&begin))
+        } else {
+            share_root(&self.multi_line_roots)
+        };
+
+        if spans_children {
+            Some(true)
+        } else if share_root(&self.single_line_roots) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Records the children of a node captured by `@sort_children`, to be
+    /// sorted by [`AtomCollection::sort_children`]. Each named child, along
+    /// with the comments on the lines before it and its trailing comment, is a
+    /// unit that is moved as a whole. Its key is its text or, given a
+    /// `#sort_key!`, the text of its child in that field. The children are
+    /// sorted in runs, which are broken by blank lines and by named children
+    /// that have no key.
+    fn record_sorted_children(&mut self, node: &Node, sort_key: Option<&str>) {
+        if !self.sorted_parents.insert(node.id()) {
+            return;
+        }
+
+        let children: Vec<Node> = node.children(&mut node.walk()).collect();
+        let mut run: Vec<SortUnit> = Vec::new();
+        let mut runs: Vec<Vec<SortUnit>> = Vec::new();
+
+        for (index, child) in children.iter().enumerate() {
+            if !child.is_named() || is_comment(child) {
+                continue;
+            }
+
+            let key = match sort_key {
+                Some(field) => child.child_by_field_name(field),
+                None => Some(*child),
+            };
+            let Some(key) = key else {
+                runs.push(mem::take(&mut run));
+                continue;
+            };
+
+            // The comments on the lines before the child are attached to it
+            let mut start = index;
+            while start > 0
+                && is_comment(&children[start - 1])
+                && !self.trailing_comments.contains(&children[start - 1].id())
+            {
+                start -= 1;
+            }
+            let end = match children.get(index + 1) {
+                Some(next) if self.trailing_comments.contains(&next.id()) => index + 1,
+                _ => index,
+            };
+
+            let after_blank_line = start > 0
+                && children[start].start_position().row()
+                    > children[start - 1].end_position().row() + 1;
+            if after_blank_line {
+                runs.push(mem::take(&mut run));
+            }
+
+            run.push(SortUnit {
+                first_leaf: self.first_leaf(&children[start]).id(),
+                last_leaf: self.last_leaf(&children[end]).id(),
+                key_leaves: (self.first_leaf(&key).id(), self.last_leaf(&key).id()),
+            });
+        }
+        runs.push(run);
+
+        let range = node.start_byte()..node.end_byte();
+        self.sorted_children
+            .extend(
+                runs.into_iter()
+                    .filter(|units| units.len() > 1)
+                    .map(|units| SortRun {
+                        range: range.clone(),
+                        units,
+                    }),
+            );
+    }
+
+    /// Sorts the runs of children recorded by `@sort_children` by their keys,
+    /// moving the leaves of each unit as a whole. Sorting is stable, so units
+    /// with equal keys keep their order. The atoms before the first and after
+    /// the last leaf of each unit's position stay at that position, so that
+    /// the spacing between the units is unchanged. This must be done before
+    /// the atoms are applied by [`AtomCollection::apply_prepends_and_appends`].
+    pub(crate) fn sort_children(&mut self) {
+        let mut runs = mem::take(&mut self.sorted_children);
+        // Sort the outer runs first, as the inner ones move within them
+        runs.sort_by_key(|run| (run.range.start, Reverse(run.range.end)));
+
+        for run in runs {
+            self.sort_run(&run.units);
+        }
+    }
+
+    fn sort_run(&mut self, units: &[SortUnit]) {
+        let positions: HashMap<usize, usize> = self
+            .atoms
+            .iter()
+            .enumerate()
+            .filter_map(|(position, atom)| match atom {
+                Atom::Leaf { id, .. } => Some((*id, position)),
+                _ => None,
+            })
+            .collect();
+
+        let mut slots: Vec<Range<usize>> = Vec::with_capacity(units.len());
+        for unit in units {
+            let (Some(&start), Some(&end)) = (
+                positions.get(&unit.first_leaf),
+                positions.get(&unit.last_leaf),
+            ) else {
+                log::debug!("Not sorting children whose leaves were not collected");
+                return;
+            };
+            if slots.last().is_some_and(|slot| slot.end > start) || end < start {
+                log::debug!("Not sorting children whose leaves overlap");
+                return;
+            }
+            slots.push(start..end + 1);
+        }
+
+        let keys: Vec<String> = units
+            .iter()
+            .map(|unit| {
+                let (first, last) = unit.key_leaves;
+                match (positions.get(&first), positions.get(&last)) {
+                    (Some(&start), Some(&end)) => self.atoms[start..=end]
+                        .iter()
+                        .filter_map(|atom| match atom {
+                            Atom::Leaf { content, .. } => Some(content.as_str()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => String::new(),
+                }
+            })
+            .collect();
+
+        let mut order: Vec<usize> = (0..units.len()).collect();
+        order.sort_by(|a, b| keys[*a].cmp(&keys[*b]));
+        if order.iter().enumerate().all(|(slot, unit)| slot == *unit) {
+            return;
+        }
+        log::debug!("Sorting children into the order {order:?}");
+
+        // The atoms around each position stay there, whichever unit moves in
+        let prepends: Vec<Vec<Atom>> = units
+            .iter()
+            .map(|unit| self.prepend.remove(&unit.first_leaf).unwrap_or_default())
+            .collect();
+        let appends: Vec<Vec<Atom>> = units
+            .iter()
+            .map(|unit| self.append.remove(&unit.last_leaf).unwrap_or_default())
+            .collect();
+        for ((&unit, prepend), append) in order.iter().zip(prepends).zip(appends) {
+            self.prepend.insert(units[unit].first_leaf, prepend);
+            self.append.insert(units[unit].last_leaf, append);
+        }
+
+        let atoms = mem::take(&mut self.atoms);
+        let mut sorted = Vec::with_capacity(atoms.len());
+        let mut cursor = 0;
+        for (slot, &unit) in slots.iter().zip(&order) {
+            sorted.extend_from_slice(&atoms[cursor..slot.start]);
+            sorted.extend_from_slice(&atoms[slots[unit].clone()]);
+            cursor = slot.end;
+        }
+        sorted.extend_from_slice(&atoms[cursor..]);
+
+        self.atoms = sorted;
+    }
+
     /// After query processing is done, a flattened/expanded vector of atoms can be created.
     pub fn apply_prepends_and_appends(&mut self) {
         let mut expanded: Vec<Atom> = Vec::new();
@@ -530,11 +1428,16 @@ impl AtomCollection {
                 prepends.sort_by_key(atom_key);
                 let appends = self.append.entry(*id).or_default();
                 appends.sort_by_key(atom_key);
+                let frozen = self.frozen_leaves.contains(&*id);
 
                 // Rather than cloning the atom from the old vector, we
                 // simply take it. This will leave a default (empty) atom
-                // in its place.
-                let swapped_atom = mem::take(atom);
+                // in its place. Frozen leaves are rendered exactly as they
+                // were in the input.
+                let swapped_atom = match mem::take(atom) {
+                    Atom::Leaf { content, .. } if frozen => Atom::Literal(content),
+                    swapped_atom => swapped_atom,
+                };
 
                 if !prepends.is_empty() {
                     log::debug!("Applying prepend of {prepends:?} to {:?}.", &swapped_atom);
@@ -575,6 +1478,7 @@ impl AtomCollection {
     /// * `node` - The current node to process.
     /// * `source` - The full source code as a byte slice.
     /// * `level` - The depth of the current node in the CST tree.
+    /// * `freeze_errors` - Whether ERROR nodes are to be passed through verbatim.
     ///
     /// # Errors
     ///
@@ -584,6 +1488,7 @@ impl AtomCollection {
         node: &Node,
         source: &[u8],
         level: usize,
+        freeze_errors: bool,
     ) -> FormatterResult<()> {
         let id = node.id();
 
@@ -596,6 +1501,8 @@ impl AtomCollection {
 
         if node.end_byte() == node.start_byte() {
             log::debug!("Skipping zero-byte node: {}", node.display_one_based());
+        } else if let Some(range) = self.disabled_range_of(node) {
+            self.collect_disabled_leaf(node, source, range);
         } else if node.child_count() == 0
             || self.specified_leaf_nodes.contains(&node.id())
             // We treat error nodes as leaves when `tolerate_parsing_errors` is set to true.
@@ -608,33 +1515,155 @@ impl AtomCollection {
                 content: String::from(node.utf8_text(source).context_to()?),
                 id,
                 original_position: node.start_position().into(),
+                original_column: column(source, node.start_byte() as usize, self.tab_width),
                 single_line_no_indent: false,
                 multi_line_indent_all: false,
+                original_line_indent: line_indent(
+                    source,
+                    node.start_byte() as usize,
+                    self.tab_width,
+                ),
                 keep_whitespace: false,
                 capitalisation: Capitalisation::Pass,
             });
             // Mark all sub-nodes as having this node as a "leaf parent"
             self.mark_leaf_parent(node, node.id());
+
+            if freeze_errors && node.kind() == "ERROR" {
+                self.freeze_leaf(node, source);
+            }
         } else {
             for child in node.children(&mut node.walk()) {
-                self.collect_leaves_inner(&child, source, level + 1)?;
+                self.collect_leaves_inner(&child, source, level + 1, freeze_errors)?;
             }
         }
 
         Ok(())
     }
 
-    /// Prepend an atom to the first leaf node in the subtree of a given node.
-    ///
-    /// # Arguments
-    ///
-    /// * `atom` - The atom to prepend.
-    /// * `node` - The node to which the atom is prepended.
-    /// * `predicates` - The query predicates to wrap the atom with.
-    fn prepend(&mut self, atom: Atom, node: &Node, predicates: &QueryPredicates) {
-        let atom = self.expand_multiline(atom, node);
-        let atom = self.wrap(atom, predicates);
-        // TODO: Pre-populate these
+    /// The range, in which formatting is disabled, that contains the node.
+    fn disabled_range_of(&self, node: &Node) -> Option<Range<usize>> {
+        let start = node.start_byte() as usize;
+        let end = node.end_byte() as usize;
+
+        self.disabled_ranges
+            .iter()
+            .find(|range| range.start <= start && end <= range.end)
+            .cloned()
+    }
+
+    /// Collects an outermost node of a range in which formatting is disabled.
+    /// The consecutive such nodes of a range are merged into a single leaf,
+    /// which holds the source from the start of the first to the end of the
+    /// last, and has the id of the first.
+    fn collect_disabled_leaf(&mut self, node: &Node, source: &[u8], range: Range<usize>) {
+        let end = node.end_byte() as usize;
+
+        if let Some(Atom::Leaf { id, content, .. }) = self.atoms.last_mut()
+            && let Some(leaf_range) = self.disabled_leaves.get_mut(id)
+            && range.contains(&leaf_range.start)
+        {
+            leaf_range.end = end;
+            *content = String::from_utf8_lossy(&source[leaf_range.start..end]).into_owned();
+
+            let leaf_id = *id;
+            self.mark_leaf_parent(node, leaf_id);
+            return;
+        }
+
+        let start = node.start_byte() as usize;
+        self.atoms.push(Atom::Leaf {
+            content: String::from_utf8_lossy(&source[start..end]).into_owned(),
+            id: node.id(),
+            original_position: node.start_position().into(),
+            original_column: column(source, start, self.tab_width),
+            single_line_no_indent: false,
+            multi_line_indent_all: false,
+            original_line_indent: line_indent(source, start, self.tab_width),
+            keep_whitespace: false,
+            capitalisation: Capitalisation::Pass,
+        });
+        self.disabled_leaves.insert(node.id(), start..end);
+        self.mark_leaf_parent(node, node.id());
+    }
+
+    /// Whether formatting is disabled for the node, as it is within the
+    /// source of a leaf that is delimited by formatting markers.
+    pub(crate) fn formatting_disabled(&self, node_id: usize) -> bool {
+        self.parent_leaf_nodes
+            .get(&node_id)
+            .is_some_and(|leaf_id| self.disabled_leaves.contains_key(leaf_id))
+    }
+
+    /// Whether the node is within one of the kinds of node that are formatted,
+    /// which it always is if all are.
+    fn is_selected(&self, node: &Node) -> bool {
+        let start = node.start_byte() as usize;
+        let end = node.end_byte() as usize;
+
+        self.selected_ranges.as_ref().is_none_or(|ranges| {
+            ranges
+                .iter()
+                .any(|range| range.start <= start && end <= range.end)
+        })
+    }
+
+    /// Marks the leaf of an ERROR node to be passed through verbatim. As the
+    /// formatting query cannot be expected to cater for the contents of the
+    /// node, the whitespace that surrounds it in the input is kept; any
+    /// whitespace the query adds around it is merged with this in
+    /// post-processing.
+    fn freeze_leaf(&mut self, node: &Node, source: &[u8]) {
+        let range = node.start_byte() as usize..node.end_byte() as usize;
+        self.freeze_range(node.id(), range, source);
+    }
+
+    /// Marks the leaves of the nodes captured in a pattern with `#verbatim!` to
+    /// be passed through exactly as they are in the input. Unlike for ERROR
+    /// nodes, the whitespace that surrounds them is still up to the query.
+    pub(crate) fn freeze_verbatim(&mut self, node_ids: &HashSet<usize>) {
+        self.frozen_leaves.extend(node_ids);
+    }
+
+    /// Marks the leaf with the given id, whose content spans the given byte
+    /// range of the source, to be passed through verbatim, along with the
+    /// whitespace that surrounds it in the input.
+    fn freeze_range(&mut self, id: usize, range: Range<usize>, source: &[u8]) {
+        self.frozen_leaves.insert(id);
+        // Captures on the node itself must target its leaf, rather than
+        // descend into the node
+        self.specified_leaf_nodes.insert(id);
+
+        let Range { start, end } = range;
+        let before = source[..start]
+            .iter()
+            .rev()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+        let after = source[end..]
+            .iter()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+
+        if let Some(atom) = original_whitespace(&source[start - before..start]) {
+            self.prepend.entry(id).or_default().push(atom);
+        }
+        if let Some(atom) = original_whitespace(&source[end..end + after]) {
+            self.append.entry(id).or_default().push(atom);
+        }
+    }
+
+    /// Prepend an atom to the first leaf node in the subtree of a given node.
+    ///
+    /// # Arguments
+    ///
+    /// * `atom` - The atom to prepend.
+    /// * `node` - The node to which the atom is prepended.
+    /// * `predicates` - The query predicates to wrap the atom with.
+    fn prepend(&mut self, atom: Atom, node: &Node, predicates: &QueryPredicates) {
+        let atom = self.expand_multiline(atom, node);
+        let atom = self.wrap(atom, predicates);
+        // TODO: Pre-populate these
         let target_node = self.first_leaf(node);
 
         log::debug!(
@@ -726,9 +1755,10 @@ impl AtomCollection {
         type ScopeId = String;
         type LineIndex = u32;
         type ScopedNodeId = usize;
-        type OpenedScopeInfo<'a> = (LineIndex, Vec<&'a Atom>, Option<bool>);
+        type OpenedScopeInfo<'a> = (usize, LineIndex, Vec<&'a Atom>, Option<bool>);
         // `opened_scopes` maintains stacks of opened scopes.
         // For each scope, we record:
+        // * the index of the atom at which they started (usize),
         // * the line at which they started (LineIndex),
         // * the list of `ScopedSoftline` and `ScopedConditional` they contain (Vec<&Atom>),
         // * if they contain a measuring scope, whether it is multi-line (Option<bool>).
@@ -748,56 +1778,51 @@ impl AtomCollection {
         // of the processing, even if the `modifications` map is empty. This is to ensure we will
         // get rid of misplaced scoped atoms.
         let mut force_apply_modifications = false;
+        // The scopes that have been closed, whose scoped atoms are replaced
+        // once all scopes have been seen.
+        let mut closed_scopes: Vec<ClosedScope> = Vec::new();
 
-        for atom in &self.atoms {
+        for (index, atom) in self.atoms.iter().enumerate() {
             if let Atom::ScopeBegin(ScopeInformation {
                 line_number: line_start,
                 scope_id,
+                ..
             }) = atom
             {
-                opened_scopes
-                    .entry(scope_id)
-                    .or_default()
-                    .push((*line_start, Vec::new(), None));
+                opened_scopes.entry(scope_id).or_default().push((
+                    index,
+                    *line_start,
+                    Vec::new(),
+                    None,
+                ));
             } else if let Atom::ScopeEnd(ScopeInformation {
                 line_number: line_end,
                 scope_id,
+                node_id: end_node,
             }) = atom
             {
-                if let Some((line_start, atoms, measuring_scope)) =
+                if let Some((begin, line_start, atoms, measuring_scope)) =
                     opened_scopes.get_mut(scope_id).and_then(Vec::pop)
                 {
-                    let multiline = if let Some(mult) = measuring_scope {
+                    // `@multi_line` and `@single_line` take precedence over
+                    // both the input and any measuring scope
+                    let forced = match &self.atoms[begin] {
+                        Atom::ScopeBegin(information) => {
+                            self.forced_scope_mode(information.node_id, *end_node)
+                        }
+                        _ => None,
+                    };
+                    let multi_line = if let Some(mult) = forced.or(measuring_scope) {
                         mult
                     } else {
                         line_start != *line_end
                     };
-                    for atom in atoms {
-                        if let Atom::ScopedSoftline { id, spaced, .. } = atom {
-                            let new_atom = if multiline {
-                                Atom::Hardline
-                            } else if *spaced {
-                                Atom::Space
-                            } else {
-                                Atom::Empty
-                            };
-                            modifications.insert(*id, new_atom);
-                        } else if let Atom::ScopedConditional {
-                            id,
-                            atom,
-                            condition,
-                            ..
-                        } = atom
-                        {
-                            let multiline_only = *condition == ScopeCondition::MultiLineOnly;
-                            let new_atom = if multiline == multiline_only {
-                                atom.deref().clone()
-                            } else {
-                                Atom::Empty
-                            };
-                            modifications.insert(*id, new_atom);
-                        }
-                    }
+                    closed_scopes.push(ClosedScope {
+                        begin,
+                        end: index,
+                        multi_line,
+                        atoms,
+                    });
                 } else {
                     log::warn!("Closing unopened scope {scope_id:?}");
                     force_apply_modifications = true;
@@ -806,6 +1831,7 @@ impl AtomCollection {
             } else if let Atom::MeasuringScopeBegin(ScopeInformation {
                 line_number: line_start,
                 scope_id,
+                ..
             }) = atom
             {
                 if opened_scopes.entry(scope_id).or_default().is_empty() {
@@ -823,17 +1849,19 @@ impl AtomCollection {
             } else if let Atom::MeasuringScopeEnd(ScopeInformation {
                 line_number: line_end,
                 scope_id,
+                ..
             }) = atom
             {
                 if let Some(line_start) =
                     opened_measuring_scopes.get_mut(scope_id).and_then(Vec::pop)
                 {
                     let multi_line = line_start != *line_end;
-                    if let Some((regular_line_start, vec, measuring_scope)) =
+                    if let Some((begin, regular_line_start, vec, measuring_scope)) =
                         opened_scopes.get_mut(scope_id).and_then(Vec::pop)
                     {
                         if measuring_scope.is_none() {
                             opened_scopes.entry(scope_id).or_default().push((
+                                begin,
                                 regular_line_start,
                                 vec,
                                 Some(multi_line),
@@ -854,7 +1882,7 @@ impl AtomCollection {
                 }
             // Register the ScopedSoftline in the correct scope
             } else if let Atom::ScopedSoftline { scope_id, .. } = atom {
-                if let Some((_, vec, _)) =
+                if let Some((_, _, vec, _)) =
                     opened_scopes.get_mut(&scope_id).and_then(|v| v.last_mut())
                 {
                     vec.push(atom);
@@ -864,7 +1892,7 @@ impl AtomCollection {
                 }
             // Register the ScopedConditional in the correct scope
             } else if let Atom::ScopedConditional { scope_id, .. } = atom {
-                if let Some((_, vec, _)) =
+                if let Some((_, _, vec, _)) =
                     opened_scopes.get_mut(&scope_id).and_then(|v| v.last_mut())
                 {
                     vec.push(atom);
@@ -891,6 +1919,67 @@ impl AtomCollection {
             force_apply_modifications = true;
         }
 
+        if let Some((max_line_width, indent_width)) = self.line_width_limit {
+            force_wide_scopes_multi_line(
+                &self.atoms,
+                &mut closed_scopes,
+                max_line_width,
+                indent_width,
+            );
+        }
+
+        for scope in &closed_scopes {
+            for (index, boundary) in [
+                (scope.begin, ScopeBoundary::Begin),
+                (scope.end, ScopeBoundary::End),
+            ] {
+                if let Atom::ScopeBegin(information) | Atom::ScopeEnd(information) =
+                    &self.atoms[index]
+                {
+                    self.resolved_scopes
+                        .entry(information.node_id)
+                        .or_default()
+                        .push(ScopeAnnotation {
+                            scope_id: information.scope_id.clone(),
+                            boundary,
+                            multi_line: scope.multi_line,
+                        });
+                }
+            }
+        }
+
+        for ClosedScope {
+            multi_line, atoms, ..
+        } in closed_scopes
+        {
+            for atom in atoms {
+                if let Atom::ScopedSoftline { id, spaced, .. } = atom {
+                    let new_atom = if multi_line {
+                        Atom::Hardline
+                    } else if *spaced {
+                        Atom::Space
+                    } else {
+                        Atom::Empty
+                    };
+                    modifications.insert(*id, new_atom);
+                } else if let Atom::ScopedConditional {
+                    id,
+                    atom,
+                    condition,
+                    ..
+                } = atom
+                {
+                    let multiline_only = *condition == ScopeCondition::MultiLineOnly;
+                    let new_atom = if multi_line == multiline_only {
+                        atom.deref().clone()
+                    } else {
+                        Atom::Empty
+                    };
+                    modifications.insert(*id, new_atom);
+                }
+            }
+        }
+
         // Remove scopes from the atom list
         for atom in &mut self.atoms {
             match atom {
@@ -927,6 +2016,86 @@ impl AtomCollection {
         }
     }
 
+    /// Pads the leaves marked by `@align`, so that the leaves of the same
+    /// alignment group, on consecutive lines, start at the same column. A run
+    /// of such lines is broken by any line that has no leaf of the group
+    /// (including blank lines, and the continuation lines of a multi-line
+    /// row); each run is aligned to its widest line. Columns are measured from
+    /// the start of the line, disregarding indentation.
+    fn align_leaves(&mut self) {
+        if self.aligned_leaves.is_empty() {
+            return;
+        }
+
+        // An open run of aligned leaves, as pairs of atom index and column,
+        // and the line on which the last leaf of the run is
+        type Run = (Vec<(usize, usize)>, usize);
+
+        // For every group, its open run of aligned leaves
+        let mut runs: HashMap<&str, Run> = HashMap::new();
+        // The indices of the atoms to pad, with the padding width
+        let mut padding: HashMap<usize, usize> = HashMap::new();
+
+        fn close_run(run: &[(usize, usize)], padding: &mut HashMap<usize, usize>) {
+            let widest = run.iter().map(|(_, column)| *column).max().unwrap_or(0);
+            for (index, column) in run {
+                padding.insert(*index, widest - column);
+            }
+        }
+
+        let mut line = 0;
+        let mut column = 0;
+        for (index, atom) in self.atoms.iter().enumerate() {
+            match atom {
+                Atom::Hardline => {
+                    line += 1;
+                    column = 0;
+                }
+                Atom::Blankline(count) => {
+                    line += count + 1;
+                    column = 0;
+                }
+                Atom::Leaf { id, content, .. } => {
+                    if let Some(group) = self.aligned_leaves.get(id) {
+                        let (run, last_line) = runs.entry(group).or_default();
+                        if !run.is_empty() && *last_line + 1 != line && *last_line != line {
+                            close_run(run, &mut padding);
+                            run.clear();
+                        }
+                        // Only the first aligned leaf of a group on each line counts
+                        if run.is_empty() || *last_line != line {
+                            run.push((index, column));
+                            *last_line = line;
+                        }
+                    }
+
+                    match content.rsplit_once('\n') {
+                        Some((before, last_line)) => {
+                            line += before.matches('\n').count() + 1;
+                            column = last_line.chars().count();
+                        }
+                        None => column += content.chars().count(),
+                    }
+                }
+                Atom::Literal(literal) => column += literal.chars().count(),
+                Atom::Space | Atom::SpaceUnlessBol => column += 1,
+                _ => {}
+            }
+        }
+        for (run, _) in runs.values() {
+            close_run(run, &mut padding);
+        }
+
+        let atoms = mem::take(&mut self.atoms);
+        self.atoms.reserve(atoms.len() + padding.len());
+        for (index, atom) in atoms.into_iter().enumerate() {
+            if let Some(width) = padding.get(&index).filter(|width| **width > 0) {
+                self.atoms.push(Atom::Literal(" ".repeat(*width)));
+            }
+            self.atoms.push(atom);
+        }
+    }
+
     /// Separate post_processing of Delete sections, to avoid interference with whitespace logic
     fn post_process_deletes(&mut self) {
         let mut delete_level = 0;
@@ -952,6 +2121,47 @@ impl AtomCollection {
         }
     }
 
+    /// Keeps each trailing comment on the line of its preceding node, if so
+    /// configured, by moving the line breaks between them to after the
+    /// comment. The comment is then separated from its preceding node by a
+    /// space.
+    fn attach_trailing_comments(&mut self) {
+        if self.comment_attachment != CommentAttachment::Preceding {
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.atoms.len() {
+            if let Atom::Leaf { id, .. } = self.atoms[i]
+                && self.trailing_comments.contains(&id)
+            {
+                let preceding = self.atoms[..i]
+                    .iter()
+                    .rposition(|atom| matches!(atom, Atom::Leaf { .. } | Atom::Literal(_)))
+                    .map_or(0, |position| position + 1);
+
+                let mut line_break: Option<Atom> = None;
+                for atom in &mut self.atoms[preceding..i] {
+                    if matches!(atom, Atom::Hardline | Atom::Blankline(_)) {
+                        let atom = mem::take(atom);
+                        if line_break.as_ref().is_none_or(|kept| atom.dominates(kept)) {
+                            line_break = Some(atom);
+                        }
+                    }
+                }
+
+                if let Some(line_break) = line_break {
+                    log::debug!("Attaching trailing comment {id} to its preceding node");
+                    self.atoms.insert(i + 1, line_break);
+                    self.atoms.insert(i, Atom::Space);
+                    i += 2;
+                }
+            }
+
+            i += 1;
+        }
+    }
+
     /// Separate post processing of capitalisation, to avoid confusion around whitespacing.
     fn post_process_capitalization(&mut self) {
         let mut case_context: Vec<Capitalisation> = Vec::new();
@@ -980,20 +2190,141 @@ impl AtomCollection {
     pub fn post_process(&mut self) {
         self.post_process_scopes();
         self.post_process_deletes();
+        self.attach_trailing_comments();
+        resolve_spaces_if_followed(&mut self.atoms);
+        collapse_spaces_around_deletions(&mut self.atoms);
         self.post_process_capitalization();
         self.post_process_inner();
 
-        // We have taken care of spaces following an antispace. Now fix the
-        // preceding spaces.
-        collapse_spaces_before_antispace(&mut self.atoms);
+        // All spaces are in place, so remove those on either side of each
+        // antispace.
+        collapse_spaces_around_antispace(&mut self.atoms);
+
+        // Remove any horizontal whitespace left dangling at the end of a line.
+        collapse_trailing_spaces(&mut self.atoms);
+
+        // Remove any whitespace after the last content atom, as the output is
+        // terminated according to the final newline policy instead.
+        collapse_final_whitespace(&mut self.atoms);
 
         // We have to do one more post-processing pass, as the collapsing of
-        // antispaces may have produced more empty atoms.
+        // antispaces and trailing spaces may have produced more empty atoms.
         self.post_process_inner();
 
+        // Now that all blank lines have been merged, cap their number.
+        cap_blank_lines(&mut self.atoms, self.blank_line_limit);
+
+        if self.preserve_whitespace {
+            self.restore_original_gaps();
+        }
+
+        // Spaces are merged and collapsed as one, whatever their number, so
+        // only now are those that remain widened.
+        self.widen_counted_spaces();
+
+        // Now that all whitespace is settled, pad the aligned leaves.
+        self.align_leaves();
+
         log::debug!("List of atoms after post-processing: {:?}", self.atoms);
     }
 
+    /// Widens each space that follows a leaf with `@append_space`, or precedes
+    /// one with `@prepend_space`, under a `#space_count!` predicate, to the
+    /// greater of their numbers of spaces.
+    fn widen_counted_spaces(&mut self) {
+        if self.space_counts_after.is_empty() && self.space_counts_before.is_empty() {
+            return;
+        }
+
+        // The space since the last leaf or literal, with the number of spaces
+        // that that leaf puts after it
+        let mut pending: Option<(usize, usize)> = None;
+        // The number of spaces that the last leaf puts after it
+        let mut count_after = 1;
+
+        for index in 0..self.atoms.len() {
+            match &self.atoms[index] {
+                atom @ (Atom::Leaf { .. } | Atom::Literal(_)) => {
+                    let (count_before, next_count_after) = match atom {
+                        Atom::Leaf { id, .. } => (
+                            self.space_counts_before.get(id).copied().unwrap_or(1),
+                            self.space_counts_after.get(id).copied().unwrap_or(1),
+                        ),
+                        _ => (1, 1),
+                    };
+
+                    if let Some((space, count)) = pending.take() {
+                        let count = count.max(count_before);
+                        if count > 1 {
+                            self.atoms[space] = Atom::Literal(" ".repeat(count));
+                        }
+                    }
+                    count_after = next_count_after;
+                }
+                Atom::Space => pending = Some((index, count_after)),
+                Atom::Hardline | Atom::Blankline(_) => {
+                    pending = None;
+                    count_after = 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Restores the input's whitespace between consecutive leaves that were
+    /// adjacent in the input, where the formatted whitespace between them is a
+    /// single space or line break and the input's is of the same kind. See
+    /// [`AtomCollection::preserve_original_whitespace`].
+    fn restore_original_gaps(&mut self) {
+        let mut previous: Option<usize> = None;
+        // The whitespace atoms since the previous leaf
+        let mut whitespace: Vec<usize> = Vec::new();
+
+        for index in 0..self.atoms.len() {
+            match &self.atoms[index] {
+                Atom::Leaf { id, .. } => {
+                    let id = *id;
+                    if let Some(previous) = previous
+                        && let Some((original_previous, gap)) = self.original_gaps.get(&id)
+                        && *original_previous == previous
+                        && let [gap_index] = whitespace[..]
+                    {
+                        let newlines = gap.matches('\n').count();
+                        let restored = match (&self.atoms[gap_index], newlines) {
+                            (Atom::Space, 0) if !gap.is_empty() => Some(Atom::Literal(gap.clone())),
+                            (Atom::Hardline | Atom::Blankline(_), 1) => Some(Atom::Hardline),
+                            (Atom::Hardline | Atom::Blankline(_), newlines) if newlines > 1 => {
+                                Some(match (newlines - 1).min(self.blank_line_limit) {
+                                    0 => Atom::Hardline,
+                                    count => Atom::Blankline(count),
+                                })
+                            }
+                            _ => None,
+                        };
+                        if let Some(atom) = restored {
+                            self.atoms[gap_index] = atom;
+                        }
+                    }
+
+                    previous = Some(id);
+                    whitespace.clear();
+                }
+                Atom::Space | Atom::Hardline | Atom::Blankline(_) => whitespace.push(index),
+                Atom::Empty
+                | Atom::IndentStart
+                | Atom::IndentStartWidth(_)
+                | Atom::IndentEnd
+                | Atom::IndentSuspend
+                | Atom::IndentResume => {}
+                // Anything else, such as a literal, stands between the leaves
+                _ => {
+                    previous = None;
+                    whitespace.clear();
+                }
+            }
+        }
+    }
+
     /// This function post-processes the atoms in the collection.
     /// It modifies the collection in-place, removing unnecessary atoms and adjusting the position of others.
     fn post_process_inner(&mut self) {
@@ -1011,7 +2342,12 @@ impl AtomCollection {
         };
 
         // Set all leading whitespace atoms to empty.
-        while let Atom::Space | Atom::Antispace | Atom::Hardline | Atom::Blankline = *prev {
+        while let Atom::Space
+        | Atom::SpaceUnlessBol
+        | Atom::Antispace
+        | Atom::Hardline
+        | Atom::Blankline(_) = *prev
+        {
             *prev = Atom::Empty;
             if let [head, tail @ ..] = remaining {
                 prev = head;
@@ -1024,21 +2360,20 @@ impl AtomCollection {
         // Process the remaining atoms in the collection.
         while !remaining.is_empty() {
             match (prev, remaining) {
-                // If an antispace atom is followed by a space or another antispace, remove the following atom.
-                (
-                    moved_prev @ Atom::Antispace,
-                    [head @ (Atom::Space | Atom::Antispace), tail @ ..],
-                ) => {
-                    *head = Atom::Empty;
-
-                    prev = moved_prev;
-                    remaining = tail;
-                }
                 // If two whitespace atoms follow each other, remove the non-dominant one.
+                // In particular, two hardlines (e.g., from an `@append_hardline`
+                // meeting a `@prepend_hardline`) collapse into one, whereas a
+                // blankline absorbs any hardline next to it.
                 (
-                    moved_prev @ (Atom::Space | Atom::Hardline | Atom::Blankline),
+                    moved_prev @ (Atom::Space
+                    | Atom::SpaceUnlessBol
+                    | Atom::Hardline
+                    | Atom::Blankline(_)),
                     [
-                        head @ (Atom::Space | Atom::Hardline | Atom::Blankline),
+                        head @ (Atom::Space
+                        | Atom::SpaceUnlessBol
+                        | Atom::Hardline
+                        | Atom::Blankline(_)),
                         tail @ ..,
                     ],
                 ) => {
@@ -1053,8 +2388,18 @@ impl AtomCollection {
                 }
                 // If a whitespace or antispace atom is followed by an indent atom, swap their positions.
                 (
-                    moved_prev @ (Atom::Antispace | Atom::Space | Atom::Hardline | Atom::Blankline),
-                    moved_remaining @ [Atom::IndentStart | Atom::IndentEnd, ..],
+                    moved_prev @ (Atom::Antispace
+                    | Atom::Space
+                    | Atom::Hardline
+                    | Atom::Blankline(_)),
+                    moved_remaining @ [
+                        Atom::IndentStart
+                        | Atom::IndentStartWidth(_)
+                        | Atom::IndentEnd
+                        | Atom::IndentSuspend
+                        | Atom::IndentResume,
+                        ..,
+                    ],
                 ) => {
                     let old_prev = moved_prev.clone();
                     let indent = moved_remaining.first_mut().unwrap();
@@ -1147,124 +2492,823 @@ pub struct QueryPredicates {
     pub delimiter: Option<String>,
     /// The predicate used to name a scope indicated by `@begin_scope` and `@end_scope`.
     pub scope_id: Option<String>,
+    /// The predicate used to name an indentation range, opened by
+    /// `@append_indent_start`/`@prepend_indent_start` and closed by
+    /// `@prepend_indent_end`/`@append_indent_end`, whose balance is checked.
+    pub indent_id: Option<String>,
     /// The flag that indicates whether the query only matches single-line nodes.
     pub single_line_only: bool,
     /// The flag that indicates whether the query only matches multi-line nodes.
     pub multi_line_only: bool,
+    /// The flag that indicates that the query does not trigger if the context
+    /// of the matched nodes is empty; that is, it has no named children.
+    pub delete_if_empty: bool,
     /// The flag that indicates that the query only triggers if the associated
     /// custom scope containing the matched nodes is single-line.
     pub single_line_scope_only: Option<String>,
     /// The flag that indicates that the query only triggers if the associated
     /// custom scope containing the matched nodes is multi-line.
     pub multi_line_scope_only: Option<String>,
-    /// A query name, for debugging/logging purposes
+    /// The predicate used to name the alignment group of the `@align` directive.
+    pub align_group: Option<String>,
+    /// The predicate used to name the field of each child whose text is the
+    /// key by which `@sort_children` sorts the children.
+    pub sort_key: Option<String>,
+    /// A query name, for debugging/logging purposes, which also namespaces
+    /// the query's scope ids
     pub query_name: Option<String>,
+    /// The flag that indicates that the query's scope ids are shared with
+    /// queries of other names, rather than namespaced by its query name.
+    pub shared_scope: bool,
+    /// The case to which the text of the captured leaves is rewritten, set by
+    /// the `#lower_case!` and `#upper_case!` predicates.
+    pub capitalisation: Capitalisation,
+    /// The prefix of the line comments, captured with the `#comment_prefix!`
+    /// predicate, whose text is normalised to one space after it.
+    pub comment_prefix: Option<String>,
+    /// The number of spaces that `@append_space` and `@prepend_space` insert,
+    /// set by the `#space_count!` predicate, if not one.
+    pub space_count: Option<usize>,
+    /// The number of indent strings by which the indentation blocks started by
+    /// the query are indented, set by the `#indent_width!` predicate, if not
+    /// one.
+    pub indent_width: Option<usize>,
 }
 
-/// Collapses spaces before antispace atoms in a vector of atoms.
-///
-/// This function modifies the given vector of atoms in place, replacing any
-/// space atoms that precede an antispace atom with empty atoms.
-///
-/// # Arguments
-///
-/// * `v` - A mutable reference to a vector of atoms.
-///
-fn collapse_spaces_before_antispace(v: &mut [Atom]) {
-    let mut antispace_mode = false;
+impl QueryPredicates {
+    /// Prefixes the scope ids of a named query with its query name, so that
+    /// the scopes of differently named queries cannot interfere, even if they
+    /// have the same id. Scope ids that are declared as shared are left as is.
+    pub(crate) fn namespace_scope_ids(&mut self) {
+        if self.shared_scope {
+            return;
+        }
 
-    for a in v.iter_mut().rev() {
-        if *a == Atom::Antispace {
-            *a = Atom::Empty;
-            antispace_mode = true;
-        } else if *a == Atom::Space && antispace_mode {
-            *a = Atom::Empty;
-        } else if *a != Atom::Empty && *a != Atom::IndentStart && *a != Atom::IndentEnd {
-            // Don't change mode when encountering Empty or Indent atoms
-            antispace_mode = false;
+        let Some(query_name) = &self.query_name else {
+            return;
+        };
+
+        for scope_id in [
+            &mut self.scope_id,
+            &mut self.single_line_scope_only,
+            &mut self.multi_line_scope_only,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *scope_id = format!("{query_name}::{scope_id}");
         }
     }
 }
 
-/// Flatten the tree, depth-first, into a vector of nodes.
-///
-/// This function takes a reference to a node and returns a vector of references
-/// to all the nodes in the tree rooted at that node, in depth-first order.
-///
-/// # Arguments
-///
-/// * `node` - A reference to a node in the tree.
-///
-/// # Returns
-///
-/// A vector of references to nodes in the provides node's subtree.
-///
-/// # Notes
+/// A child of a node captured by `@sort_children`, which is moved as a whole
+/// when the children are sorted.
+#[derive(Debug)]
+struct SortUnit {
+    /// The id of the first leaf of the unit, including the comments before it
+    first_leaf: usize,
+    /// The id of the last leaf of the unit, including its trailing comment
+    last_leaf: usize,
+    /// The ids of the first and last leaves of the node whose text is the
+    /// unit's key
+    key_leaves: (usize, usize),
+}
+
+/// Consecutive children of a node captured by `@sort_children`, which are
+/// sorted among themselves.
+#[derive(Debug)]
+struct SortRun {
+    /// The byte range of the captured node
+    range: Range<u32>,
+    units: Vec<SortUnit>,
+}
+
+/// A scope whose end has been reached during the processing of scopes.
+struct ClosedScope<'a> {
+    /// The index of the scope's `ScopeBegin` atom
+    begin: usize,
+    /// The index of the scope's `ScopeEnd` atom
+    end: usize,
+    /// Whether the scope is to be rendered in multi-line mode
+    multi_line: bool,
+    /// The `ScopedSoftline` and `ScopedConditional` atoms within the scope
+    atoms: Vec<&'a Atom>,
+}
+
+/// Forces the single-line scopes among `scopes` into multi-line mode if any
+/// line they span would be wider than `max_line_width` columns.
 ///
-/// This function uses an iterative approach instead of a recursive one for performance reasons.
-/// See https://github.com/topiary/topiary/pull/417#issuecomment-1499085230 for more details.
-fn dfs_flatten<'tree>(node: &Node<'tree>) -> Vec<Node<'tree>> {
-    // Flatten the tree, depth-first, into a vector of nodes
-    let mut walker = node.walk();
-    let mut dfs_nodes = Vec::new();
+/// Scopes are measured outermost first, so that the line breaks introduced by
+/// forcing an enclosing scope are taken into account when measuring the scopes
+/// it contains. Widths are estimates: they count the display width of the
+/// text (so that, e.g., a full-width CJK character counts as two columns and a
+/// combining mark as none), and take the scoped atoms of inner scopes that have
+/// yet to be measured to be single-line.
+fn force_wide_scopes_multi_line(
+    atoms: &[Atom],
+    scopes: &mut [ClosedScope],
+    max_line_width: usize,
+    indent_width: usize,
+) {
+    // The indentation, in columns, with which a line break before each atom
+    // would be followed
+    let mut indentation = Vec::with_capacity(atoms.len());
+    let mut levels: Vec<usize> = Vec::new();
+    for atom in atoms {
+        indentation.push(levels.iter().sum());
+        match atom {
+            Atom::IndentStart => levels.push(indent_width),
+            Atom::IndentStartWidth(width) => levels.push(width * indent_width),
+            Atom::IndentEnd => {
+                levels.pop();
+            }
+            _ => {}
+        }
+    }
 
-    'walk: loop {
-        dfs_nodes.push(walker.node());
+    // The ids of the scoped softlines that will be rendered as line breaks
+    let mut breaking: HashSet<usize> = scopes
+        .iter()
+        .filter(|scope| scope.multi_line)
+        .flat_map(|scope| scoped_softline_ids(&scope.atoms))
+        .collect();
 
-        if !walker.goto_first_child() {
-            while !walker.goto_next_sibling() {
-                if !walker.goto_parent() {
-                    break 'walk;
+    let breaks_line = |atom: &Atom, breaking: &HashSet<usize>| match atom {
+        Atom::Hardline | Atom::Blankline(_) => true,
+        Atom::ScopedSoftline { id, .. } => breaking.contains(id),
+        Atom::Leaf { content, .. } => content.contains('\n'),
+        _ => false,
+    };
+
+    scopes.sort_by_key(|scope| scope.begin);
+
+    for scope in scopes.iter_mut().filter(|scope| !scope.multi_line) {
+        let line_start = atoms[..scope.begin]
+            .iter()
+            .rposition(|atom| breaks_line(atom, &breaking))
+            .unwrap_or(0);
+
+        let mut column = 0;
+        let mut too_wide = false;
+        for (index, atom) in atoms.iter().enumerate().take(scope.end).skip(line_start) {
+            column = match atom {
+                Atom::Hardline | Atom::Blankline(_) => indentation[index],
+                Atom::ScopedSoftline { id, spaced, .. } => {
+                    if breaking.contains(id) {
+                        indentation[index]
+                    } else {
+                        column + usize::from(*spaced)
+                    }
                 }
+                Atom::Leaf { content, .. } => match content.rsplit_once('\n') {
+                    Some((_, last_line)) => last_line.width(),
+                    None => column + content.width(),
+                },
+                Atom::Literal(literal) => column + literal.width(),
+                Atom::Space | Atom::SpaceUnlessBol => column + 1,
+                _ => column,
+            };
+
+            if index >= scope.begin && column > max_line_width {
+                too_wide = true;
+                break;
             }
         }
+
+        if too_wide {
+            log::debug!("Forcing scope at atom {} into multi-line mode", scope.begin);
+            scope.multi_line = true;
+            breaking.extend(scoped_softline_ids(&scope.atoms));
+        }
     }
+}
 
-    dfs_nodes
+fn scoped_softline_ids<'a>(atoms: &'a [&'a Atom]) -> impl Iterator<Item = usize> + 'a {
+    atoms.iter().filter_map(|atom| match atom {
+        Atom::ScopedSoftline { id, .. } => Some(*id),
+        _ => None,
+    })
 }
 
-/// Detects multi-line nodes in a vector of nodes and returns a set of their ids.
-///
-/// This function takes a slice of `Node`s that represents the nodes in a depth-first search
-/// order of a syntax tree and iterates over them. For each node, it compares the start and end
-/// line numbers and checks if they are different. If they are, it means the node spans multiple
-/// lines and its id is added to the returned set.
-///
-/// # Arguments
-///
-/// * `dfs_nodes` - A slice of nodes in depth-first search order.
-///
-/// # Returns
-///
-/// A `HashSet` containing the ids of the multi-line nodes.
-fn detect_multi_line_nodes(dfs_nodes: &[Node]) -> HashSet<usize> {
-    dfs_nodes
+/// The whitespace that precedes each of the leaves among `atoms` in the input,
+/// keyed by the leaf's id, along with the id of the leaf that precedes it. Only
+/// leaves that follow another with nothing but whitespace in between have one.
+fn original_gaps(
+    atoms: &[Atom],
+    dfs_nodes: &[Node],
+    disabled_leaves: &HashMap<usize, Range<usize>>,
+    source: &[u8],
+) -> HashMap<usize, (usize, String)> {
+    let ranges: HashMap<usize, Range<usize>> = dfs_nodes
         .iter()
-        .filter_map(|node| {
-            let start_line = node.start_position().row();
-            let end_line = node.end_position().row();
+        .map(|node| (node.id(), node.byte_range()))
+        .collect();
 
-            if end_line > start_line {
-                log::debug!(
-                    "Multi-line node {}: {}",
-                    node.id(),
-                    node.display_one_based()
-                );
-                return Some(node.id());
-            }
+    let mut gaps = HashMap::new();
+    let mut previous: Option<(usize, usize)> = None;
+    for atom in atoms {
+        let Atom::Leaf { id, .. } = atom else {
+            continue;
+        };
+        let Some(range) = disabled_leaves.get(id).or_else(|| ranges.get(id)) else {
+            continue;
+        };
 
-            None
-        })
-        .collect()
+        if let Some((previous_id, end)) = previous
+            && end <= range.start
+            && let Ok(gap) = std::str::from_utf8(&source[end..range.start])
+            && gap.chars().all(char::is_whitespace)
+        {
+            gaps.insert(*id, (previous_id, gap.to_owned()));
+        }
+        previous = Some((*id, range.end));
+    }
+
+    gaps
 }
 
-/// Detects line breaks between nodes in a flattened vector of nodes.
-///
-/// This function takes a slice of nodes that have been flattened by a depth-first search (DFS)
-/// and returns a struct that contains two sets of node IDs: one for the nodes that have a line break
-/// before them, and one for the nodes that have a line break after them.
-///
-/// # Arguments
+/// The whitespace atom that best reproduces the given run of input whitespace,
+/// if it is not empty.
+fn original_whitespace(whitespace: &[u8]) -> Option<Atom> {
+    if whitespace.is_empty() {
+        return None;
+    }
+
+    Some(match whitespace.iter().filter(|b| **b == b'\n').count() {
+        0 => Atom::Space,
+        1 => Atom::Hardline,
+        newlines => Atom::Blankline(newlines - 1),
+    })
+}
+
+/// The part of the line of `source` that precedes `offset`
+fn line_prefix(source: &[u8], offset: usize) -> Cow<'_, str> {
+    let line_start = source[..offset]
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |newline| newline + 1);
+
+    String::from_utf8_lossy(&source[line_start..offset])
+}
+
+/// The width, in columns, of the indentation of the line of `source` that
+/// contains `offset`, with tabs `tab_width` columns wide
+fn line_indent(source: &[u8], offset: usize, tab_width: usize) -> u32 {
+    let prefix = line_prefix(source, offset);
+    let rest = prefix.trim_start_matches([' ', '\t']);
+
+    column_width(&prefix[..prefix.len() - rest.len()], tab_width) as u32
+}
+
+/// The column, counted from 0, of `offset` in its line of `source`, with tabs
+/// `tab_width` columns wide
+fn column(source: &[u8], offset: usize, tab_width: usize) -> u32 {
+    column_width(&line_prefix(source, offset), tab_width) as u32
+}
+
+/// Splits a delimiter into the sequence of atoms it should render as.
+///
+/// Newlines within the delimiter become `Hardline` atoms, so that the
+/// indentation of the following line is handled as usual; everything in
+/// between becomes a `Literal`.
+fn delimiter_atoms(delimiter: &str) -> Vec<Atom> {
+    let mut atoms = Vec::new();
+
+    for (index, segment) in delimiter.split('\n').enumerate() {
+        if index > 0 {
+            atoms.push(Atom::Hardline);
+        }
+        if !segment.is_empty() {
+            atoms.push(Atom::Literal(segment.to_owned()));
+        }
+    }
+
+    atoms
+}
+
+/// The atom that starts an indentation block, of the width given by the
+/// `#indent_width!` predicate, if any.
+fn indent_start(predicates: &QueryPredicates) -> Atom {
+    match predicates.indent_width {
+        Some(width) => Atom::IndentStartWidth(width),
+        None => Atom::IndentStart,
+    }
+}
+
+/// Turns each `SpaceIfFollowed` into a space if a leaf or literal with content
+/// follows it before the next line break, or into an empty atom otherwise.
+/// Leaves with no content, such as optional nodes that are present but empty,
+/// do not count as following.
+fn resolve_spaces_if_followed(v: &mut [Atom]) {
+    for index in 0..v.len() {
+        if v[index] != Atom::SpaceIfFollowed {
+            continue;
+        }
+
+        let followed = v[index + 1..]
+            .iter()
+            .find_map(|atom| match atom {
+                Atom::Leaf { content, .. } | Atom::Literal(content) if !content.is_empty() => {
+                    Some(true)
+                }
+                Atom::Hardline | Atom::Blankline(_) => Some(false),
+                _ => None,
+            })
+            .unwrap_or(false);
+
+        v[index] = if followed { Atom::Space } else { Atom::Empty };
+    }
+}
+
+/// Collapses the horizontal whitespace around segments deleted by
+/// `@delete_with_surrounding_space`, which are bounded by `DeleteBoundary`
+/// atoms once their contents have been emptied.
+///
+/// Each maximal run of horizontal whitespace, empty atoms and boundaries that
+/// contains a boundary is considered in one go, so that adjacent deleted
+/// segments (e.g., a list element and its separator) are handled together.
+/// If the run has whitespace both before its first boundary and after its last
+/// one, a single space is kept; otherwise, the whitespace is removed entirely.
+/// That is, a deleted node between two spaced nodes leaves a single space, but
+/// a deleted node at the end of a list leaves no space before the closing
+/// delimiter.
+fn collapse_spaces_around_deletions(v: &mut [Atom]) {
+    let is_gap = |atom: &Atom| {
+        matches!(
+            atom,
+            Atom::Empty | Atom::Space | Atom::SpaceUnlessBol | Atom::DeleteBoundary
+        )
+    };
+
+    let mut start = 0;
+    while start < v.len() {
+        if !is_gap(&v[start]) {
+            start += 1;
+            continue;
+        }
+
+        let end = v[start..]
+            .iter()
+            .position(|atom| !is_gap(atom))
+            .map_or(v.len(), |offset| start + offset);
+        let gap = &mut v[start..end];
+
+        let first_boundary = gap.iter().position(|atom| *atom == Atom::DeleteBoundary);
+        let last_boundary = gap.iter().rposition(|atom| *atom == Atom::DeleteBoundary);
+
+        if let (Some(first), Some(last)) = (first_boundary, last_boundary) {
+            let is_space = |atom: &Atom| matches!(atom, Atom::Space | Atom::SpaceUnlessBol);
+            let spaced = gap[..first].iter().any(is_space) && gap[last..].iter().any(is_space);
+
+            for atom in gap.iter_mut() {
+                *atom = Atom::Empty;
+            }
+            if spaced {
+                gap[first] = Atom::Space;
+            }
+        }
+
+        start = end;
+    }
+}
+
+/// Caps the number of blank lines of each blank line atom at `max_blank_lines`,
+/// turning them into hardlines if it is 0.
+fn cap_blank_lines(v: &mut [Atom], max_blank_lines: usize) {
+    for atom in v.iter_mut() {
+        if let Atom::Blankline(count) = atom {
+            *atom = if max_blank_lines == 0 {
+                Atom::Hardline
+            } else {
+                Atom::Blankline((*count).min(max_blank_lines))
+            };
+        }
+    }
+}
+
+/// Collapses spaces around antispace atoms in a vector of atoms.
+///
+/// This function modifies the given vector of atoms in place, replacing each
+/// antispace atom, and any space atoms that directly precede or follow it,
+/// with empty atoms. Empty, indent and other antispace atoms do not interrupt
+/// the collapse, as they produce no output by themselves. As both sides are
+/// handled alike, `@prepend_antispace` removes a space appended to the
+/// preceding node just as `@append_antispace` removes one prepended to the
+/// following node.
+///
+/// # Arguments
+///
+/// * `v` - A mutable reference to a vector of atoms.
+///
+fn collapse_spaces_around_antispace(v: &mut [Atom]) {
+    fn collapse<'a>(atoms: impl Iterator<Item = &'a mut Atom>) {
+        for atom in atoms {
+            match atom {
+                Atom::Space | Atom::SpaceUnlessBol => *atom = Atom::Empty,
+                Atom::Empty
+                | Atom::IndentStart
+                | Atom::IndentStartWidth(_)
+                | Atom::IndentEnd
+                | Atom::IndentSuspend
+                | Atom::IndentResume
+                | Atom::Antispace => {}
+                _ => break,
+            }
+        }
+    }
+
+    for index in 0..v.len() {
+        if v[index] == Atom::Antispace {
+            let (before, after) = v.split_at_mut(index);
+            collapse(before.iter_mut().rev());
+            collapse(after.iter_mut().skip(1));
+        }
+    }
+
+    for atom in v.iter_mut().filter(|atom| **atom == Atom::Antispace) {
+        *atom = Atom::Empty;
+    }
+}
+
+/// Collapses spaces directly preceding a line break in a vector of atoms.
+///
+/// This function modifies the given vector of atoms in place, replacing any
+/// space atoms (and space-only literals, such as those produced by a `" "`
+/// delimiter) that precede a hardline or blankline with empty atoms. Indent
+/// atoms do not interrupt the collapse, as they produce no output by
+/// themselves.
+///
+/// # Arguments
+///
+/// * `v` - A mutable reference to a vector of atoms.
+///
+fn collapse_trailing_spaces(v: &mut [Atom]) {
+    let mut line_break_mode = false;
+
+    for a in v.iter_mut().rev() {
+        match a {
+            Atom::Hardline | Atom::Blankline(_) => line_break_mode = true,
+            Atom::Space | Atom::SpaceUnlessBol if line_break_mode => *a = Atom::Empty,
+            Atom::Literal(s) if line_break_mode && is_space_literal(s) => *a = Atom::Empty,
+            // Don't change mode when encountering Empty or Indent atoms
+            Atom::Empty
+            | Atom::IndentStart
+            | Atom::IndentStartWidth(_)
+            | Atom::IndentEnd
+            | Atom::IndentSuspend
+            | Atom::IndentResume => {}
+            _ => line_break_mode = false,
+        }
+    }
+}
+
+/// Collapses the whitespace after the last content atom in a vector of atoms.
+///
+/// The output is terminated according to the final newline policy, so any
+/// line break that the query appends to the last node (e.g., with
+/// `@append_hardline`) must not compound with it. Atoms that produce no output
+/// by themselves, such as indent and scope atoms, are kept.
+///
+/// # Arguments
+///
+/// * `v` - A mutable reference to a vector of atoms.
+///
+fn collapse_final_whitespace(v: &mut [Atom]) {
+    for a in v.iter_mut().rev() {
+        match a {
+            Atom::Leaf { .. } => break,
+            Atom::Literal(s) if !s.trim().is_empty() => break,
+            Atom::Space
+            | Atom::SpaceUnlessBol
+            | Atom::Antispace
+            | Atom::Hardline
+            | Atom::Blankline(_)
+            | Atom::Literal(_) => *a = Atom::Empty,
+            _ => {}
+        }
+    }
+}
+
+/// Whether a literal consists only of spaces, and thus renders as trailing
+/// whitespace when followed by a line break.
+fn is_space_literal(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c == ' ')
+}
+
+/// Flatten the tree, depth-first, into a vector of nodes.
+///
+/// This function takes a reference to a node and returns a vector of references
+/// to all the nodes in the tree rooted at that node, in depth-first order.
+///
+/// # Arguments
+///
+/// * `node` - A reference to a node in the tree.
+///
+/// # Returns
+///
+/// A vector of references to nodes in the provides node's subtree.
+///
+/// # Notes
+///
+/// This function uses an iterative approach instead of a recursive one for performance reasons.
+/// See https://github.com/topiary/topiary/pull/417#issuecomment-1499085230 for more details.
+pub(crate) fn dfs_flatten<'tree>(node: &Node<'tree>) -> Vec<Node<'tree>> {
+    // Flatten the tree, depth-first, into a vector of nodes
+    let mut walker = node.walk();
+    let mut dfs_nodes = Vec::new();
+
+    'walk: loop {
+        dfs_nodes.push(walker.node());
+
+        if !walker.goto_first_child() {
+            while !walker.goto_next_sibling() {
+                if !walker.goto_parent() {
+                    break 'walk;
+                }
+            }
+        }
+    }
+
+    dfs_nodes
+}
+
+/// Whether the node is a comment: that is, whether its kind contains
+/// "comment".
+fn is_comment(node: &Node) -> bool {
+    node.kind().contains("comment")
+}
+
+/// Whether the node is preceded, in its parent, by a named sibling that is not
+/// a comment. Anonymous siblings, such as an opening brace, and comments, such
+/// as one before the first statement of a block, do not count.
+fn has_preceding_named_sibling(node: &Node) -> bool {
+    let mut sibling = node.prev_named_sibling();
+    while let Some(node) = sibling {
+        if !is_comment(&node) {
+            return true;
+        }
+        sibling = node.prev_named_sibling();
+    }
+
+    false
+}
+
+/// Finds the comments that start on the line on which the preceding node
+/// ends, and end their own line, and so trail that node: for instance, the
+/// comment in `foo, // note`. A comment that is followed by another node on
+/// its line, as in `foo, /* note */ bar`, is inline rather than trailing.
+/// Comments within comments are not considered.
+fn detect_trailing_comments(dfs_nodes: &[Node]) -> HashSet<usize> {
+    fn preceding_node<'tree>(node: &Node<'tree>) -> Option<Node<'tree>> {
+        let mut node = *node;
+        loop {
+            if let Some(sibling) = node.prev_sibling() {
+                return Some(sibling);
+            }
+            node = node.parent()?;
+        }
+    }
+
+    fn following_node<'tree>(node: &Node<'tree>) -> Option<Node<'tree>> {
+        let mut node = *node;
+        loop {
+            if let Some(sibling) = node.next_sibling() {
+                return Some(sibling);
+            }
+            node = node.parent()?;
+        }
+    }
+
+    dfs_nodes
+        .iter()
+        .filter(|node| is_comment(node) && !node.parent().is_some_and(|parent| is_comment(&parent)))
+        .filter(|comment| {
+            preceding_node(comment).is_some_and(|preceding| {
+                preceding.end_position().row() == comment.start_position().row()
+            })
+        })
+        .filter(|comment| {
+            following_node(comment).is_none_or(|following| {
+                following.start_position().row() > comment.end_position().row()
+            })
+        })
+        .map(|comment| comment.id())
+        .collect()
+}
+
+/// Finds the byte ranges in which formatting is disabled: each runs from the
+/// end of a comment containing the `off` marker to the start of the next
+/// comment containing the `on` marker. Unbalanced markers are ignored, with a
+/// warning. Comments are recognised as the nodes whose kind contains
+/// "comment".
+fn disabled_ranges(
+    dfs_nodes: &[Node],
+    source: &[u8],
+    markers: &FormattingMarkers,
+) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut disabled_from: Option<&Node> = None;
+
+    // Nodes within comments (e.g., their content) are part of the comment
+    for comment in dfs_nodes
+        .iter()
+        .filter(|node| is_comment(node) && !node.parent().is_some_and(|parent| is_comment(&parent)))
+    {
+        let text = String::from_utf8_lossy(
+            &source[comment.start_byte() as usize..comment.end_byte() as usize],
+        );
+
+        if text.contains(&markers.off) {
+            if disabled_from.is_some() {
+                log::warn!(
+                    "Ignoring formatting marker {:?} in {}, as formatting is already disabled",
+                    markers.off,
+                    comment.display_one_based()
+                );
+            } else {
+                disabled_from = Some(comment);
+            }
+        } else if text.contains(&markers.on) {
+            match disabled_from.take() {
+                Some(off) => ranges.push(off.end_byte() as usize..comment.start_byte() as usize),
+                None => log::warn!(
+                    "Ignoring formatting marker {:?} in {}, as formatting is not disabled",
+                    markers.on,
+                    comment.display_one_based()
+                ),
+            }
+        }
+    }
+
+    if let Some(off) = disabled_from {
+        log::warn!(
+            "Ignoring formatting marker {:?} in {}, as formatting is never re-enabled",
+            markers.off,
+            off.display_one_based()
+        );
+    }
+
+    ranges
+}
+
+/// Finds the byte ranges of the outermost nodes whose kind is one of `kinds`.
+/// Nodes of those kinds that are nested within another are part of its range.
+fn selected_ranges(dfs_nodes: &[Node], kinds: &[String]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+
+    // Nodes are in depth-first order, so an outer node precedes those within it
+    for node in dfs_nodes {
+        let range = node.start_byte() as usize..node.end_byte() as usize;
+        if kinds.iter().any(|kind| kind.as_str() == node.kind())
+            && ranges.last().is_none_or(|last| last.end <= range.start)
+        {
+            ranges.push(range);
+        }
+    }
+
+    ranges
+}
+
+/// Finds the byte ranges that the given lines of the source select to be
+/// formatted: on each line, the span of the nodes that lie within it, so that
+/// the whitespace between them is formatted too. A line within which no node
+/// lies, such as one in the middle of a multi-line string, selects the smallest
+/// node that encloses its content instead. Blank lines, and lines beyond the end
+/// of the source, select nothing.
+fn selected_line_ranges(
+    dfs_nodes: &[Node],
+    source: &[u8],
+    lines: &[Range<usize>],
+) -> Vec<Range<usize>> {
+    let Some(root) = dfs_nodes.first() else {
+        return Vec::new();
+    };
+    let rows: HashSet<usize> = lines.iter().flat_map(Range::clone).collect();
+
+    let mut spans: HashMap<usize, Range<usize>> = HashMap::new();
+    for node in dfs_nodes {
+        let row = node.start_position().row() as usize;
+        let range = node.byte_range();
+        if range.is_empty() || node.end_position().row() as usize != row || !rows.contains(&row) {
+            continue;
+        }
+
+        let span = spans.entry(row).or_insert_with(|| range.clone());
+        span.start = span.start.min(range.start);
+        span.end = span.end.max(range.end);
+    }
+
+    // The byte offsets at which each line starts
+    let line_starts: Vec<usize> = iter::once(0)
+        .chain(
+            source
+                .iter()
+                .enumerate()
+                .filter(|(_, byte)| **byte == b'\n')
+                .map(|(index, _)| index + 1),
+        )
+        .collect();
+
+    for row in rows {
+        let Some(&start) = line_starts.get(row).filter(|_| !spans.contains_key(&row)) else {
+            continue;
+        };
+
+        let end = line_starts.get(row + 1).copied().unwrap_or(source.len());
+        let content = &source[start..end];
+        if let (Some(first), Some(last)) = (
+            content.iter().position(|byte| !byte.is_ascii_whitespace()),
+            content.iter().rposition(|byte| !byte.is_ascii_whitespace()),
+        ) {
+            let node =
+                root.descendant_for_byte_range((start + first) as u32, (start + last + 1) as u32);
+            spans.insert(
+                row,
+                node.map_or_else(|| root.byte_range(), |node| node.byte_range()),
+            );
+        }
+    }
+
+    spans.into_values().collect()
+}
+
+/// Orders the selected byte ranges, merging those that overlap, so that they
+/// are disjoint.
+fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start < last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+/// The non-empty byte ranges, within a source of length `len`, that are not
+/// covered by the given ranges, which must be ordered and disjoint.
+fn complement(ranges: &[Range<usize>], len: usize) -> Vec<Range<usize>> {
+    let mut gaps = Vec::new();
+    let mut start = 0;
+
+    for range in ranges {
+        if start < range.start {
+            gaps.push(start..range.start);
+        }
+        start = range.end;
+    }
+    if start < len {
+        gaps.push(start..len);
+    }
+
+    gaps
+}
+
+/// Detects multi-line nodes in a vector of nodes and returns a set of their ids.
+///
+/// This function takes a slice of `Node`s that represents the nodes in a depth-first search
+/// order of a syntax tree and iterates over them. For each node, it compares the start and end
+/// line numbers and checks if they are different. If they are, it means the node spans multiple
+/// lines and its id is added to the returned set.
+///
+/// # Arguments
+///
+/// * `dfs_nodes` - A slice of nodes in depth-first search order.
+///
+/// # Returns
+///
+/// A `HashSet` containing the ids of the multi-line nodes.
+fn detect_multi_line_nodes(dfs_nodes: &[Node]) -> HashSet<usize> {
+    dfs_nodes
+        .iter()
+        .filter_map(|node| {
+            let start_line = node.start_position().row();
+            let end_line = node.end_position().row();
+
+            if end_line > start_line {
+                log::debug!(
+                    "Multi-line node {}: {}",
+                    node.id(),
+                    node.display_one_based()
+                );
+                return Some(node.id());
+            }
+
+            None
+        })
+        .collect()
+}
+
+/// Detects line breaks between nodes in a flattened vector of nodes.
+///
+/// This function takes a slice of nodes that have been flattened by a depth-first search (DFS)
+/// and returns a struct that contains two sets of node IDs: one for the nodes that have a line break
+/// before them, and one for the nodes that have a line break after them.
+///
+/// # Arguments
 ///
 /// * `dfs_nodes` - A slice of nodes that have been flattened by a DFS.
 /// * `minimum_line_breaks` - The minimum number of line breaks that must exist between two adjacent nodes
@@ -1299,8 +3343,8 @@ fn detect_line_breaks(dfs_nodes: &[Node], minimum_line_breaks: u32) -> NodesWith
                 //   <Left> <Line Breaks> <Right>
                 //   \----- after ------/
                 //
-                // NOTE We only need the node IDs
-                return Some((right.id(), left.id()));
+                let line_breaks = next - last;
+                return Some(((right.id(), line_breaks), (left.id(), line_breaks)));
             }
 
             None
@@ -1310,6 +3354,15 @@ fn detect_line_breaks(dfs_nodes: &[Node], minimum_line_breaks: u32) -> NodesWith
     NodesWithLinebreaks { before, after }
 }
 
+/// The number of blank lines around each of the given nodes, from their number
+/// of line breaks, of which there must be at least two.
+fn blank_lines(line_breaks: HashMap<usize, u32>) -> HashMap<usize, usize> {
+    line_breaks
+        .into_iter()
+        .map(|(id, line_breaks)| (id, line_breaks as usize - 1))
+        .collect()
+}
+
 /// So that we can easily extract the atoms using `&atom_collection[..]`
 impl<Idx> std::ops::Index<Idx> for AtomCollection
 where
@@ -1324,7 +3377,12 @@ where
 
 #[cfg(test)]
 mod test {
-    use crate::{Atom, atom_collection::AtomCollection};
+    use crate::{
+        Atom, Capitalisation, ScopeCondition, ScopeInformation,
+        atom_collection::AtomCollection,
+        pretty::render,
+        tree_sitter::{InjectionSpan, Position},
+    };
     use test_log::test;
 
     #[test]
@@ -1372,27 +3430,97 @@ mod test {
     }
 
     #[test]
-    fn post_process_empty_blank_hard() {
+    fn post_process_hardlines_across_indent_collapse() {
         let mut atom_collection = AtomCollection::new(vec![
-            Atom::Empty,
-            Atom::Blankline,
-            Atom::Hardline,
             Atom::Literal("foo".into()),
-        ]);
-
+            Atom::Hardline,
+            Atom::IndentStart,
+            Atom::Hardline,
+            Atom::Literal("bar".into()),
+            Atom::IndentEnd,
+        ]);
+
+        atom_collection.post_process();
+
+        assert_eq!(render(&atom_collection[..], "  ", 8).unwrap(), "foo\n  bar");
+    }
+
+    #[test]
+    fn post_process_interleaved_hardlines_and_blanklines() {
+        let mut atom_collection = AtomCollection::new(vec![
+            Atom::Literal("a".into()),
+            // append_hardline + prepend_hardline
+            Atom::Hardline,
+            Atom::Hardline,
+            Atom::Literal("b".into()),
+            // allow_blank_line_before on its own
+            Atom::Blankline(1),
+            Atom::Literal("c".into()),
+            // allow_blank_line_before between two hardlines
+            Atom::Hardline,
+            Atom::Blankline(1),
+            Atom::Hardline,
+            Atom::Literal("d".into()),
+            // hardlines separated by an antispace
+            Atom::Hardline,
+            Atom::Antispace,
+            Atom::Hardline,
+            Atom::Literal("e".into()),
+        ]);
+
+        atom_collection.post_process();
+
+        let rendered = render(&atom_collection[..], "  ", 8).unwrap();
+        assert_eq!(rendered, "a\nb\n\nc\n\nd\ne");
+        assert_eq!(rendered.lines().count(), 7);
+    }
+
+    #[test]
+    fn post_process_empty_blank_hard() {
+        let mut atom_collection = AtomCollection::new(vec![
+            Atom::Empty,
+            Atom::Blankline(1),
+            Atom::Hardline,
+            Atom::Literal("foo".into()),
+        ]);
+
         atom_collection.post_process();
 
         assert_eq!(
             atom_collection.atoms,
             vec![
                 Atom::Empty,
-                Atom::Blankline,
+                Atom::Blankline(1),
                 Atom::Empty,
                 Atom::Literal("foo".into()),
             ]
         );
     }
 
+    #[test]
+    fn post_process_final_whitespace() {
+        let mut atom_collection = AtomCollection::new(vec![
+            Atom::Literal("foo".into()),
+            Atom::Hardline,
+            Atom::IndentEnd,
+            Atom::Blankline(1),
+            Atom::Space,
+        ]);
+
+        atom_collection.post_process();
+
+        assert_eq!(
+            atom_collection.atoms,
+            vec![
+                Atom::Literal("foo".into()),
+                Atom::IndentEnd,
+                Atom::Empty,
+                Atom::Empty,
+                Atom::Empty,
+            ]
+        );
+    }
+
     #[test]
     fn issue_549_post_process_indent_before_hardline_with_antispace_in_between() {
         let mut atom_collection = AtomCollection::new(vec![
@@ -1416,4 +3544,600 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn post_process_space_before_hardline_across_indent_end() {
+        let mut atom_collection = AtomCollection::new(vec![
+            Atom::Literal("foo".into()),
+            Atom::Space,
+            Atom::Literal(" ".into()),
+            Atom::IndentEnd,
+            Atom::Hardline,
+            Atom::Literal("foo".into()),
+        ]);
+
+        atom_collection.post_process();
+
+        assert_eq!(
+            atom_collection.atoms,
+            vec![
+                Atom::Literal("foo".into()),
+                Atom::Empty,
+                Atom::Empty,
+                Atom::IndentEnd,
+                Atom::Hardline,
+                Atom::Literal("foo".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn post_process_trailing_space_collapse_is_idempotent() {
+        let atoms = vec![
+            Atom::Literal("foo".into()),
+            Atom::Antispace,
+            Atom::Space,
+            Atom::Literal("bar".into()),
+            Atom::Space,
+            Atom::IndentStart,
+            Atom::Blankline(1),
+            Atom::Literal("baz".into()),
+        ];
+
+        let mut once = AtomCollection::new(atoms);
+        once.post_process();
+        let mut twice = AtomCollection::new(once.atoms.clone());
+        twice.post_process();
+
+        assert_eq!(
+            once.atoms,
+            vec![
+                Atom::Literal("foo".into()),
+                Atom::Empty,
+                Atom::Empty,
+                Atom::Literal("bar".into()),
+                Atom::IndentStart,
+                Atom::Empty,
+                Atom::Blankline(1),
+                Atom::Literal("baz".into()),
+            ]
+        );
+        assert_eq!(once.atoms, twice.atoms);
+    }
+
+    #[test]
+    fn render_space_unless_bol_on_same_line() {
+        let mut atom_collection = AtomCollection::new(vec![
+            Atom::Literal("foo".into()),
+            Atom::SpaceUnlessBol,
+            Atom::Literal("+".into()),
+        ]);
+
+        atom_collection.post_process();
+
+        assert_eq!(render(&atom_collection[..], "  ", 8).unwrap(), "foo +");
+    }
+
+    #[test]
+    fn render_space_unless_bol_on_wrapped_line() {
+        let atoms = vec![
+            Atom::Literal("foo".into()),
+            Atom::IndentStart,
+            Atom::Hardline,
+            Atom::SpaceUnlessBol,
+            Atom::Literal("+".into()),
+            Atom::IndentEnd,
+        ];
+
+        assert_eq!(render(&atoms, "  ", 8).unwrap(), "foo\n  +");
+    }
+
+    /// Renders a doubly-indented Nix binding of `x` to an indented string,
+    /// preceded on its line by the `prefix` literals, where the string leaf
+    /// started at `column` (counted from 0, with tabs expanded) on an input
+    /// line indented by `line_indent` columns.
+    fn render_nix_indented_string(
+        indent: &str,
+        prefix: &[&str],
+        content: &str,
+        column: u32,
+        line_indent: u32,
+    ) -> String {
+        let mut atoms = vec![Atom::IndentStart, Atom::IndentStart, Atom::Hardline];
+        for literal in prefix {
+            atoms.push(Atom::Literal((*literal).into()));
+            atoms.push(Atom::Space);
+        }
+        atoms.extend([
+            Atom::Leaf {
+                content: content.into(),
+                id: 0,
+                original_position: Position {
+                    row: 2,
+                    column: column + 1,
+                },
+                original_column: column,
+                single_line_no_indent: false,
+                multi_line_indent_all: true,
+                original_line_indent: line_indent,
+                keep_whitespace: false,
+                capitalisation: Capitalisation::Pass,
+            },
+            Atom::IndentEnd,
+            Atom::IndentEnd,
+        ]);
+
+        render(&atoms, indent, 8).unwrap()
+    }
+
+    #[test]
+    fn render_multi_line_indent_all_ignores_preceding_comment() {
+        // {
+        //   x = ''
+        //     foo
+        //   '';
+        // }
+        let plain = render_nix_indented_string("  ", &["x", "="], "''\n    foo\n  ''", 6, 2);
+
+        // {
+        //   x = /* c */ ''
+        //     foo
+        //   '';
+        // }
+        let commented =
+            render_nix_indented_string("  ", &["x", "=", "/* c */"], "''\n    foo\n  ''", 14, 2);
+
+        assert_eq!(plain, "\n    x = ''\n      foo\n    ''");
+        assert_eq!(commented, "\n    x = /* c */ ''\n      foo\n    ''");
+    }
+
+    #[test]
+    fn render_multi_line_indent_all_ignores_value_placement() {
+        // {
+        //   x = ''
+        //     foo
+        //   '';
+        // }
+        let same_line = render_nix_indented_string("  ", &["x", "="], "''\n    foo\n  ''", 6, 2);
+
+        // {
+        //   x =
+        //     ''
+        //       foo
+        //     '';
+        // }
+        let next_line =
+            render_nix_indented_string("  ", &["x", "="], "''\n      foo\n    ''", 4, 4);
+
+        assert_eq!(same_line, next_line);
+    }
+
+    #[test]
+    fn render_multi_line_indent_all_with_tabs() {
+        // {
+        // \tx = ''
+        // \t\tfoo
+        // \t'';
+        // }
+        let rendered = render_nix_indented_string("\t", &["x", "="], "''\n\t\tfoo\n\t''", 12, 8);
+
+        assert_eq!(rendered, "\n\t\tx = ''\n\t\t\tfoo\n\t\t''");
+    }
+
+    #[test]
+    fn render_multi_line_indent_all_with_tabs_aligns_with_spaces() {
+        // foo /* a
+        //      * b */
+        let atoms = vec![
+            Atom::IndentStart,
+            Atom::Hardline,
+            Atom::Literal("foo".into()),
+            Atom::Space,
+            Atom::Leaf {
+                content: "/* a\n     * b */".into(),
+                id: 0,
+                original_position: Position { row: 1, column: 5 },
+                original_column: 4,
+                single_line_no_indent: false,
+                multi_line_indent_all: true,
+                original_line_indent: 0,
+                keep_whitespace: false,
+                capitalisation: Capitalisation::Pass,
+            },
+            Atom::IndentEnd,
+        ];
+
+        // Tabs up to the indentation level, then spaces to align with the leaf
+        assert_eq!(
+            render(&atoms, "\t", 8).unwrap(),
+            "\n\tfoo /* a\n\t     * b */"
+        );
+    }
+
+    #[test]
+    fn injected_content_is_spliced_into_enclosing_leaf() {
+        //   let q = "select a,b
+        //   from t";
+        let string_leaf = Atom::Leaf {
+            content: "\"select a,b\n  from t\"".into(),
+            id: 1,
+            original_position: Position { row: 1, column: 11 },
+            original_column: 10,
+            single_line_no_indent: false,
+            multi_line_indent_all: false,
+            original_line_indent: 2,
+            keep_whitespace: false,
+            capitalisation: Capitalisation::Pass,
+        };
+        let mut atom_collection = AtomCollection::new(vec![string_leaf]);
+        atom_collection.parent_leaf_nodes.insert(2, 1);
+
+        let mut span = InjectionSpan {
+            content: "select a,b\n  from t",
+            language: "sql".into(),
+            node_id: 2,
+            position: Position { row: 1, column: 12 },
+            indentation: "  ",
+            escapes: Vec::new(),
+        };
+        assert!(atom_collection.splice_injected_content(&span, "select a, b\nfrom t"));
+        assert_eq!(
+            render(&atom_collection[..], "  ", 8).unwrap(),
+            "\"select a, b\n  from t\""
+        );
+
+        // The leaf no longer holds the original text where expected
+        span.content = "select a,b";
+        assert!(!atom_collection.splice_injected_content(&span, "select a, b"));
+    }
+
+    /// Builds the atoms of a single-line function call, whose arguments are
+    /// separated by scoped softlines in a scope named after the function.
+    fn scoped_call(name: &str, args: Vec<Vec<Atom>>, next_id: &mut usize) -> Vec<Atom> {
+        let scope = || ScopeInformation {
+            line_number: 0,
+            scope_id: name.into(),
+            node_id: 0,
+        };
+        let mut softline = |spaced| {
+            *next_id += 1;
+            Atom::ScopedSoftline {
+                id: *next_id,
+                scope_id: name.into(),
+                spaced,
+            }
+        };
+
+        let mut atoms = vec![
+            Atom::ScopeBegin(scope()),
+            Atom::Literal(format!("{name}(")),
+            softline(false),
+        ];
+        let arg_count = args.len();
+        for (index, arg) in args.into_iter().enumerate() {
+            atoms.extend(arg);
+            if index + 1 < arg_count {
+                atoms.push(Atom::Literal(",".into()));
+                atoms.push(softline(true));
+            }
+        }
+        atoms.extend([
+            softline(false),
+            Atom::Literal(")".into()),
+            Atom::ScopeEnd(scope()),
+        ]);
+
+        atoms
+    }
+
+    fn render_with_width(atoms: Vec<Atom>, max_line_width: Option<usize>) -> String {
+        let mut atom_collection = AtomCollection::new(atoms);
+        if let Some(max_line_width) = max_line_width {
+            atom_collection.limit_line_width(max_line_width, "  ");
+        }
+        atom_collection.post_process();
+
+        render(&atom_collection[..], "  ", 8).unwrap()
+    }
+
+    #[test]
+    fn max_line_width_forces_wide_scope_multi_line() {
+        let mut id = 0;
+        let atoms = scoped_call(
+            "foo",
+            vec![
+                vec![Atom::Literal("aaaa".into())],
+                vec![Atom::Literal("bbbb".into())],
+            ],
+            &mut id,
+        );
+
+        assert_eq!(render_with_width(atoms.clone(), None), "foo(aaaa, bbbb)");
+        assert_eq!(
+            render_with_width(atoms.clone(), Some(15)),
+            "foo(aaaa, bbbb)"
+        );
+        assert_eq!(render_with_width(atoms, Some(14)), "foo(\naaaa,\nbbbb\n)");
+    }
+
+    #[test]
+    fn max_line_width_counts_full_width_characters_as_two_columns() {
+        let mut id = 0;
+        // Each of these characters is two columns wide, so the call is 15
+        // columns wide, though it is only 11 characters long
+        let atoms = scoped_call(
+            "foo",
+            vec![
+                vec![Atom::Literal("日本".into())],
+                vec![Atom::Literal("中文".into())],
+            ],
+            &mut id,
+        );
+
+        assert_eq!(
+            render_with_width(atoms.clone(), Some(15)),
+            "foo(日本, 中文)"
+        );
+        assert_eq!(render_with_width(atoms, Some(14)), "foo(\n日本,\n中文\n)");
+    }
+
+    #[test]
+    fn max_line_width_ignores_combining_marks() {
+        let mut id = 0;
+        // "e" followed by a combining acute accent is one column wide
+        let atoms = scoped_call(
+            "foo",
+            vec![
+                vec![Atom::Literal("e\u{301}e\u{301}".into())],
+                vec![Atom::Literal("bb".into())],
+            ],
+            &mut id,
+        );
+
+        assert_eq!(
+            render_with_width(atoms, Some(11)),
+            "foo(e\u{301}e\u{301}, bb)"
+        );
+    }
+
+    #[test]
+    fn max_line_width_measures_outer_scopes_first() {
+        let mut id = 0;
+        let g = scoped_call(
+            "g",
+            vec![
+                vec![Atom::Literal("aa".into())],
+                vec![Atom::Literal("bb".into())],
+            ],
+            &mut id,
+        );
+        let h = scoped_call(
+            "h",
+            vec![
+                vec![Atom::Literal("cc".into())],
+                vec![Atom::Literal("dd".into())],
+            ],
+            &mut id,
+        );
+        let atoms = scoped_call("f", vec![g, h], &mut id);
+
+        assert_eq!(
+            render_with_width(atoms, Some(15)),
+            "f(\ng(aa, bb),\nh(cc, dd)\n)"
+        );
+    }
+
+    /// Renders a multi-line list, whose last element is a call marked by
+    /// `@single_line_scope_no_indent`, ending on the given line
+    fn render_list_with_unindented_call(call_end_line: u32) -> String {
+        let mut id = 0;
+        let mut call = scoped_call(
+            "f",
+            vec![
+                vec![Atom::Literal("a".into())],
+                vec![Atom::Literal("b".into())],
+            ],
+            &mut id,
+        );
+        let mut conditional = |atom| {
+            id += 1;
+            Atom::ScopedConditional {
+                id,
+                scope_id: "f".into(),
+                condition: ScopeCondition::SingleLineOnly,
+                atom: Box::new(atom),
+            }
+        };
+        call.insert(1, conditional(Atom::IndentSuspend));
+        call.insert(call.len() - 1, conditional(Atom::IndentResume));
+        if let Some(Atom::ScopeEnd(scope)) = call.last_mut() {
+            scope.line_number = call_end_line;
+        }
+
+        let list = |line_number| ScopeInformation {
+            line_number,
+            scope_id: "list".into(),
+            node_id: 0,
+        };
+        let mut softline = |spaced| {
+            id += 1;
+            Atom::ScopedSoftline {
+                id,
+                scope_id: "list".into(),
+                spaced,
+            }
+        };
+
+        let mut atoms = vec![
+            Atom::ScopeBegin(list(0)),
+            Atom::Literal("[".into()),
+            Atom::IndentStart,
+            softline(false),
+            Atom::Literal("x,".into()),
+            softline(true),
+        ];
+        atoms.extend(call);
+        atoms.extend([
+            Atom::IndentEnd,
+            softline(false),
+            Atom::Literal("]".into()),
+            Atom::ScopeEnd(list(3)),
+        ]);
+
+        render_post_processed(atoms)
+    }
+
+    #[test]
+    fn single_line_scope_no_indent_only_unindents_single_line_scopes() {
+        // A single-line call is not indented, even within a multi-line list
+        assert_eq!(render_list_with_unindented_call(0), "[\n  x,\nf(a, b)\n]");
+        // A multi-line call keeps its indentation
+        assert_eq!(
+            render_list_with_unindented_call(2),
+            "[\n  x,\n  f(\n  a,\n  b\n  )\n]"
+        );
+    }
+
+    fn render_post_processed(atoms: Vec<Atom>) -> String {
+        let mut atom_collection = AtomCollection::new(atoms);
+        atom_collection.post_process();
+
+        render(&atom_collection[..], "  ", 8).unwrap()
+    }
+
+    /// The atoms of a node deleted by `@delete_with_surrounding_space`
+    fn deleted_with_surrounding_space(literal: &str) -> [Atom; 5] {
+        [
+            Atom::DeleteBoundary,
+            Atom::DeleteBegin,
+            Atom::Literal(literal.into()),
+            Atom::DeleteEnd,
+            Atom::DeleteBoundary,
+        ]
+    }
+
+    #[test]
+    fn delete_last_list_element_with_its_separator() {
+        // [a, b] with both the last "," and "b" deleted
+        let mut atoms = vec![Atom::Literal("[".into()), Atom::Literal("a".into())];
+        atoms.extend(deleted_with_surrounding_space(","));
+        atoms.push(Atom::Space);
+        atoms.extend(deleted_with_surrounding_space("b"));
+        atoms.push(Atom::Literal("]".into()));
+
+        assert_eq!(render_post_processed(atoms), "[a]");
+    }
+
+    #[test]
+    fn delete_last_list_element_removes_dangling_space() {
+        // [a, b] with "b" deleted
+        let prefix = vec![
+            Atom::Literal("[".into()),
+            Atom::Literal("a".into()),
+            Atom::Literal(",".into()),
+            Atom::Space,
+        ];
+
+        let mut plain = prefix.clone();
+        plain.extend([
+            Atom::DeleteBegin,
+            Atom::Literal("b".into()),
+            Atom::DeleteEnd,
+            Atom::Literal("]".into()),
+        ]);
+        assert_eq!(render_post_processed(plain), "[a, ]");
+
+        let mut collapsed = prefix;
+        collapsed.extend(deleted_with_surrounding_space("b"));
+        collapsed.push(Atom::Literal("]".into()));
+        assert_eq!(render_post_processed(collapsed), "[a,]");
+    }
+
+    #[test]
+    fn delete_between_spaced_nodes_keeps_one_space() {
+        let mut atoms = vec![Atom::Literal("a".into()), Atom::Space];
+        atoms.extend(deleted_with_surrounding_space("x"));
+        atoms.extend([Atom::Space, Atom::Literal("b".into())]);
+
+        assert_eq!(render_post_processed(atoms), "a b");
+    }
+
+    #[test]
+    fn space_if_followed_by_optional_node() {
+        // let x: t, with the optional type annotation present or absent
+        let binding = |annotation: &[&str]| {
+            let mut atoms = vec![
+                Atom::Literal("let".into()),
+                Atom::Space,
+                Atom::Literal("x".into()),
+                Atom::SpaceIfFollowed,
+            ];
+            atoms.extend(annotation.iter().map(|s| Atom::Literal((*s).into())));
+            atoms.extend([Atom::Hardline, Atom::Literal("next".into())]);
+            atoms
+        };
+
+        assert_eq!(render_post_processed(binding(&[": t"])), "let x : t\nnext");
+        assert_eq!(render_post_processed(binding(&[])), "let x\nnext");
+        // An optional node that is present, but empty
+        assert_eq!(render_post_processed(binding(&[""])), "let x\nnext");
+    }
+
+    #[test]
+    fn space_if_followed_by_deleted_node() {
+        let atoms = vec![
+            Atom::Literal("x".into()),
+            Atom::SpaceIfFollowed,
+            Atom::DeleteBegin,
+            Atom::Literal("y".into()),
+            Atom::DeleteEnd,
+        ];
+
+        assert_eq!(render_post_processed(atoms), "x");
+    }
+
+    #[test]
+    fn antispace_removes_space_on_either_side() {
+        let around = |atoms: &[Atom]| {
+            let mut around = vec![Atom::Literal("a".into())];
+            around.extend_from_slice(atoms);
+            around.push(Atom::Literal("b".into()));
+            render_post_processed(around)
+        };
+
+        // @append_space on the left node, @prepend_antispace on the right one
+        assert_eq!(around(&[Atom::Space, Atom::Antispace]), "ab");
+        // @append_antispace on the left node, @prepend_space on the right one
+        assert_eq!(around(&[Atom::Antispace, Atom::Space]), "ab");
+        // Indentation does not get in the way, on either side
+        assert_eq!(
+            around(&[Atom::Space, Atom::IndentStart, Atom::Antispace]),
+            "ab"
+        );
+        assert_eq!(
+            around(&[Atom::Antispace, Atom::IndentStart, Atom::SpaceUnlessBol]),
+            "ab"
+        );
+        // But anything that produces output does
+        assert_eq!(
+            around(&[Atom::Space, Atom::Literal(",".into()), Atom::Antispace]),
+            "a ,b"
+        );
+    }
+
+    #[test]
+    fn meeting_blank_lines_keep_the_most_numerous() {
+        let atoms = vec![
+            Atom::Literal("a".into()),
+            Atom::Blankline(1),
+            Atom::Hardline,
+            Atom::Blankline(3),
+            Atom::Literal("b".into()),
+        ];
+        let mut atom_collection = AtomCollection::new(atoms);
+        atom_collection.limit_blank_lines(5);
+        atom_collection.post_process();
+
+        assert_eq!(render(&atom_collection[..], "  ", 8).unwrap(), "a\n\n\n\nb");
+    }
 }
@@ -0,0 +1,108 @@
+//! Formatting with the queries bundled with Topiary, for embedders that do not
+//! need to manage their own.
+
+use rootcause::{prelude::ResultExt, report};
+use topiary_config::{Configuration, error::TopiaryConfigError};
+
+use crate::{
+    FinalNewline, FormatterError, FormatterResult, FormattingMarkers, InjectionQuery, Language,
+    LineEnding, Operation, ParsingErrorTolerance, TopiaryQuery, formatter_str,
+};
+
+/// Formats `input` as the named language, using its bundled query and the
+/// grammar that the default Topiary configuration specifies for it (which is
+/// fetched and built, if needed). The input must parse without errors and its
+/// formatting must be idempotent; for any other settings, build a [`Language`]
+/// and use [`formatter`](crate::formatter) instead.
+///
+/// Only the languages whose features are enabled are available. Language
+/// injections are not formatted.
+///
+/// # Errors
+///
+/// If the language is not available, a `FormatterError::UnknownLanguage` will
+/// be returned. If its grammar cannot be loaded, a
+/// `FormatterError::GrammarLoad` will be returned. If formatting fails for any
+/// other reason, a `FormatterError` will be returned.
+///
+/// # Examples
+///
+/// ```
+/// use topiary_core::{FormatterError, format_str};
+///
+/// let formatted = format_str("[1,2]", "json").unwrap();
+/// assert_eq!(formatted, "[ 1, 2 ]\n");
+///
+/// let unknown = format_str("[1,2]", "jason").unwrap_err();
+/// assert_eq!(
+///     unknown.current_context(),
+///     &FormatterError::UnknownLanguage("jason".to_owned())
+/// );
+/// ```
+pub fn format_str(input: &str, language: &str) -> FormatterResult<String> {
+    let unknown = || report!(FormatterError::UnknownLanguage(language.to_owned()));
+
+    let formatting_query = topiary_queries::formatting(language).ok_or_else(unknown)?;
+    let config = Configuration::default();
+    let config_language = config.get_language(language).map_err(|e| match e {
+        TopiaryConfigError::UnknownLanguage(_) => unknown(),
+        e => report!(FormatterError::Internal(e.to_string())),
+    })?;
+    let grammar = config_language
+        .grammar()
+        .map_err(|e| report!(FormatterError::GrammarLoad(e.to_string())).attach(e.to_string()))?;
+
+    let formatting_query =
+        TopiaryQuery::with_node_aliases(&grammar, formatting_query, config_language.node_aliases())
+            .context(FormatterError::Query(format!(
+                "Could not parse the bundled query of {language}"
+            )))?;
+    let language = Language {
+        name: language.to_owned(),
+        formatting_query,
+        injection_query: topiary_queries::injections(language)
+            .map(|query| InjectionQuery::new(&grammar, query))
+            .transpose()?,
+        grammar,
+        indent: config_language.indent(),
+        max_blank_lines: config_language.max_blank_lines(),
+        tab_width: config_language.tab_width(),
+        formatting_markers: config_language
+            .formatting_markers()
+            .map(FormattingMarkers::from),
+        comment_attachment: config_language.into(),
+    };
+
+    let mut output = Vec::new();
+    formatter_str(
+        input,
+        &mut output,
+        &language,
+        Operation::Format {
+            skip_idempotence: false,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
+        },
+        None,
+    )?;
+
+    String::from_utf8(output).context_to()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FormatterError, format_str};
+
+    #[test]
+    fn bogus_language_is_unknown() {
+        let err = format_str("{}", "bogus").unwrap_err();
+        assert_eq!(
+            err.current_context(),
+            &FormatterError::UnknownLanguage("bogus".to_owned())
+        );
+        assert_eq!(err.current_context().kind(), "unknown_language");
+    }
+}
@@ -1,22 +1,26 @@
 //! This module defines all errors that might be propagated out of the library,
 //! including all of the trait implementations one might expect for Errors.
 
-use std::{error::Error, fmt, io};
+use std::{error::Error, fmt, io, ops::Range as ByteRange};
 
 use rootcause::{Report, ReportConversion, markers, report};
+use serde::{Deserialize, Serialize};
 
 pub use error_span::{ErrorSpan, SpanAttachment};
-use topiary_tree_sitter_facade::QueryError;
+use topiary_tree_sitter_facade::{QueryError, Range};
+
+use crate::tree_sitter::Position;
 
 mod error_span;
 
 /// The various errors the formatter may return.
 #[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum FormatterError {
     /// The input produced output that isn't idempotent, i.e. formatting the
     /// output again made further changes. If this happened using our provided
     /// query files, it is a bug. Please log an issue.
-    Idempotence,
+    Idempotence(Box<IdempotenceDetails>),
 
     /// The input produced invalid output, i.e. formatting the output again led
     /// to a parsing error. If this happened using our provided query files, it
@@ -27,12 +31,11 @@ pub enum FormatterError {
     Internal(String),
 
     /// An injected language could not be resolved.
-    InjectionLanguageResolution {
-        language: String,
-    },
+    InjectionLanguageResolution { language: String },
 
-    // Tree-sitter could not parse the input without errors.
-    Parsing,
+    /// Tree-sitter could not parse the input without errors. The range is that
+    /// of the first ERROR node in the input, if Tree-sitter produced a tree.
+    Parsing(Option<DiagnosticRange>),
 
     /// The query contains a pattern that had no match in the input file.
     PatternDoesNotMatch,
@@ -41,6 +44,12 @@ pub enum FormatterError {
     /// provided query files, it is a bug. Please log an issue.
     Query(String),
 
+    /// The language has no bundled query and grammar.
+    UnknownLanguage(String),
+
+    /// The grammar of the language could not be loaded.
+    GrammarLoad(String),
+
     /// I/O-related errors
     Io,
 }
@@ -49,10 +58,10 @@ impl fmt::Display for FormatterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let please_log_message = "If this happened with the built-in query files, it is a bug. It would be\nhelpful if you logged this error at\nhttps://github.com/topiary/topiary/issues/new?assignees=&labels=type%3A+bug&template=bug_report.md";
         match self {
-            Self::Idempotence => {
+            Self::Idempotence(details) => {
                 write!(
                     f,
-                    "The formatter did not produce the same\nresult when invoked twice (idempotence check).\n\n{please_log_message}"
+                    "The formatter did not produce the same\nresult when invoked twice (idempotence check).\n\n{details}\n\n{please_log_message}"
                 )
             }
 
@@ -63,7 +72,16 @@ impl fmt::Display for FormatterError {
                 )
             }
 
-            Self::Parsing => {
+            Self::Parsing(Some(range)) => {
+                write!(
+                    f,
+                    "Tree-sitter could not parse the input without errors, starting at line {}, column {}.",
+                    range.start_line + 1,
+                    range.start_col + 1
+                )
+            }
+
+            Self::Parsing(None) => {
                 write!(f, "Tree-sitter could not parse the input without errors.")
             }
 
@@ -73,7 +91,9 @@ impl fmt::Display for FormatterError {
                     "The query contains a pattern that does not match the input"
                 )
             }
-            Self::Io => {
+            // A grammar that cannot be loaded is reported as it was before it had a variant of
+            // its own, with the cause attached
+            Self::Io | Self::GrammarLoad(_) => {
                 write!(f, "I/O Error")
             }
             Self::Internal(message) | Self::Query(message) => {
@@ -83,18 +103,249 @@ impl fmt::Display for FormatterError {
             Self::InjectionLanguageResolution { language, .. } => {
                 write!(f, "Could not resolve injected language \"{language}\"")
             }
+
+            Self::UnknownLanguage(language) => {
+                write!(f, "Topiary does not bundle the language \"{language}\"")
+            }
         }
     }
 }
 
 impl Error for FormatterError {}
 
+impl FormatterError {
+    /// A short, stable identifier for the error variant, as used in
+    /// [`Diagnostic::kind`].
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Idempotence(_) => "idempotence",
+            Self::IdempotenceParsing => "idempotence_parsing",
+            Self::Internal(_) => "internal",
+            Self::InjectionLanguageResolution { .. } => "injection_language_resolution",
+            Self::Parsing(_) => "parsing",
+            Self::PatternDoesNotMatch => "pattern_does_not_match",
+            Self::Query(_) => "query",
+            Self::UnknownLanguage(_) => "unknown_language",
+            Self::GrammarLoad(_) => "grammar_load",
+            Self::Io => "io",
+        }
+    }
+}
+
+/// Where the output of the first and second formatting passes diverge, as
+/// reported by [`FormatterError::Idempotence`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct IdempotenceDetails {
+    /// The smallest byte range of the first pass's output outside of which
+    /// both passes agree. It is empty if the second pass only inserted text.
+    pub range: ByteRange<usize>,
+    /// The one-based line of the first pass's output on which the first
+    /// difference occurs
+    pub line: usize,
+    /// That line, as produced by the first pass, truncated if too long
+    pub before: String,
+    /// The same line, as produced by the second pass, truncated if too long
+    pub after: String,
+}
+
+impl IdempotenceDetails {
+    /// The maximum number of characters of each snippet
+    const MAX_SNIPPET_LENGTH: usize = 80;
+
+    /// Compares the output of two formatting passes. This only scans for the
+    /// longest common prefix and suffix, rather than computing a full diff,
+    /// so it is linear in the size of the input.
+    pub(crate) fn new(first: &str, second: &str) -> Self {
+        let (first_bytes, second_bytes) = (first.as_bytes(), second.as_bytes());
+
+        let mut start = first_bytes
+            .iter()
+            .zip(second_bytes)
+            .take_while(|(a, b)| a == b)
+            .count();
+        // The common prefix is identical in both, so a character boundary in
+        // one is a boundary in the other
+        while !first.is_char_boundary(start) {
+            start -= 1;
+        }
+
+        let suffix = first_bytes[start..]
+            .iter()
+            .rev()
+            .zip(second_bytes[start..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut end = first.len() - suffix;
+        while !first.is_char_boundary(end) {
+            end += 1;
+        }
+
+        let line_start = first[..start].rfind('\n').map_or(0, |i| i + 1);
+        let snippet = |text: &str| -> String {
+            let line = text[line_start..].lines().next().unwrap_or_default();
+            if line.chars().count() > Self::MAX_SNIPPET_LENGTH {
+                let truncated: String = line.chars().take(Self::MAX_SNIPPET_LENGTH).collect();
+                format!("{truncated}…")
+            } else {
+                line.to_string()
+            }
+        };
+
+        Self {
+            range: start..end,
+            line: first[..start].matches('\n').count() + 1,
+            before: snippet(first),
+            after: snippet(second),
+        }
+    }
+}
+
+impl fmt::Display for IdempotenceDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The first difference is on line {} of the formatted output:\n  first pass:  {:?}\n  second pass: {:?}",
+            self.line, self.before, self.after
+        )
+    }
+}
+
+/// A non-fatal issue found while formatting. Unlike a [`FormatterError`], it
+/// does not stop formatting, but callers may want to surface it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// A pattern of the formatting query had no match in the input. This is
+    /// a [`FormatterError::PatternDoesNotMatch`] when formatting strictly.
+    PatternDoesNotMatch {
+        /// The position of the pattern in the query file
+        position: Position,
+        /// The name given to the pattern by `#query_name!`, if any
+        query_name: Option<String>,
+    },
+
+    /// A capture of the formatting query is neither one that Topiary
+    /// recognises, a private capture, nor an alias. It may be a helper capture
+    /// for the query's own predicates, or a typo. This is a
+    /// [`FormatterError::Query`] when formatting strictly.
+    UnknownCapture {
+        /// The capture name, without its `@`
+        name: String,
+        /// The position of the capture in the query file
+        position: Position,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::PatternDoesNotMatch {
+                position,
+                query_name: Some(name),
+            } => write!(
+                f,
+                "The query pattern \"{name}\" at {position} does not match the input"
+            ),
+            Self::PatternDoesNotMatch {
+                position,
+                query_name: None,
+            } => write!(
+                f,
+                "The query pattern at {position} does not match the input"
+            ),
+            Self::UnknownCapture { name, position } => write!(
+                f,
+                "@{name} at {position} is not a capture name that Topiary recognises"
+            ),
+        }
+    }
+}
+
+/// A machine-readable rendering of a [`FormatterError`] report, for editor
+/// integrations that want structured data rather than prose.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Diagnostic {
+    /// See [`FormatterError::kind`]
+    pub kind: String,
+    /// The [`Display`](fmt::Display) output of the error
+    pub message: String,
+    /// The location of the error, if the report carries an [`ErrorSpan`] with
+    /// a range: the offending node for parsing errors, or the offending line
+    /// of the query file for query errors
+    pub range: Option<DiagnosticRange>,
+    /// The byte offset of the error in the query file, for query errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+    pub severity: Severity,
+}
+
+/// A range of lines and columns. Both are zero-based, as in Tree-sitter and
+/// the Language Server Protocol.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DiagnosticRange {
+    pub start_line: u32,
+    pub start_col: u32,
+    pub end_line: u32,
+    pub end_col: u32,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl From<Range> for DiagnosticRange {
+    fn from(range: Range) -> Self {
+        let start = range.start_point();
+        let end = range.end_point();
+        Self {
+            start_line: start.row(),
+            start_col: start.column(),
+            end_line: end.row(),
+            end_col: end.column(),
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Builds a diagnostic from a report. The range is taken from the first
+    /// [`ErrorSpan`] found in the report tree, so spans attached to a causing
+    /// report (such as a Tree-sitter [`QueryError`]) are picked up too.
+    pub fn from_report(report: &Report<FormatterError>) -> Self {
+        let error = report.current_context();
+        let range = report
+            .iter_reports()
+            .flat_map(|r| r.attachments().iter())
+            .filter_map(|a| a.downcast_inner::<ErrorSpan>())
+            .find_map(|span| span.range);
+
+        Self {
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+            range: range.map(DiagnosticRange::from),
+            offset: range
+                .filter(|_| matches!(error, FormatterError::Query(_)))
+                .map(|range| range.start_byte()),
+            severity: Severity::Error,
+        }
+    }
+
+    /// Serialises the diagnostic as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        // A struct of strings and integers cannot fail to serialise
+        serde_json::to_string(self).expect("diagnostic serialisation")
+    }
+}
+
 // private convenience macro to do [`rootcause::ReportConversion`]
 // https://docs.rs/rootcause/latest/rootcause/trait.ReportConversion.html
 macro_rules! report_conversion {
-    ($($from:ty)|+, $err:ident::$variant:ident, $msg:literal) => {
+    ($($from:ty)|+, $error:expr, $msg:literal) => {
         $(
-            impl<T> ReportConversion<$from, markers::Mutable, T> for $err
+            impl<T> ReportConversion<$from, markers::Mutable, T> for FormatterError
             where
                 Self: markers::ObjectMarkerFor<T>,
                 &'static str: markers::ObjectMarkerFor<T>,
@@ -102,7 +353,7 @@ macro_rules! report_conversion {
                 fn convert_report(
                     report: Report<$from, markers::Mutable, T>,
                 ) -> Report<Self, markers::Mutable, T> {
-                    let report = report.context($err::$variant);
+                    let report = report.context($error);
                     let report = report.attach($msg);
                     report
 
@@ -132,13 +383,13 @@ report_conversion!(
 
 report_conversion!(
     topiary_tree_sitter_facade::LanguageError,
-    FormatterError::Parsing,
+    FormatterError::Parsing(None),
     "Error while loading language grammar"
 );
 
 report_conversion!(
     topiary_tree_sitter_facade::ParserError,
-    FormatterError::Parsing,
+    FormatterError::Parsing(None),
     "Error while parsing"
 );
 
@@ -180,9 +431,27 @@ where
 
 pub(crate) fn query_error_report(err: QueryError) -> Report<QueryError> {
     let range = err.range;
-    let label = format!("{err}");
+    let label = query_error_label(&err);
     report!(err)
         .attach_range(range)
         .attach_label(label)
         .attach_language("tree_sitter_query".into())
 }
+
+/// Describes a query error by its kind and position, followed by what is at
+/// fault, if anything more specific than the position: for example, "Invalid
+/// node type at line 2, column 4: strnig". Positions count from 1.
+fn query_error_label(err: &QueryError) -> String {
+    let start = err.range.start_point();
+    let position = format!(
+        "{} at line {}, column {}",
+        err.kind(),
+        start.row() + 1,
+        start.column() + 1
+    );
+
+    match err.detail() {
+        Some(detail) => format!("{position}: {detail}"),
+        None => position,
+    }
+}
@@ -1,8 +1,10 @@
 //! GraphViz visualisation for our SyntaxTree representation.
 //! Named syntax nodes are elliptical; anonymous are rectangular.
-use std::{borrow::Cow, fmt, io};
+//! Edges to children that appear under a field are labelled with its name.
+//! The boundaries of scopes at a node, if annotated, are listed in its label.
+use std::{borrow::Cow, io};
 
-use crate::tree_sitter::SyntaxNode;
+use crate::tree_sitter::{ScopeBoundary, SyntaxNode};
 
 /// Doubly escapes whitespace (\n and \t) so it is
 /// rendered as the escaped value in the GraphViz output
@@ -62,30 +64,58 @@ fn escape(input: &str) -> Cow<'_, str> {
     }
 }
 
-impl fmt::Display for SyntaxNode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let shape = if self.is_named { "ellipse" } else { "box" };
+/// Writes the node and its descendants as DOT statements. Nodes are numbered
+/// in pre-order, from `next_id`, so that the output is deterministic. Edges
+/// are labelled with the children's field names.
+fn write_node(
+    output: &mut dyn io::Write,
+    node: &SyntaxNode,
+    next_id: &mut usize,
+) -> io::Result<()> {
+    let id = *next_id;
+    *next_id += 1;
+
+    // The boundaries of scopes, if any, are listed under the node's kind
+    let mut label = escape(&node.kind).into_owned();
+    for scope in &node.scopes {
+        let boundary = match scope.boundary {
+            ScopeBoundary::Begin => "begins",
+            ScopeBoundary::End => "ends",
+        };
+        let mode = if scope.multi_line {
+            "multi-line"
+        } else {
+            "single-line"
+        };
+        label += &format!("\\n{boundary} scope {} ({mode})", escape(&scope.scope_id));
+    }
+
+    let shape = if node.is_named { "ellipse" } else { "box" };
+    writeln!(output, "  {id} [label=\"{label}\", shape={shape}];")?;
 
-        writeln!(
-            f,
-            "  {} [label=\"{}\", shape={shape}];",
-            self.id,
-            escape(&self.kind)
-        )?;
+    for child in &node.children {
+        // The child will be numbered next
+        let child_id = *next_id;
 
-        for child in &self.children {
-            writeln!(f, "  {} -- {};", self.id, child.id)?;
-            write!(f, "{child}")?;
+        match &child.field_name {
+            Some(field_name) => writeln!(
+                output,
+                "  {id} -- {child_id} [label=\"{}\"];",
+                escape(field_name)
+            )?,
+            None => writeln!(output, "  {id} -- {child_id};")?,
         }
 
-        Ok(())
+        write_node(output, child, next_id)?;
     }
+
+    Ok(())
 }
 
 /// Writes the Graphviz Graph in the dot format to the specified output buffer.
 pub fn write(output: &mut dyn io::Write, root: &SyntaxNode) -> std::io::Result<()> {
     writeln!(output, "graph {{")?;
-    write!(output, "{root}")?;
+    write_node(output, root, &mut 0)?;
     writeln!(output, "}}")?;
 
     Ok(())
@@ -2,6 +2,10 @@ use std::fmt;
 
 use crate::{InjectionQuery, TopiaryQuery};
 
+/// The width, in columns, of a tab in the input, if a [`Language`] does not
+/// set its `tab_width`
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
 /// A Language contains all the information Topiary requires to format that
 /// specific languages.
 #[derive(Debug)]
@@ -18,10 +22,96 @@ pub struct Language {
     pub injection_query: Option<InjectionQuery>,
     /// The tree-sitter Language. Topiary will use this Language for parsing.
     pub grammar: topiary_tree_sitter_facade::Language,
-    /// The indentation string used for that particular language. Defaults to "  "
-    /// if not provided. Any string can be provided, but in most instances will be
-    /// some whitespace: "  ", "    ", or "\t".
+    /// The indentation string used for that particular language. Defaults to
+    /// that which the formatting query declares with `#indent_string!`, or "  "
+    /// if neither is provided. Any string can be provided, but in most instances
+    /// will be some whitespace: "  ", "    ", or "\t".
     pub indent: Option<String>,
+    /// The maximum number of consecutive blank lines kept from the input, by
+    /// `@allow_blank_line_before` and the like. Defaults to 1 if not provided;
+    /// 0 removes all blank lines.
+    pub max_blank_lines: Option<usize>,
+    /// The markers that, when found in comments, disable and re-enable
+    /// formatting. If not provided, formatting cannot be disabled.
+    pub formatting_markers: Option<FormattingMarkers>,
+    /// The node to which a comment that follows another on the same line is
+    /// attached, when the query would otherwise move it onto a line of its
+    /// own. Defaults to the following node, which leaves the query in charge.
+    pub comment_attachment: CommentAttachment,
+    /// The width, in columns, of a tab in the input. This is used to measure
+    /// the original indentation of multi-line leaves, such as those marked by
+    /// `@multi_line_indent_all`, and is unrelated to the `indent` of the
+    /// output. Defaults to 8 if not provided.
+    pub tab_width: Option<usize>,
+}
+
+/// The pair of markers that disable and re-enable formatting. The source
+/// between a comment containing the `off` marker and the next comment
+/// containing the `on` marker is passed through verbatim.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FormattingMarkers {
+    /// The marker of the comment after which formatting is disabled
+    pub off: String,
+    /// The marker of the comment before which formatting is re-enabled
+    pub on: String,
+}
+
+/// The node to which a trailing comment -- one that starts on the line on
+/// which the preceding node ends -- is attached.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CommentAttachment {
+    /// The comment is attached to the following node: it is placed wherever
+    /// the query puts it, which may be on a line of its own.
+    #[default]
+    Following,
+    /// The comment is attached to the preceding node: any line break that the
+    /// query puts between that node and the comment is moved after the
+    /// comment, so that it stays on the same line (e.g., `foo, // note`).
+    Preceding,
+}
+
+#[cfg(feature = "config")]
+impl From<&topiary_config::language::FormattingMarkers> for FormattingMarkers {
+    fn from(markers: &topiary_config::language::FormattingMarkers) -> Self {
+        Self {
+            off: markers.off.clone(),
+            on: markers.on.clone(),
+        }
+    }
+}
+
+/// The comment attachment of a configured language, which defaults to
+/// [`CommentAttachment::Following`]
+#[cfg(feature = "config")]
+impl From<&topiary_config::language::Language> for CommentAttachment {
+    fn from(language: &topiary_config::language::Language) -> Self {
+        match language.comment_attachment() {
+            Some(topiary_config::language::CommentAttachment::Preceding) => Self::Preceding,
+            Some(topiary_config::language::CommentAttachment::Following) | None => Self::Following,
+        }
+    }
+}
+
+impl Language {
+    /// The indentation string for this language, defaulting to that which its
+    /// formatting query declares, and then to `"  "`.
+    pub(crate) fn indent(&self) -> &str {
+        self.indent
+            .as_deref()
+            .or(self.formatting_query.indent.as_deref())
+            .unwrap_or("  ")
+    }
+
+    /// The maximum number of consecutive blank lines for this language,
+    /// defaulting to 1.
+    pub(crate) fn max_blank_lines(&self) -> usize {
+        self.max_blank_lines.unwrap_or(1)
+    }
+
+    /// The width of a tab in the input for this language, defaulting to 8.
+    pub(crate) fn tab_width(&self) -> usize {
+        self.tab_width.unwrap_or(DEFAULT_TAB_WIDTH)
+    }
 }
 
 impl fmt::Display for Language {
@@ -10,22 +10,38 @@
 //! More details can be found on
 //! [GitHub](https://github.com/topiary/topiary).
 
-use std::{io, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io, iter,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use pretty_assertions::StrComparison;
 use rootcause::{prelude::ResultExt, report};
-use tree_sitter::Position;
 
+#[cfg(all(feature = "bundled", not(target_arch = "wasm32")))]
+pub use crate::bundled::format_str;
 pub use crate::{
-    error::{ErrorSpan, FormatterError, SpanAttachment},
-    language::Language,
+    error::{
+        Diagnostic, DiagnosticRange, ErrorSpan, FormatterError, IdempotenceDetails, Severity,
+        SpanAttachment, Warning,
+    },
+    language::{CommentAttachment, DEFAULT_TAB_WIDTH, FormattingMarkers, Language},
     tree_sitter::{
-        CoverageData, InjectionQuery, InjectionSpan, SyntaxNode, TopiaryQuery, Visualisation,
-        apply_query, check_query_coverage, collect_injections, parse,
+        CoverageData, CoverageReport, InjectionQuery, InjectionSpan, PatternCoverage, Position,
+        PredicateArgument, PredicateSpec, QueryDiagnostic, QueryDiagnostics, ScopeAnnotation,
+        ScopeBoundary, SyntaxNode, TextEdit, TopiaryQuery, Visualisation, apply_query,
+        check_query_coverage, collect_injections, parse, query_coverage, supported_captures,
+        supported_predicates, validate_query,
     },
 };
 
 mod atom_collection;
+#[cfg(all(feature = "bundled", not(target_arch = "wasm32")))]
+mod bundled;
 mod error;
 mod graphviz;
 mod language;
@@ -39,6 +55,8 @@ pub mod test_utils;
 pub struct ScopeInformation {
     line_number: u32,
     scope_id: String,
+    /// The id of the node that was captured to begin or end the scope
+    node_id: usize,
 }
 
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
@@ -52,10 +70,13 @@ pub enum Capitalisation {
 /// into atoms, and we add white-space atoms where appropriate. The final list
 /// of atoms is rendered to the output.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum Atom {
-    /// We don't allow consecutive `Hardline`, but a `Blankline` will render two
-    /// newlines to produce a blank line.
-    Blankline,
+    /// Represents the given number of blank lines; that is, one more newline
+    /// than that. We don't allow consecutive `Hardline`, and where blank lines
+    /// meet, only the most numerous are kept. Their number is capped during
+    /// post-processing, to the language's `max_blank_lines`.
+    Blankline(usize),
     /// A "no-op" atom that will not produce any output.
     #[default]
     Empty,
@@ -63,21 +84,37 @@ pub enum Atom {
     Hardline,
     /// Signals the end of an indentation block.
     IndentEnd,
+    /// Signals the end of a region started by `IndentSuspend`.
+    IndentResume,
     /// Signals the start of an indentation block. Any lines between the
     /// beginning and the end will be indented. In single-line constructs where
     /// the beginning and the end occurs on the same line, there will be no
     /// indentation.
     IndentStart,
+    /// Signals the start of an indentation block, like `IndentStart`, whose
+    /// lines are indented by the given number of the language's indent
+    /// strings, rather than by one.
+    IndentStartWidth(usize),
+    /// Signals the start of a region whose lines are not indented by the
+    /// indentation blocks that enclose it. Indentation blocks opened within the
+    /// region still apply, relative to the start of the line.
+    IndentSuspend,
     /// Represents the contents of a named Tree-sitter node. We track the node id here
     /// as well.
     Leaf {
         content: String,
         id: usize,
         original_position: Position,
+        // the column, counted from 0, at which the leaf starts in the input,
+        // with tabs expanded
+        original_column: u32,
         // marks the leaf to be printed on a single line, with no indentation
         single_line_no_indent: bool,
         // if the leaf is multi-line, each line will be indented, not just the first
         multi_line_indent_all: bool,
+        // the width of the indentation of the input line on which the leaf
+        // starts, with tabs expanded
+        original_line_indent: u32,
         // don't trim trailing newline characters if set to true
         keep_whitespace: bool,
         capitalisation: Capitalisation,
@@ -91,6 +128,15 @@ pub enum Atom {
     },
     /// Represents a space. Consecutive spaces are reduced to one before rendering.
     Space,
+    /// Represents a space that is only rendered if it does not fall at the
+    /// beginning of a line; that is, if anything other than indentation
+    /// precedes it on the current output line. The decision is deferred to
+    /// rendering, once all softlines have been resolved.
+    SpaceUnlessBol,
+    /// Represents a space that is only rendered if a non-empty leaf or literal
+    /// follows it before the next line break. It is resolved into a `Space` or
+    /// nothing during post-processing, after deletions have been applied.
+    SpaceIfFollowed,
     /// Represents the destruction of errant spaces. Adjacent consecutive spaces are
     /// reduced to zero before rendering.
     Antispace,
@@ -99,6 +145,10 @@ pub enum Atom {
     // it might happen that it contains several leaves.
     DeleteBegin,
     DeleteEnd,
+    /// Marks the boundary of a segment deleted by
+    /// `@delete_with_surrounding_space`: after deletion, the horizontal
+    /// whitespace around the segment is collapsed.
+    DeleteBoundary,
 
     CaseBegin(Capitalisation),
     CaseEnd,
@@ -142,13 +192,20 @@ pub enum Atom {
 
 impl Atom {
     /// This function is only expected to take spaces and newlines as argument.
-    /// It defines the order Blankline > Hardline > Space > Empty.
+    /// It defines the order Blankline > Hardline > Space > SpaceUnlessBol > Empty.
     pub(crate) fn dominates(&self, other: &Atom) -> bool {
         match self {
             Atom::Empty => false,
-            Atom::Space => matches!(other, Atom::Empty),
-            Atom::Hardline => matches!(other, Atom::Space | Atom::Empty),
-            Atom::Blankline => matches!(other, Atom::Hardline | Atom::Space | Atom::Empty),
+            Atom::SpaceUnlessBol => matches!(other, Atom::Empty),
+            Atom::Space => matches!(other, Atom::SpaceUnlessBol | Atom::Empty),
+            Atom::Hardline => matches!(other, Atom::Space | Atom::SpaceUnlessBol | Atom::Empty),
+            Atom::Blankline(count) => match other {
+                Atom::Blankline(other_count) => count > other_count,
+                other => matches!(
+                    other,
+                    Atom::Hardline | Atom::Space | Atom::SpaceUnlessBol | Atom::Empty
+                ),
+            },
             _ => panic!("Unexpected character in is_dominant"),
         }
     }
@@ -183,6 +240,98 @@ pub type FormatterResult<T, E = FormatterError> = Result<T, rootcause::Report<E>
 /// operations such as visualisation.
 pub type LanguageResolver<'a> = dyn Fn(&str) -> FormatterResult<Option<Arc<Language>>> + 'a;
 
+/// How the formatted output should be terminated.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FinalNewline {
+    /// End the output with as many newlines as the input ended with
+    Preserve,
+    /// End the output with exactly one newline
+    #[default]
+    Single,
+    /// Don't end the output with a newline
+    None,
+}
+
+/// How each line of the formatted output should be ended.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LineEnding {
+    /// End lines with a line feed (`\n`)
+    #[default]
+    Lf,
+    /// End lines with a carriage return and a line feed (`\r\n`)
+    CrLf,
+    /// End lines as most lines of the input are ended, even if the input mixes line endings
+    Auto,
+}
+
+/// How the formatter should deal with parsing errors in the input.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ParsingErrorTolerance {
+    /// Fail with a [`FormatterError::Parsing`] error
+    #[default]
+    None,
+    /// Treat each ERROR node as a leaf, to which the formatting query still
+    /// applies, and continue formatting
+    BestEffort,
+    /// Pass each ERROR node through verbatim, formatting only the error-free
+    /// parts of the tree. Whitespace around an ERROR node is kept as in the
+    /// input, unless the formatting query says otherwise.
+    Verbatim,
+}
+
+impl ParsingErrorTolerance {
+    /// Whether parsing errors are tolerated at all
+    pub fn tolerates_errors(self) -> bool {
+        self != Self::None
+    }
+}
+
+impl From<bool> for ParsingErrorTolerance {
+    fn from(tolerate_parsing_errors: bool) -> Self {
+        if tolerate_parsing_errors {
+            Self::BestEffort
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Profiling data for the stages of a formatting run, as returned by
+/// [`formatter_str_with_stats`].
+///
+/// Only the top-level language is measured: time spent formatting injected
+/// languages, or checking idempotence, is not attributed to any stage.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct FormatStats {
+    /// Time spent parsing the input with Tree-sitter
+    pub parse_time: Duration,
+    /// Time spent matching the formatting query and collecting atoms
+    pub query_apply_time: Duration,
+    /// The number of atoms after post-processing
+    pub atom_count: usize,
+    /// Time spent post-processing the atoms
+    pub post_process_time: Duration,
+    /// Time spent rendering the atoms to text
+    pub render_time: Duration,
+    /// The number of matches of the formatting query's patterns
+    pub query_match_count: usize,
+}
+
+/// Runs `f`, adding the time it took to `duration`, if any. When no duration
+/// is to be measured, this does nothing more than call `f`.
+fn timed<T>(duration: Option<&mut Duration>, f: impl FnOnce() -> T) -> T {
+    match duration {
+        Some(duration) => {
+            let start = Instant::now();
+            let result = f();
+            *duration += start.elapsed();
+            result
+        }
+        None => f(),
+    }
+}
+
 /// Operations that can be performed by the formatter.
 #[derive(Clone, Copy, Debug)]
 pub enum Operation {
@@ -192,18 +341,38 @@ pub enum Operation {
         /// If true, skips the idempotence check (where we format twice,
         /// succeeding only if the intermediate and final result are identical)
         skip_idempotence: bool,
-        /// If true, Topiary will consider an ERROR as it does a leaf node,
-        /// and continues formatting instead of exiting with an error
-        tolerate_parsing_errors: bool,
+        /// Whether, and how, Topiary continues formatting in the presence of
+        /// ERROR nodes, instead of exiting with an error
+        tolerate_parsing_errors: ParsingErrorTolerance,
+        /// How many newlines the formatted output should end with
+        final_newline: FinalNewline,
+        /// How each line of the formatted output should be ended
+        line_ending: LineEnding,
+        /// If set, scopes whose single-line rendering would be wider than this
+        /// many columns have their scoped softlines rendered in multi-line mode
+        max_line_width: Option<usize>,
+        /// If true, formatting fails if there are any warnings, such as a
+        /// pattern of the query that matched nothing in the input (see
+        /// [`Warning`])
+        strict: bool,
     },
     /// Visualises the parsed file's tree-sitter tree
     Visualise {
         /// Choose the type of visualation Topiary should output
         output_format: Visualisation,
+        /// Whether anonymous nodes (punctuation, keywords, etc.) are omitted
+        named_only: bool,
+        /// Whether the nodes at which the formatting query begins or ends a
+        /// scope are annotated with whether that scope resolved to be
+        /// multi-line (see [`SyntaxNode::scopes`])
+        scopes: bool,
     },
 }
 
 /// The function that takes an input and formats, or visualises an output.
+/// When formatting, the non-fatal issues that were found are returned, such as
+/// the patterns of the query that matched nothing in the input (see
+/// [`Warning`]); there are none when visualising.
 ///
 /// # Errors
 ///
@@ -222,7 +391,10 @@ pub enum Operation {
 ///
 /// ```
 /// # tokio_test::block_on(async {
-/// use topiary_core::{formatter, Language, FormatterError, TopiaryQuery, Operation};
+/// use topiary_core::{
+///     formatter, CommentAttachment, FinalNewline, Language, LineEnding, FormatterError,
+///     ParsingErrorTolerance, TopiaryQuery, Operation,
+/// };
 ///
 /// let input = "[1,2]".to_string();
 /// let mut input = input.as_bytes();
@@ -238,11 +410,15 @@ pub enum Operation {
 ///     formatting_query: TopiaryQuery::new(&grammar, topiary_queries::json()).unwrap(),
 ///     grammar,
 ///     indent: None,
+///     max_blank_lines: None,
+///     formatting_markers: None,
+///     comment_attachment: CommentAttachment::default(),
+///     tab_width: None,
 ///     injection_query: None,
 /// };
 ///
-/// match formatter(&mut input, &mut output, &language, Operation::Format{ skip_idempotence: false, tolerate_parsing_errors: false }, None) {
-///   Ok(()) => {
+/// match formatter(&mut input, &mut output, &language, Operation::Format{ skip_idempotence: false, tolerate_parsing_errors: ParsingErrorTolerance::None, final_newline: FinalNewline::Single, line_ending: LineEnding::Lf, max_line_width: None, strict: false }, None) {
+///   Ok(_warnings) => {
 ///     let formatted = String::from_utf8(output).expect("valid utf-8");
 ///   }
 ///   Err(r) => {
@@ -260,7 +436,7 @@ pub fn formatter(
     language: &Language,
     operation: Operation,
     resolve: Option<&LanguageResolver<'_>>,
-) -> FormatterResult<()> {
+) -> FormatterResult<Vec<Warning>> {
     let content = read_input(input)
         .context_to()
         .attach("Failed to read input contents")?;
@@ -269,6 +445,7 @@ pub fn formatter(
 }
 
 /// The function that takes a string slice and formats, or visualises an output.
+/// Like [`formatter`], it returns the non-fatal issues that were found.
 ///
 /// # Errors
 ///
@@ -283,23 +460,150 @@ pub fn formatter_str(
     language: &Language,
     operation: Operation,
     resolve: Option<&LanguageResolver<'_>>,
-) -> FormatterResult<()> {
+) -> FormatterResult<Vec<Warning>> {
+    formatter_str_profiled(input, output, language, operation, resolve, None)
+}
+
+/// The byte order mark with which UTF-8 text may start
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Formats, or visualises, a byte slice like [`formatter_str`], returning the
+/// output as bytes.
+///
+/// A UTF-8 byte order mark at the start of `input` is stripped before parsing,
+/// so that it is not mistaken for content. If `preserve_bom` is set, it is
+/// prepended to the formatted output again; it is never added to the output of
+/// [`Operation::Visualise`], nor to that of an input that had none.
+///
+/// # Errors
+///
+/// If `input` is not valid UTF-8, a `FormatterError::Io` will be returned. If
+/// formatting fails for any other reason, a `FormatterError` will be returned.
+///
+/// # Language injections
+///
+/// See [`formatter`] for the `resolve` argument's semantics.
+pub fn format_bytes(
+    input: &[u8],
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+    preserve_bom: bool,
+) -> FormatterResult<Vec<u8>> {
+    let (has_bom, input) = match input.strip_prefix(UTF8_BOM) {
+        Some(input) => (true, input),
+        None => (false, input),
+    };
+    let input = std::str::from_utf8(input).context_to()?;
+
+    let mut output = Vec::new();
+    if has_bom && preserve_bom && matches!(operation, Operation::Format { .. }) {
+        output.extend_from_slice(UTF8_BOM);
+    }
+    formatter_str(input, &mut output, language, operation, resolve)?;
+
+    Ok(output)
+}
+
+/// Formats, or visualises, a string slice like [`formatter_str`], additionally
+/// returning how long each stage of the pipeline took, along with the
+/// warnings that [`formatter_str`] returns.
+///
+/// Collecting these statistics is opt-in, so [`formatter_str`] itself does
+/// not pay for any time measurement.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+///
+/// # Language injections
+///
+/// See [`formatter`] for the `resolve` argument's semantics.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn formatter_str_with_stats(
+    input: &str,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+) -> FormatterResult<(FormatStats, Vec<Warning>)> {
+    let mut stats = FormatStats::default();
+    let warnings = formatter_str_profiled(
+        input,
+        output,
+        language,
+        operation,
+        resolve,
+        Some(&mut stats),
+    )?;
+
+    Ok((stats, warnings))
+}
+
+fn formatter_str_profiled(
+    input: &str,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+    mut stats: Option<&mut FormatStats>,
+) -> FormatterResult<Vec<Warning>> {
     let tolerate_parsing_errors = match operation {
         Operation::Format {
             tolerate_parsing_errors,
             ..
-        } => tolerate_parsing_errors,
+        } => tolerate_parsing_errors.tolerates_errors(),
         _ => false,
     };
 
-    let tree = tree_sitter::parse(input, &language.grammar, tolerate_parsing_errors)?;
+    // Visualisation shows the input as it is, carriage returns included
+    let mut operation = operation;
+    let normalised = match &mut operation {
+        Operation::Format { line_ending, .. } => {
+            let normalised;
+            (normalised, *line_ending) = normalise_line_endings(input, *line_ending);
+            normalised
+        }
+        _ => Cow::Borrowed(input),
+    };
+    let input = normalised.as_ref();
 
-    formatter_tree(tree, input, output, language, operation, resolve)?;
+    let tree = timed(stats.as_deref_mut().map(|s| &mut s.parse_time), || {
+        tree_sitter::parse(input, &language.grammar, tolerate_parsing_errors)
+    })?;
 
-    Ok(())
+    formatter_tree_profiled(tree, input, output, language, operation, resolve, stats)
+}
+
+/// Ends every line of `input` with a line feed, so that carriage returns never end up in the
+/// atoms, resolving `line_ending` against the input as it was given. Every formatting function
+/// passes its input through here once, before parsing it, and formats the normalised text.
+///
+/// An input that mixes line endings is formatted as any other, but a warning is logged, as every
+/// line of its output will be ended alike.
+fn normalise_line_endings(input: &str, line_ending: LineEnding) -> (Cow<'_, str>, LineEnding) {
+    if !input.contains("\r\n") {
+        return (Cow::Borrowed(input), line_ending);
+    }
+
+    let line_ending = match pretty::newline(input, line_ending) {
+        "\r\n" => LineEnding::CrLf,
+        _ => LineEnding::Lf,
+    };
+
+    let crlf_count = input.matches("\r\n").count();
+    let lf_count = input.matches('\n').count() - crlf_count;
+    if lf_count > 0 {
+        log::warn!(
+            "The input mixes {crlf_count} CRLF and {lf_count} LF line endings, which are all output as {line_ending:?}"
+        );
+    }
+
+    (Cow::Owned(input.replace("\r\n", "\n")), line_ending)
 }
 
 /// The function that takes a tree and formats, or visualises an output.
+/// Like [`formatter`], it returns the non-fatal issues that were found.
 ///
 /// # Errors
 ///
@@ -315,479 +619,4499 @@ pub fn formatter_tree(
     language: &Language,
     operation: Operation,
     resolve: Option<&LanguageResolver<'_>>,
-) -> FormatterResult<()> {
+) -> FormatterResult<Vec<Warning>> {
+    // The tree is of the input as it was given, so the input is parsed again
+    // once its line endings are normalised
+    let mut operation = operation;
+    if let Operation::Format {
+        tolerate_parsing_errors,
+        line_ending,
+        ..
+    } = &mut operation
+        && input_content.contains("\r\n")
+    {
+        let normalised;
+        (normalised, *line_ending) = normalise_line_endings(input_content, *line_ending);
+        let tree = tree_sitter::parse(
+            &normalised,
+            &language.grammar,
+            tolerate_parsing_errors.tolerates_errors(),
+        )?;
+
+        return formatter_tree_profiled(
+            tree,
+            &normalised,
+            output,
+            language,
+            operation,
+            resolve,
+            None,
+        );
+    }
+
+    formatter_tree_profiled(
+        tree,
+        input_content,
+        output,
+        language,
+        operation,
+        resolve,
+        None,
+    )
+}
+
+fn formatter_tree_profiled(
+    tree: topiary_tree_sitter_facade::Tree,
+    input_content: &str,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+    stats: Option<&mut FormatStats>,
+) -> FormatterResult<Vec<Warning>> {
     match operation {
         Operation::Format {
             skip_idempotence,
             tolerate_parsing_errors,
+            final_newline,
+            line_ending,
+            max_line_width,
+            strict,
         } => {
-            log::debug!("Discovering potentially injected languages");
-            let spans = match &language.injection_query {
-                Some(injection_query) => collect_injections(&tree, input_content, injection_query),
-                None => Vec::new(),
-            };
-
-            // Create a list of nodes that are injection formatted.
-            // These must will be treated as leaves (although, in all likelihood, they already are).
-            let injection_leaf_nodes = spans.iter().map(|span| span.node_id);
-
-            // All the work related to tree-sitter and the query is done here
-            log::debug!("Apply Tree-sitter query");
-
-            let mut atoms = tree_sitter::apply_query_tree_with_forced_leaves(
+            let (rendered, warnings) = format_tree_to_string(
                 tree,
                 input_content,
-                &language.formatting_query,
-                injection_leaf_nodes,
+                language,
+                &FormatOptions {
+                    skip_idempotence,
+                    tolerate_parsing_errors,
+                    final_newline,
+                    line_ending,
+                    max_line_width,
+                    strict,
+                    only_kinds: Vec::new(),
+                    only_lines: None,
+                    preserve_whitespace: false,
+                },
+                resolve,
+                None,
+                stats,
             )?;
 
-            rewrite_injected_leaves(&mut atoms, spans, resolve, tolerate_parsing_errors)?;
-
-            // Various post-processing of whitespace
-            atoms.post_process();
+            write!(output, "{rendered}").context_to()?;
 
-            // Pretty-print atoms
-            log::debug!("Pretty-print output");
-            let rendered = pretty::render(
-                &atoms[..],
-                // Default to "  " if the language has no indentation specified
-                language.indent.as_ref().map_or("  ", |v| v.as_str()),
-            )?;
+            Ok(warnings)
+        }
 
-            // Add a final line break if missing
-            let rendered = format!("{}\n", rendered.trim());
+        Operation::Visualise {
+            output_format,
+            named_only,
+            scopes,
+        } => {
+            let mut root = SyntaxNode::new(tree.root_node(), named_only);
 
-            if !skip_idempotence {
-                idempotence_check(&rendered, language, tolerate_parsing_errors, resolve)?;
+            if scopes {
+                // Scopes are resolved along with the rest of the atoms, as for
+                // formatting, which is otherwise left undone
+                let mut atoms = atomise(
+                    tree,
+                    input_content,
+                    language,
+                    &FormatOptions {
+                        skip_idempotence: true,
+                        tolerate_parsing_errors: ParsingErrorTolerance::None,
+                        final_newline: FinalNewline::default(),
+                        line_ending: LineEnding::default(),
+                        max_line_width: None,
+                        strict: false,
+                        only_kinds: Vec::new(),
+                        only_lines: None,
+                        preserve_whitespace: false,
+                    },
+                    None,
+                    None,
+                )?;
+                root.annotate_scopes(&mut atoms.take_resolved_scopes());
             }
 
-            write!(output, "{rendered}").context_to()?;
-        }
-
-        Operation::Visualise { output_format } => {
-            let root: SyntaxNode = tree.root_node().into();
-
             match output_format {
                 Visualisation::GraphViz => graphviz::write(output, &root).context_to()?,
                 Visualisation::Json => serde_json::to_writer(output, &root).context_to()?,
             };
-        }
-    };
-    Ok(())
-}
-
-fn rewrite_injected_leaves(
-    atoms: &mut atom_collection::AtomCollection,
-    spans: Vec<InjectionSpan>,
-    resolve: Option<&LanguageResolver<'_>>,
-    tolerate_parsing_errors: bool,
-) -> FormatterResult<()> {
-    for span in spans {
-        // If the injected language is unsupported, skip formatting this injection
-        // by continuing the loop. This leaves the original, unformatted text intact.
-        let Some(inner_language) = resolve_injected_language(resolve, &span.language)? else {
-            log::warn!(
-                "Skipping injection for unsupported language: {}",
-                span.language
-            );
-            continue;
-        };
-
-        let mut formatted_inner = Vec::new();
-        formatter_str(
-            span.content,
-            &mut formatted_inner,
-            &inner_language,
-            Operation::Format {
-                skip_idempotence: true,
-                tolerate_parsing_errors,
-            },
-            resolve,
-        )?;
-
-        let formatted_inner = String::from_utf8(formatted_inner)
-            .context_to()?
-            .trim_end_matches('\n')
-            .to_owned();
 
-        if !atoms.rewrite_injected_leaf_content(span.node_id, formatted_inner) {
-            return Err(report!(FormatterError::Internal(format!(
-                "Could not find leaf for injected {} span",
-                span.language
-            ))));
+            Ok(Vec::new())
         }
     }
+}
 
-    Ok(())
+/// The settings of [`Operation::Format`], for formatting in memory, along with
+/// those that can only be set on a [`FormatterBuilder`]
+#[derive(Clone)]
+struct FormatOptions {
+    skip_idempotence: bool,
+    tolerate_parsing_errors: ParsingErrorTolerance,
+    final_newline: FinalNewline,
+    line_ending: LineEnding,
+    max_line_width: Option<usize>,
+    strict: bool,
+    only_kinds: Vec<String>,
+    only_lines: Option<Vec<Range<usize>>>,
+    preserve_whitespace: bool,
 }
 
-/// Resolves a language string from an injection (e.g. "rust" in ```rust) into a `Language`
-/// instance.
-///
-/// Returns `Ok(None)` if the language is not configured or cannot be resolved, allowing the caller
-/// to gracefully skip formatting rather than failing the entire formatting operation.
-fn resolve_injected_language(
-    resolve: Option<&LanguageResolver<'_>>,
-    language: &str,
-) -> FormatterResult<Option<Arc<Language>>> {
-    let Some(resolve) = resolve else {
-        return Ok(None);
+/// When formatting strictly, fails if there are any `warnings`, each of which
+/// is attached to the report. Patterns that match nothing take precedence, as
+/// a `FormatterError::PatternDoesNotMatch`; otherwise, the query uses capture
+/// names that Topiary does not recognise, which is a `FormatterError::Query`.
+fn check_strict(warnings: &[Warning]) -> FormatterResult<()> {
+    if warnings.is_empty() {
+        return Ok(());
+    }
+
+    let error = if warnings
+        .iter()
+        .any(|warning| matches!(warning, Warning::PatternDoesNotMatch { .. }))
+    {
+        FormatterError::PatternDoesNotMatch
+    } else {
+        FormatterError::Query("The query uses capture names that Topiary does not recognise".into())
     };
 
-    match resolve(language) {
-        Ok(Some(language_cfg)) => {
-            log::info!("resolved injected language: {language}");
-            Ok(Some(language_cfg))
-        }
-        Ok(None) => Ok(None),
-        Err(err)
-            if matches!(
-                err.current_context(),
-                FormatterError::InjectionLanguageResolution { .. }
-            ) =>
-        {
-            Err(err)
-        }
-        Err(err) => Err(err.context(FormatterError::InjectionLanguageResolution {
-            language: language.to_owned(),
-        })),
+    let mut report = report!(error);
+    for warning in warnings {
+        report = report.attach(warning.to_string());
     }
+    Err(report)
 }
 
-/// Simple helper function to read the full content of an io Read stream
-fn read_input(input: &mut dyn io::Read) -> Result<String, io::Error> {
-    let mut content = String::new();
-    input.read_to_string(&mut content)?;
-    Ok(content)
-}
-
-/// Perform the idempotence check. Given the already formatted content of the
-/// file, formats the content again and checks if the two are identical.
-/// Result in: `Ok(())`` if the idempotence check succeeded (the content is
-/// identical to the formatted content)
-///
-/// # Errors
-///
-/// `Err(FormatterError::Idempotence)` if the idempotence check failed
-/// `Err(FormatterError::Formatting(...))` if the formatting failed
-fn idempotence_check(
-    content: &str,
+/// Formats the parsed input entirely in memory, returning the formatted text
+/// along with the non-fatal issues that were found. Nothing on this path
+/// performs I/O.
+fn format_tree_to_string(
+    tree: topiary_tree_sitter_facade::Tree,
+    input_content: &str,
     language: &Language,
-    tolerate_parsing_errors: bool,
+    options: &FormatOptions,
     resolve: Option<&LanguageResolver<'_>>,
-) -> FormatterResult<()> {
-    log::info!("Checking for idempotence ...");
-
-    let mut input = content.as_bytes();
-    let mut output = io::BufWriter::new(Vec::new());
-
-    match formatter(
-        &mut input,
-        &mut output,
+    mut post_process: Option<&mut PostProcessor>,
+    mut stats: Option<&mut FormatStats>,
+) -> FormatterResult<(String, Vec<Warning>)> {
+    let mut atoms = atomise(
+        tree,
+        input_content,
         language,
-        Operation::Format {
-            skip_idempotence: true,
-            tolerate_parsing_errors,
-        },
+        options,
         resolve,
-    ) {
-        Ok(()) => {
-            let reformatted = output
-                .into_inner()
-                .context_to()
-                .map(String::from_utf8)?
-                .context_to()?;
-
-            if content == reformatted {
-                Ok(())
-            } else {
-                log::error!("Failed idempotence check");
-                log::error!("{}", StrComparison::new(content, &reformatted));
-                Err(report!(FormatterError::Idempotence))
-            }
-        }
-        Err(report) if matches!(report.current_context(), FormatterError::Parsing) => {
-            Err(report.context(FormatterError::IdempotenceParsing))
-        }
-        Err(error) => Err(error),
+        stats.as_deref_mut(),
+    )?;
+    let warnings = atoms.take_warnings();
+    if options.strict {
+        check_strict(&warnings)?;
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
-
-    use test_log::test;
 
-    use crate::{
-        FormatterError, InjectionQuery, Language, Operation, SpanAttachment, TopiaryQuery,
-        collect_injections, formatter, formatter_str, parse, test_utils::pretty_assert_eq,
-    };
+    // Pretty-print atoms
+    log::debug!("Pretty-print output");
+    let rendered = timed(stats.map(|s| &mut s.render_time), || {
+        pretty::render(&atoms[..], language.indent(), language.tab_width())
+    })?;
 
-    fn language(name: &str, formatting_query: &str, injection_query: Option<&str>) -> Language {
-        let config = topiary_config::Configuration::default();
-        let config_language = config.get_language(name).unwrap();
-        let grammar = config_language.grammar().unwrap();
+    // Terminate the output according to the final newline policy
+    let mut rendered = pretty::terminate(&rendered, input_content, options.final_newline);
 
-        Language {
-            name: name.to_owned(),
-            formatting_query: TopiaryQuery::new(&grammar, formatting_query).unwrap(),
-            injection_query: injection_query
-                .map(|query_content| InjectionQuery::new(&grammar, query_content).unwrap()),
-            grammar,
-            indent: config_language.indent(),
-        }
+    // The idempotence check is of the text that is actually emitted, so it
+    // comes after post-processing
+    if let Some(post_process) = post_process.as_deref_mut() {
+        rendered = post_process(&rendered);
     }
 
-    fn ocamllex_language() -> Language {
-        language(
-            "ocamllex",
-            topiary_queries::ocamllex(),
-            Some(topiary_queries::ocamllex_injections()),
-        )
+    if !options.skip_idempotence {
+        idempotence_check(&rendered, language, options, resolve, post_process)?;
     }
 
-    fn ocaml_language() -> Language {
-        language("ocaml", topiary_queries::ocaml(), None)
-    }
+    let rendered = pretty::translate_line_endings(
+        &rendered,
+        pretty::newline(input_content, options.line_ending),
+    );
 
-    fn unstable_ocaml_language() -> Language {
-        language(
+    Ok((rendered, warnings))
+}
+
+/// Formats `input` with the given formatting query and grammar, returning the
+/// formatted text.
+///
+/// Unlike [`formatter`], this neither reads its input nor writes its output
+/// through `std::io`, and it assumes no filesystem: the grammar must already
+/// be loaded, and the query is given as source. This makes it suitable for
+/// embedding Topiary where there is no filesystem, such as in WebAssembly.
+///
+/// The input must parse without errors and its formatting must be idempotent.
+/// The output is indented with two spaces and ends with a single newline.
+/// Language injections are not formatted. For any other settings, build a
+/// [`Language`] and use [`formatter_str`] instead.
+///
+/// # Errors
+///
+/// If the query cannot be parsed, a `FormatterError::Query` will be returned.
+/// If formatting fails for any other reason, a `FormatterError` will be
+/// returned; this is never a `FormatterError::Io`.
+pub fn format_string(
+    input: &str,
+    query: &str,
+    grammar: topiary_tree_sitter_facade::Language,
+) -> FormatterResult<String> {
+    format_string_with(input, query, grammar, false)
+}
+
+/// Returns whether formatting `input` with the given formatting query and
+/// grammar would change it, as `topiary format --check` does for files.
+///
+/// This formats `input` as [`format_string`] does, and compares the result to
+/// it, without checking that the formatting is idempotent: formatted input
+/// that would be formatted differently again needs formatting all the same.
+///
+/// # Errors
+///
+/// If the query cannot be parsed, a `FormatterError::Query` will be returned.
+/// If formatting fails for any other reason, a `FormatterError` will be
+/// returned; this is never a `FormatterError::Io`.
+pub fn needs_formatting(
+    input: &str,
+    query: &str,
+    grammar: topiary_tree_sitter_facade::Language,
+) -> FormatterResult<bool> {
+    format_string_with(input, query, grammar, true).map(|formatted| formatted != input)
+}
+
+fn format_string_with(
+    input: &str,
+    query: &str,
+    grammar: topiary_tree_sitter_facade::Language,
+    skip_idempotence: bool,
+) -> FormatterResult<String> {
+    let formatting_query = TopiaryQuery::new(&grammar, query).context(FormatterError::Query(
+        "Could not parse the formatting query".into(),
+    ))?;
+    let language = Language {
+        name: String::new(),
+        formatting_query,
+        injection_query: None,
+        grammar,
+        indent: None,
+        max_blank_lines: None,
+        formatting_markers: None,
+        comment_attachment: CommentAttachment::default(),
+        tab_width: None,
+    };
+
+    let (input, _) = normalise_line_endings(input, LineEnding::Lf);
+    let tree = tree_sitter::parse(&input, &language.grammar, false)?;
+    format_tree_to_string(
+        tree,
+        &input,
+        &language,
+        &FormatOptions {
+            skip_idempotence,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
+            only_kinds: Vec::new(),
+            only_lines: None,
+            preserve_whitespace: false,
+        },
+        None,
+        None,
+        None,
+    )
+    .map(|(formatted, _)| formatted)
+}
+
+/// A formatter for a single language, built once and then used to format any
+/// number of inputs.
+///
+/// Each of the free functions, such as [`formatter_str`], creates a new
+/// Tree-sitter parser for the input it formats. A `Formatter` instead holds on
+/// to its [`Language`], with its compiled queries, and to its parser, reusing
+/// them for every call to [`Formatter::format`]. This suits long-running
+/// processes that format many inputs in the same language.
+///
+/// A `Formatter` is built with [`Formatter::builder`], whose settings are those
+/// of [`Operation::Format`].
+///
+/// # Incremental parsing
+///
+/// A `Formatter` also holds on to the last input it parsed, and to its syntax
+/// tree. Editor integrations that format on every keystroke can describe how
+/// the input changed since, with a [`TextEdit`], and call
+/// [`Formatter::format_edited`], which reparses only what the edit changed.
+///
+/// # Thread safety
+///
+/// Formatting needs exclusive access to the parser, so [`Formatter::format`]
+/// takes `&mut self`. A `Formatter` is `Send`, so it can be moved to another
+/// thread, but it is not `Sync`: to format concurrently, build one `Formatter`
+/// per thread.
+///
+/// # Examples
+///
+/// ```
+/// use topiary_core::{CommentAttachment, Formatter, Language, TopiaryQuery};
+///
+/// let config = topiary_config::Configuration::default();
+/// let grammar = config.get_language("json").unwrap().grammar().unwrap();
+///
+/// let language = Language {
+///     name: "json".to_owned(),
+///     formatting_query: TopiaryQuery::new(&grammar, topiary_queries::json()).unwrap(),
+///     grammar,
+///     indent: None,
+///     max_blank_lines: None,
+///     formatting_markers: None,
+///     comment_attachment: CommentAttachment::default(),
+///     tab_width: None,
+///     injection_query: None,
+/// };
+///
+/// let mut formatter = Formatter::builder(language).build().unwrap();
+/// assert_eq!(formatter.format("[1,2]").unwrap(), "[ 1, 2 ]\n");
+/// assert_eq!(formatter.format("[3]").unwrap(), "[ 3 ]\n");
+/// ```
+pub struct Formatter {
+    language: Arc<Language>,
+    parser: topiary_tree_sitter_facade::Parser,
+    options: FormatOptions,
+    resolve: Option<Box<SendLanguageResolver>>,
+    post_process: Option<Box<PostProcessor>>,
+    /// Whether to keep each input and its syntax tree; see
+    /// [`FormatterBuilder::incremental`]
+    #[cfg(not(target_arch = "wasm32"))]
+    incremental: bool,
+    /// The last input that was parsed, along with its syntax tree, from which
+    /// the next input can be reparsed incrementally
+    #[cfg(not(target_arch = "wasm32"))]
+    previous: Option<(String, topiary_tree_sitter_facade::Tree)>,
+}
+
+/// A [`LanguageResolver`] that can be moved between threads, as held by a
+/// [`Formatter`]
+type SendLanguageResolver = dyn Fn(&str) -> FormatterResult<Option<Arc<Language>>> + Send;
+
+/// A function that rewrites the formatted text, as set by
+/// [`FormatterBuilder::post_process`]
+type PostProcessor = dyn FnMut(&str) -> String + Send;
+
+impl Formatter {
+    /// Starts building a `Formatter` for the given language. Unless changed on
+    /// the builder, the input must parse without errors, its formatting must
+    /// be idempotent, and the output ends with a single newline, with lines
+    /// ended by a line feed. The language can be shared with other formatters,
+    /// and with the rest of the program, by passing it in an `Arc`.
+    pub fn builder(language: impl Into<Arc<Language>>) -> FormatterBuilder {
+        FormatterBuilder {
+            language: language.into(),
+            options: FormatOptions {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+                only_kinds: Vec::new(),
+                only_lines: None,
+                preserve_whitespace: false,
+            },
+            resolve: None,
+            post_process: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            incremental: false,
+        }
+    }
+
+    /// The language this formatter formats.
+    pub fn language(&self) -> &Language {
+        &self.language
+    }
+
+    /// Formats `input`, returning the formatted text.
+    ///
+    /// # Errors
+    ///
+    /// If formatting fails for any reason, a `FormatterError` will be returned.
+    /// The formatter can still be used afterwards.
+    pub fn format(&mut self, input: &str) -> FormatterResult<String> {
+        self.format_with_warnings(input)
+            .map(|(formatted, _)| formatted)
+    }
+
+    /// Formats `input`, returning the formatted text along with the non-fatal
+    /// issues that were found, such as the patterns of the query that matched
+    /// nothing in the input.
+    ///
+    /// # Errors
+    ///
+    /// If formatting fails for any reason, a `FormatterError` will be returned.
+    /// When formatting strictly, that includes any warning (see
+    /// [`FormatterBuilder::strict`]).
+    pub fn format_with_warnings(&mut self, input: &str) -> FormatterResult<(String, Vec<Warning>)> {
+        let (input, line_ending) = normalise_line_endings(input, self.options.line_ending);
+        let tree = self.parse(&input, None)?;
+        self.format_tree(tree, &input, line_ending)
+    }
+
+    /// Formats `input`, which `edit` made of the input last given to this
+    /// formatter, returning the formatted text. Only the parts of the syntax
+    /// tree that the edit may have changed are reparsed, but the result is the
+    /// same as that of [`Formatter::format`].
+    ///
+    /// If the formatter was not built to keep its inputs (see
+    /// [`FormatterBuilder::incremental`]), if there was no previous input, or
+    /// if `edit` does not describe how it became `input`, the input is parsed
+    /// in full.
+    ///
+    /// # Errors
+    ///
+    /// If formatting fails for any reason, a `FormatterError` will be returned.
+    /// An input that fails to parse is nonetheless kept, so the next edit can
+    /// still be reparsed incrementally.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn format_edited(&mut self, input: &str, edit: &TextEdit) -> FormatterResult<String> {
+        // The previous input is kept with its line endings normalised, so the
+        // edit is made of the normalised inputs
+        let edit = match &self.previous {
+            Some((previous, _)) => edit.normalised(input, previous.len()),
+            None => *edit,
+        };
+        let (input, line_ending) = normalise_line_endings(input, self.options.line_ending);
+        let tree = self.parse(&input, Some(&edit))?;
+        self.format_tree(tree, &input, line_ending)
+            .map(|(formatted, _)| formatted)
+    }
+
+    /// Parses `input`, incrementally if `edit` describes how the previous input
+    /// became `input`, and keeps it for the next edit if the formatter is
+    /// incremental. The line endings of `input` must already be normalised.
+    fn parse(
+        &mut self,
+        input: &str,
+        edit: Option<&TextEdit>,
+    ) -> FormatterResult<topiary_tree_sitter_facade::Tree> {
+        #[cfg(not(target_arch = "wasm32"))]
+        let old_tree = self
+            .previous
+            .take()
+            .zip(edit)
+            .and_then(|((previous, mut tree), edit)| {
+                let input_edit = edit.to_input_edit(&previous, input);
+                if input_edit.is_none() {
+                    log::debug!("The edit does not match the input, so it is parsed in full");
+                }
+                tree.edit(&input_edit?);
+                Some(tree)
+            });
+        // Trees cannot be kept in WebAssembly, where they cannot be cloned
+        #[cfg(target_arch = "wasm32")]
+        let old_tree: Option<topiary_tree_sitter_facade::Tree> = {
+            let _ = edit;
+            None
+        };
+
+        let tree = tree_sitter::reparse_with(&mut self.parser, input, old_tree.as_ref())?;
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.incremental {
+            self.previous = Some((input.to_owned(), tree.clone()));
+        }
+
+        tree_sitter::check_parse(
+            &tree,
+            input,
+            self.options.tolerate_parsing_errors.tolerates_errors(),
+        )?;
+
+        Ok(tree)
+    }
+
+    /// Formats the parsed `input`, whose lines are ended as `line_ending`
+    /// resolves to, for the input as it was given.
+    fn format_tree(
+        &mut self,
+        tree: topiary_tree_sitter_facade::Tree,
+        input: &str,
+        line_ending: LineEnding,
+    ) -> FormatterResult<(String, Vec<Warning>)> {
+        let options = if line_ending == self.options.line_ending {
+            Cow::Borrowed(&self.options)
+        } else {
+            Cow::Owned(FormatOptions {
+                line_ending,
+                ..self.options.clone()
+            })
+        };
+
+        format_tree_to_string(
+            tree,
+            input,
+            &self.language,
+            &options,
+            self.resolve
+                .as_deref()
+                .map(|resolve| resolve as &LanguageResolver<'_>),
+            self.post_process.as_deref_mut(),
+            None,
+        )
+    }
+}
+
+/// Builds a [`Formatter`]; see [`Formatter::builder`].
+pub struct FormatterBuilder {
+    language: Arc<Language>,
+    options: FormatOptions,
+    resolve: Option<Box<SendLanguageResolver>>,
+    post_process: Option<Box<PostProcessor>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    incremental: bool,
+}
+
+impl FormatterBuilder {
+    /// Whether to skip the idempotence check, where the output is formatted
+    /// again and must be unchanged.
+    pub fn skip_idempotence(mut self, skip_idempotence: bool) -> Self {
+        self.options.skip_idempotence = skip_idempotence;
+        self
+    }
+
+    /// Whether, and how, to continue formatting in the presence of ERROR
+    /// nodes, instead of failing.
+    pub fn tolerate_parsing_errors(
+        mut self,
+        tolerate_parsing_errors: ParsingErrorTolerance,
+    ) -> Self {
+        self.options.tolerate_parsing_errors = tolerate_parsing_errors;
+        self
+    }
+
+    /// How many newlines the formatted output should end with.
+    pub fn final_newline(mut self, final_newline: FinalNewline) -> Self {
+        self.options.final_newline = final_newline;
+        self
+    }
+
+    /// How each line of the formatted output should be ended.
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.options.line_ending = line_ending;
+        self
+    }
+
+    /// The width beyond which scopes have their scoped softlines rendered in
+    /// multi-line mode.
+    pub fn max_line_width(mut self, max_line_width: usize) -> Self {
+        self.options.max_line_width = Some(max_line_width);
+        self
+    }
+
+    /// How to resolve the languages injected into the input. This is needed
+    /// when the language has an injection query; see [`formatter`] for its
+    /// semantics.
+    pub fn resolve(
+        mut self,
+        resolve: impl Fn(&str) -> FormatterResult<Option<Arc<Language>>> + Send + 'static,
+    ) -> Self {
+        self.resolve = Some(Box::new(resolve));
+        self
+    }
+
+    /// Whether to keep each input, along with its syntax tree, so that the
+    /// next can be reparsed incrementally by [`Formatter::format_edited`].
+    /// This costs a copy of each input and tree, so it is off by default.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// A function that rewrites the formatted text, for fix-ups that are
+    /// easier made on the text than in the query. It is given the rendered
+    /// output, with its lines ended by line feeds, and its result is what the
+    /// idempotence check verifies and what is returned (with the chosen line
+    /// endings). By default, the formatted text is returned as it is.
+    ///
+    /// The idempotence check formats the post-processed text again, including
+    /// post-processing, so a function whose result would be formatted
+    /// differently, or that does not give the same result twice, makes
+    /// formatting fail its idempotence check.
+    pub fn post_process(
+        mut self,
+        post_process: impl FnMut(&str) -> String + Send + 'static,
+    ) -> Self {
+        self.post_process = Some(Box::new(post_process));
+        self
+    }
+
+    /// Whether to fail on any [`Warning`], rather than to return it from
+    /// [`Formatter::format_with_warnings`]. A pattern of the formatting query
+    /// that matches nothing in the input is then a
+    /// `FormatterError::PatternDoesNotMatch`, and a capture name that Topiary
+    /// does not recognise is a `FormatterError::Query`. This suits checking
+    /// that a query has no dead patterns or misspelt captures against an input
+    /// that is meant to exercise them all. By default, formatting is not
+    /// strict.
+    ///
+    /// Patterns cannot be enumerated in WebAssembly, where this has no effect.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
+
+    /// The kinds of node that are formatted, such as `function_definition`,
+    /// leaving everything else exactly as it is in the input. A node of one
+    /// of these kinds is formatted along with everything within it, including
+    /// any nodes of these kinds nested in it. By default, or if `only_kinds`
+    /// is empty, the whole input is formatted.
+    pub fn only_kinds(mut self, only_kinds: Vec<String>) -> Self {
+        self.options.only_kinds = only_kinds;
+        self
+    }
+
+    /// Whether to keep the whitespace of the input wherever the formatting
+    /// differs from it only in amount, so that formatting changes as little
+    /// of the input as possible: the spaces between nodes on the same line
+    /// are kept, as are blank lines between nodes on different lines (up to
+    /// the language's maximum). Line breaks are still added and removed as
+    /// the query says, and indentation is always that of the formatting.
+    pub fn preserve_whitespace(mut self, preserve_whitespace: bool) -> Self {
+        self.options.preserve_whitespace = preserve_whitespace;
+        self
+    }
+
+    /// Builds the formatter, creating its parser.
+    ///
+    /// # Errors
+    ///
+    /// If the parser cannot be created for the language's grammar, a
+    /// `FormatterError` will be returned.
+    pub fn build(self) -> FormatterResult<Formatter> {
+        let parser = tree_sitter::parser(&self.language.grammar)?;
+
+        Ok(Formatter {
+            language: self.language,
+            parser,
+            options: self.options,
+            resolve: self.resolve,
+            post_process: self.post_process,
+            #[cfg(not(target_arch = "wasm32"))]
+            incremental: self.incremental,
+            #[cfg(not(target_arch = "wasm32"))]
+            previous: None,
+        })
+    }
+}
+
+/// Formats `input` to `output`, like [`formatter`], but renders straight to
+/// `output` rather than collecting the formatted text in memory first.
+///
+/// Tree-sitter needs the whole input to parse it, so the input and its atoms
+/// are still held in memory; however, the formatted output is written
+/// incrementally. The exception is when the idempotence check is enabled:
+/// that needs the formatted output in full, so it is buffered as it would be
+/// by [`formatter`]. The same is true of operations other than formatting.
+///
+/// # Errors
+///
+/// If formatting fails for any reason, a `FormatterError` will be returned.
+/// Note that, when streaming, some output may already have been written by
+/// the time rendering fails.
+///
+/// # Language injections
+///
+/// See [`formatter`] for the `resolve` argument's semantics.
+pub fn format_reader_to_writer(
+    input: &mut impl io::Read,
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+) -> FormatterResult<()> {
+    let Operation::Format {
+        skip_idempotence: true,
+        tolerate_parsing_errors,
+        final_newline,
+        line_ending,
+        max_line_width,
+        strict: false,
+    } = operation
+    else {
+        return formatter(input, output, language, operation, resolve).map(|_| ());
+    };
+
+    let content = read_input(input)
+        .context_to()
+        .attach("Failed to read input contents")?;
+    let (content, line_ending) = normalise_line_endings(&content, line_ending);
+    let tree = tree_sitter::parse(
+        &content,
+        &language.grammar,
+        tolerate_parsing_errors.tolerates_errors(),
+    )?;
+
+    let atoms = atomise(
+        tree,
+        &content,
+        language,
+        &FormatOptions {
+            skip_idempotence: true,
+            tolerate_parsing_errors,
+            final_newline,
+            line_ending,
+            max_line_width,
+            strict: false,
+            only_kinds: Vec::new(),
+            only_lines: None,
+            preserve_whitespace: false,
+        },
+        resolve,
+        None,
+    )?;
+
+    log::debug!("Pretty-print output");
+    pretty::render_terminated(
+        &atoms[..],
+        language.indent(),
+        language.tab_width(),
+        pretty::final_newlines(&content, final_newline),
+        pretty::newline(&content, line_ending),
+        output,
+    )
+}
+
+/// Applies the formatting query to `input` and post-processes the result,
+/// returning the atoms that would otherwise be rendered to text.
+///
+/// This runs the same pipeline as [`formatter`], up to but excluding pretty
+/// printing. It is intended for tooling that needs to inspect Topiary's layout
+/// decisions. Each [`Atom::Leaf`] carries the id of the Tree-sitter node it
+/// originates from, as well as its position in the input. Language injections
+/// are not formatted; injected regions are kept as their original leaves.
+///
+/// # Errors
+///
+/// If parsing or the application of the query fails, a `FormatterError` will
+/// be returned.
+pub fn to_atoms(
+    input: &str,
+    query: &TopiaryQuery,
+    grammar: &topiary_tree_sitter_facade::Language,
+) -> FormatterResult<Vec<Atom>> {
+    let mut atoms = apply_query(input, query, grammar, false)?;
+    atoms.post_process();
+    atoms.check_indent_balance()?;
+
+    Ok(atoms.into_atoms())
+}
+
+/// The outcome of [`format_range`]: the formatted text to splice into the
+/// input in place of `range`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeFormatting {
+    /// The byte range of the input to replace, once snapped to node boundaries
+    pub range: Range<usize>,
+    /// The formatted replacement for `range`
+    pub text: String,
+}
+
+/// Formats only the part of `input` within the byte `range`, as needed by an
+/// editor's "format selection".
+///
+/// The whole input is parsed and formatted, for context, but only the atoms
+/// that originate from the requested range are rendered. A range may start or
+/// end part way through a node, which would produce invalid partial output, so
+/// it is first snapped outward to the smallest node that encloses it (or, if
+/// that node lies within a leaf, such as a string, to the whole leaf). The
+/// returned [`RangeFormatting`] holds this snapped range, along with its
+/// replacement.
+///
+/// The lines of the replacement are indented as they would be in the fully
+/// formatted input, except for the first: it carries on from whatever precedes
+/// the range on its line, which is left alone, as is any whitespace around the
+/// snapped node. As with [`to_atoms`], language injections are not formatted.
+///
+/// # Errors
+///
+/// If `range` lies outside `input`, or if parsing or the application of the
+/// query fails, a `FormatterError` will be returned.
+pub fn format_range(
+    input: &str,
+    range: Range<usize>,
+    query: &TopiaryQuery,
+    grammar: &topiary_tree_sitter_facade::Language,
+    indent: &str,
+) -> FormatterResult<RangeFormatting> {
+    if range.start > range.end || range.end > input.len() {
+        rootcause::bail!(FormatterError::Internal(format!(
+            "Range {range:?} lies outside of the input, of length {}",
+            input.len()
+        )));
+    }
+
+    let tree = tree_sitter::parse(input, grammar, false)?;
+
+    // The node ids and byte ranges of the snapped node and its ancestors, from
+    // the innermost outward, and the node ids of its descendants
+    let (ancestors, descendants) = {
+        let root = tree.root_node();
+        let snapped = root
+            .descendant_for_byte_range(range.start as u32, range.end as u32)
+            .unwrap_or(root);
+
+        let descendants: HashSet<_> = atom_collection::dfs_flatten(&snapped)
+            .iter()
+            .map(|node| node.id())
+            .collect();
+
+        let ancestors: Vec<_> = iter::successors(Some(snapped), |node| node.parent())
+            .map(|node| (node.id(), node.byte_range()))
+            .collect();
+
+        (ancestors, descendants)
+    };
+
+    let mut atoms = tree_sitter::apply_query_tree(tree, input, query)?;
+    atoms.post_process();
+    atoms.check_indent_balance()?;
+    let atoms = atoms.into_atoms();
+
+    let leaf_id = |atom: &Atom| match atom {
+        Atom::Leaf { id, .. } => Some(*id),
+        _ => None,
+    };
+
+    // Leaves do not nest, so at most one of the snapped node's ancestors is a
+    // leaf. If there is none, every leaf within the snapped node is rendered.
+    let enclosing_leaf = atoms.iter().enumerate().find_map(|(index, atom)| {
+        let id = leaf_id(atom)?;
+        let (_, range) = ancestors.iter().find(|(ancestor, _)| *ancestor == id)?;
+        Some((range.clone(), index..=index))
+    });
+    let snapped = ancestors[0].1.clone();
+    let (range, selected) = match enclosing_leaf {
+        Some(leaf) => leaf,
+        None => {
+            let within = |atom: &Atom| leaf_id(atom).is_some_and(|id| descendants.contains(&id));
+            match (
+                atoms.iter().position(within),
+                atoms.iter().rposition(within),
+            ) {
+                (Some(first), Some(last)) => (snapped, first..=last),
+                // Nothing within the snapped node survived formatting
+                _ => {
+                    return Ok(RangeFormatting {
+                        range: snapped,
+                        text: String::new(),
+                    });
+                }
+            }
+        }
+    };
+
+    // Open the indentation blocks that are open in the full output at the
+    // start of the selection, and suspend those that are suspended there, so
+    // the replacement's lines are indented in place. As when rendering, a block
+    // end closes the innermost block, and a resumption the innermost suspension.
+    let mut selection = Vec::new();
+    let close_innermost = |selection: &mut Vec<Atom>, is_opening: fn(&Atom) -> bool| {
+        if let Some(index) = selection.iter().rposition(is_opening) {
+            selection.remove(index);
+        }
+    };
+    for atom in &atoms[..*selected.start()] {
+        match atom {
+            Atom::IndentStart | Atom::IndentStartWidth(_) | Atom::IndentSuspend => {
+                selection.push(atom.clone())
+            }
+            Atom::IndentEnd => close_innermost(&mut selection, |atom| {
+                matches!(atom, Atom::IndentStart | Atom::IndentStartWidth(_))
+            }),
+            Atom::IndentResume => {
+                close_innermost(&mut selection, |atom| matches!(atom, Atom::IndentSuspend))
+            }
+            _ => {}
+        }
+    }
+    selection.extend_from_slice(&atoms[selected]);
+
+    Ok(RangeFormatting {
+        range,
+        text: pretty::render(&selection, indent, DEFAULT_TAB_WIDTH)?,
+    })
+}
+
+/// Formats `input` like [`formatter_str`], but leaves it exactly as it is,
+/// except for the nodes on the given `lines` and the whitespace between them
+/// on each line. This suits formatting only the lines that changed since some
+/// revision.
+///
+/// Lines are numbered from zero, and each range of them excludes its end. A
+/// line within which no node lies, such as one in the middle of a multi-line
+/// string, formats the smallest node that encloses it instead. Blank lines,
+/// and lines beyond the end of the input, select nothing, so if no line selects
+/// a node, the input is left as it is (but for its final newline).
+///
+/// As the lines of the output no longer correspond to those of the input, the
+/// idempotence check is not performed, whatever the `operation` says.
+///
+/// # Errors
+///
+/// If `operation` is not [`Operation::Format`], a `FormatterError::Internal`
+/// will be returned. If formatting fails for any other reason, a
+/// `FormatterError` will be returned.
+///
+/// # Language injections
+///
+/// See [`formatter`] for the `resolve` argument's semantics.
+pub fn format_lines(
+    input: &str,
+    lines: &[Range<usize>],
+    output: &mut impl io::Write,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+) -> FormatterResult<Vec<Warning>> {
+    let Operation::Format {
+        tolerate_parsing_errors,
+        final_newline,
+        line_ending,
+        max_line_width,
+        strict,
+        ..
+    } = operation
+    else {
+        rootcause::bail!(FormatterError::Internal(
+            "Only formatting can be restricted to lines".into()
+        ));
+    };
+
+    let (input, line_ending) = normalise_line_endings(input, line_ending);
+    let tree = tree_sitter::parse(
+        &input,
+        &language.grammar,
+        tolerate_parsing_errors.tolerates_errors(),
+    )?;
+
+    let (rendered, warnings) = format_tree_to_string(
+        tree,
+        &input,
+        language,
+        &FormatOptions {
+            skip_idempotence: true,
+            tolerate_parsing_errors,
+            final_newline,
+            line_ending,
+            max_line_width,
+            strict,
+            only_kinds: Vec::new(),
+            only_lines: Some(lines.to_vec()),
+            preserve_whitespace: false,
+        },
+        resolve,
+        None,
+        None,
+    )?;
+
+    write!(output, "{rendered}").context_to()?;
+
+    Ok(warnings)
+}
+
+/// A region of formatted output, mapped back to the input node from which it
+/// originates, as returned by [`format_with_source_map`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceMapping {
+    /// The byte range of the formatted output
+    pub output_range: Range<usize>,
+    /// The byte range of the input node
+    pub input_range: Range<usize>,
+    /// The kind of the input node, as named by the grammar
+    pub node_kind: String,
+}
+
+/// The outcome of [`format_with_source_map`]: the formatted output, along with
+/// a source map from its leaves back to the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatWithSourceMap {
+    /// The formatted output, ending with a single newline
+    pub output: String,
+    /// The mapping of every leaf of the output, in output order
+    pub source_map: Vec<SourceMapping>,
+}
+
+/// Formats `input`, additionally returning which input node each region of
+/// the output came from, as needed by incremental editor integrations.
+///
+/// Only the leaves of the output are mapped: the whitespace that Topiary
+/// inserts between them, as well as any delimiters, come from no particular
+/// node and are left out of the source map. Collecting the source map is
+/// opt-in, so the other formatting functions do not pay for it. As with
+/// [`to_atoms`], language injections are not formatted.
+///
+/// # Errors
+///
+/// If parsing, the application of the query or rendering fails, a
+/// `FormatterError` will be returned.
+pub fn format_with_source_map(
+    input: &str,
+    query: &TopiaryQuery,
+    grammar: &topiary_tree_sitter_facade::Language,
+    indent: &str,
+) -> FormatterResult<FormatWithSourceMap> {
+    let tree = tree_sitter::parse(input, grammar, false)?;
+
+    // The byte range and kind of every node, by id
+    let nodes: HashMap<_, _> = atom_collection::dfs_flatten(&tree.root_node())
+        .iter()
+        .map(|node| (node.id(), (node.byte_range(), node.kind().into_owned())))
+        .collect();
+
+    let mut atoms = tree_sitter::apply_query_tree(tree, input, query)?;
+    atoms.post_process();
+    atoms.check_indent_balance()?;
+    let (rendered, leaf_ranges) =
+        pretty::render_with_leaf_ranges(&atoms[..], indent, DEFAULT_TAB_WIDTH)?;
+
+    // Terminating the output trims surrounding whitespace, so leaf ranges are
+    // shifted by as much as is trimmed from the start, and clamped to the end
+    let trimmed_start = rendered.len() - rendered.trim_start().len();
+    let content_len = rendered.trim().len();
+    let shift = |offset: usize| offset.saturating_sub(trimmed_start).min(content_len);
+
+    let source_map = leaf_ranges
+        .into_iter()
+        .filter_map(|(range, id)| {
+            let (input_range, node_kind) = nodes.get(&id)?.clone();
+            Some(SourceMapping {
+                output_range: shift(range.start)..shift(range.end),
+                input_range,
+                node_kind,
+            })
+        })
+        .collect();
+
+    Ok(FormatWithSourceMap {
+        output: pretty::terminate(&rendered, input, FinalNewline::Single),
+        source_map,
+    })
+}
+
+/// A cell of the input to [`format_cells`] that could not be parsed without
+/// errors.
+#[derive(Debug)]
+pub struct CellParsingError {
+    /// The index of the cell, counting from 0
+    pub cell: usize,
+    /// The parsing error
+    pub error: rootcause::Report<FormatterError>,
+}
+
+/// The outcome of [`format_cells`]: the formatted cells, rejoined with their
+/// separators, along with the cells that could not be parsed without errors.
+#[derive(Debug)]
+pub struct CellsFormatting {
+    /// The formatted output
+    pub output: String,
+    /// The cells that could not be parsed without errors, in input order. This
+    /// is only ever non-empty when parsing errors are tolerated.
+    pub parsing_errors: Vec<CellParsingError>,
+}
+
+/// Formats input made of several independent documents, or cells, such as
+/// those of a notebook or REPL session, with the same language.
+///
+/// The input is split into cells on every line that consists of `separator`
+/// alone. Each cell is parsed and formatted independently, per `operation`,
+/// and the formatted cells are rejoined with the separator lines kept as they
+/// were. Cells that contain nothing but whitespace are kept as they are.
+///
+/// When `operation` tolerates parsing errors, a cell that cannot be parsed
+/// without errors does not prevent the others from being formatted: it is
+/// formatted as tolerated, and its parsing error is returned along with the
+/// cell's index.
+///
+/// # Errors
+///
+/// If formatting a cell fails for any reason, including a parsing error when
+/// these are not tolerated, a `FormatterError` will be returned, to which the
+/// index of the cell is attached.
+///
+/// # Language injections
+///
+/// See [`formatter`] for the `resolve` argument's semantics.
+pub fn format_cells(
+    input: &str,
+    separator: &str,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+) -> FormatterResult<CellsFormatting> {
+    let tolerates_errors = match operation {
+        Operation::Format {
+            tolerate_parsing_errors,
+            ..
+        } => tolerate_parsing_errors.tolerates_errors(),
+        _ => false,
+    };
+
+    // The cells of the input, each with the separator line that follows it, if any
+    let mut cells = vec![(String::new(), None)];
+    for line in input.split_inclusive('\n') {
+        if line.trim_end_matches(['\n', '\r']) == separator {
+            cells.last_mut().expect("never empty").1 = Some(line);
+            cells.push((String::new(), None));
+        } else {
+            cells.last_mut().expect("never empty").0.push_str(line);
+        }
+    }
+
+    let mut formatting = CellsFormatting {
+        output: String::new(),
+        parsing_errors: Vec::new(),
+    };
+
+    for (index, (cell, separator_line)) in cells.into_iter().enumerate() {
+        if cell.trim().is_empty() {
+            formatting.output.push_str(&cell);
+        } else {
+            if tolerates_errors
+                && let Err(error) = tree_sitter::parse(&cell, &language.grammar, false)
+            {
+                formatting
+                    .parsing_errors
+                    .push(CellParsingError { cell: index, error });
+            }
+
+            let mut output = Vec::new();
+            formatter_str(&cell, &mut output, language, operation, resolve)
+                .attach(format!("In cell {index}"))?;
+            formatting
+                .output
+                .push_str(&String::from_utf8(output).context_to()?);
+        }
+
+        if let Some(separator_line) = separator_line {
+            if !formatting.output.is_empty() && !formatting.output.ends_with('\n') {
+                formatting.output.push('\n');
+            }
+            formatting.output.push_str(separator_line);
+        }
+    }
+
+    Ok(formatting)
+}
+
+/// Shrinks an input whose formatting is not idempotent to a smaller input
+/// whose formatting still is not, suitable for a bug report.
+///
+/// Starting from `input`, this repeatedly tries candidates made of the source
+/// of one of its syntax nodes alone, or of the input with one of its syntax
+/// nodes removed, largest nodes first. The first candidate that still fails
+/// the idempotence check replaces the input, until none does. Candidates are
+/// thus snapped to node boundaries; those that cannot be parsed without errors
+/// are discarded. At most `max_runs` candidates are formatted, after which the
+/// smallest reproducer found so far is returned.
+///
+/// Returns `None` if formatting `input` per `operation` does not fail the
+/// idempotence check, which is always the case for operations other than
+/// formatting. The idempotence check is performed even if `operation` skips
+/// it.
+///
+/// # Errors
+///
+/// If formatting `input` fails for a reason other than idempotence, that
+/// `FormatterError` will be returned.
+///
+/// # Language injections
+///
+/// See [`formatter`] for the `resolve` argument's semantics.
+pub fn minimize_idempotence_failure(
+    input: &str,
+    language: &Language,
+    operation: Operation,
+    resolve: Option<&LanguageResolver<'_>>,
+    max_runs: usize,
+) -> FormatterResult<Option<String>> {
+    let Operation::Format {
+        tolerate_parsing_errors,
+        final_newline,
+        line_ending,
+        max_line_width,
+        ..
+    } = operation
+    else {
+        return Ok(None);
+    };
+
+    let format = |candidate: &str,
+                  tolerate_parsing_errors: ParsingErrorTolerance|
+     -> FormatterResult<String> {
+        let tree = tree_sitter::parse(
+            candidate,
+            &language.grammar,
+            tolerate_parsing_errors.tolerates_errors(),
+        )?;
+        format_tree_to_string(
+            tree,
+            candidate,
+            language,
+            &FormatOptions {
+                skip_idempotence: false,
+                tolerate_parsing_errors,
+                final_newline,
+                line_ending,
+                max_line_width,
+                strict: false,
+                only_kinds: Vec::new(),
+                only_lines: None,
+                preserve_whitespace: false,
+            },
+            resolve,
+            None,
+            None,
+        )
+        .map(|(formatted, _)| formatted)
+    };
+    let fails_idempotence = |report: &rootcause::Report<FormatterError>| {
+        matches!(
+            report.current_context(),
+            FormatterError::Idempotence(_) | FormatterError::IdempotenceParsing
+        )
+    };
+
+    match format(input, tolerate_parsing_errors) {
+        Ok(_) => return Ok(None),
+        Err(report) if !fails_idempotence(&report) => return Err(report),
+        Err(_) => {}
+    }
+
+    let mut reproducer = input.to_owned();
+    let mut runs = 0;
+
+    'shrink: loop {
+        let tree = tree_sitter::parse(&reproducer, &language.grammar, true)?;
+        let mut tried = HashSet::new();
+
+        // Nodes are visited in pre-order, so larger nodes are tried first
+        for node in atom_collection::dfs_flatten(&tree.root_node()) {
+            let (start, end) = (node.start_byte() as usize, node.end_byte() as usize);
+            let candidates = [
+                reproducer[start..end].to_owned(),
+                format!("{}{}", &reproducer[..start], &reproducer[end..]),
+            ];
+
+            for candidate in candidates {
+                if candidate.trim().is_empty()
+                    || candidate.len() >= reproducer.len()
+                    || !tried.insert(candidate.clone())
+                {
+                    continue;
+                }
+                if runs == max_runs {
+                    log::info!("Stopped minimizing after {runs} formatting runs");
+                    break 'shrink;
+                }
+                runs += 1;
+
+                if format(&candidate, ParsingErrorTolerance::None)
+                    .is_err_and(|report| fails_idempotence(&report))
+                {
+                    log::debug!("Minimized reproducer to {} bytes", candidate.len());
+                    reproducer = candidate;
+                    continue 'shrink;
+                }
+            }
+        }
+
+        break;
+    }
+
+    Ok(Some(reproducer))
+}
+
+/// Builds the post-processed atoms for a parsed input: this is everything in
+/// the formatting pipeline up to, but excluding, pretty printing.
+fn atomise(
+    tree: topiary_tree_sitter_facade::Tree,
+    input_content: &str,
+    language: &Language,
+    options: &FormatOptions,
+    resolve: Option<&LanguageResolver<'_>>,
+    mut stats: Option<&mut FormatStats>,
+) -> FormatterResult<atom_collection::AtomCollection> {
+    let tolerate_parsing_errors = options.tolerate_parsing_errors;
+
+    log::debug!("Discovering potentially injected languages");
+    let spans = match &language.injection_query {
+        Some(injection_query) => collect_injections(&tree, input_content, injection_query)?,
+        None => Vec::new(),
+    };
+
+    // Create a list of nodes that are injection formatted.
+    // These must will be treated as leaves (although, in all likelihood, they already are).
+    let injection_leaf_nodes = spans.iter().map(|span| span.node_id);
+
+    // All the work related to tree-sitter and the query is done here
+    log::debug!("Apply Tree-sitter query");
+
+    let mut atoms = timed(
+        stats.as_deref_mut().map(|s| &mut s.query_apply_time),
+        || {
+            tree_sitter::apply_query_tree_with_forced_leaves(
+                tree,
+                input_content,
+                &language.formatting_query,
+                injection_leaf_nodes,
+                atom_collection::Verbatim {
+                    errors: tolerate_parsing_errors == ParsingErrorTolerance::Verbatim,
+                    formatting_markers: language.formatting_markers.as_ref(),
+                    only_kinds: &options.only_kinds,
+                    only_lines: options.only_lines.as_deref(),
+                    whitespace: options.preserve_whitespace,
+                },
+                language.tab_width(),
+            )
+        },
+    )?;
+
+    rewrite_injected_leaves(&mut atoms, spans, resolve, tolerate_parsing_errors)?;
+
+    if let Some(max_line_width) = options.max_line_width {
+        atoms.limit_line_width(max_line_width, language.indent());
+    }
+    atoms.limit_blank_lines(language.max_blank_lines());
+    atoms.attach_comments(language.comment_attachment);
+    if options.preserve_whitespace {
+        atoms.preserve_original_whitespace();
+    }
+
+    // Various post-processing of whitespace
+    timed(
+        stats.as_deref_mut().map(|s| &mut s.post_process_time),
+        || atoms.post_process(),
+    );
+    atoms.check_indent_balance()?;
+
+    if let Some(stats) = stats {
+        stats.atom_count = atoms[..].len();
+        stats.query_match_count = atoms.query_match_count();
+    }
+
+    Ok(atoms)
+}
+
+fn rewrite_injected_leaves(
+    atoms: &mut atom_collection::AtomCollection,
+    spans: Vec<InjectionSpan>,
+    resolve: Option<&LanguageResolver<'_>>,
+    tolerate_parsing_errors: ParsingErrorTolerance,
+) -> FormatterResult<()> {
+    for span in spans {
+        // Injections in regions where formatting is disabled are left as is
+        if atoms.formatting_disabled(span.node_id) {
+            continue;
+        }
+
+        // If the injected language is unsupported, skip formatting this injection
+        // by continuing the loop. This leaves the original, unformatted text intact.
+        let Some(inner_language) = resolve_injected_language(resolve, &span.language)? else {
+            log::warn!(
+                "Skipping injection for unsupported language: {}",
+                span.language
+            );
+            continue;
+        };
+
+        // The host's escape sequences are not part of the injected language
+        let unescaped = replace_sequences(
+            span.content,
+            span.escapes
+                .iter()
+                .map(|(escaped, unescaped)| (escaped.as_str(), unescaped.as_str())),
+        );
+
+        let mut formatted_inner = Vec::new();
+        formatter_str(
+            &unescaped,
+            &mut formatted_inner,
+            &inner_language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            resolve,
+        )?;
+
+        let formatted_inner = String::from_utf8(formatted_inner).context_to()?;
+        let formatted_inner = replace_sequences(
+            formatted_inner.trim_end_matches('\n'),
+            span.escapes
+                .iter()
+                .map(|(escaped, unescaped)| (unescaped.as_str(), escaped.as_str())),
+        );
+
+        // An injection within a host leaf, such as a string, keeps the
+        // whitespace with which it is delimited
+        let trimmed = span.content.trim();
+        let leading = &span.content[..span.content.find(trimmed).unwrap_or(0)];
+        let trailing = &span.content[leading.len() + trimmed.len()..];
+        let delimited = format!("{leading}{}{trailing}", formatted_inner.trim_start());
+
+        if !atoms.rewrite_injected_leaf_content(span.node_id, formatted_inner)
+            && !atoms.splice_injected_content(&span, &delimited)
+        {
+            return Err(report!(FormatterError::Internal(format!(
+                "Could not find leaf for injected {} span",
+                span.language
+            ))));
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces every occurrence of the first sequence of each pair with the
+/// second, in a single pass, so that replacements are never replaced again.
+/// Where several sequences occur at the same point, the first pair wins.
+fn replace_sequences<'a>(
+    content: &str,
+    pairs: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+) -> String {
+    let mut replaced = String::with_capacity(content.len());
+    let mut rest = content;
+
+    'outer: while let Some(c) = rest.chars().next() {
+        for (from, to) in pairs.clone() {
+            if !from.is_empty()
+                && let Some(after) = rest.strip_prefix(from)
+            {
+                replaced.push_str(to);
+                rest = after;
+                continue 'outer;
+            }
+        }
+
+        replaced.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    replaced
+}
+
+/// Resolves a language string from an injection (e.g. "rust" in ```rust) into a `Language`
+/// instance.
+///
+/// Returns `Ok(None)` if the language is not configured or cannot be resolved, allowing the caller
+/// to gracefully skip formatting rather than failing the entire formatting operation.
+fn resolve_injected_language(
+    resolve: Option<&LanguageResolver<'_>>,
+    language: &str,
+) -> FormatterResult<Option<Arc<Language>>> {
+    let Some(resolve) = resolve else {
+        return Ok(None);
+    };
+
+    match resolve(language) {
+        Ok(Some(language_cfg)) => {
+            log::info!("resolved injected language: {language}");
+            Ok(Some(language_cfg))
+        }
+        Ok(None) => Ok(None),
+        Err(err)
+            if matches!(
+                err.current_context(),
+                FormatterError::InjectionLanguageResolution { .. }
+            ) =>
+        {
+            Err(err)
+        }
+        Err(err) => Err(err.context(FormatterError::InjectionLanguageResolution {
+            language: language.to_owned(),
+        })),
+    }
+}
+
+/// Simple helper function to read the full content of an io Read stream
+fn read_input(input: &mut dyn io::Read) -> Result<String, io::Error> {
+    let mut content = String::new();
+    input.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// The size, in bytes, above which a failed idempotence check does not log the
+/// full diff between the two formatting passes
+const MAX_LOGGED_DIFF_LENGTH: usize = 64 * 1024;
+
+/// Perform the idempotence check. Given the already formatted content of the
+/// file, formats the content again and checks if the two are identical.
+/// Result in: `Ok(())`` if the idempotence check succeeded (the content is
+/// identical to the formatted content)
+///
+/// # Errors
+///
+/// `Err(FormatterError::Idempotence(...))` if the idempotence check failed
+/// `Err(FormatterError::Formatting(...))` if the formatting failed
+fn idempotence_check(
+    content: &str,
+    language: &Language,
+    options: &FormatOptions,
+    resolve: Option<&LanguageResolver<'_>>,
+    post_process: Option<&mut PostProcessor>,
+) -> FormatterResult<()> {
+    log::info!("Checking for idempotence ...");
+
+    let reformatted = tree_sitter::parse(
+        content,
+        &language.grammar,
+        options.tolerate_parsing_errors.tolerates_errors(),
+    )
+    .and_then(|tree| {
+        format_tree_to_string(
+            tree,
+            content,
+            language,
+            &FormatOptions {
+                skip_idempotence: true,
+                line_ending: LineEnding::Lf,
+                strict: false,
+                ..options.clone()
+            },
+            resolve,
+            post_process,
+            None,
+        )
+        .map(|(reformatted, _)| reformatted)
+    });
+
+    match reformatted {
+        Ok(reformatted) => {
+            if content == reformatted {
+                Ok(())
+            } else {
+                let details = IdempotenceDetails::new(content, &reformatted);
+                log::error!("Failed idempotence check");
+                // A full diff is too expensive for large outputs
+                if content.len().max(reformatted.len()) <= MAX_LOGGED_DIFF_LENGTH {
+                    log::error!("{}", StrComparison::new(content, &reformatted));
+                }
+                Err(report!(FormatterError::Idempotence(Box::new(details))))
+            }
+        }
+        Err(report) if matches!(report.current_context(), FormatterError::Parsing(_)) => {
+            Err(report.context(FormatterError::IdempotenceParsing))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use test_log::test;
+
+    use crate::{
+        Atom, CommentAttachment, Diagnostic, DiagnosticRange, FinalNewline, Formatter,
+        FormatterError, FormattingMarkers, IdempotenceDetails, InjectionQuery, Language,
+        LineEnding, Operation, ParsingErrorTolerance, Position, PredicateArgument, Severity,
+        SpanAttachment, TextEdit, TopiaryQuery, Visualisation, Warning, apply_query,
+        collect_injections, format_bytes, format_cells, format_lines, format_range,
+        format_reader_to_writer, format_string, format_with_source_map, formatter, formatter_str,
+        formatter_str_with_stats, minimize_idempotence_failure, needs_formatting, parse,
+        query_coverage, replace_sequences, supported_captures, supported_predicates,
+        test_utils::pretty_assert_eq, to_atoms, validate_query,
+    };
+
+    fn language(name: &str, formatting_query: &str, injection_query: Option<&str>) -> Language {
+        let config = topiary_config::Configuration::default();
+        let config_language = config.get_language(name).unwrap();
+        let grammar = config_language.grammar().unwrap();
+
+        Language {
+            name: name.to_owned(),
+            formatting_query: TopiaryQuery::new(&grammar, formatting_query).unwrap(),
+            injection_query: injection_query
+                .map(|query_content| InjectionQuery::new(&grammar, query_content).unwrap()),
+            grammar,
+            indent: config_language.indent(),
+            max_blank_lines: config_language.max_blank_lines(),
+            formatting_markers: None,
+            comment_attachment: CommentAttachment::default(),
+            tab_width: None,
+        }
+    }
+
+    fn ocamllex_language() -> Language {
+        language(
+            "ocamllex",
+            topiary_queries::ocamllex(),
+            Some(topiary_queries::ocamllex_injections()),
+        )
+    }
+
+    fn ocaml_language() -> Language {
+        language("ocaml", topiary_queries::ocaml(), None)
+    }
+
+    fn unstable_ocaml_language() -> Language {
+        language(
+            "ocaml",
+            r#"
+((value_name) @append_delimiter
+ (#delimiter! "x"))
+"#,
+            None,
+        )
+    }
+
+    /// Attempt to parse invalid json, expecting a failure
+    #[test(tokio::test)]
+    async fn parsing_error_fails_formatting() {
+        let mut input = r#"{"foo":{"bar"}}"#.as_bytes();
+        let mut output = Vec::new();
+        let language = language("json", "(#language! json)", None);
+
+        let mut result = formatter(
+            &mut input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        );
+
+        if let Some(range) = result
+            .get_span()
+            .and_then(|s| s.range)
+            .inspect(|r| println!("{r:?}"))
+            && range.start_point().row() == 0
+            && range.end_point().row() == 0
+        {
+            return;
+        }
+        panic!("Expected a parsing error on line 1, but got {result:?}");
+    }
+
+    #[test(tokio::test)]
+    async fn parsing_error_diagnostic_round_trips() {
+        let mut input = "{\"foo\":\n  {\"bar\"}}".as_bytes();
+        let mut output = Vec::new();
+        let language = language("json", "(#language! json)", None);
+
+        let report = formatter(
+            &mut input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        )
+        .unwrap_err();
+
+        let diagnostic = Diagnostic::from_report(&report);
+        let json = diagnostic.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value["kind"], "parsing");
+        assert_eq!(value["severity"], "error");
+        assert_eq!(value["message"], report.current_context().to_string());
+        assert_eq!(value["range"]["start_line"], 1);
+        assert!(value.get("offset").is_none());
+        assert_eq!(
+            serde_json::from_str::<Diagnostic>(&json).unwrap(),
+            diagnostic
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn parsing_error_carries_the_range_of_the_error_node() {
+        let mut input = "{\"foo\":\n  {\"bar\"}}".as_bytes();
+        let mut output = Vec::new();
+        let language = language("json", "(#language! json)", None);
+
+        let report = formatter(
+            &mut input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        )
+        .unwrap_err();
+
+        // The payload agrees with the span attached to the report
+        let FormatterError::Parsing(Some(range)) = *report.current_context() else {
+            panic!("Expected a parsing error with a range, but got {report:?}");
+        };
+        assert_eq!(Diagnostic::from_report(&report).range, Some(range));
+        assert_eq!(range.start_line, 1);
+        assert!(
+            report
+                .current_context()
+                .to_string()
+                .contains(&format!("line 2, column {}", range.start_col + 1))
+        );
+    }
+
+    #[test]
+    fn query_error_diagnostic_includes_offset() {
+        let config = topiary_config::Configuration::default();
+        let grammar = config.get_language("json").unwrap().grammar().unwrap();
+        let query = "(string) @leaf\n(no_such_node) @leaf";
+
+        let report = InjectionQuery::new(&grammar, query).unwrap_err();
+        let diagnostic = Diagnostic::from_report(&report);
+
+        assert_eq!(diagnostic.kind, "query");
+        assert_eq!(diagnostic.offset, Some(16));
+        assert_eq!(
+            diagnostic.range,
+            Some(DiagnosticRange {
+                start_line: 1,
+                start_col: 1,
+                end_line: 1,
+                end_col: 20,
+            })
+        );
+
+        let json = diagnostic.to_json();
+        assert_eq!(
+            serde_json::from_str::<Diagnostic>(&json).unwrap(),
+            diagnostic
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn tolerate_parsing_errors() {
+        // Contains the invalid object {"bar"   "baz"}. It should be left untouched.
+        let mut input = "{\"one\":{\"bar\"   \"baz\"},\"two\":\"bar\"}".as_bytes();
+        let expected = "{ \"one\": {\"bar\"   \"baz\"}, \"two\": \"bar\" }\n";
+
+        let mut output = Vec::new();
+        let language = language("json", topiary_queries::json(), None);
+
+        formatter(
+            &mut input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::BestEffort,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        )
+        .unwrap();
+
+        let formatted = String::from_utf8(output).unwrap();
+        log::debug!("{formatted}");
+
+        pretty_assert_eq(expected, &formatted);
+    }
+
+    /// The text of every outermost ERROR node in `input`
+    fn error_texts<'a>(input: &'a str, language: &Language) -> Vec<&'a str> {
+        fn walk<'a>(
+            node: &topiary_tree_sitter_facade::Node,
+            input: &'a str,
+            acc: &mut Vec<&'a str>,
+        ) {
+            if node.kind() == "ERROR" {
+                acc.push(&input[node.byte_range()]);
+                return;
+            }
+            for child in node.children(&mut node.walk()) {
+                walk(&child, input, acc);
+            }
+        }
+
+        let tree = parse(input, &language.grammar, true).unwrap();
+        let mut texts = Vec::new();
+        walk(&tree.root_node(), input, &mut texts);
+        texts
+    }
+
+    /// The options with which the tests format their input. Unless a test
+    /// says otherwise, the idempotence check is skipped and parsing errors are
+    /// not tolerated.
+    #[derive(Clone, Copy)]
+    struct Options {
+        skip_idempotence: bool,
+        tolerate_parsing_errors: ParsingErrorTolerance,
+        final_newline: FinalNewline,
+        line_ending: LineEnding,
+        max_line_width: Option<usize>,
+        strict: bool,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Self {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            }
+        }
+    }
+
+    impl Options {
+        fn operation(self) -> Operation {
+            Operation::Format {
+                skip_idempotence: self.skip_idempotence,
+                tolerate_parsing_errors: self.tolerate_parsing_errors,
+                final_newline: self.final_newline,
+                line_ending: self.line_ending,
+                max_line_width: self.max_line_width,
+                strict: self.strict,
+            }
+        }
+    }
+
+    /// Formats `input` as `language`, with the given `options`.
+    fn format_with(input: &str, language: &Language, options: Options) -> String {
+        let mut output = Vec::new();
+        formatter_str(input, &mut output, language, options.operation(), None).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test(tokio::test)]
+    async fn format_stats_accompany_unchanged_output() {
+        let input = "{\"a\":[1,2,3],\"b\":{\"c\":null}}";
+        let language = language("json", topiary_queries::json(), None);
+        let operation = Operation::Format {
+            skip_idempotence: true,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
+        };
+
+        let mut expected = Vec::new();
+        let expected_warnings =
+            formatter_str(input, &mut expected, &language, operation, None).unwrap();
+
+        let mut output = Vec::new();
+        let (stats, warnings) =
+            formatter_str_with_stats(input, &mut output, &language, operation, None).unwrap();
+
+        pretty_assert_eq(
+            &String::from_utf8(expected).unwrap(),
+            &String::from_utf8(output).unwrap(),
+        );
+        assert_eq!(expected_warnings, warnings);
+        assert!(stats.atom_count > 0);
+        assert!(stats.query_match_count > 0);
+    }
+
+    #[test(tokio::test)]
+    async fn blank_input_softline_reproduces_input_paragraphs() {
+        let append = language("json", r#"(array "," @append_blank_input_softline)"#, None);
+        let prepend = language(
+            "json",
+            r#"(array "," . (_) @prepend_blank_input_softline)"#,
+            None,
+        );
+
+        for (input, expected) in [
+            ("[1,2]", "[1, 2]\n"),
+            ("[1,\n2]", "[1,\n2]\n"),
+            ("[1,\n\n2]", "[1,\n\n2]\n"),
+            ("[1,\n\n\n2]", "[1,\n\n2]\n"),
+        ] {
+            for language in [&append, &prepend] {
+                let formatted = format_with(input, language, Options::default());
+                pretty_assert_eq(expected, &formatted);
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn scope_ids_are_namespaced_by_query_name() {
+        // The softline of the "objects" queries, after the array's first
+        // number, is outside of any object. It must not be resolved by the
+        // scope of the "arrays" queries, despite both scopes being "body".
+        let query = |shared: &str| {
+            format!(
+                r#"
+(array "[" @append_begin_scope "]" @prepend_end_scope
+  (#scope_id! "body") (#query_name! "arrays") {shared})
+(array "," @append_spaced_scoped_softline
+  (#scope_id! "body") (#query_name! "arrays") {shared})
+(object "{{" @append_begin_scope "}}" @prepend_end_scope
+  (#scope_id! "body") (#query_name! "objects") {shared})
+((number) @append_spaced_scoped_softline
+  (#scope_id! "body") (#query_name! "objects") {shared})
+"#
+            )
+        };
+        let input = "[1,\n{\"a\":2}]";
+
+        let namespaced = language("json", &query(""), None);
+        let formatted = format_with(input, &namespaced, Options::default());
+        pretty_assert_eq("[1,\n{\"a\":2 }]\n", &formatted);
+
+        // Scopes that are declared as shared interfere as they would unnamed
+        let shared = language("json", &query("(#shared_scope!)"), None);
+        let formatted = format_with(input, &shared, Options::default());
+        pretty_assert_eq("[1\n,\n{\"a\":2 }]\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn doubled_hardlines_collapse_but_blank_lines_survive() {
+        let input = "[1,2,\n\n3,4]";
+        let query = r#"
+(array "[" @append_indent_start)
+(array "]" @prepend_indent_end @prepend_hardline)
+(array "," @append_hardline)
+(array (number) @prepend_hardline @allow_blank_line_before)
+"#;
+        let language = language("json", query, None);
+
+        let formatted = format_with(input, &language, Options::default());
+
+        pretty_assert_eq("[\n  1,\n  2,\n\n  3,\n  4\n]\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn verbatim_parsing_errors_are_untouched() {
+        let input = "{\"one\":{\"bar\"   \"baz\"},\"two\":\"bar\"}";
+        let query = format!("{}\n(document) @upper_case", topiary_queries::json());
+        let language = language("json", &query, None);
+
+        let errors = error_texts(input, &language);
+        assert!(!errors.is_empty());
+
+        let best_effort = format_with(
+            input,
+            &language,
+            Options {
+                tolerate_parsing_errors: ParsingErrorTolerance::BestEffort,
+                ..Default::default()
+            },
+        );
+        let verbatim = format_with(
+            input,
+            &language,
+            Options {
+                tolerate_parsing_errors: ParsingErrorTolerance::Verbatim,
+                ..Default::default()
+            },
+        );
+
+        // Outside the ERROR nodes, both modes format alike
+        assert!(best_effort.contains("\"TWO\": \"BAR\""));
+        assert!(verbatim.contains("\"TWO\": \"BAR\""));
+        for error in errors {
+            if error.chars().any(char::is_lowercase) {
+                assert!(!best_effort.contains(error));
+            }
+            assert!(verbatim.contains(error));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn verbatim_parsing_errors_keep_surrounding_line_breaks() {
+        let input = "{\n\"one\":1,\n\"two\": {\"bar\"   \"baz\"},\n\n\"three\":3\n}\n";
+        let language = language("json", topiary_queries::json(), None);
+
+        let errors = error_texts(input, &language);
+        assert!(!errors.is_empty());
+
+        let formatted = format_with(
+            input,
+            &language,
+            Options {
+                tolerate_parsing_errors: ParsingErrorTolerance::Verbatim,
+                ..Default::default()
+            },
+        );
+
+        // The error-free parts of the file are formatted...
+        assert!(formatted.contains("\"one\": 1,"));
+        assert!(formatted.lines().any(|line| line.trim() == "\"three\": 3"));
+        // ...while the ERROR nodes are passed through as they were
+        for error in errors {
+            assert!(formatted.contains(error));
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn collect_injections_returns_content_span() {
+        let input = r#"rule token = parse
+  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+"#;
+        let language = ocamllex_language();
+        let tree = parse(input, &language.grammar, false).unwrap();
+        let spans =
+            collect_injections(&tree, input, language.injection_query.as_ref().unwrap()).unwrap();
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].language, "ocaml");
+        assert_eq!(
+            spans[0].content,
+            r#"let values=[1;2;3] in List.map (fun x->x+1) values"#
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn collect_injections_skips_pattern_without_content_capture() {
+        let input = r#"rule token = parse
+  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+"#;
+        let mut language = ocamllex_language();
+        language.injection_query = Some(
+            InjectionQuery::new(
+                &language.grammar,
+                r#"
+(ocaml) @injection.language
+"#,
+            )
+            .unwrap(),
+        );
+        let tree = parse(input, &language.grammar, false).unwrap();
+        let spans =
+            collect_injections(&tree, input, language.injection_query.as_ref().unwrap()).unwrap();
+
+        assert!(spans.is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn unresolved_injection_skips_formatting() {
+        let input = r#"rule token = parse
+  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+"#;
+        let language = ocamllex_language();
+        let mut output = Vec::new();
+
+        let result = formatter_str(
+            input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        );
+
+        assert!(result.is_ok());
+    }
+
+    #[test(tokio::test)]
+    async fn resolver_error_fails_formatting() {
+        let input = r#"rule token = parse
+  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+"#;
+        let language = ocamllex_language();
+        let mut output = Vec::new();
+
+        let result = formatter_str(
+            input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            Some(&|_| {
+                Err(rootcause::report!(FormatterError::Query(
+                    "resolver failed while loading language".into()
+                )))
+            }),
+        );
+
+        assert!(matches!(
+            result,
+            Err(ref report)
+                if matches!(
+                    report.current_context(),
+                    FormatterError::InjectionLanguageResolution { language } if language == "ocaml"
+                )
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn resolved_injection_rewrites_forced_leaf() {
+        let input = r#"rule token = parse
+  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+"#;
+        let language = ocamllex_language();
+        let inner_language: Arc<Language> = Arc::new(ocaml_language());
+        let mut output = Vec::new();
+
+        formatter_str(
+            input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            Some(&|name| Ok((name == "ocaml").then_some(inner_language.clone()))),
+        )
+        .unwrap();
+
+        let formatted = String::from_utf8(output).unwrap();
+        pretty_assert_eq(
+            r#"rule token = parse
+  | "x" { let values = [1; 2; 3] in List.map (fun x -> x + 1) values }"#,
+            formatted.trim_end(),
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn injection_within_string_is_unescaped_and_spliced() {
+        let input = r#"{"a": "[1,2]", "b": "{\"c\":1}"}"#;
+        let inner_language = Arc::new(language("json", topiary_queries::json(), None));
+        let language = language(
+            "json",
+            topiary_queries::json(),
+            Some(
+                r#"
+(
+  (string (string_content) @injection.content)
+  (#injection_language! "json")
+  (#injection_escape! "\\\"" "\"")
+)
+"#,
+            ),
+        );
+        let mut output = Vec::new();
+
+        formatter_str(
+            input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            Some(&|name| Ok((name == "json").then_some(inner_language.clone()))),
+        )
+        .unwrap();
+
+        pretty_assert_eq(
+            r#"{ "a": "[ 1, 2 ]", "b": "{ \"c\": 1 }" }
+"#,
+            &String::from_utf8(output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn replace_sequences_replaces_in_a_single_pass() {
+        let unescape = [("\\\\", "\\"), ("\\\"", "\"")];
+        let unescaped = replace_sequences(r#"a\\"b\"c"#, unescape.into_iter());
+        assert_eq!(unescaped, r#"a\"b"c"#);
+
+        let escape = unescape.map(|(escaped, unescaped)| (unescaped, escaped));
+        assert_eq!(
+            replace_sequences(&unescaped, escape.into_iter()),
+            r#"a\\\"b\"c"#
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn invalid_injected_source_fails_formatting() {
+        let input = r#"rule token = parse
+  | "x" { let x = }
+"#;
+        let language = ocamllex_language();
+        let inner_language: Arc<Language> = Arc::new(ocaml_language());
+        let mut output = Vec::new();
+
+        let result = formatter_str(
+            input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            Some(&|name| Ok((name == "ocaml").then_some(inner_language.clone()))),
+        );
+
+        assert!(
+            matches!(result, Err(ref report) if matches!(report.current_context(), FormatterError::Parsing(_)))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn non_idempotent_injection_fails_outer_idempotence() {
+        let input = r#"rule token = parse
+  | "x" { value }
+"#;
+        let language = ocamllex_language();
+        let inner_language: Arc<Language> = Arc::new(unstable_ocaml_language());
+        let mut output = Vec::new();
+
+        let result = formatter_str(
+            input,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            Some(&|name| Ok((name == "ocaml").then_some(inner_language.clone()))),
+        );
+
+        assert!(matches!(
+            result,
+            Err(ref report) if matches!(report.current_context(), FormatterError::Idempotence(_))
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn idempotence_error_locates_difference() {
+        let language = unstable_ocaml_language();
+        let mut output = Vec::new();
+
+        let report = formatter_str(
+            "value",
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        )
+        .unwrap_err();
+
+        let FormatterError::Idempotence(details) = report.current_context() else {
+            panic!("Expected an idempotence error, but got {report:?}");
+        };
+
+        assert_eq!(
+            **details,
+            IdempotenceDetails {
+                range: 6..6,
+                line: 1,
+                before: "valuex".into(),
+                after: "valuexx".into(),
+            }
+        );
+        assert!(report.current_context().to_string().contains("line 1"));
+    }
+
+    #[test(tokio::test)]
+    async fn minimize_idempotence_failure_shrinks_input() {
+        // Only the names starting with "b" are unstable
+        let language = language(
             "ocaml",
             r#"
+(value_definition) @append_hardline
+"let" @append_space
+"=" @prepend_space @append_space
 ((value_name) @append_delimiter
+ (#match? @append_delimiter "^b")
  (#delimiter! "x"))
 "#,
             None,
+        );
+        let operation = Operation::Format {
+            skip_idempotence: true,
+            tolerate_parsing_errors: ParsingErrorTolerance::None,
+            final_newline: FinalNewline::Single,
+            line_ending: LineEnding::Lf,
+            max_line_width: None,
+            strict: false,
+        };
+        let input = "let a = 1\nlet b = 2\nlet c = 3\n";
+
+        let reproducer =
+            minimize_idempotence_failure(input, &language, operation, None, 100).unwrap();
+        assert_eq!(reproducer.as_deref(), Some("b"));
+
+        // Without any runs, the input is its own reproducer
+        let reproducer =
+            minimize_idempotence_failure(input, &language, operation, None, 0).unwrap();
+        assert_eq!(reproducer.as_deref(), Some(input));
+
+        let reproducer =
+            minimize_idempotence_failure("let a = 1\n", &language, operation, None, 100).unwrap();
+        assert_eq!(reproducer, None);
+    }
+
+    #[test]
+    fn idempotence_details_truncate_long_lines() {
+        let long_line = "é".repeat(100);
+        let first = format!("one\ntwo {long_line}\nthree\n");
+        let second = format!("one\ntwo  {long_line}\nthree\n");
+
+        let details = IdempotenceDetails::new(&first, &second);
+
+        assert_eq!(details.line, 2);
+        assert_eq!(details.range, 8..8);
+        assert_eq!(details.before.chars().count(), 81);
+        assert!(details.before.starts_with("two é"));
+        assert!(details.after.starts_with("two  é"));
+        assert!(details.after.ends_with('…'));
+    }
+
+    fn json_grammar() -> topiary_tree_sitter_facade::Language {
+        let config = topiary_config::Configuration::default();
+        config.get_language("json").unwrap().grammar().unwrap()
+    }
+
+    #[test]
+    fn validate_query_warns_about_unknown_captures() {
+        let query =
+            "; @comment_capture\n(pair key: (_) @append_space @key)\n(string) @leaf @_private";
+
+        let diagnostics = validate_query(query, &json_grammar(), &HashMap::new()).unwrap();
+
+        assert!(!diagnostics.has_errors());
+        assert_eq!(diagnostics.diagnostics.len(), 1);
+        assert_eq!(diagnostics.diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics.diagnostics[0].message.contains("@key"));
+        assert_eq!(
+            diagnostics.diagnostics[0].position,
+            Position { row: 2, column: 30 }
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn delete_if_empty_keeps_empty_containers_compact() {
+        let query = r#"
+(object
+  "{" @append_hardline @append_indent_start
+  "}" @prepend_hardline @prepend_indent_end
+  (#delete_if_empty!)
+)
+"#;
+        let language = language("json", query, None);
+
+        for (input, expected) in [
+            ("{}", "{}\n"),
+            ("{ }", "{}\n"),
+            // A comment is not trivia
+            ("{ /*c*/ }", "{\n  /*c*/\n}\n"),
+        ] {
+            let formatted = format_with(input, &language, Options::default());
+            pretty_assert_eq(expected, &formatted);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn match_predicates_gate_formatting_captures() {
+        let query = |operator: &str| {
+            format!(
+                r#"
+((number) @append_delimiter
+  (#{operator} @append_delimiter "^[0-9]+$")
+  (#delimiter! "i"))
+"#
+            )
+        };
+        let input = "[1,2.5,3]";
+
+        for (operator, expected) in [("match?", "[1i,2.5,3i]\n"), ("not-match?", "[1,2.5i,3]\n")] {
+            let language = language("json", &query(operator), None);
+            let formatted = format_with(input, &language, Options::default());
+            pretty_assert_eq(expected, &formatted);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn eq_predicates_gate_formatting_on_private_captures() {
+        // The key is only captured to be compared, not to be formatted
+        let query = |operator: &str| {
+            format!(
+                r#"
+(pair
+  key: (string (string_content) @_key)
+  ":" @append_space
+  (#{operator} @_key "a"))
+"#
+            )
+        };
+        let input = r#"{"a":1,"b":2}"#;
+
+        for (operator, expected) in [
+            ("eq?", "{\"a\": 1,\"b\":2}\n"),
+            ("not-eq?", "{\"a\":1,\"b\": 2}\n"),
+        ] {
+            let language = language("json", &query(operator), None);
+            let formatted = format_with(input, &language, Options::default());
+            pretty_assert_eq(expected, &formatted);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn capture_aliases_format_as_their_expansions() {
+        let expanded = language("json", topiary_queries::json(), None);
+        let aliased_query = topiary_queries::json()
+            .replace("(string) @leaf", "(string) @verbatim")
+            .replace(
+                "@append_spaced_softline @append_indent_start",
+                "@open_block",
+            )
+            .replace(
+                "@prepend_spaced_softline @prepend_indent_end",
+                "@close_block",
+            );
+        let aliased_query = format!(
+            r#"{aliased_query}
+((null) @do_nothing
+  (#define! "verbatim" "leaf")
+  (#define! "open_block" "append_spaced_softline" "append_indent_start")
+  (#define! "close_block" "prepend_spaced_softline" "prepend_indent_end"))
+"#
+        );
+        let aliased = language("json", &aliased_query, None);
+
+        for input in [
+            r#"{"a": [1, 2], "b": "x"}"#,
+            "{\n\"a\": [1,\n2], \"b\": {\"c\": \"  d  \"}}",
+        ] {
+            pretty_assert_eq(
+                &format_with(input, &expanded, Options::default()),
+                &format_with(input, &aliased, Options::default()),
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn capture_aliases_must_not_collide_with_built_in_captures() {
+        for definition in [
+            r#"(#define! "append_space" "append_hardline")"#,
+            r#"(#define! "spaced" "not_a_capture")"#,
+            r#"(#define! "spaced")"#,
+        ] {
+            let query = format!("((number) @spaced {definition})");
+            let language = language("json", &query, None);
+            let mut output = Vec::new();
+
+            let error = formatter_str(
+                "[1]",
+                &mut output,
+                &language,
+                Operation::Format {
+                    skip_idempotence: true,
+                    tolerate_parsing_errors: ParsingErrorTolerance::None,
+                    final_newline: FinalNewline::Single,
+                    line_ending: LineEnding::Lf,
+                    max_line_width: None,
+                    strict: false,
+                },
+                None,
+            )
+            .unwrap_err();
+
+            assert!(
+                matches!(error.current_context(), FormatterError::Query(_)),
+                "{definition}"
+            );
+            assert!(
+                validate_query(&query, &json_grammar(), &HashMap::new())
+                    .unwrap()
+                    .has_errors()
+            );
+        }
+    }
+
+    #[test]
+    fn validate_query_reports_all_predicate_errors() {
+        let query = r#"
+((number) @append_delimiter (#delimiter! "," ";"))
+((number) @append_space (#single_line_only! "x"))
+((string) @leaf (#no_such_predicate!))
+((null) @append_space (#multi_line_only!))
+"#;
+
+        let diagnostics = validate_query(query, &json_grammar(), &HashMap::new()).unwrap();
+        let errors: Vec<_> = diagnostics
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.position.row))
+            .collect();
+
+        assert!(diagnostics.has_errors());
+        assert_eq!(
+            errors,
+            vec![
+                (Severity::Error, 2),
+                (Severity::Error, 3),
+                (Severity::Error, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_query_fails_on_syntax_errors() {
+        let result = validate_query("(string @leaf", &json_grammar(), &HashMap::new());
+
+        assert!(matches!(
+            result,
+            Err(ref report) if matches!(report.current_context(), FormatterError::Query(_))
+        ));
+    }
+
+    #[test]
+    fn node_aliases_resolve_to_grammar_kinds() {
+        let grammar = json_grammar();
+        let query = "; A stable name for (object)\n(dictionary (pair \":\" @append_space))";
+        let node_aliases = HashMap::from([
+            ("dictionary".to_owned(), "object".to_owned()),
+            // Ignored, with a warning, as the grammar has no such kind
+            ("renamed".to_owned(), "not_a_kind".to_owned()),
+        ]);
+
+        // The alias is not a kind of the grammar
+        assert!(TopiaryQuery::new(&grammar, query).is_err());
+
+        let language = Language {
+            name: "json".to_owned(),
+            formatting_query: TopiaryQuery::with_node_aliases(&grammar, query, &node_aliases)
+                .unwrap(),
+            injection_query: None,
+            grammar,
+            indent: None,
+            max_blank_lines: None,
+            formatting_markers: None,
+            comment_attachment: CommentAttachment::default(),
+            tab_width: None,
+        };
+
+        pretty_assert_eq(
+            "{\"a\": 1}\n",
+            &format_with(r#"{"a":1}"#, &language, Options::default()),
+        );
+    }
+
+    #[test]
+    fn node_alias_positions_refer_to_the_original_query() {
+        let grammar = json_grammar();
+        // The alias is shorter than the kind, so positions in the resolved
+        // query would be further along
+        let node_aliases = HashMap::from([("o".to_owned(), "object".to_owned())]);
+
+        let query = "(o) @append_space ((o) @append_delimiter (#delimiter!))";
+        let compiled = TopiaryQuery::with_node_aliases(&grammar, query, &node_aliases).unwrap();
+        assert_eq!(
+            compiled.pattern_position(1),
+            Position { row: 1, column: 19 }
+        );
+
+        let diagnostics = validate_query(query, &grammar, &node_aliases).unwrap();
+        let positions: Vec<_> = diagnostics
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.position)
+            .collect();
+        assert_eq!(positions, vec![Position { row: 1, column: 19 }]);
+
+        let mut error =
+            TopiaryQuery::with_node_aliases(&grammar, "(o) (o) (strnig)", &node_aliases)
+                .unwrap_err();
+        let span = error.get_span().unwrap();
+        assert_eq!(span.range.unwrap().start_byte(), 9);
+        let label = span.primary_label.as_deref().unwrap();
+        assert!(
+            label.starts_with("Invalid node type at line 1, column 10"),
+            "{label}"
+        );
+    }
+
+    #[test]
+    fn supported_captures_are_all_interpreted() {
+        let grammar = json_grammar();
+
+        for capture in supported_captures() {
+            // Give every predicate that a capture may require
+            let query = format!(
+                r#"((number) @{capture} (#delimiter! ",") (#scope_id! "s") (#align_group! "g"))"#
+            );
+            let query = TopiaryQuery::new(&grammar, &query).unwrap();
+
+            assert!(
+                apply_query("[1]", &query, &grammar, false).is_ok(),
+                "@{capture} is listed, but not interpreted"
+            );
+        }
+
+        let query = TopiaryQuery::new(&grammar, "(number) @not_a_capture").unwrap();
+        assert!(apply_query("[1]", &query, &grammar, false).is_err());
+    }
+
+    #[test]
+    fn supported_predicates_are_all_interpreted() {
+        let grammar = json_grammar();
+
+        for spec in supported_predicates() {
+            let args: Vec<&str> = spec
+                .arguments
+                .iter()
+                .map(|argument| match argument {
+                    PredicateArgument::Delimiter => r#"",""#,
+                    PredicateArgument::Identifier => r#""id""#,
+                    PredicateArgument::Name => r#""name""#,
+                    PredicateArgument::Alias => r#""alias""#,
+                    PredicateArgument::CaptureName => r#""append_space""#,
+                    PredicateArgument::FieldName => r#""key""#,
+                    PredicateArgument::Text => r#""//""#,
+                    PredicateArgument::Count => r#""2""#,
+                })
+                .collect();
+            let query = format!(
+                "((number) @append_space (#{} {}))",
+                spec.name,
+                args.join(" ")
+            );
+
+            let diagnostics = validate_query(&query, &grammar, &HashMap::new()).unwrap();
+            assert!(
+                diagnostics.diagnostics.is_empty(),
+                "#{} is listed, but not valid: {diagnostics:?}",
+                spec.name
+            );
+
+            let query = TopiaryQuery::new(&grammar, &query).unwrap();
+            assert!(
+                apply_query("[1]", &query, &grammar, false).is_ok(),
+                "#{} is listed, but not interpreted",
+                spec.name
+            );
+        }
+    }
+
+    #[test]
+    fn predicate_arguments_are_checked_as_by_validate_query() {
+        let grammar = json_grammar();
+
+        for query in [
+            r#"((number) @append_space (#scope_id! "a" "b"))"#,
+            r#"((number) @append_space (#space_count! "two"))"#,
+            r#"((number) @append_space (#define! "append_space" "append_hardline"))"#,
+        ] {
+            assert!(
+                validate_query(query, &grammar, &HashMap::new())
+                    .unwrap()
+                    .has_errors()
+            );
+
+            let query = TopiaryQuery::new(&grammar, query).unwrap();
+            let result = apply_query("[1]", &query, &grammar, false);
+            assert!(
+                matches!(
+                    result,
+                    Err(ref report) if matches!(report.current_context(), FormatterError::Query(_))
+                ),
+                "{}",
+                query.query_content
+            );
+        }
+    }
+
+    #[test]
+    fn query_errors_report_their_position_and_kind() {
+        let mut error =
+            TopiaryQuery::new(&json_grammar(), "(string) @leaf\n(array @leaf").unwrap_err();
+        let span = error.get_span().unwrap();
+
+        // The unterminated pattern is on the second line, as the label says
+        assert_eq!(span.range.unwrap().start_point().row(), 1);
+        let label = span.primary_label.as_deref().unwrap();
+        assert!(
+            label.starts_with("Invalid syntax at line 2, column "),
+            "{label}"
+        );
+    }
+
+    #[test]
+    fn query_coverage_counts_matches_per_pattern() {
+        let grammar = json_grammar();
+        let query = TopiaryQuery::new(
+            &grammar,
+            r#"
+((number) @append_space (#query_name! "numbers"))
+(null) @append_space
+"#,
+        )
+        .unwrap();
+
+        let mut report = query_coverage("[1, 22]", &query, &grammar).unwrap();
+        let numbers = &report.patterns[0];
+        assert_eq!(numbers.query_name.as_deref(), Some("numbers"));
+        assert_eq!(numbers.match_count, 2);
+        assert_eq!(numbers.ranges, vec![1..2, 4..6]);
+
+        let unmatched: Vec<_> = report.unmatched().map(|p| p.pattern_index).collect();
+        assert_eq!(unmatched, vec![1]);
+
+        let other = query_coverage("[null]", &query, &grammar).unwrap();
+        report.merge(&other);
+        assert_eq!(report.patterns[0].match_count, 2);
+        assert_eq!(report.patterns[1].match_count, 1);
+        assert_eq!(report.unmatched().count(), 0);
+    }
+
+    #[test(tokio::test)]
+    async fn prepend_antispace_removes_space_appended_by_left_sibling() {
+        let query = r#"
+(pair key: (_) @append_space)
+(pair ":" @prepend_antispace @append_space)
+"#;
+        let language = language("json", query, None);
+
+        let formatted = format_with("{\"a\" :1}", &language, Options::default());
+
+        pretty_assert_eq("{\"a\": 1}\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn max_blank_lines_caps_blank_lines_from_input() {
+        let query = r#"
+(array "," @append_hardline)
+(array (_) @allow_blank_line_before)
+"#;
+        let mut language = language("json", query, None);
+        let with_blank_lines = |count: usize| format!("[1,\n{}2]", "\n".repeat(count));
+
+        for (blank_lines, max_blank_lines, expected_blank_lines) in [
+            (0, 0, 0),
+            (0, 2, 0),
+            (1, 0, 0),
+            (1, 1, 1),
+            (1, 2, 1),
+            (3, 0, 0),
+            (3, 1, 1),
+            (3, 2, 2),
+        ] {
+            language.max_blank_lines = Some(max_blank_lines);
+            let formatted = format_with(
+                &with_blank_lines(blank_lines),
+                &language,
+                Options::default(),
+            );
+            pretty_assert_eq(
+                &format!("{}\n", with_blank_lines(expected_blank_lines)),
+                &formatted,
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn visualised_scopes_report_their_resolved_mode() {
+        let query = r#"
+(array) @prepend_begin_scope @append_end_scope (#scope_id! "array")
+(array "," @append_spaced_scoped_softline (#scope_id! "array"))
+"#;
+        let language = language("json", query, None);
+
+        let mut output = Vec::new();
+        formatter_str(
+            "[[1, 2], [\n3\n]]",
+            &mut output,
+            &language,
+            Operation::Visualise {
+                output_format: Visualisation::Json,
+                named_only: true,
+                scopes: true,
+            },
+            None,
         )
+        .unwrap();
+        let root: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+        // The scopes of the annotated nodes, in pre-order
+        fn annotated(node: &serde_json::Value, scopes: &mut Vec<serde_json::Value>) {
+            if let Some(node_scopes) = node.get("scopes") {
+                scopes.push(node_scopes.clone());
+            }
+            for child in node["children"].as_array().unwrap() {
+                annotated(child, scopes);
+            }
+        }
+        let mut scopes = Vec::new();
+        annotated(&root, &mut scopes);
+
+        let boundaries = |multi_line| {
+            serde_json::json!([
+                { "scope_id": "array", "boundary": "begin", "multi_line": multi_line },
+                { "scope_id": "array", "boundary": "end", "multi_line": multi_line },
+            ])
+        };
+        assert_eq!(
+            vec![boundaries(true), boundaries(false), boundaries(true)],
+            scopes
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn allow_blank_line_before_keeps_one_of_many_blank_lines() {
+        // Both captures allow a blank line before the same leaf, which must not
+        // add up to more than one
+        let query = r#"
+(object "{" @append_hardline "}" @prepend_hardline)
+(object "," @append_hardline)
+(pair) @allow_blank_line_before
+(pair key: (_) @allow_blank_line_before)
+(pair ":" @append_space)
+"#;
+        let mut language = language("json", query, None);
+        // Leave the cap at its default
+        language.max_blank_lines = None;
+
+        let formatted = format_with(
+            "{\"a\": 1,\n\n\n\n\n\n\"b\": 2}",
+            &language,
+            Options::default(),
+        );
+
+        pretty_assert_eq("{\n\"a\": 1,\n\n\"b\": 2\n}\n", &formatted);
+    }
+
+    fn json_with_formatting_markers() -> Language {
+        let query = r#"
+(comment) @prepend_hardline @append_hardline
+(object "{" @append_indent_start "}" @prepend_hardline @prepend_indent_end)
+(pair) @prepend_hardline
+":" @append_space
+(array "," @append_space)
+"#;
+        let mut language = language("json", query, None);
+        language.formatting_markers = Some(FormattingMarkers {
+            off: "topiary: off".into(),
+            on: "topiary: on".into(),
+        });
+
+        language
+    }
+
+    #[test(tokio::test)]
+    async fn formatting_markers_protect_region_verbatim() {
+        let input = r#"{"a":1,
+// topiary: off
+"m": [1,  0,
+      0,  1],
+// topiary: on
+"b":[2,3]}"#;
+        let expected = r#"{
+  "a": 1,
+  // topiary: off
+  "m": [1,  0,
+      0,  1],
+  // topiary: on
+  "b": [2, 3]
+}
+"#;
+
+        let language = json_with_formatting_markers();
+        let formatted = format_with(input, &language, Options::default());
+        pretty_assert_eq(expected, &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn unbalanced_formatting_markers_are_ignored() {
+        let language = json_with_formatting_markers();
+
+        for (input, expected) in [
+            (
+                "{\"a\":[1,2],\n// topiary: off\n\"b\":[2,3]}",
+                "{\n  \"a\": [1, 2],\n  // topiary: off\n  \"b\": [2, 3]\n}\n",
+            ),
+            (
+                "{\"a\":[1,2],\n// topiary: on\n\"b\":[2,3]}",
+                "{\n  \"a\": [1, 2],\n  // topiary: on\n  \"b\": [2, 3]\n}\n",
+            ),
+        ] {
+            let formatted = format_with(input, &language, Options::default());
+            pretty_assert_eq(expected, &formatted);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn format_lines_formats_the_nodes_on_those_lines_alone() {
+        let query = r#"
+(pair ":" @append_space)
+(array "," @append_space)
+"#;
+        let language = language("json", query, None);
+        let input = "{\n  \"a\":[1,2],\n  \"b\":[3,4],\n\n  \"c\":[\n5]\n}\n";
+
+        for (lines, expected) in [
+            // Only the pair on the selected line is formatted
+            (
+                vec![2..3],
+                "{\n  \"a\":[1,2],\n  \"b\": [3, 4],\n\n  \"c\":[\n5]\n}\n",
+            ),
+            // A blank line selects nothing
+            (vec![3..4], input),
+            // Nor does a line beyond the end of the input
+            (vec![10..12], input),
+            // Within a node that spans lines, only the nodes on the line are
+            (
+                vec![1..2, 4..5],
+                "{\n  \"a\": [1, 2],\n  \"b\":[3,4],\n\n  \"c\": [\n5]\n}\n",
+            ),
+        ] {
+            let mut output = Vec::new();
+            format_lines(
+                input,
+                &lines,
+                &mut output,
+                &language,
+                Operation::Format {
+                    skip_idempotence: false,
+                    tolerate_parsing_errors: ParsingErrorTolerance::None,
+                    final_newline: FinalNewline::Single,
+                    line_ending: LineEnding::Lf,
+                    max_line_width: None,
+                    strict: false,
+                },
+                None,
+            )
+            .unwrap();
+
+            pretty_assert_eq(expected, str::from_utf8(&output).unwrap());
+        }
+    }
+
+    fn json_with_comments(comment_attachment: CommentAttachment) -> Language {
+        let query = r#"
+(comment) @prepend_hardline @append_hardline
+(object "{" @append_indent_start "}" @prepend_hardline @prepend_indent_end)
+(pair) @prepend_hardline
+":" @append_space
+"#;
+        let mut language = language("json", query, None);
+        language.comment_attachment = comment_attachment;
+        language
+    }
+
+    #[test]
+    fn trailing_comment_attaches_to_following_node_by_default() {
+        let input = "{\"a\":1, // note\n\"b\":2}";
+
+        pretty_assert_eq(
+            "{\n  \"a\": 1,\n  // note\n  \"b\": 2\n}\n",
+            &format_with(
+                input,
+                &json_with_comments(CommentAttachment::Following),
+                Options {
+                    skip_idempotence: false,
+                    ..Default::default()
+                },
+            ),
+        );
+    }
+
+    #[test]
+    fn trailing_comment_attaches_to_preceding_node() {
+        let input = "{\"a\":1, // note\n\"b\":2}";
+
+        pretty_assert_eq(
+            "{\n  \"a\": 1, // note\n  \"b\": 2\n}\n",
+            &format_with(
+                input,
+                &json_with_comments(CommentAttachment::Preceding),
+                Options {
+                    skip_idempotence: false,
+                    ..Default::default()
+                },
+            ),
+        );
+    }
+
+    #[test]
+    fn leading_comment_stays_on_its_own_line() {
+        let input = "{\"a\":1,\n// note\n\"b\":2}";
+        let expected = "{\n  \"a\": 1,\n  // note\n  \"b\": 2\n}\n";
+
+        for comment_attachment in [CommentAttachment::Following, CommentAttachment::Preceding] {
+            pretty_assert_eq(
+                expected,
+                &format_with(
+                    input,
+                    &json_with_comments(comment_attachment),
+                    Options {
+                        skip_idempotence: false,
+                        ..Default::default()
+                    },
+                ),
+            );
+        }
+    }
+
+    #[test]
+    fn sort_children_sorts_imports_with_their_trailing_comments() {
+        let query = r#"
+(compilation_unit) @sort_children
+"open" @append_space
+(open_module) @prepend_hardline
+(comment) @prepend_space
+"#;
+        let language = language("ocaml", query, None);
+        let input = "open C\nopen A (* for a *)\nopen B\n";
+
+        pretty_assert_eq(
+            "open A (* for a *)\nopen B\nopen C\n",
+            &format_with(input, &language, Options::default()),
+        );
+    }
+
+    #[test]
+    fn sort_children_is_stable_for_equal_keys() {
+        let query = r#"
+((object) @sort_children (#sort_key! "key"))
+(pair ":" @append_space)
+"," @append_space
+"#;
+        let language = language("json", query, None);
+        let input = r#"{"c":1,"a":2,"b":3,"a":0}"#;
+
+        pretty_assert_eq(
+            "{\"a\": 2, \"a\": 0, \"b\": 3, \"c\": 1}\n",
+            &format_with(input, &language, Options::default()),
+        );
+    }
+
+    #[test]
+    fn prepend_hardline_unless_first_separates_siblings() {
+        let query = r#"
+(object (pair) @prepend_hardline_unless_first)
+(pair ":" @append_space)
+(comment) @append_space
+"#;
+        let language = language("json", query, None);
+
+        pretty_assert_eq(
+            "{\"a\": 1,\n\"b\": 2,\n\"c\": 3}\n",
+            &format_with(r#"{"a":1,"b":2,"c":3}"#, &language, Options::default()),
+        );
+
+        // A comment before the first pair does not make it any less the first
+        pretty_assert_eq(
+            "{/* c */ \"a\": 1,\n\"b\": 2,\n\"c\": 3}\n",
+            &format_with(
+                r#"{/* c */"a":1,"b":2,"c":3}"#,
+                &language,
+                Options::default(),
+            ),
+        );
+    }
+
+    #[test]
+    fn single_line_collapses_multi_line_input() {
+        let query = r#"
+(array "," @append_spaced_softline)
+(array "[" @append_empty_softline @append_indent_start)
+(array "]" @prepend_empty_softline @prepend_indent_end)
+(array (array) @single_line)
+"#;
+        let language = language("json", query, None);
+        let input = "[[1,\n2],\n[3,\n4]]";
+
+        pretty_assert_eq(
+            "[\n  [1, 2],\n  [3, 4]\n]\n",
+            &format_with(input, &language, Options::default()),
+        );
+    }
+
+    #[test]
+    fn single_line_keeps_nodes_with_comments_multi_line() {
+        let query = r#"
+(array "," @append_spaced_softline)
+(array "[" @append_empty_softline @append_indent_start)
+(array "]" @prepend_empty_softline @prepend_indent_end)
+(array (array) @single_line)
+(comment) @append_hardline
+"#;
+        let language = language("json", query, None);
+        let input = "[[1, // one\n2],\n[3,\n4]]";
+
+        let formatted = format_with(input, &language, Options::default());
+
+        // The comment would otherwise swallow the second element
+        assert!(formatted.contains("// one\n"), "{formatted}");
+        assert!(formatted.contains("[3, 4]"), "{formatted}");
+    }
+
+    #[test]
+    fn multi_line_expands_single_line_input() {
+        let query = r#"
+(object "{" @append_spaced_softline @append_indent_start)
+(object "}" @prepend_spaced_softline @prepend_indent_end)
+(object "," @append_spaced_softline)
+(pair ":" @append_space)
+(object) @multi_line
+"#;
+        let language = language("json", query, None);
+
+        pretty_assert_eq(
+            "{\n  \"a\": 1\n}\n",
+            &format_with(r#"{"a":1}"#, &language, Options::default()),
+        );
+    }
+
+    #[test]
+    fn multi_line_does_not_extend_to_nested_nodes() {
+        let query = r#"
+(object "{" @append_spaced_softline @append_indent_start)
+(object "}" @prepend_spaced_softline @prepend_indent_end)
+(object "," @append_spaced_softline)
+(pair ":" @append_space)
+(document (object) @multi_line)
+"#;
+        let language = language("json", query, None);
+
+        pretty_assert_eq(
+            "{\n  \"a\": { \"b\": 1 },\n  \"c\": 2\n}\n",
+            &format_with(r#"{"a":{"b":1},"c":2}"#, &language, Options::default()),
+        );
     }
 
-    /// Attempt to parse invalid json, expecting a failure
     #[test(tokio::test)]
-    async fn parsing_error_fails_formatting() {
-        let mut input = r#"{"foo":{"bar"}}"#.as_bytes();
+    async fn indent_id_spans_sibling_tokens() {
+        let query = r#"
+((object "{" @append_indent_start @append_hardline) (#indent_id! "braces"))
+((object "}" @prepend_indent_end @prepend_hardline) (#indent_id! "braces"))
+(object "," @append_hardline)
+"#;
+        let language = language("json", query, None);
+
+        let formatted = format_with("{\"a\":1,\"b\":2}", &language, Options::default());
+
+        pretty_assert_eq("{\n  \"a\":1,\n  \"b\":2\n}\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn unbalanced_indent_id_is_a_query_error() {
+        let query = r#"
+((object "{" @append_indent_start) (#indent_id! "braces"))
+((object "}" @prepend_indent_end) (#indent_id! "brackets"))
+"#;
+        let language = language("json", query, None);
         let mut output = Vec::new();
-        let language = language("json", "(#language! json)", None);
 
-        let mut result = formatter(
-            &mut input,
+        let result = formatter_str(
+            "{\"a\":1}",
             &mut output,
             &language,
             Operation::Format {
                 skip_idempotence: true,
-                tolerate_parsing_errors: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
             },
             None,
         );
 
-        if let Some(range) = result
-            .get_span()
-            .and_then(|s| s.range)
-            .inspect(|r| println!("{r:?}"))
-            && range.start_point().row() == 0
-            && range.end_point().row() == 0
-        {
-            return;
-        }
-        panic!("Expected a parsing error on line 1, but got {result:?}");
+        assert!(matches!(
+            result,
+            Err(ref report) if matches!(report.current_context(), FormatterError::Query(_))
+        ));
     }
 
     #[test(tokio::test)]
-    async fn tolerate_parsing_errors() {
-        // Contains the invalid object {"bar"   "baz"}. It should be left untouched.
-        let mut input = "{\"one\":{\"bar\"   \"baz\"},\"two\":\"bar\"}".as_bytes();
-        let expected = "{ \"one\": {\"bar\"   \"baz\"}, \"two\": \"bar\" }\n";
+    async fn escaped_newline_delimiter_becomes_hardline() {
+        let formatted = format_with(
+            "[1,2]",
+            &language(
+                "json",
+                r#"((number) @append_delimiter (#delimiter! ";\\n"))"#,
+                None,
+            ),
+            Options::default(),
+        );
+
+        pretty_assert_eq("[1;\n,2;\n]\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn escaped_tab_delimiter_is_literal() {
+        let formatted = format_with(
+            "[1,2]",
+            &language(
+                "json",
+                r#"((number) @prepend_delimiter (#delimiter! "\\t"))"#,
+                None,
+            ),
+            Options::default(),
+        );
+
+        pretty_assert_eq("[\t1,\t2]\n", &formatted);
+    }
 
+    #[test(tokio::test)]
+    async fn unknown_delimiter_escape_fails_formatting() {
+        let language = language(
+            "json",
+            r#"((number) @append_delimiter (#delimiter! "\\q"))"#,
+            None,
+        );
         let mut output = Vec::new();
-        let language = language("json", topiary_queries::json(), None);
 
-        formatter(
-            &mut input,
+        let result = formatter_str(
+            "[1]",
             &mut output,
             &language,
             Operation::Format {
                 skip_idempotence: true,
-                tolerate_parsing_errors: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        );
+
+        assert!(matches!(
+            result,
+            Err(ref report) if matches!(report.current_context(), FormatterError::Query(_))
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn final_newline_single() {
+        let formatted = format_with(
+            "[1,2]\n\n\n",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                final_newline: FinalNewline::Single,
+                ..Default::default()
+            },
+        );
+        pretty_assert_eq("[ 1, 2 ]\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn final_newline_preserve() {
+        for newlines in 0..3 {
+            let input = format!("[1,2]{}", "\n".repeat(newlines));
+            let expected = format!("[ 1, 2 ]{}", "\n".repeat(newlines));
+            let formatted = format_with(
+                &input,
+                &language("json", topiary_queries::json(), None),
+                Options {
+                    skip_idempotence: false,
+                    final_newline: FinalNewline::Preserve,
+                    ..Default::default()
+                },
+            );
+            pretty_assert_eq(&expected, &formatted);
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn final_newline_none() {
+        let formatted = format_with(
+            "[1,2]\n\n",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                final_newline: FinalNewline::None,
+                ..Default::default()
             },
+        );
+        pretty_assert_eq("[ 1, 2 ]", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn format_bytes_strips_bom() {
+        let formatted = format_bytes(
+            b"\xEF\xBB\xBF[1,2]\n",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                ..Default::default()
+            }
+            .operation(),
             None,
+            false,
         )
         .unwrap();
+        assert_eq!(formatted, b"[ 1, 2 ]\n");
+    }
 
-        let formatted = String::from_utf8(output).unwrap();
-        log::debug!("{formatted}");
+    #[test(tokio::test)]
+    async fn format_bytes_preserves_bom() {
+        let formatted = format_bytes(
+            b"\xEF\xBB\xBF[1,2]\n",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                ..Default::default()
+            }
+            .operation(),
+            None,
+            true,
+        )
+        .unwrap();
+        assert_eq!(formatted, b"\xEF\xBB\xBF[ 1, 2 ]\n");
+    }
 
-        pretty_assert_eq(expected, &formatted);
+    #[test(tokio::test)]
+    async fn format_bytes_without_bom() {
+        for preserve_bom in [false, true] {
+            let formatted = format_bytes(
+                b"[1,2]\n",
+                &language("json", topiary_queries::json(), None),
+                Options {
+                    skip_idempotence: false,
+                    ..Default::default()
+                }
+                .operation(),
+                None,
+                preserve_bom,
+            )
+            .unwrap();
+            assert_eq!(formatted, b"[ 1, 2 ]\n");
+        }
     }
 
     #[test(tokio::test)]
-    async fn collect_injections_returns_content_span() {
-        let input = r#"rule token = parse
-  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+    async fn format_bytes_rejects_invalid_utf8() {
+        let error = format_bytes(
+            b"\xEF\xBB\xBF[\"\xFF\"]\n",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                ..Default::default()
+            }
+            .operation(),
+            None,
+            true,
+        )
+        .unwrap_err();
+        assert!(matches!(error.current_context(), FormatterError::Io));
+    }
+
+    #[test(tokio::test)]
+    async fn line_ending_is_applied_to_every_line() {
+        let language = language("json", topiary_queries::json(), None);
+        let format = |input: &str, options: Options| {
+            let mut output = Vec::new();
+            format_reader_to_writer(
+                &mut input.as_bytes(),
+                &mut output,
+                &language,
+                options.operation(),
+                None,
+            )
+            .unwrap();
+            output
+        };
+        let lf_input = "[\n1,\n2\n]\n";
+        let crlf_input = "[\r\n1,\r\n2\r\n]\r\n";
+        let lf_output = b"[\n  1,\n  2\n]\n";
+        let crlf_output = b"[\r\n  1,\r\n  2\r\n]\r\n";
+
+        // Both when buffered, for the idempotence check, and when streamed
+        for skip_idempotence in [false, true] {
+            for (input, line_ending, expected) in [
+                (lf_input, LineEnding::Lf, lf_output.as_slice()),
+                (lf_input, LineEnding::CrLf, crlf_output.as_slice()),
+                (crlf_input, LineEnding::CrLf, crlf_output.as_slice()),
+                (lf_input, LineEnding::Auto, lf_output.as_slice()),
+                (crlf_input, LineEnding::Auto, crlf_output.as_slice()),
+            ] {
+                let formatted = format(
+                    input,
+                    Options {
+                        skip_idempotence,
+                        line_ending,
+                        ..Default::default()
+                    },
+                );
+                assert_eq!(
+                    expected,
+                    formatted.as_slice(),
+                    "{line_ending:?} line endings for {input:?}"
+                );
+            }
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn mixed_line_endings_are_made_consistent() {
+        let language = language("json", topiary_queries::json(), None);
+        let format = |input: &str, options: Options| {
+            let mut output = Vec::new();
+            format_reader_to_writer(
+                &mut input.as_bytes(),
+                &mut output,
+                &language,
+                options.operation(),
+                None,
+            )
+            .unwrap();
+            output
+        };
+        let mostly_crlf = "[\r\n1,\n2\r\n]\r\n";
+        let mostly_lf = "[\n1,\r\n2\n]\n";
+        let crlf_output = b"[\r\n  1,\r\n  2\r\n]\r\n";
+        let lf_output = b"[\n  1,\n  2\n]\n";
+
+        for skip_idempotence in [false, true] {
+            for (input, line_ending, expected) in [
+                (mostly_crlf, LineEnding::Auto, crlf_output.as_slice()),
+                (mostly_lf, LineEnding::Auto, lf_output.as_slice()),
+                (mostly_crlf, LineEnding::Lf, lf_output.as_slice()),
+                (mostly_lf, LineEnding::CrLf, crlf_output.as_slice()),
+            ] {
+                let formatted = format(
+                    input,
+                    Options {
+                        skip_idempotence,
+                        line_ending,
+                        ..Default::default()
+                    },
+                );
+                assert_eq!(
+                    expected,
+                    formatted.as_slice(),
+                    "{line_ending:?} line endings for {input:?}"
+                );
+
+                // Formatting the output again changes nothing
+                let output = str::from_utf8(&formatted).unwrap();
+                let reformatted = format(
+                    output,
+                    Options {
+                        skip_idempotence,
+                        line_ending,
+                        ..Default::default()
+                    },
+                );
+                assert_eq!(formatted, reformatted);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_line_indent_all_expands_tabs_of_the_input() {
+        let query = r#"
+(array "[" @append_hardline @append_indent_start)
+(array "]" @prepend_hardline @prepend_indent_end)
+(array "," @append_hardline)
+(comment) @prepend_space @multi_line_indent_all
 "#;
-        let language = ocamllex_language();
-        let tree = parse(input, &language.grammar, false).unwrap();
-        let spans = collect_injections(&tree, input, language.injection_query.as_ref().unwrap());
+        let expected = "[\n  1 /* a\n     * b */,\n  2\n]\n";
+
+        // The comment's second line is aligned with its first in the input,
+        // given the width of the tab that precedes it
+        for (tab_width, input) in [
+            (4, "[\n\t1 /* a\n       * b */,\n\t2\n]"),
+            (2, "[\n\t1 /* a\n     * b */,\n\t2\n]"),
+        ] {
+            let mut language = language("json", query, None);
+            language.tab_width = Some(tab_width);
+
+            pretty_assert_eq(expected, &format_with(input, &language, Options::default()));
+        }
+    }
+
+    #[test]
+    fn final_hardline_does_not_compound_with_final_newline() {
+        let query = "(pair) @append_hardline\n(object) @append_hardline @append_hardline";
+        let input = "{\"a\":1,\"b\":2}\n\n";
+
+        for (final_newline, expected) in [
+            (FinalNewline::Single, "{\"a\":1\n,\"b\":2\n}\n"),
+            (FinalNewline::None, "{\"a\":1\n,\"b\":2\n}"),
+        ] {
+            let formatted = Formatter::builder(language("json", query, None))
+                .final_newline(final_newline)
+                .build()
+                .unwrap()
+                .format(input)
+                .unwrap();
+            pretty_assert_eq(expected, &formatted);
+        }
+
+        // The hardlines appended to the last node are not even atoms
+        let language = language("json", query, None);
+        let atoms = to_atoms(input, &language.formatting_query, &language.grammar).unwrap();
+        assert!(matches!(
+            atoms.iter().rfind(|atom| **atom != Atom::Empty),
+            Some(Atom::Leaf { content, .. }) if content == "}"
+        ));
+    }
+
+    #[test(tokio::test)]
+    async fn to_atoms_returns_post_processed_leaves() {
+        let language = language("json", topiary_queries::json(), None);
+        let atoms = to_atoms("[1,\n2]", &language.formatting_query, &language.grammar).unwrap();
+
+        let leaves: Vec<_> = atoms
+            .iter()
+            .filter_map(|atom| match atom {
+                Atom::Leaf {
+                    content,
+                    original_position,
+                    ..
+                } => Some((content.as_str(), original_position.row)),
+                _ => None,
+            })
+            .collect();
 
-        assert_eq!(spans.len(), 1);
-        assert_eq!(spans[0].language, "ocaml");
         assert_eq!(
-            spans[0].content,
-            r#"let values=[1;2;3] in List.map (fun x->x+1) values"#
+            leaves,
+            vec![("[", 1), ("1", 1), (",", 1), ("2", 2), ("]", 2)]
+        );
+        // Post-processing has already merged consecutive whitespace
+        assert!(
+            atoms
+                .windows(2)
+                .all(|pair| !matches!(pair, [Atom::Space, Atom::Space]))
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn format_range_snaps_to_enclosing_node() {
+        let language = language("json", topiary_queries::json(), None);
+        let input = "[\n  [1,2],\n  [3,\n4],\n  [5,6]\n]\n";
+
+        // From the middle of the second inner array, up to and including `4`
+        let start = input.find('3').unwrap();
+        let end = input.find('4').unwrap() + 1;
+        let formatting = format_range(
+            input,
+            start..end,
+            &language.formatting_query,
+            &language.grammar,
+            language.indent(),
+        )
+        .unwrap();
+
+        // The range is snapped outward to the whole inner array, which is
+        // formatted at its depth in the outer array
+        let array = input.find("[3").unwrap()..input.find("4]").unwrap() + 2;
+        assert_eq!(formatting.range, array);
+        pretty_assert_eq("[\n    3,\n    4\n  ]", &formatting.text);
+
+        // The rest of the input is untouched once the replacement is spliced in
+        let mut spliced = input.to_string();
+        spliced.replace_range(formatting.range, &formatting.text);
+        pretty_assert_eq(
+            "[\n  [1,2],\n  [\n    3,\n    4\n  ],\n  [5,6]\n]\n",
+            &spliced,
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn format_range_keeps_the_suspended_indentation() {
+        let query = r#"
+(array
+  "[" @append_indent_start
+  "]" @prepend_hardline @prepend_indent_end
+)
+
+(array
+  (_) @prepend_hardline
+)
+
+(
+  (array
+    (array) @prepend_begin_scope @single_line_scope_no_indent @append_end_scope
+  )
+  (#scope_id! "inner")
+)
+"#;
+        let language = language("json", query, None);
+        let input = "[\n1,\n[2,3]\n]\n";
+
+        // The inner array is single-line in the input, so it is not indented by
+        // the outer array, but its own block still indents its elements
+        let formatted = format_with(input, &language, Options::default());
+        pretty_assert_eq("[\n  1,\n[\n  2,\n  3\n]\n]\n", &formatted);
+
+        let start = input.find("[2").unwrap();
+        let formatting = format_range(
+            input,
+            start..start + "[2,3]".len(),
+            &language.formatting_query,
+            &language.grammar,
+            language.indent(),
+        )
+        .unwrap();
+        pretty_assert_eq("[\n  2,\n  3\n]", &formatting.text);
+        assert!(formatted.contains(&formatting.text));
+    }
+
+    #[test(tokio::test)]
+    async fn source_map_maps_leaves_back_to_input_nodes() {
+        let language = language("json", topiary_queries::json(), None);
+        let input = "\n{\"a\":[1,\n2]}";
+
+        let formatted = format_with_source_map(
+            input,
+            &language.formatting_query,
+            &language.grammar,
+            language.indent(),
+        )
+        .unwrap();
+        pretty_assert_eq("{\n  \"a\": [\n    1,\n    2\n  ]\n}\n", &formatted.output);
+
+        let kinds: Vec<_> = formatted
+            .source_map
+            .iter()
+            .map(|mapping| mapping.node_kind.as_str())
+            .collect();
+        assert_eq!(
+            kinds,
+            vec!["{", "string", ":", "[", "number", ",", "number", "]", "}"]
+        );
+
+        // Each leaf is output as it was input
+        for mapping in &formatted.source_map {
+            assert_eq!(
+                &formatted.output[mapping.output_range.clone()],
+                &input[mapping.input_range.clone()],
+            );
+        }
+    }
+
+    #[test(tokio::test)]
+    async fn format_cells_formats_each_cell_independently() {
+        let formatting = format_cells(
+            "[1,2]\n# %%\n\n# %%\n{\"a\":1}",
+            "# %%",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                ..Default::default()
+            }
+            .operation(),
+            None,
+        )
+        .unwrap();
+
+        pretty_assert_eq("[ 1, 2 ]\n# %%\n\n# %%\n{ \"a\": 1 }\n", &formatting.output);
+        assert!(formatting.parsing_errors.is_empty());
+    }
+
+    #[test(tokio::test)]
+    async fn format_cells_reports_parsing_errors_by_cell() {
+        let input = "[1,2]\n# %%\n\n# %%\n[1,,2]\n";
+
+        let error = format_cells(
+            input,
+            "# %%",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                ..Default::default()
+            }
+            .operation(),
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Parsing(_)
+        ));
+        assert!(format!("{error}").contains("In cell 2"));
+
+        // When tolerated, the erroneous cell doesn't stop the others from being formatted
+        let formatting = format_cells(
+            input,
+            "# %%",
+            &language("json", topiary_queries::json(), None),
+            Options {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::Verbatim,
+                ..Default::default()
+            }
+            .operation(),
+            None,
+        )
+        .unwrap();
+        assert!(formatting.output.starts_with("[ 1, 2 ]\n# %%\n\n# %%\n"));
+        let cells: Vec<_> = formatting
+            .parsing_errors
+            .iter()
+            .map(|parsing_error| parsing_error.cell)
+            .collect();
+        assert_eq!(cells, vec![2]);
+    }
+
+    #[test]
+    fn query_declared_indent_applies_without_configuration() {
+        let query = r#"
+(object "{" @append_indent_start "}" @prepend_hardline @prepend_indent_end)
+(pair) @prepend_hardline
+":" @append_space
+((document) (#indent_string! "    "))
+"#;
+        let mut language = language("json", query, None);
+        language.indent = None;
+
+        pretty_assert_eq(
+            "{\n    \"a\": 1\n}\n",
+            &format_with(r#"{"a":1}"#, &language, Options::default()),
+        );
+
+        // The language's own indentation takes precedence
+        language.indent = Some("\t".into());
+        pretty_assert_eq(
+            "{\n\t\"a\": 1\n}\n",
+            &format_with(r#"{"a":1}"#, &language, Options::default()),
+        );
+    }
+
+    #[test]
+    fn last_query_declared_indent_wins() {
+        let query = TopiaryQuery::new(
+            &json_grammar(),
+            "((object) (#indent_string! \"  \"))\n((array) (#indent_string! \"\\t\"))",
+        )
+        .unwrap();
+
+        assert_eq!(query.indent.as_deref(), Some("\t"));
+    }
+
+    #[test]
+    fn space_count_predicate_inserts_that_many_spaces() {
+        let query = r#"
+(object "{" @append_indent_start "}" @prepend_hardline @prepend_indent_end)
+(pair) @prepend_hardline
+((":" @append_space) (#space_count! "2"))
+(("," @append_space) (#space_count! "3"))
+"#;
+        let language = language("json", query, None);
+
+        // The spaces after the commas that end a line are removed, however many
+        let input = r#"{"a":1,"b":[1,2]}"#;
+        let expected = "{\n  \"a\":  1,\n  \"b\":  [1,   2]\n}\n";
+
+        pretty_assert_eq(expected, &format_with(input, &language, Options::default()));
+    }
+
+    #[test]
+    fn space_count_predicate_needs_a_positive_number() {
+        let grammar = json_grammar();
+
+        for count in ["0", "two"] {
+            let query = TopiaryQuery::new(
+                &grammar,
+                &format!(r#"(("," @append_space) (#space_count! "{count}"))"#),
+            )
+            .unwrap();
+            let error = apply_query("[1,2]", &query, &grammar, false).unwrap_err();
+            assert!(matches!(error.current_context(), FormatterError::Query(_)));
+        }
+    }
+
+    #[test]
+    fn comment_prefix_predicate_normalises_the_space_after_the_prefix() {
+        let language = language(
+            "json",
+            r#"((comment) @prepend_hardline @append_hardline (#comment_prefix! "//"))"#,
+            None,
         );
+
+        // Separators, doc comments and empty comments are left alone
+        let input = "[1, //note\n//   spaced\n//\tindented\n// - item\n//-----\n///doc\n//\n2]";
+        let expected = "[1,\n// note\n// spaced\n// indented\n// - item\n//-----\n///doc\n//\n2]\n";
+
+        pretty_assert_eq(expected, &format_with(input, &language, Options::default()));
     }
 
-    #[test(tokio::test)]
-    async fn collect_injections_skips_pattern_without_content_capture() {
-        let input = r#"rule token = parse
-  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
-"#;
-        let mut language = ocamllex_language();
-        language.injection_query = Some(
-            InjectionQuery::new(
-                &language.grammar,
-                r#"
-(ocaml) @injection.language
-"#,
-            )
-            .unwrap(),
+    #[test]
+    fn comment_prefix_predicate_leaves_shebang_lines_alone() {
+        let language = language(
+            "toml",
+            r##"((comment) @append_hardline (#comment_prefix! "#"))"##,
+            None,
         );
-        let tree = parse(input, &language.grammar, false).unwrap();
-        let spans = collect_injections(&tree, input, language.injection_query.as_ref().unwrap());
 
-        assert!(spans.is_empty());
+        let input = "#!/usr/bin/env taplo\n#note\n";
+        let expected = "#!/usr/bin/env taplo\n# note\n";
+
+        pretty_assert_eq(expected, &format_with(input, &language, Options::default()));
     }
 
     #[test(tokio::test)]
-    async fn unresolved_injection_skips_formatting() {
-        let input = r#"rule token = parse
-  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
-"#;
-        let language = ocamllex_language();
+    async fn upper_case_predicate_handles_multibyte_leaves() {
+        let language = language("json", r#"((string_content) @leaf (#upper_case!))"#, None);
         let mut output = Vec::new();
 
-        let result = formatter_str(
-            input,
+        formatter_str(
+            r#"["ärger","straße","日本"]"#,
             &mut output,
             &language,
             Operation::Format {
-                skip_idempotence: true,
-                tolerate_parsing_errors: false,
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
             },
             None,
+        )
+        .unwrap();
+
+        pretty_assert_eq(
+            "[\"ÄRGER\",\"STRASSE\",\"日本\"]\n",
+            &String::from_utf8(output).unwrap(),
         );
+    }
 
-        assert!(result.is_ok());
+    #[test(tokio::test)]
+    async fn lower_case_predicate_handles_multibyte_leaves() {
+        let language = language("json", r#"((string_content) @leaf (#lower_case!))"#, None);
+        let mut output = Vec::new();
+
+        formatter_str(
+            r#"["ÀÉÎ","ΣΟΦΙΑ"]"#,
+            &mut output,
+            &language,
+            Operation::Format {
+                skip_idempotence: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            },
+            None,
+        )
+        .unwrap();
+
+        pretty_assert_eq("[\"àéî\",\"σοφια\"]\n", &String::from_utf8(output).unwrap());
     }
 
     #[test(tokio::test)]
-    async fn resolver_error_fails_formatting() {
-        let input = r#"rule token = parse
-  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
-"#;
-        let language = ocamllex_language();
+    async fn conflicting_case_predicates_fail_formatting() {
+        let language = language(
+            "json",
+            r#"((string_content) @leaf (#lower_case!) (#upper_case!))"#,
+            None,
+        );
         let mut output = Vec::new();
 
         let result = formatter_str(
-            input,
+            r#"["a"]"#,
             &mut output,
             &language,
             Operation::Format {
                 skip_idempotence: true,
-                tolerate_parsing_errors: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
             },
-            Some(&|_| {
-                Err(rootcause::report!(FormatterError::Query(
-                    "resolver failed while loading language".into()
-                )))
-            }),
+            None,
         );
 
         assert!(matches!(
             result,
-            Err(ref report)
-                if matches!(
-                    report.current_context(),
-                    FormatterError::InjectionLanguageResolution { language } if language == "ocaml"
-                )
+            Err(ref report) if matches!(report.current_context(), FormatterError::Query(_))
         ));
     }
 
     #[test(tokio::test)]
-    async fn resolved_injection_rewrites_forced_leaf() {
-        let input = r#"rule token = parse
-  | "x" { let values=[1;2;3] in List.map (fun x->x+1) values }
+    async fn streaming_output_matches_buffered_output() {
+        let input = "\n\n{\"one\":{\"bar\":\"baz\"},\"two\":[1,2,\n3]}\n\n\n";
+        let language = language("json", topiary_queries::json(), None);
+
+        for final_newline in [
+            FinalNewline::Preserve,
+            FinalNewline::Single,
+            FinalNewline::None,
+        ] {
+            let operation = Operation::Format {
+                skip_idempotence: true,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
+            };
+
+            let mut buffered = Vec::new();
+            formatter(
+                &mut input.as_bytes(),
+                &mut buffered,
+                &language,
+                operation,
+                None,
+            )
+            .unwrap();
+
+            let mut streamed = Vec::new();
+            format_reader_to_writer(
+                &mut input.as_bytes(),
+                &mut streamed,
+                &language,
+                operation,
+                None,
+            )
+            .unwrap();
+
+            pretty_assert_eq(
+                &String::from_utf8(buffered).unwrap(),
+                &String::from_utf8(streamed).unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn format_string_formats_in_memory() {
+        let formatted = format_string("[1,2]", topiary_queries::json(), json_grammar()).unwrap();
+        pretty_assert_eq("[ 1, 2 ]\n", &formatted);
+
+        let error = format_string("[1,2]", "(nonexistent_node) @leaf", json_grammar()).unwrap_err();
+        assert!(matches!(error.current_context(), FormatterError::Query(_)));
+
+        let error = format_string("[1,", topiary_queries::json(), json_grammar()).unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Parsing(_)
+        ));
+    }
+
+    #[test]
+    fn every_formatting_function_normalises_line_endings() {
+        let query = r#"
+(array "[" @append_hardline @append_indent_start)
+(array "]" @prepend_hardline @prepend_indent_end)
+(comment) @append_hardline
 "#;
-        let language = ocamllex_language();
-        let inner_language: Arc<Language> = Arc::new(ocaml_language());
-        let mut output = Vec::new();
+        // The line ending within the comment would otherwise be kept in its atom
+        let lf_input = "[\n/* a\n b */\n]\n";
+        let crlf_input = lf_input.replace('\n', "\r\n");
+        let expected = format_string(lf_input, query, json_grammar()).unwrap();
+        let expected_crlf = expected.replace('\n', "\r\n");
 
-        formatter_str(
-            input,
+        pretty_assert_eq(
+            &expected,
+            &format_string(&crlf_input, query, json_grammar()).unwrap(),
+        );
+
+        let language = Arc::new(language("json", query, None));
+        let mut formatter = Formatter::builder(language.clone())
+            .line_ending(LineEnding::CrLf)
+            .build()
+            .unwrap();
+        pretty_assert_eq(&expected_crlf, &formatter.format(&crlf_input).unwrap());
+
+        // A tree parsed from the input as it was given is parsed again
+        let tree = tree_sitter::parse(&crlf_input, &language.grammar, false).unwrap();
+        let mut output = Vec::new();
+        formatter_tree(
+            tree,
+            &crlf_input,
             &mut output,
             &language,
-            Operation::Format {
-                skip_idempotence: true,
-                tolerate_parsing_errors: false,
-            },
-            Some(&|name| Ok((name == "ocaml").then_some(inner_language.clone()))),
+            Options {
+                line_ending: LineEnding::Auto,
+                ..Default::default()
+            }
+            .operation(),
+            None,
         )
         .unwrap();
+        pretty_assert_eq(&expected_crlf, str::from_utf8(&output).unwrap());
+    }
 
-        let formatted = String::from_utf8(output).unwrap();
-        pretty_assert_eq(
-            r#"rule token = parse
-  | "x" { let values = [1; 2; 3] in List.map (fun x -> x + 1) values }"#,
-            formatted.trim_end(),
-        );
+    #[test]
+    fn panics_in_tree_sitter_calls_become_internal_errors() {
+        use crate::tree_sitter::catch_panic;
+
+        let error = catch_panic("parsing", || panic!("malformed grammar")).unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Internal(message) if message.contains("malformed grammar")
+        ));
+
+        assert_eq!(catch_panic("parsing", || 1).unwrap(), 1);
     }
 
-    #[test(tokio::test)]
-    async fn invalid_injected_source_fails_formatting() {
-        let input = r#"rule token = parse
-  | "x" { let x = }
+    #[test]
+    fn panics_in_query_matching_become_internal_errors() {
+        let grammar = json_grammar();
+        let query = InjectionQuery::new(
+            &grammar,
+            r#"((string) @injection.content (#eq? @injection.content "\"a\"") (#injection_language! "json"))"#,
+        )
+        .unwrap();
+
+        // The tree is that of a longer input than the one given, so Tree-sitter
+        // panics when it reads the text of a string to check the predicate
+        let tree = parse(r#"["a", "a"]"#, &grammar, false).unwrap();
+        let error = collect_injections(&tree, "[", &query).unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Internal(message) if message.contains("matching the injection query")
+        ));
+    }
+
+    #[test]
+    fn needs_formatting_compares_formatted_to_input() {
+        let needs = |input| needs_formatting(input, topiary_queries::json(), json_grammar());
+
+        assert!(!needs("[ 1, 2 ]\n").unwrap());
+        assert!(needs("[1,2]").unwrap());
+        // Only a missing final newline needs formatting, too
+        assert!(needs("[ 1, 2 ]").unwrap());
+
+        let error = needs("[1,").unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Parsing(_)
+        ));
+    }
+
+    #[test]
+    fn unbalanced_indentation_is_a_query_error() {
+        let query = |value_capture| {
+            format!(
+                r#"
+                (object "{{" @append_indent_start "}}" @prepend_indent_end)
+                (pair value: (_) {value_capture})
+                "#
+            )
+        };
+
+        // Nested blocks are fine, as long as each is ended
+        format_string(
+            r#"{"a":1}"#,
+            &query("@prepend_indent_start @append_indent_end"),
+            json_grammar(),
+        )
+        .unwrap();
+
+        let error = format_string(
+            r#"{"a":1}"#,
+            &query("@prepend_indent_start"),
+            json_grammar(),
+        )
+        .unwrap_err();
+        match error.current_context() {
+            FormatterError::Query(message) => pretty_assert_eq(
+                r#"Indentation block started after "{" at (1,1) is never ended"#,
+                message,
+            ),
+            other => panic!("Expected a query error, got {other:?}"),
+        }
+
+        // Of several blocks that are never ended, the innermost is reported
+        let error = format_string(
+            r#"{"a":1}"#,
+            r#"
+            (object "{" @append_indent_start)
+            (pair value: (_) @prepend_indent_start)
+            "#,
+            json_grammar(),
+        )
+        .unwrap_err();
+        match error.current_context() {
+            FormatterError::Query(message) => pretty_assert_eq(
+                r#"Indentation block started after ":" at (1,5) is never ended"#,
+                message,
+            ),
+            other => panic!("Expected a query error, got {other:?}"),
+        }
+
+        let error =
+            format_string(r#"{"a":1}"#, &query("@append_indent_end"), json_grammar()).unwrap_err();
+        assert!(matches!(error.current_context(), FormatterError::Query(_)));
+    }
+
+    #[test]
+    fn formatter_formats_repeatedly() {
+        let mut formatter = Formatter::builder(language("json", topiary_queries::json(), None))
+            .build()
+            .unwrap();
+
+        for n in 0..100 {
+            let formatted = formatter.format(&format!("[{n},{{\"a\":{n}}}]")).unwrap();
+            pretty_assert_eq(&format!("[ {n}, {{ \"a\": {n} }} ]\n"), &formatted);
+        }
+
+        // A failure leaves the formatter usable
+        let error = formatter.format("[1,").unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Parsing(_)
+        ));
+        pretty_assert_eq("[ 1, 2 ]\n", &formatter.format("[1,2]").unwrap());
+    }
+
+    #[test]
+    fn unmatched_patterns_are_warnings_unless_strict() {
+        let query = r#"(number) @append_space
+
+(true) @prepend_space
+
+((null) @prepend_space (#query_name! "nulls"))
 "#;
-        let language = ocamllex_language();
-        let inner_language: Arc<Language> = Arc::new(ocaml_language());
-        let mut output = Vec::new();
 
-        let result = formatter_str(
-            input,
+        let mut output = Vec::new();
+        let warnings = formatter_str(
+            "[1]",
             &mut output,
-            &language,
+            &language("json", query, None),
             Operation::Format {
                 skip_idempotence: true,
-                tolerate_parsing_errors: false,
+                tolerate_parsing_errors: ParsingErrorTolerance::None,
+                final_newline: FinalNewline::Single,
+                line_ending: LineEnding::Lf,
+                max_line_width: None,
+                strict: false,
             },
-            Some(&|name| Ok((name == "ocaml").then_some(inner_language.clone()))),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::PatternDoesNotMatch {
+                    position: Position { row: 3, column: 1 },
+                    query_name: None,
+                },
+                Warning::PatternDoesNotMatch {
+                    position: Position { row: 5, column: 1 },
+                    query_name: Some("nulls".into()),
+                },
+            ]
+        );
+
+        let mut formatter = Formatter::builder(language("json", query, None))
+            .skip_idempotence(true)
+            .strict(true)
+            .build()
+            .unwrap();
+        let error = formatter.format("[1]").unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::PatternDoesNotMatch
+        ));
+
+        let (_, warnings) = formatter.format_with_warnings("[1,true,null]").unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn strict_formatting_fails_on_unknown_captures() {
+        let query = "(number) @append_space\n(true) @prepend_spcae\n";
+        let format = |strict| {
+            let mut output = Vec::new();
+            formatter_str(
+                "[1]",
+                &mut output,
+                &language("json", query, None),
+                Operation::Format {
+                    skip_idempotence: true,
+                    tolerate_parsing_errors: ParsingErrorTolerance::None,
+                    final_newline: FinalNewline::Single,
+                    line_ending: LineEnding::Lf,
+                    max_line_width: None,
+                    strict,
+                },
+                None,
+            )
+        };
+
+        assert_eq!(
+            format(false).unwrap(),
+            vec![
+                Warning::PatternDoesNotMatch {
+                    position: Position { row: 2, column: 1 },
+                    query_name: None,
+                },
+                Warning::UnknownCapture {
+                    name: "prepend_spcae".into(),
+                    position: Position { row: 2, column: 8 },
+                },
+            ]
+        );
+
+        let error = format(true).unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::PatternDoesNotMatch
+        ));
+        assert!(format!("{error}").contains("@prepend_spcae at (2,8)"));
+    }
+
+    #[test]
+    fn post_process_output_is_checked_for_idempotence() {
+        let mut calls = 0;
+        let mut formatter = Formatter::builder(language("json", topiary_queries::json(), None))
+            .post_process(move |formatted| {
+                calls += 1;
+                assert!(calls <= 2, "post-processed more than once per format");
+                formatted.replace("\"generated\"", "\"kept\"")
+            })
+            .build()
+            .unwrap();
+
+        // The idempotence check formats "kept", which post-processing leaves as it is
+        pretty_assert_eq(
+            "[ \"kept\", 1 ]\n",
+            &formatter.format("[\"generated\",1]").unwrap(),
+        );
+
+        // Post-processing whose output formats differently fails the check
+        let mut formatter = Formatter::builder(language("json", topiary_queries::json(), None))
+            .post_process(|formatted| formatted.replace("[ ", "[ [ ").replace(" ]", " ] ]"))
+            .build()
+            .unwrap();
+        let error = formatter.format("[1]").unwrap_err();
+        assert!(matches!(
+            error.current_context(),
+            FormatterError::Idempotence(_)
+        ));
+
+        // Unless the check is skipped
+        let mut formatter = Formatter::builder(language("json", topiary_queries::json(), None))
+            .skip_idempotence(true)
+            .post_process(|formatted| formatted.replace("[ ", "[ [ ").replace(" ]", " ] ]"))
+            .build()
+            .unwrap();
+        pretty_assert_eq("[ [ 1 ] ]\n", &formatter.format("[1]").unwrap());
+    }
+
+    #[test]
+    fn formatter_edited_matches_full_format() {
+        let new_formatter = |incremental| {
+            Formatter::builder(language("json", topiary_queries::json(), None))
+                .incremental(incremental)
+                .build()
+                .unwrap()
+        };
+        let mut incremental = new_formatter(true);
+        let mut full = new_formatter(false);
+
+        let mut input = String::from("{\"a\":[1,2],\"b\":{\"c\":3}}");
+        incremental.format(&input).unwrap();
+
+        // Each edit replaces the given range of the input; the third leaves it
+        // unparsable, and the fourth mends it
+        let edits = [
+            (9, 9, ",3"),
+            (22, 23, "[4,5]"),
+            (1, 1, "\"x\":"),
+            (1, 5, ""),
+            (5, 5, "\n"),
+        ];
+        for (start_byte, old_end_byte, replacement) in edits {
+            input.replace_range(start_byte..old_end_byte, replacement);
+            let edit = TextEdit {
+                start_byte,
+                old_end_byte,
+                new_end_byte: start_byte + replacement.len(),
+            };
+
+            match (
+                full.format(&input),
+                incremental.format_edited(&input, &edit),
+            ) {
+                (Ok(expected), Ok(formatted)) => pretty_assert_eq(&expected, &formatted),
+                (Err(_), Err(_)) => {}
+                (expected, formatted) => {
+                    panic!("Formatting {input:?} gave {expected:?} in full, but {formatted:?}")
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn formatter_edited_ignores_mismatched_edits() {
+        let mut formatter = Formatter::builder(language("json", topiary_queries::json(), None))
+            .incremental(true)
+            .build()
+            .unwrap();
+        formatter.format("[1,2]").unwrap();
+
+        // The edit claims that "2" was replaced, but "1" was: the input is then
+        // parsed in full
+        let edit = TextEdit {
+            start_byte: 3,
+            old_end_byte: 4,
+            new_end_byte: 5,
+        };
+        pretty_assert_eq(
+            "[ 10, 2 ]\n",
+            &formatter.format_edited("[10,2]", &edit).unwrap(),
+        );
+
+        // As is an edit that is out of bounds
+        let edit = TextEdit {
+            start_byte: 10,
+            old_end_byte: 10,
+            new_end_byte: 12,
+        };
+        pretty_assert_eq(
+            "[ 10, 2, 3 ]\n",
+            &formatter.format_edited("[10,2,3]", &edit).unwrap(),
         );
+    }
+
+    #[test]
+    fn formatter_edited_reparses_crlf_inputs_incrementally() {
+        let old = "[\r\n1,\r\n2\r\n]\r\n";
+        let new = "[\r\n1,\r\n20,\r\n3\r\n]\r\n";
+        // "2" is replaced by "20,\r\n3"
+        let edit = TextEdit {
+            start_byte: 7,
+            old_end_byte: 8,
+            new_end_byte: 13,
+        };
 
+        let (old_normalised, new_normalised) =
+            (old.replace("\r\n", "\n"), new.replace("\r\n", "\n"));
+        let normalised = edit.normalised(new, old_normalised.len());
+        assert_eq!(
+            TextEdit {
+                start_byte: 5,
+                old_end_byte: 6,
+                new_end_byte: 10,
+            },
+            normalised
+        );
         assert!(
-            matches!(result, Err(ref report) if report.current_context() == &FormatterError::Parsing)
+            normalised
+                .to_input_edit(&old_normalised, &new_normalised)
+                .is_some()
+        );
+
+        let mut formatter = Formatter::builder(language("json", topiary_queries::json(), None))
+            .incremental(true)
+            .line_ending(LineEnding::Auto)
+            .build()
+            .unwrap();
+        formatter.format(old).unwrap();
+        pretty_assert_eq(
+            "[\r\n  1,\r\n  20,\r\n  3\r\n]\r\n",
+            &formatter.format_edited(new, &edit).unwrap(),
+        );
+    }
+
+    #[test]
+    fn formatter_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Formatter>();
+    }
+
+    fn aligned_toml() -> Language {
+        let query = format!(
+            "{}\n{}",
+            topiary_queries::toml(),
+            r#"((pair "=" @align) (#align_group! "pairs"))"#
         );
+        language("toml", &query, None)
     }
 
     #[test(tokio::test)]
-    async fn non_idempotent_injection_fails_outer_idempotence() {
-        let input = r#"rule token = parse
-  | "x" { value }
-"#;
-        let language = ocamllex_language();
-        let inner_language: Arc<Language> = Arc::new(unstable_ocaml_language());
-        let mut output = Vec::new();
+    async fn align_consecutive_bindings() {
+        let formatted = format_with(
+            "a = 1\nlong_key = 2\nmid = 3\n",
+            &aligned_toml(),
+            Options {
+                skip_idempotence: false,
+                ..Default::default()
+            },
+        );
 
-        let result = formatter_str(
-            input,
-            &mut output,
-            &language,
-            Operation::Format {
+        pretty_assert_eq("a        = 1\nlong_key = 2\nmid      = 3\n", &formatted);
+    }
+
+    #[test(tokio::test)]
+    async fn align_groups_reset_at_blank_lines() {
+        let formatted = format_with(
+            "a = 1\nlong_key = 2\n\nmid = 3\nx = 4\n",
+            &aligned_toml(),
+            Options {
                 skip_idempotence: false,
-                tolerate_parsing_errors: false,
+                ..Default::default()
             },
-            Some(&|name| Ok((name == "ocaml").then_some(inner_language.clone()))),
         );
 
-        assert!(
-            matches!(result, Err(ref report) if report.current_context() == &FormatterError::Idempotence)
+        pretty_assert_eq(
+            "a        = 1\nlong_key = 2\n\nmid = 3\nx   = 4\n",
+            &formatted,
+        );
+    }
+
+    #[test(tokio::test)]
+    async fn align_ignores_multi_line_rows() {
+        let formatted = format_with(
+            "a = 1\nlong_key = [\n  1,\n]\nmid = 3\n",
+            &aligned_toml(),
+            Options {
+                skip_idempotence: false,
+                ..Default::default()
+            },
         );
+
+        pretty_assert_eq("a = 1\nlong_key = [\n  1,\n]\nmid = 3\n", &formatted);
     }
 }
@@ -2,11 +2,16 @@
 //! module is responsible for rendering the slice of Atoms back into a displayable
 //! format.
 
-use std::fmt::Write;
+use std::{
+    borrow::Cow,
+    fmt::{self, Write},
+    io,
+    ops::Range,
+};
 
 use rootcause::prelude::ResultExt;
 
-use crate::{Atom, Capitalisation, FormatterError, FormatterResult};
+use crate::{Atom, Capitalisation, FinalNewline, FormatterError, FormatterResult, LineEnding};
 
 /// Renders a slice of [`Atom`]s into formatted source code.
 ///
@@ -16,44 +21,153 @@ use crate::{Atom, Capitalisation, FormatterError, FormatterResult};
 /// output buffer.
 ///
 /// The `indent` parameter specifies the string used for one level of
-/// indentation (e.g. `"  "`, `"    "`, or `"\t"`).
+/// indentation (e.g. `"  "`, `"    "`, or `"\t"`). The `tab_width` parameter
+/// is the width, in columns, of a tab in the input, with which the original
+/// indentation of multi-line leaves is measured.
 ///
 /// # Errors
 ///
 /// Returns an error if an atom that should have been removed during
 /// post-processing is still present, or if indentation blocks are
 /// mismatched.
-pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
+pub fn render(atoms: &[Atom], indent: &str, tab_width: usize) -> FormatterResult<String> {
     let mut buffer = String::new();
-    let mut indent_level: usize = 0;
+    render_to(atoms, indent, tab_width, &mut buffer, None)?;
+
+    Ok(buffer)
+}
+
+/// The byte range of the output that each leaf was rendered to, with the id of
+/// the leaf's node
+pub type LeafRanges = Vec<(Range<usize>, usize)>;
+
+/// Renders a slice of [`Atom`]s like [`render`], additionally returning the
+/// byte range of the output that each [`Atom::Leaf`] was rendered to, along
+/// with the id of the leaf's node, in output order.
+///
+/// # Errors
+///
+/// As for [`render`].
+pub fn render_with_leaf_ranges(
+    atoms: &[Atom],
+    indent: &str,
+    tab_width: usize,
+) -> FormatterResult<(String, LeafRanges)> {
+    let mut buffer = String::new();
+    let mut leaf_ranges = Vec::new();
+    render_to(
+        atoms,
+        indent,
+        tab_width,
+        &mut buffer,
+        Some(&mut leaf_ranges),
+    )?;
+
+    Ok((buffer, leaf_ranges))
+}
+
+/// Renders a slice of [`Atom`]s straight to `output`, trimming surrounding
+/// whitespace and ending with `newlines` newlines, as [`terminate`] would.
+/// Every line is ended with `newline`, as [`translate_line_endings`] would.
+/// Unlike [`render`], the formatted output is never held in memory in full.
+///
+/// # Errors
+///
+/// As for [`render`], or if writing to `output` fails.
+pub fn render_terminated(
+    atoms: &[Atom],
+    indent: &str,
+    tab_width: usize,
+    newlines: usize,
+    newline: &str,
+    output: &mut impl io::Write,
+) -> FormatterResult<()> {
+    let mut trimmed = Trimmed {
+        inner: io::BufWriter::new(output),
+        newline,
+        started: false,
+        pending: String::new(),
+        error: None,
+    };
+
+    let rendered = render_to(atoms, indent, tab_width, &mut trimmed, None);
+    if let Some(error) = trimmed.error.take() {
+        return Err(error).context_to();
+    }
+    rendered?;
+
+    let mut output = trimmed.inner;
+    io::Write::write_all(&mut output, newline.repeat(newlines).as_bytes()).context_to()?;
+    io::Write::flush(&mut output).context_to()?;
+
+    Ok(())
+}
+
+fn render_to(
+    atoms: &[Atom],
+    indent: &str,
+    tab_width: usize,
+    output: &mut impl Write,
+    mut leaf_ranges: Option<&mut LeafRanges>,
+) -> FormatterResult<()> {
+    let mut buffer = LineTracker {
+        inner: output,
+        line: String::new(),
+        written: 0,
+    };
+    // The indentation of each open indentation block, innermost last
+    let mut levels: Vec<Cow<str>> = Vec::new();
+    // The indentation levels at which indentation was suspended, innermost last
+    let mut suspended_at: Vec<usize> = Vec::new();
+    let indentation = |levels: &[Cow<str>], suspended_at: &[usize]| {
+        levels
+            .get(suspended_at.last().copied().unwrap_or(0)..)
+            .unwrap_or_default()
+            .concat()
+    };
+    // Whether nothing but indentation has been written on the current line
+    let mut at_line_start = true;
 
     for atom in atoms {
         match atom {
-            Atom::Blankline => {
-                write!(buffer, "\n\n{}", indent.repeat(indent_level)).context_to()?
+            Atom::Blankline(count) => {
+                let newlines = "\n".repeat(count + 1);
+                write!(buffer, "{newlines}{}", indentation(&levels, &suspended_at)).context_to()?;
+                at_line_start = true;
             }
 
             Atom::Empty => (),
 
-            Atom::Hardline => write!(buffer, "\n{}", indent.repeat(indent_level)).context_to()?,
+            Atom::Hardline => {
+                write!(buffer, "\n{}", indentation(&levels, &suspended_at)).context_to()?;
+                at_line_start = true;
+            }
 
             Atom::IndentEnd => {
-                if indent_level == 0 {
+                if levels.pop().is_none() {
                     rootcause::bail!(FormatterError::Query(
                         "Trying to close an unopened indentation block".to_owned(),
                     ));
                 }
+            }
 
-                indent_level -= 1;
+            Atom::IndentResume => {
+                suspended_at.pop();
             }
 
-            Atom::IndentStart => indent_level += 1,
+            Atom::IndentStart => levels.push(Cow::Borrowed(indent)),
+
+            Atom::IndentStartWidth(width) => levels.push(Cow::Owned(indent.repeat(*width))),
+
+            Atom::IndentSuspend => suspended_at.push(levels.len()),
 
             Atom::Leaf {
                 content,
-                original_position,
+                id,
+                original_column,
                 single_line_no_indent,
                 multi_line_indent_all,
+                original_line_indent,
                 keep_whitespace,
                 capitalisation,
                 ..
@@ -70,19 +184,28 @@ pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
                 };
 
                 let mut content = if *multi_line_indent_all {
-                    let cursor = current_column(&buffer) as i32;
-
-                    // original_position is 1-based
-                    let original_column = original_position.column as i32 - 1;
+                    // The leading whitespace of the following lines is rebased from where the
+                    // leaf was anchored in the input onto the current line. This reuses the
+                    // current line's own indentation, so the configured indent string is never
+                    // assumed to be made of spaces.
+                    let (prefix, anchor) = if aligned_with_leaf(
+                        content,
+                        *original_column,
+                        *original_line_indent,
+                        tab_width,
+                    ) {
+                        (alignment_prefix(&buffer.line), *original_column as usize)
+                    } else {
+                        // Only the indentation Topiary has introduced on the
+                        // leaf's first line counts; whatever precedes the leaf
+                        // on that line is irrelevant
+                        (
+                            line_indentation(&buffer.line).to_string(),
+                            *original_line_indent as usize,
+                        )
+                    };
 
-                    let indenting = cursor - original_column;
-
-                    // The following assumes spaces are used for indenting
-                    match indenting {
-                        0 => content.into(),
-                        n if n > 0 => add_spaces_after_newlines(content, indenting),
-                        _ => try_removing_spaces_after_newlines(content, -indenting),
-                    }
+                    rebase_after_newlines(content, &prefix, anchor, tab_width)
                 } else {
                     content.into()
                 };
@@ -95,12 +218,29 @@ pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
                     }
                     _ => {}
                 }
+                let start = buffer.written;
                 write!(buffer, "{content}").context_to()?;
+                if let Some(leaf_ranges) = leaf_ranges.as_deref_mut() {
+                    leaf_ranges.push((start..buffer.written, *id));
+                }
+                at_line_start = false;
             }
 
-            Atom::Literal(s) => write!(buffer, "{s}").context_to()?,
+            Atom::Literal(s) => {
+                write!(buffer, "{s}").context_to()?;
+                at_line_start &= s.is_empty();
+            }
 
-            Atom::Space => write!(buffer, " ").context_to()?,
+            Atom::Space => {
+                write!(buffer, " ").context_to()?;
+                at_line_start = false;
+            }
+
+            Atom::SpaceUnlessBol => {
+                if !at_line_start {
+                    write!(buffer, " ").context_to()?;
+                }
+            }
 
             // All other atom kinds should have been post-processed at that point
             other => {
@@ -111,47 +251,211 @@ pub fn render(atoms: &[Atom], indent: &str) -> FormatterResult<String> {
         };
     }
 
-    Ok(buffer)
+    Ok(())
 }
 
-fn current_column(s: &str) -> usize {
-    s.chars().rev().take_while(|c| *c != '\n').count()
+/// A sink that remembers the line currently being written to it, so that
+/// rendering can measure the current column without keeping the whole output.
+struct LineTracker<W> {
+    inner: W,
+    line: String,
+    /// The number of bytes written so far
+    written: usize,
 }
 
-fn add_spaces_after_newlines(s: &str, n: i32) -> String {
-    let mut result = String::new();
+impl<W: Write> Write for LineTracker<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match s.rsplit_once('\n') {
+            Some((_, last_line)) => {
+                self.line.clear();
+                self.line.push_str(last_line);
+            }
+            None => self.line.push_str(s),
+        }
 
-    let mut chars = s.chars().peekable();
+        self.written += s.len();
+        self.inner.write_str(s)
+    }
+}
 
-    while let Some(c) = chars.next() {
-        result.push(c);
+/// A sink that forwards to an [`io::Write`], dropping leading whitespace and
+/// holding back whitespace until something else follows it, so that trailing
+/// whitespace is never written.
+struct Trimmed<'a, W> {
+    inner: W,
+    /// The sequence with which lines are ended
+    newline: &'a str,
+    /// Whether anything other than whitespace has been written
+    started: bool,
+    /// Whitespace that will only be written if something else follows it
+    pending: String,
+    /// The I/O error that interrupted writing, if any
+    error: Option<io::Error>,
+}
 
-        if c == '\n' && !matches!(chars.peek(), Some('\n') | None) {
-            for _ in 0..n {
-                result.push(' ');
+impl<W: io::Write> Write for Trimmed<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let content_end = s.trim_end().len();
+        if content_end == 0 {
+            if self.started {
+                self.pending.push_str(s);
             }
+            return Ok(());
         }
+
+        let (content, trailing) = s.split_at(content_end);
+        let content = if self.started {
+            content
+        } else {
+            content.trim_start()
+        };
+
+        let pending = translate_line_endings(&self.pending, self.newline);
+        let content = translate_line_endings(content, self.newline);
+        self.inner
+            .write_all(pending.as_bytes())
+            .and_then(|()| self.inner.write_all(content.as_bytes()))
+            .map_err(|error| {
+                self.error = Some(error);
+                fmt::Error
+            })?;
+
+        self.started = true;
+        self.pending.clear();
+        self.pending.push_str(trailing);
+
+        Ok(())
     }
+}
 
-    result
+/// Terminates rendered output according to the final newline policy.
+///
+/// Surrounding whitespace is trimmed from `rendered`, which is then ended with
+/// the number of newlines dictated by `final_newline`. The original `input` is
+/// only consulted to count its trailing newlines, for
+/// [`FinalNewline::Preserve`].
+pub fn terminate(rendered: &str, input: &str, final_newline: FinalNewline) -> String {
+    format!(
+        "{}{}",
+        rendered.trim(),
+        "\n".repeat(final_newlines(input, final_newline))
+    )
 }
 
-fn try_removing_spaces_after_newlines(s: &str, n: i32) -> String {
-    let mut result = String::new();
+/// The number of newlines with which the output for `input` should end, per
+/// the `final_newline` policy.
+pub fn final_newlines(input: &str, final_newline: FinalNewline) -> usize {
+    match final_newline {
+        FinalNewline::Preserve => input[input.trim_end().len()..].matches('\n').count(),
+        FinalNewline::Single => 1,
+        FinalNewline::None => 0,
+    }
+}
 
-    let mut chars = s.chars().peekable();
+/// The sequence with which each line of the output for `input` should end, per
+/// the `line_ending` policy. [`LineEnding::Auto`] picks CRLF only when `input`
+/// has more CRLF line endings than bare LF ones.
+pub fn newline(input: &str, line_ending: LineEnding) -> &'static str {
+    let crlf = || {
+        let crlf_count = input.matches("\r\n").count();
+        crlf_count > input.matches('\n').count() - crlf_count
+    };
 
-    while let Some(c) = chars.next() {
-        result.push(c);
+    match line_ending {
+        LineEnding::Lf => "\n",
+        LineEnding::CrLf => "\r\n",
+        LineEnding::Auto if crlf() => "\r\n",
+        LineEnding::Auto => "\n",
+    }
+}
 
-        if c == '\n' {
-            for _ in 0..n {
-                if let Some(' ') = chars.peek() {
-                    chars.next();
-                } else {
+/// Ends every line of `rendered` with `newline`, whichever line ending it had.
+pub fn translate_line_endings(rendered: &str, newline: &str) -> String {
+    if newline == "\n" {
+        return rendered.to_string();
+    }
+
+    rendered.replace("\r\n", "\n").replace('\n', newline)
+}
+
+/// The leading whitespace of a line
+fn line_indentation(line: &str) -> &str {
+    let end = line.find(|c| c != ' ' && c != '\t').unwrap_or(line.len());
+
+    &line[..end]
+}
+
+/// Whether the lines of a multi-line leaf after its first are aligned with the leaf, rather than
+/// indented relative to the line on which it starts: that is, whether something precedes the leaf
+/// on that line of the input, and none of its following lines is indented less than its `column`.
+fn aligned_with_leaf(content: &str, column: u32, line_indent: u32, tab_width: usize) -> bool {
+    column > line_indent
+        && content
+            .split('\n')
+            .skip(1)
+            .filter(|line| !line.trim().is_empty())
+            .all(|line| column_width(line_indentation(line), tab_width) >= column as usize)
+}
+
+/// Whitespace that reaches the end of `line`: its indentation is kept as is (be it tabs or
+/// spaces), and anything after it is replaced by spaces, for alignment
+fn alignment_prefix(line: &str) -> String {
+    let indentation = line_indentation(line);
+    let alignment = line[indentation.len()..].chars().count();
+
+    format!("{indentation}{}", " ".repeat(alignment))
+}
+
+/// The width, in columns, of `s` when it starts at column 0, with each tab advancing to the next
+/// multiple of `tab_width`
+pub(crate) fn column_width(s: &str, tab_width: usize) -> usize {
+    s.chars()
+        .fold(0, |column, c| next_column(column, c, tab_width))
+}
+
+/// The column after `c`, when it is written at `column`
+fn next_column(column: usize, c: char, tab_width: usize) -> usize {
+    match c {
+        '\t' if tab_width > 0 => (column / tab_width + 1) * tab_width,
+        _ => column + 1,
+    }
+}
+
+/// Replaces the first `anchor` columns of leading whitespace on each non-empty line after the
+/// first with `prefix`, with tabs `tab_width` columns wide. A tab that reaches past the anchor
+/// leaves as many spaces as it does. A line with less leading whitespace than that instead loses
+/// as many characters from the end of `prefix` as it lacks, or all of `prefix`, if it lacks more.
+fn rebase_after_newlines(s: &str, prefix: &str, anchor: usize, tab_width: usize) -> String {
+    let mut lines = s.split('\n');
+    let mut result = lines.next().unwrap_or_default().to_string();
+
+    for line in lines {
+        result.push('\n');
+        if line.is_empty() {
+            continue;
+        }
+
+        let whitespace = line_indentation(line);
+        let width = column_width(whitespace, tab_width);
+        if width >= anchor {
+            // Skip the whitespace up to the anchor
+            let mut column = 0;
+            let mut skipped = 0;
+            for c in whitespace.chars() {
+                if column >= anchor {
                     break;
                 }
+                column = next_column(column, c, tab_width);
+                skipped += c.len_utf8();
             }
+
+            result.push_str(prefix);
+            result.push_str(&" ".repeat(column - anchor));
+            result.push_str(&line[skipped..]);
+        } else {
+            let keep = prefix.len().saturating_sub(anchor - width);
+            result.push_str(&prefix[..keep]);
+            result.push_str(&line[whitespace.len()..]);
         }
     }
 
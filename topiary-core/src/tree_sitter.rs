@@ -2,7 +2,10 @@
 // streaming_iterator::StreamingIterator
 #![cfg_attr(target_arch = "wasm32", allow(unused_imports))]
 
-use std::{collections::HashSet, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 
 use miette::{LabeledSpan, Severity, SourceSpan};
 use rootcause::{prelude::ResultExt, report};
@@ -10,15 +13,16 @@ use serde::Serialize;
 
 use topiary_tree_sitter_facade::{
     Node, Parser, Point, Query, QueryCapture, QueryCursor, QueryError, QueryMatch, QueryPredicate,
-    Tree,
+    Range, Tree, TreeCursor,
 };
 
 use streaming_iterator::StreamingIterator;
 
 use crate::{
-    FormatterResult,
-    atom_collection::{AtomCollection, QueryPredicates},
-    error::{FormatterError, SpanAttachment, query_error_report},
+    Capitalisation, FormatterResult,
+    atom_collection::{AtomCollection, CAPTURE_NAMES, QueryPredicates, Verbatim},
+    error::{self, FormatterError, SpanAttachment, Warning, query_error_report},
+    language::DEFAULT_TAB_WIDTH,
 };
 
 /// Supported visualisation formats
@@ -37,6 +41,30 @@ pub struct Position {
     pub column: u32,
 }
 
+impl Position {
+    /// The position of the given byte offset in `content`.
+    pub(crate) fn of_offset(content: &str, byte_offset: usize) -> Self {
+        Position { row: 1, column: 1 }.advance(&content[..byte_offset])
+    }
+
+    /// The position reached from this one by moving over `text`.
+    fn advance(self, text: &str) -> Self {
+        text.chars().fold(self, |Position { row, column }, c| {
+            if c == '\n' {
+                Position {
+                    row: row + 1,
+                    column: 1,
+                }
+            } else {
+                Position {
+                    row,
+                    column: column + 1,
+                }
+            }
+        })
+    }
+}
+
 impl Display for Position {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(f, "({},{})", self.row, self.column)
@@ -50,6 +78,56 @@ impl Display for Position {
 pub struct TopiaryQuery {
     pub query: Query,
     pub query_content: String,
+    /// The indentation string that the query declares with `#indent_string!`,
+    /// if any, which applies where the language does not set its own
+    pub indent: Option<String>,
+    /// The query that was compiled, if node aliases were resolved in
+    /// `query_content`
+    resolved: Option<ResolvedQuery>,
+}
+
+/// A query in which node aliases were resolved, along with what is needed to
+/// map its byte offsets back to the query as it was written.
+#[derive(Debug)]
+struct ResolvedQuery {
+    content: String,
+    /// The end of each replaced node kind, in the resolved query and in the
+    /// original one
+    replacement_ends: Vec<(usize, usize)>,
+}
+
+impl ResolvedQuery {
+    /// The byte offset in the original query of `offset` in the resolved one
+    fn original_offset(&self, offset: usize) -> usize {
+        match self
+            .replacement_ends
+            .iter()
+            .rev()
+            .find(|(resolved, _)| *resolved <= offset)
+        {
+            Some((resolved, original)) => original + (offset - resolved),
+            None => offset,
+        }
+    }
+
+    /// The error, as found in the resolved query, located in the original one
+    #[cfg(not(target_arch = "wasm32"))]
+    fn original_error(&self, original: &str, mut err: QueryError) -> QueryError {
+        let offset = self.original_offset(err.range.start_byte() as usize);
+        let line_start = original[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let start_point = Point::new(
+            original[..offset].matches('\n').count() as u32,
+            (offset - line_start) as u32,
+        );
+        err.range = Range::new_linewise(original, offset as u32, &start_point);
+        err
+    }
+
+    /// Query errors carry no range in the WASM build.
+    #[cfg(target_arch = "wasm32")]
+    fn original_error(&self, _original: &str, err: QueryError) -> QueryError {
+        err
+    }
 }
 
 impl TopiaryQuery {
@@ -64,35 +142,92 @@ impl TopiaryQuery {
         grammar: &topiary_tree_sitter_facade::Language,
         query_content: &str,
     ) -> FormatterResult<TopiaryQuery, QueryError> {
-        let query = Query::new(grammar, query_content)
+        let query = compile_query(grammar, query_content)
             .map_err(query_error_report)
             .attach_source(query_content.into())?;
 
         Ok(TopiaryQuery {
+            indent: query_indent(&query),
             query,
             query_content: query_content.to_owned(),
+            resolved: None,
         })
     }
 
+    /// Like [`TopiaryQuery::new`], but first resolves the node kind aliases
+    /// used by the query: each named node whose kind is a key of
+    /// `node_aliases` is replaced by the kind that it maps to. This lets a
+    /// query refer to node kinds by stable names, which can be kept across
+    /// grammar versions that rename them. Aliases that map to a kind which the
+    /// grammar does not have are ignored, with a warning.
+    ///
+    /// The query keeps its original content, and the positions that it
+    /// reports, in errors, warnings and coverage, refer to that content.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if tree-sitter failed to parse the
+    /// query file, once its aliases are resolved.
+    pub fn with_node_aliases(
+        grammar: &topiary_tree_sitter_facade::Language,
+        query_content: &str,
+        node_aliases: &HashMap<String, String>,
+    ) -> FormatterResult<TopiaryQuery, QueryError> {
+        let node_aliases: HashMap<&str, &str> = node_aliases
+            .iter()
+            .filter(|(alias, kind)| {
+                let known = grammar.id_for_node_kind(kind, true) != 0;
+                if !known {
+                    log::warn!(
+                        "Ignoring node alias {alias:?}, as the grammar has no node of kind {kind:?}"
+                    );
+                }
+                known
+            })
+            .map(|(alias, kind)| (alias.as_str(), kind.as_str()))
+            .collect();
+
+        let resolved = resolve_node_aliases(query_content, &node_aliases);
+        if resolved.replacement_ends.is_empty() {
+            return Self::new(grammar, query_content);
+        }
+
+        let query = compile_query(grammar, &resolved.content)
+            .map_err(|err| query_error_report(resolved.original_error(query_content, err)))
+            .attach_source(query_content.into())?;
+
+        Ok(TopiaryQuery {
+            indent: query_indent(&query),
+            query,
+            query_content: query_content.to_owned(),
+            resolved: Some(resolved),
+        })
+    }
+
+    /// The query that was compiled, which differs from `query_content` when
+    /// node aliases were resolved. The byte offsets given by `query` refer to
+    /// this content.
+    pub(crate) fn compiled_content(&self) -> &str {
+        match &self.resolved {
+            Some(resolved) => &resolved.content,
+            None => &self.query_content,
+        }
+    }
+
+    /// The byte offset in `query_content` of a byte offset given by `query`
+    pub(crate) fn original_offset(&self, offset: usize) -> usize {
+        match &self.resolved {
+            Some(resolved) => resolved.original_offset(offset),
+            None => offset,
+        }
+    }
+
     /// Calculates the provided position of the Pattern in the query source file
     /// from the byte offset of the pattern in the query.
     #[cfg(not(target_arch = "wasm32"))]
     pub fn pattern_position(&self, pattern_index: usize) -> Position {
         let byte_offset = self.query.start_byte_for_pattern(pattern_index);
-        let (row, column) =
-            self.query_content[..byte_offset]
-                .chars()
-                .fold((0, 0), |(row, column), c| {
-                    if c == '\n' {
-                        (row + 1, 0)
-                    } else {
-                        (row, column + 1)
-                    }
-                });
-        Position {
-            row: row + 1,
-            column: column + 1,
-        }
+        Position::of_offset(&self.query_content, self.original_offset(byte_offset))
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -130,7 +265,7 @@ impl InjectionQuery {
         grammar: &topiary_tree_sitter_facade::Language,
         query_content: &str,
     ) -> FormatterResult<InjectionQuery> {
-        let query = Query::new(grammar, query_content)
+        let query = compile_query(grammar, query_content)
             .map_err(query_error_report)
             .attach_source(query_content.into())
             .context(FormatterError::Query(
@@ -158,6 +293,15 @@ pub struct InjectionSpan<'a> {
     /// single parse IDs are stable. Used to locate and rewrite the
     /// corresponding `Atom::Leaf` after the host has been atomised.
     pub node_id: usize,
+    /// Where the captured node starts in the host input.
+    pub position: Position,
+    /// The indentation of the host input line on which the captured node
+    /// starts.
+    pub indentation: &'a str,
+    /// The escape sequences of the host that enclose the injected content,
+    /// as pairs of escaped and unescaped text, taken from the
+    /// `#injection_escape!` predicates of the matching pattern.
+    pub escapes: Vec<(String, String)>,
 }
 
 /// Run an [`InjectionQuery`] against a parsed `tree`, returning every
@@ -168,10 +312,25 @@ pub struct InjectionSpan<'a> {
 /// are skipped (with a warning logged).
 ///
 /// Missing predicates or unmatched captures are logged, not raised.
+///
+/// # Errors
+///
+/// This function returns a `FormatterError::Internal` if Tree-sitter panics
+/// while matching the query.
 pub fn collect_injections<'a>(
     tree: &Tree,
     input_content: &'a str,
     query: &InjectionQuery,
+) -> FormatterResult<Vec<InjectionSpan<'a>>> {
+    catch_panic("matching the injection query", || {
+        injection_spans(tree, input_content, query)
+    })
+}
+
+fn injection_spans<'a>(
+    tree: &Tree,
+    input_content: &'a str,
+    query: &InjectionQuery,
 ) -> Vec<InjectionSpan<'a>> {
     let root = tree.root_node();
     let source = input_content.as_bytes();
@@ -224,14 +383,47 @@ pub fn collect_injections<'a>(
             continue;
         };
 
+        let mut escapes = Vec::new();
+        for predicate in query
+            .query
+            .general_predicates(query_match.pattern_index())
+            .into_iter()
+            .filter(|p| &*p.operator() == "injection_escape!")
+        {
+            let args = predicate.args();
+            if args.len() % 2 != 0 {
+                log::warn!(
+                    "#injection_escape! in injection query pattern {} takes pairs of escaped and unescaped text; ignoring it",
+                    query_match.pattern_index()
+                );
+                continue;
+            }
+
+            escapes.extend(
+                args.chunks_exact(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone())),
+            );
+        }
+
         for capture in content_captures {
             let node = capture.node();
+            let start = node.start_byte() as usize;
+            let line_start = input_content[..start]
+                .rfind('\n')
+                .map_or(0, |newline| newline + 1);
+            let indentation_length = input_content[line_start..start]
+                .find(|c| c != ' ' && c != '\t')
+                .unwrap_or(start - line_start);
+
             spans.push(InjectionSpan {
                 content: input_content
                     .get(node.byte_range())
                     .expect("`tree-sitter::Node::{start_byte, end_byte}` should always return a valid string slice indexes range."),
                 language: language_name.clone(),
                 node_id: node.id(),
+                position: node.start_position().into(),
+                indentation: &input_content[line_start..line_start + indentation_length],
+                escapes: escapes.clone(),
             });
         }
     }
@@ -255,35 +447,107 @@ pub struct SyntaxNode {
     pub id: usize,
 
     pub kind: String,
+    /// The name of the field under which the node appears in its parent, if any
+    pub field_name: Option<String>,
     pub is_named: bool,
     is_extra: bool,
     is_error: bool,
     is_missing: bool,
     start: Position,
     end: Position,
+    start_byte: u32,
+    end_byte: u32,
+    /// The boundaries of the formatting query's scopes that were captured at
+    /// this node, if scopes were asked for (see [`SyntaxNode::annotate_scopes`])
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<ScopeAnnotation>,
 
     pub children: Vec<SyntaxNode>,
 }
 
-impl From<Node<'_>> for SyntaxNode {
-    fn from(node: Node) -> Self {
-        let mut walker = node.walk();
-        let children = node.children(&mut walker).map(Self::from).collect();
+/// The boundary of a scope of the formatting query, at the node that was
+/// captured to begin or end it, and whether the scope was resolved to be
+/// multi-line, so that its scoped softlines became hardlines
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ScopeAnnotation {
+    pub scope_id: String,
+    pub boundary: ScopeBoundary,
+    pub multi_line: bool,
+}
+
+/// Which boundary of a scope a [`ScopeAnnotation`] is
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopeBoundary {
+    Begin,
+    End,
+}
+
+impl SyntaxNode {
+    /// Builds the tree rooted at `node`, visiting children in the order that
+    /// queries are applied to them. If `named_only` is set, anonymous nodes
+    /// (punctuation, keywords, etc.) are omitted.
+    pub fn new(node: Node, named_only: bool) -> Self {
+        Self::from_cursor(&mut node.walk(), named_only)
+    }
+
+    fn from_cursor(cursor: &mut TreeCursor, named_only: bool) -> Self {
+        let node = cursor.node();
+        let field_name = cursor.field_name().map(|name| name.into_owned());
+
+        let mut children = Vec::new();
+        if cursor.goto_first_child() {
+            loop {
+                if !named_only || cursor.node().is_named() {
+                    children.push(Self::from_cursor(cursor, named_only));
+                }
+
+                if !cursor.goto_next_sibling() {
+                    break;
+                }
+            }
+
+            cursor.goto_parent();
+        }
 
         Self {
             id: node.id(),
 
             kind: node.kind().into(),
+            field_name,
             is_named: node.is_named(),
             is_extra: node.is_extra(),
             is_error: node.is_error(),
             is_missing: node.is_missing(),
             start: node.start_position().into(),
             end: node.end_position().into(),
+            start_byte: node.start_byte(),
+            end_byte: node.end_byte(),
+            scopes: Vec::new(),
 
             children,
         }
     }
+
+    /// Annotates this node and its descendants with the boundaries of the
+    /// scopes that were captured at them, keyed by node id. The boundaries of
+    /// nodes that are not in the tree, such as anonymous nodes when only named
+    /// nodes are visualised, are left out.
+    pub fn annotate_scopes(&mut self, scopes: &mut HashMap<usize, Vec<ScopeAnnotation>>) {
+        if let Some(annotations) = scopes.remove(&self.id) {
+            self.scopes = annotations;
+        }
+
+        for child in &mut self.children {
+            child.annotate_scopes(scopes);
+        }
+    }
+}
+
+impl From<Node<'_>> for SyntaxNode {
+    fn from(node: Node) -> Self {
+        Self::new(node, false)
+    }
 }
 
 /// Extension trait for [`Node`] to allow for 1-based display in logs.
@@ -456,14 +720,26 @@ pub fn apply_query_tree(
     input_content: &str,
     query: &TopiaryQuery,
 ) -> FormatterResult<AtomCollection> {
-    apply_query_tree_with_forced_leaves(tree, input_content, query, std::iter::empty())
+    apply_query_tree_with_forced_leaves(
+        tree,
+        input_content,
+        query,
+        std::iter::empty(),
+        Verbatim::default(),
+        DEFAULT_TAB_WIDTH,
+    )
 }
 
+/// As [`apply_query_tree`], but additionally treats the nodes with the given
+/// ids as leaves, and passes the parts of the input set by `verbatim` through
+/// as they are. Tabs in the input are taken to be `tab_width` columns wide.
 pub(crate) fn apply_query_tree_with_forced_leaves(
     tree: Tree,
     input_content: &str,
     query: &TopiaryQuery,
     forced_leaf_nodes: impl Iterator<Item = usize>,
+    verbatim: Verbatim,
+    tab_width: usize,
 ) -> FormatterResult<AtomCollection> {
     let root = tree.root_node();
     let source = input_content.as_bytes();
@@ -472,28 +748,56 @@ pub(crate) fn apply_query_tree_with_forced_leaves(
     let mut cursor = QueryCursor::new();
     let mut matches: Vec<LocalQueryMatch> = Vec::new();
     let capture_names = query.query.capture_names();
+    let aliases = capture_aliases(query)?;
 
-    let mut query_matches = query.query.matches(&root, source, &mut cursor);
-    #[allow(clippy::while_let_on_iterator)] // This is not a normal iterator
-    while let Some(query_match) = query_matches.next() {
-        let local_captures: Vec<QueryCapture> = query_match.captures().collect();
+    catch_panic("matching the query", || {
+        let mut query_matches = query.query.matches(&root, source, &mut cursor);
+        #[allow(clippy::while_let_on_iterator)] // This is not a normal iterator
+        while let Some(query_match) = query_matches.next() {
+            let local_captures: Vec<QueryCapture> = query_match.captures().collect();
 
-        matches.push(LocalQueryMatch {
-            pattern_index: query_match.pattern_index(),
-            captures: local_captures,
-        });
-    }
+            matches.push(LocalQueryMatch {
+                pattern_index: query_match.pattern_index(),
+                captures: local_captures,
+            });
+        }
+    })?;
 
     // Find the ids of all tree-sitter nodes that were identified as a leaf
     // We want to avoid recursing into them in the collect_leaves function.
     let mut specified_leaf_nodes: HashSet<usize> =
-        collect_leaf_ids(&matches, capture_names.clone());
+        collect_leaf_ids(&matches, capture_names.clone(), &aliases);
     specified_leaf_nodes.extend(forced_leaf_nodes);
 
+    // Nodes captured in patterns with #verbatim! are leaves, too, which are
+    // then frozen once collected
+    let verbatim_nodes = collect_verbatim_ids(&query.query, &matches);
+    specified_leaf_nodes.extend(&verbatim_nodes);
+
     // The Flattening: collects all terminal nodes of the tree-sitter tree in a Vec
-    let mut atoms = AtomCollection::collect_leaves(&root, source, specified_leaf_nodes)?;
+    let mut atoms =
+        AtomCollection::collect_leaves(&root, source, specified_leaf_nodes, verbatim, tab_width)?;
+
+    atoms.freeze_verbatim(&verbatim_nodes);
 
     log::debug!("List of atoms before formatting: {atoms:?}");
+    atoms.set_query_match_count(matches.len());
+    atoms.set_warnings(query_warnings(query, &matches));
+
+    // Nodes captured by @single_line and @multi_line must be known to be
+    // single-line or multi-line before the first softline is expanded
+    atoms.force_single_line(&collect_captured_nodes(
+        &matches,
+        &capture_names,
+        &aliases,
+        "single_line",
+    ));
+    atoms.force_multi_line(&collect_captured_nodes(
+        &matches,
+        &capture_names,
+        &aliases,
+        "multi_line",
+    ));
 
     // Memoization of the pattern positions
     let mut pattern_positions: Vec<Option<Position>> = Vec::new();
@@ -520,6 +824,7 @@ pub(crate) fn apply_query_tree_with_forced_leaves(
             predicates = handle_predicate(&p, &predicates)?;
         }
         check_predicates(&predicates)?;
+        predicates.namespace_scope_ids();
 
         // NOTE: Only performed if logging is enabled to avoid unnecessary computation of Position
         if log::log_enabled!(log::Level::Info) {
@@ -546,19 +851,31 @@ pub(crate) fn apply_query_tree_with_forced_leaves(
         }
 
         // If any capture is a do_nothing, then do nothing.
-        if m.captures
-            .iter()
-            .any(|c| c.name(capture_names.as_slice()) == "do_nothing")
-        {
+        if m.captures.iter().any(|c| {
+            expand_capture(&c.name(capture_names.as_slice()), &aliases).contains(&"do_nothing")
+        }) {
             continue;
         }
 
         for c in m.captures {
             let name = c.name(capture_names.as_slice());
-            atoms.resolve_capture(&name, &c.node(), &predicates)?;
+            // Private captures only name nodes for Tree-sitter's predicates
+            if is_private_capture(&name) {
+                continue;
+            }
+
+            for name in expand_capture(&name, &aliases) {
+                atoms.resolve_capture(name, &c.node(), &predicates)?;
+            }
         }
     }
 
+    atoms.check_indent_ranges()?;
+
+    // Reorder the children marked by @sort_children, before their
+    // surrounding atoms are applied.
+    atoms.sort_children();
+
     // Now apply all atoms in prepend and append to the leaf nodes.
     atoms.apply_prepends_and_appends();
 
@@ -582,30 +899,202 @@ pub fn parse(
     grammar: &topiary_tree_sitter_facade::Language,
     tolerate_parsing_errors: bool,
 ) -> FormatterResult<Tree> {
+    let mut parser = parser(grammar)?;
+    parse_with(&mut parser, content, tolerate_parsing_errors)
+}
+
+/// Creates a tree-sitter parser for the given grammar.
+pub(crate) fn parser(grammar: &topiary_tree_sitter_facade::Language) -> FormatterResult<Parser> {
     let mut parser = Parser::new().context_to()?;
-    parser
-        .set_language(grammar)
+    catch_panic("applying the grammar", || parser.set_language(grammar))?
         .context_to()
         .attach("Could not apply Tree-sitter grammar")?;
 
-    let tree = parser.parse(content, None).context_to()?.ok_or_else(|| {
-        report!(FormatterError::Internal(
-            "Could not parse input".to_string()
+    Ok(parser)
+}
+
+/// Runs `operation`, a call into Tree-sitter such as parsing, turning any panic
+/// in it into a `FormatterError::Internal`, rather than unwinding through the
+/// caller. This protects long-running processes from malformed grammars and
+/// queries. A panic is described by what was being done, given by `doing`, and
+/// its message.
+///
+/// Tree-sitter's C code can also abort the process, which cannot be caught.
+pub(crate) fn catch_panic<T>(doing: &str, operation: impl FnOnce() -> T) -> FormatterResult<T> {
+    panic_message(operation).map_err(|message| {
+        report!(FormatterError::Internal(format!(
+            "Tree-sitter panicked while {doing}: {message}"
+        )))
+    })
+}
+
+/// Runs `operation`, returning the message of any panic in it as an error
+fn panic_message<T>(operation: impl FnOnce() -> T) -> Result<T, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(operation)).map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|message| (*message).to_owned())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_owned())
+    })
+}
+
+/// Compiles a query, turning any panic in Tree-sitter into a query error, as
+/// [`catch_panic`] does for the other calls into Tree-sitter, so that its
+/// position and source can be attached like those of any other.
+#[cfg(not(target_arch = "wasm32"))]
+fn compile_query(
+    grammar: &topiary_tree_sitter_facade::Language,
+    query_content: &str,
+) -> Result<Query, QueryError> {
+    panic_message(|| Query::new(grammar, query_content)).unwrap_or_else(|message| {
+        Err(QueryError::other(
+            query_content,
+            format!("Tree-sitter panicked while compiling the query: {message}"),
         ))
-    })?;
+    })
+}
 
+/// Panics abort in the WASM build, so there is nothing to catch.
+#[cfg(target_arch = "wasm32")]
+fn compile_query(
+    grammar: &topiary_tree_sitter_facade::Language,
+    query_content: &str,
+) -> Result<Query, QueryError> {
+    Query::new(grammar, query_content)
+}
+
+/// Parses source code with an existing parser, like [`parse`]. The parser
+/// keeps no state between parses, so it can be reused for any number of them.
+pub(crate) fn parse_with(
+    parser: &mut Parser,
+    content: &str,
+    tolerate_parsing_errors: bool,
+) -> FormatterResult<Tree> {
+    let tree = reparse_with(parser, content, None)?;
+    check_parse(&tree, content, tolerate_parsing_errors)?;
+
+    Ok(tree)
+}
+
+/// Parses source code with an existing parser, reusing the parts of
+/// `old_tree` that are unchanged. `old_tree` must already have been edited to
+/// match `content`, with [`TextEdit::to_input_edit`]. Unlike [`parse_with`],
+/// this does not check the tree for ERROR nodes.
+pub(crate) fn reparse_with(
+    parser: &mut Parser,
+    content: &str,
+    old_tree: Option<&Tree>,
+) -> FormatterResult<Tree> {
+    catch_panic("parsing", || parser.parse(content, old_tree))?
+        .context_to()?
+        .ok_or_else(|| {
+            report!(FormatterError::Internal(
+                "Could not parse input".to_string()
+            ))
+        })
+}
+
+/// Fails if the tree has ERROR nodes, unless parsing errors are tolerated.
+pub(crate) fn check_parse(
+    tree: &Tree,
+    content: &str,
+    tolerate_parsing_errors: bool,
+) -> FormatterResult<()> {
     // Fail parsing if we don't get a complete syntax tree.
     if !tolerate_parsing_errors {
         check_for_error_nodes(&tree.root_node()).attach_source(Some(content))?;
     }
 
-    Ok(tree)
+    Ok(())
+}
+
+/// An edit of the input, for incremental reparsing with
+/// [`Formatter::format_edited`](crate::Formatter::format_edited): the bytes
+/// `start_byte..old_end_byte` of the previous input were replaced, and are
+/// `start_byte..new_end_byte` of the new input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextEdit {
+    pub start_byte: usize,
+    pub old_end_byte: usize,
+    pub new_end_byte: usize,
+}
+
+impl TextEdit {
+    /// The edit, as Tree-sitter describes it, from `old` to `new`. This is
+    /// `None` if the edit does not describe how `old` became `new`: that is,
+    /// if its offsets are out of bounds or not on character boundaries, or if
+    /// the text outside of the edit differs.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn to_input_edit(
+        self,
+        old: &str,
+        new: &str,
+    ) -> Option<topiary_tree_sitter_facade::InputEdit> {
+        let in_bounds = self.start_byte <= self.old_end_byte
+            && self.start_byte <= self.new_end_byte
+            && old.is_char_boundary(self.start_byte)
+            && old.is_char_boundary(self.old_end_byte)
+            && new.is_char_boundary(self.start_byte)
+            && new.is_char_boundary(self.new_end_byte);
+        if !in_bounds
+            || old[..self.start_byte] != new[..self.start_byte]
+            || old[self.old_end_byte..] != new[self.new_end_byte..]
+        {
+            return None;
+        }
+
+        Some(topiary_tree_sitter_facade::InputEdit::new(
+            u32::try_from(self.start_byte).ok()?,
+            u32::try_from(self.old_end_byte).ok()?,
+            u32::try_from(self.new_end_byte).ok()?,
+            &point_of_offset(old, self.start_byte),
+            &point_of_offset(old, self.old_end_byte),
+            &point_of_offset(new, self.new_end_byte),
+        ))
+    }
+
+    /// The edit, as it was made of the inputs once their CRLF line endings are
+    /// replaced by LF, given the `new` input as it is and the length of the
+    /// previous input once normalised. Should the edit not fit `new`, it is
+    /// returned as it is, for [`TextEdit::to_input_edit`] to reject.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn normalised(self, new: &str, old_normalised_len: usize) -> Self {
+        let crlf_count =
+            |range: std::ops::Range<usize>| new.get(range).map(|text| text.matches("\r\n").count());
+        let (Some(before), Some(within), Some(after)) = (
+            crlf_count(0..self.start_byte),
+            crlf_count(self.start_byte..self.new_end_byte),
+            crlf_count(self.new_end_byte..new.len()),
+        ) else {
+            return self;
+        };
+
+        // The text after the edit is the same in both inputs
+        let after_len = new.len() - self.new_end_byte - after;
+        Self {
+            start_byte: self.start_byte - before,
+            old_end_byte: old_normalised_len.saturating_sub(after_len),
+            new_end_byte: self.new_end_byte - before - within,
+        }
+    }
+}
+
+/// The Tree-sitter point of the given byte offset in `content`: its row and
+/// its column in bytes, both zero-based.
+#[cfg(not(target_arch = "wasm32"))]
+fn point_of_offset(content: &str, byte_offset: usize) -> Point {
+    let before = &content[..byte_offset];
+    let row = before.matches('\n').count();
+    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1);
+
+    Point::new(row as u32, column as u32)
 }
 
 // returns first error node encountered
 fn check_for_error_nodes(node: &Node) -> FormatterResult<()> {
     if node.is_error() {
-        return Err(report!(FormatterError::Parsing)
+        return Err(report!(FormatterError::Parsing(Some(node.range().into())))
             .attach_range(node.range())
             .attach_language(node.language_name()));
     }
@@ -620,13 +1109,18 @@ fn check_for_error_nodes(node: &Node) -> FormatterResult<()> {
 /// Collects the IDs of all leaf nodes in a set of query matches.
 ///
 /// This function takes a slice of `LocalQueryMatch` and a slice of capture names,
-/// and returns a `HashSet` of node IDs that are matched by the "leaf" capture name.
-fn collect_leaf_ids(matches: &[LocalQueryMatch], capture_names: Vec<&str>) -> HashSet<usize> {
+/// and returns a `HashSet` of node IDs that are matched by the "leaf" capture name,
+/// or by an alias of it.
+fn collect_leaf_ids(
+    matches: &[LocalQueryMatch],
+    capture_names: Vec<&str>,
+    aliases: &HashMap<String, Vec<String>>,
+) -> HashSet<usize> {
     let mut ids = HashSet::new();
 
     for m in matches {
         for c in &m.captures {
-            if c.name(capture_names.as_slice()) == "leaf" {
+            if expand_capture(&c.name(capture_names.as_slice()), aliases).contains(&"leaf") {
                 ids.insert(c.node().id());
             }
         }
@@ -634,6 +1128,377 @@ fn collect_leaf_ids(matches: &[LocalQueryMatch], capture_names: Vec<&str>) -> Ha
     ids
 }
 
+/// A warning for each pattern of `query` that has none of the `matches`, in
+/// the order of the query file, followed by one for each occurrence of a
+/// capture name that Topiary does not recognise.
+#[cfg(not(target_arch = "wasm32"))]
+fn query_warnings(query: &TopiaryQuery, matches: &[LocalQueryMatch]) -> Vec<Warning> {
+    let mut warnings = unmatched_pattern_warnings(query, matches);
+    warnings.extend(
+        unknown_captures(&query.query, &query.query_content)
+            .into_iter()
+            .map(|(name, position)| Warning::UnknownCapture {
+                name: name.to_owned(),
+                position,
+            }),
+    );
+    warnings
+}
+
+/// A warning for each pattern of `query` that has none of the `matches`, in
+/// the order of the query file.
+#[cfg(not(target_arch = "wasm32"))]
+fn unmatched_pattern_warnings(query: &TopiaryQuery, matches: &[LocalQueryMatch]) -> Vec<Warning> {
+    let matched: HashSet<usize> = matches.iter().map(|m| m.pattern_index).collect();
+
+    // Positions are found in a single pass over the query, as patterns are
+    // numbered in the order in which they appear
+    let mut offset = 0;
+    let mut position = Position { row: 1, column: 1 };
+
+    (0..query.query.pattern_count())
+        .filter(|index| !matched.contains(index))
+        .map(|index| {
+            let start = query.original_offset(query.query.start_byte_for_pattern(index));
+            position = if start < offset {
+                Position::of_offset(&query.query_content, start)
+            } else {
+                position.advance(&query.query_content[offset..start])
+            };
+            offset = start;
+
+            let query_name = query
+                .query
+                .general_predicates(index)
+                .into_iter()
+                .find_map(|p| {
+                    (&*p.operator() == "query_name!")
+                        .then(|| p.args().into_iter().next())
+                        .flatten()
+                })
+                .map(|name| name.to_string());
+
+            Warning::PatternDoesNotMatch {
+                position,
+                query_name,
+            }
+        })
+        .collect()
+}
+
+/// Patterns cannot be enumerated in the WASM build, where no warnings are
+/// given.
+#[cfg(target_arch = "wasm32")]
+fn query_warnings(_query: &TopiaryQuery, _matches: &[LocalQueryMatch]) -> Vec<Warning> {
+    Vec::new()
+}
+
+/// The ids of the nodes captured in the matches of the patterns that have a
+/// `#verbatim!` predicate.
+fn collect_verbatim_ids(query: &Query, matches: &[LocalQueryMatch]) -> HashSet<usize> {
+    // Memoization of whether each pattern has the predicate
+    let mut verbatim_patterns: HashMap<usize, bool> = HashMap::new();
+
+    matches
+        .iter()
+        .filter(|m| {
+            *verbatim_patterns.entry(m.pattern_index).or_insert_with(|| {
+                query
+                    .general_predicates(m.pattern_index)
+                    .iter()
+                    .any(|p| &*p.operator() == "verbatim!")
+            })
+        })
+        .flat_map(|m| &m.captures)
+        .map(|c| c.node().id())
+        .collect()
+}
+
+/// The nodes captured by `capture`, directly or through an alias, in any of
+/// the matches.
+fn collect_captured_nodes<'m>(
+    matches: &'m [LocalQueryMatch],
+    capture_names: &[&str],
+    aliases: &HashMap<String, Vec<String>>,
+    capture: &str,
+) -> Vec<Node<'m>> {
+    matches
+        .iter()
+        .flat_map(|m| &m.captures)
+        .filter(|c| expand_capture(&c.name(capture_names), aliases).contains(&capture))
+        .map(|c| c.node())
+        .collect()
+}
+
+/// The capture aliases defined by the `#define!` predicates of `query`, mapping
+/// each alias to the built-in capture names it stands for, in order. An alias
+/// may be defined in any pattern, and used in every pattern of the query.
+///
+/// Patterns cannot be enumerated in the WASM build, where no aliases can be
+/// defined.
+///
+/// # Errors
+///
+/// If a definition is invalid, per [`parse_predicate`], or an alias is
+/// defined more than once with different expansions, a `FormatterError::Query`
+/// is returned. In the WASM build, so is any use of `#define!`.
+#[cfg(not(target_arch = "wasm32"))]
+fn capture_aliases(query: &TopiaryQuery) -> FormatterResult<HashMap<String, Vec<String>>> {
+    let query = &query.query;
+    let mut aliases: HashMap<String, Vec<String>> = HashMap::new();
+
+    for pattern_index in 0..query.pattern_count() {
+        for predicate in query.general_predicates(pattern_index) {
+            if predicate.operator() != "define!" {
+                continue;
+            }
+
+            let args = parse_predicate("define!", predicate.args())?;
+
+            let (alias, captures) = args.split_first().expect("checked to be non-empty");
+            match aliases.get(alias) {
+                Some(defined) if defined != captures => {
+                    rootcause::bail!(FormatterError::Query(format!(
+                        "@{alias} is defined more than once, with different captures"
+                    )));
+                }
+                _ => {
+                    aliases.insert(alias.clone(), captures.to_vec());
+                }
+            }
+        }
+    }
+
+    Ok(aliases)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn capture_aliases(query: &TopiaryQuery) -> FormatterResult<HashMap<String, Vec<String>>> {
+    if query.query_content.contains("#define!") {
+        rootcause::bail!(FormatterError::Query(
+            "Capture aliases cannot be defined with #define! in the WASM build".into()
+        ));
+    }
+
+    Ok(HashMap::new())
+}
+
+/// The indentation string that the query declares with an `#indent_string!`
+/// predicate, which may be in any pattern. Should the query declare several,
+/// as a query composed of others may, the last one wins, with a warning.
+///
+/// Patterns cannot be enumerated in the WASM build, where no indentation is
+/// declared.
+#[cfg(not(target_arch = "wasm32"))]
+fn query_indent(query: &Query) -> Option<String> {
+    let mut indent: Option<String> = None;
+
+    for pattern_index in 0..query.pattern_count() {
+        for predicate in query.general_predicates(pattern_index) {
+            if predicate.operator() != "indent_string!" {
+                continue;
+            }
+
+            let Some(declared) = predicate.args().into_iter().next() else {
+                continue;
+            };
+            if let Some(previous) = &indent
+                && *previous != declared
+            {
+                log::warn!(
+                    "The query declares the indentation {previous:?}, and then {declared:?}, which wins"
+                );
+            }
+            indent = Some(declared);
+        }
+    }
+
+    indent
+}
+
+#[cfg(target_arch = "wasm32")]
+fn query_indent(_query: &Query) -> Option<String> {
+    None
+}
+
+/// The built-in capture names that the capture `name` stands for: those of
+/// its definition, if it is an alias, or otherwise just `name` itself.
+fn expand_capture<'a>(name: &'a str, aliases: &'a HashMap<String, Vec<String>>) -> Vec<&'a str> {
+    match aliases.get(name) {
+        Some(captures) => captures.iter().map(String::as_str).collect(),
+        None => vec![name],
+    }
+}
+
+/// Whether the capture name is private (i.e., starts with an underscore).
+/// Private captures do not format the nodes they capture, but can be the
+/// subjects of Tree-sitter's text predicates, such as `#match?`.
+fn is_private_capture(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+/// The kind of an argument of a predicate.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum PredicateArgument {
+    /// Text to be inserted into the output, in which escape sequences are
+    /// interpreted, such as the argument of `#delimiter!`
+    Delimiter,
+    /// A name that relates the captures of several patterns, such as that of
+    /// a scope, an indentation range or an alignment group
+    Identifier,
+    /// A descriptive name, such as that of a query pattern
+    Name,
+    /// A new capture name, without the leading `@`
+    Alias,
+    /// A capture name that Topiary understands, without the leading `@`
+    CaptureName,
+    /// The name of a field of the grammar, such as that of the sort key
+    FieldName,
+    /// Literal text, such as the comment prefix of `#comment_prefix!` or the
+    /// indentation of `#indent_string!`
+    Text,
+    /// A whole number, such as that of the spaces of `#space_count!` or the
+    /// indent strings of `#indent_width!`
+    Count,
+}
+
+impl PredicateArgument {
+    /// Checks an argument of this kind, given to the predicate `operator`,
+    /// and returns it with its escape sequences interpreted, for a delimiter.
+    fn parse(self, operator: &str, argument: String) -> Result<String, FormatterError> {
+        let is_built_in = |name: &str| CAPTURE_NAMES.contains(&name);
+
+        match self {
+            Self::Delimiter => unescape_delimiter(&argument),
+            Self::Count if argument.parse::<usize>().is_err() => Err(FormatterError::Query(
+                format!("#{operator} needs a whole number, not \"{argument}\""),
+            )),
+            Self::Alias if is_built_in(&argument) => Err(FormatterError::Query(format!(
+                "@{argument} is a built-in capture name, so cannot be defined as an alias"
+            ))),
+            Self::CaptureName if !is_built_in(&argument) => Err(FormatterError::Query(format!(
+                "#{operator} can only alias built-in capture names, which @{argument} is not"
+            ))),
+            _ => Ok(argument),
+        }
+    }
+}
+
+/// The description of a predicate that Topiary understands.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct PredicateSpec {
+    /// The name of the predicate, without the leading `#` (e.g., `delimiter!`)
+    pub name: &'static str,
+    /// The kinds of the arguments that the predicate takes, in order
+    pub arguments: &'static [PredicateArgument],
+    /// Whether the last argument may be repeated, any number of times
+    pub variadic: bool,
+}
+
+impl PredicateSpec {
+    const fn new(name: &'static str, arguments: &'static [PredicateArgument]) -> Self {
+        Self {
+            name,
+            arguments,
+            variadic: false,
+        }
+    }
+
+    /// The number of arguments that the predicate takes; for a variadic
+    /// predicate, this is the minimum.
+    pub fn arity(&self) -> usize {
+        self.arguments.len()
+    }
+
+    /// Whether the predicate accepts the given number of arguments.
+    pub fn accepts(&self, count: usize) -> bool {
+        count == self.arity() || (self.variadic && count > self.arity())
+    }
+}
+
+/// The predicates understood by [`handle_predicate`] (and, for `#define!`,
+/// [`capture_aliases`]). When adding a predicate, also add it to this table.
+const PREDICATES: &[PredicateSpec] = {
+    use PredicateArgument::*;
+
+    &[
+        PredicateSpec::new("delimiter!", &[Delimiter]),
+        PredicateSpec::new("scope_id!", &[Identifier]),
+        PredicateSpec::new("indent_id!", &[Identifier]),
+        PredicateSpec::new("single_line_scope_only!", &[Identifier]),
+        PredicateSpec::new("multi_line_scope_only!", &[Identifier]),
+        PredicateSpec::new("align_group!", &[Identifier]),
+        PredicateSpec::new("query_name!", &[Name]),
+        PredicateSpec::new("sort_key!", &[FieldName]),
+        PredicateSpec::new("shared_scope!", &[]),
+        PredicateSpec::new("single_line_only!", &[]),
+        PredicateSpec::new("multi_line_only!", &[]),
+        PredicateSpec::new("delete_if_empty!", &[]),
+        PredicateSpec::new("lower_case!", &[]),
+        PredicateSpec::new("upper_case!", &[]),
+        PredicateSpec::new("comment_prefix!", &[Text]),
+        PredicateSpec::new("space_count!", &[Count]),
+        PredicateSpec::new("indent_width!", &[Count]),
+        PredicateSpec::new("indent_string!", &[Text]),
+        PredicateSpec::new("verbatim!", &[]),
+        PredicateSpec {
+            name: "define!",
+            arguments: &[Alias, CaptureName],
+            variadic: true,
+        },
+    ]
+};
+
+/// The capture names that Topiary understands, without their leading `@`.
+/// This is meant for tools, such as query editors, that need to know them.
+pub fn supported_captures() -> &'static [&'static str] {
+    CAPTURE_NAMES
+}
+
+/// The predicates that Topiary understands, along with the arguments that
+/// each takes. This is meant for tools, such as query editors, that need to
+/// know them.
+pub fn supported_predicates() -> &'static [PredicateSpec] {
+    PREDICATES
+}
+
+/// Checks a predicate against [`PREDICATES`]: that it is known, that it is
+/// given as many arguments as it takes, and that each is of the kind that the
+/// table gives. Formatting and [`validate_query`] both check predicates this
+/// way, so that neither can disagree with the table.
+///
+/// # Returns
+///
+/// The arguments of the predicate, with the escape sequences of delimiters
+/// interpreted.
+///
+/// # Errors
+///
+/// If the predicate is unknown, or its arguments do not match those of the
+/// table, a `FormatterError::Query` is returned.
+fn parse_predicate(operator: &str, args: Vec<String>) -> Result<Vec<String>, FormatterError> {
+    let Some(spec) = PREDICATES.iter().find(|spec| spec.name == operator) else {
+        return Err(FormatterError::Query(format!(
+            "#{operator} is an unknown predicate. Maybe you forgot a \"!\"?"
+        )));
+    };
+
+    if !spec.accepts(args.len()) {
+        return Err(FormatterError::Query(format!(
+            "#{operator} takes {} argument(s), but {} were given",
+            spec.arity(),
+            args.len()
+        )));
+    }
+
+    // The arguments of a variadic predicate beyond those of the table are of
+    // its last kind
+    args.into_iter()
+        .enumerate()
+        .map(|(index, arg)| spec.arguments[index.min(spec.arity() - 1)].parse(operator, arg))
+        .collect()
+}
+
 /// Handles a query predicate and returns a new set of query predicates with the corresponding field updated.
 ///
 /// # Arguments
@@ -641,66 +1506,116 @@ fn collect_leaf_ids(matches: &[LocalQueryMatch], capture_names: Vec<&str>) -> Ha
 /// * `predicate` - A reference to a `QueryPredicate` object that represents a predicate in a query pattern.
 /// * `predicates` - A reference to a `QueryPredicates` object that holds the current state of the query predicates.
 ///
-/// # Returns
-///
-/// `Ok(())` after mutating `predicates` in place, or a `FormatterError` if
-/// the predicate is unknown or missing a required argument.
-///
 /// # Errors
 ///
 /// This function will return an error if:
 ///
 /// * The predicate operator is not one of the supported ones.
-/// * The predicate operator requires an argument but none is provided.
+/// * The arguments of the predicate do not match those of [`PREDICATES`].
 fn handle_predicate(
     predicate: &QueryPredicate,
     predicates: &QueryPredicates,
 ) -> FormatterResult<QueryPredicates> {
     let operator = &*predicate.operator();
+    let args = parse_predicate(operator, predicate.args())?;
+
+    // The number and the kinds of the arguments have been checked against the
+    // table of predicates
+    let argument = || args[0].clone();
+    let count = || {
+        args[0]
+            .parse::<usize>()
+            .expect("checked to be a whole number")
+    };
+
+    let mut predicates = predicates.clone();
     match operator {
-        "delimiter!" => Ok(QueryPredicates {
-            delimiter: Some(next_string_arg(predicate, operator)?),
-            ..predicates.clone()
-        }),
-        "scope_id!" => Ok(QueryPredicates {
-            scope_id: Some(next_string_arg(predicate, operator)?),
-            ..predicates.clone()
-        }),
-        "single_line_scope_only!" => Ok(QueryPredicates {
-            single_line_scope_only: Some(next_string_arg(predicate, operator)?),
-            ..predicates.clone()
-        }),
-        "multi_line_scope_only!" => Ok(QueryPredicates {
-            multi_line_scope_only: Some(next_string_arg(predicate, operator)?),
-            ..predicates.clone()
-        }),
-        "query_name!" => Ok(QueryPredicates {
-            query_name: Some(next_string_arg(predicate, operator)?),
-            ..predicates.clone()
-        }),
-        "single_line_only!" => Ok(QueryPredicates {
-            single_line_only: true,
-            ..predicates.clone()
-        }),
-        "multi_line_only!" => Ok(QueryPredicates {
-            multi_line_only: true,
-            ..predicates.clone()
-        }),
-        _ => Err(FormatterError::Query(format!(
-            "{operator} is an unknown predicate. Maybe you forgot a \"!\"?"
-        )))
-        .into_report(),
+        "delimiter!" => predicates.delimiter = Some(argument()),
+        "scope_id!" => predicates.scope_id = Some(argument()),
+        "indent_id!" => predicates.indent_id = Some(argument()),
+        "single_line_scope_only!" => predicates.single_line_scope_only = Some(argument()),
+        "multi_line_scope_only!" => predicates.multi_line_scope_only = Some(argument()),
+        "align_group!" => predicates.align_group = Some(argument()),
+        "query_name!" => predicates.query_name = Some(argument()),
+        "sort_key!" => predicates.sort_key = Some(argument()),
+        "shared_scope!" => predicates.shared_scope = true,
+        "single_line_only!" => predicates.single_line_only = true,
+        "multi_line_only!" => predicates.multi_line_only = true,
+        "delete_if_empty!" => predicates.delete_if_empty = true,
+        "comment_prefix!" => predicates.comment_prefix = Some(argument()),
+        "space_count!" => {
+            if count() == 0 {
+                rootcause::bail!(FormatterError::Query(format!(
+                    "#{operator} needs a positive number of spaces"
+                )));
+            }
+            predicates.space_count = Some(count());
+        }
+        "indent_width!" => predicates.indent_width = Some(count()),
+        // Capture aliases and the indentation apply to the whole query, and
+        // verbatim nodes must be known before leaves are collected, so all are
+        // collected up front
+        "define!" | "verbatim!" | "indent_string!" => {}
+        "lower_case!" | "upper_case!" => {
+            if predicates.capitalisation != Capitalisation::Pass {
+                rootcause::bail!(FormatterError::Query(
+                    "A query can contain at most one #lower_case!/#upper_case! predicate".into(),
+                ));
+            }
+
+            predicates.capitalisation = if operator == "lower_case!" {
+                Capitalisation::LowerCase
+            } else {
+                Capitalisation::UpperCase
+            };
+        }
+        _ => unreachable!("#{operator} is in the table of predicates, but is not handled"),
     }
+
+    Ok(predicates)
 }
 
-/// Extract the first string argument from `predicate`, returning a
-/// `FormatterError::Query` mentioning `operator` if no argument is present.
-fn next_string_arg(predicate: &QueryPredicate, operator: &str) -> Result<String, FormatterError> {
-    predicate
-        .args()
-        .into_iter()
-        .next()
-        .ok_or_else(|| FormatterError::Query(format!("{operator} needs an argument")))
+/// Interpret the escape sequences in a `#delimiter!` argument.
+///
+/// Tree-sitter already processes escapes in query string literals, so this
+/// second pass affects the escapes that survive it (e.g., `"\\n"` in a query
+/// file). The recognised escape grammar is:
+///
+/// * `\n` -- a newline, which will be rendered as a hardline
+/// * `\t` -- a tab
+/// * `\\` -- a literal backslash
+/// * `\"` -- a literal double quote
+///
+/// Any other escape, including a trailing backslash, is a query error.
+fn unescape_delimiter(raw: &str) -> Result<String, FormatterError> {
+    let mut delimiter = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            delimiter.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => delimiter.push('\n'),
+            Some('t') => delimiter.push('\t'),
+            Some('\\') => delimiter.push('\\'),
+            Some('"') => delimiter.push('"'),
+            Some(other) => {
+                return Err(FormatterError::Query(format!(
+                    "#delimiter! contains an unknown escape sequence: \\{other}"
+                )));
+            }
+            None => {
+                return Err(FormatterError::Query(
+                    "#delimiter! ends with an unterminated escape sequence".into(),
+                ));
+            }
+        }
+    }
+
+    Ok(delimiter)
 }
 
 /// Checks the validity of the query predicates.
@@ -761,13 +1676,15 @@ pub fn check_query_coverage(
 
     // Match queries
     let mut cursor = QueryCursor::new();
-    let ref_match_count = original_query
-        .query
-        .matches(&root, source, &mut cursor)
-        .count();
+    let ref_match_count = catch_panic("matching the query", || {
+        original_query
+            .query
+            .matches(&root, source, &mut cursor)
+            .count()
+    })?;
 
     let pattern_count = original_query.query.pattern_count();
-    let query_content = &original_query.query_content;
+    let query_content = original_query.compiled_content();
     let query = &original_query.query;
 
     // If there are no queries at all (e.g., when debugging) return early
@@ -787,7 +1704,7 @@ pub fn check_query_coverage(
         if ref_match_count == 0 {
             missing_patterns.push(LabeledSpan::new_with_span(
                 Some("empty query".into()),
-                SourceSpan::from(0..query_content.len()),
+                SourceSpan::from(0..original_query.query_content.len()),
             ));
             cover_percentage = 0.0
         }
@@ -797,45 +1714,51 @@ pub fn check_query_coverage(
         });
     }
 
-    let missing_patterns: Vec<LabeledSpan> = (0..pattern_count)
-        .into_par_iter()
-        .filter_map(|i| {
-            // The TreeSitter API doesn't support splitting a query per pattern subqueries.
-            // We do so manually here by using the `query_content` and `query` fields for the same
-            // `TopiaryQuery` object.
-
-            let start_idx = query.start_byte_for_pattern(i);
-            let end_idx = query.end_byte_for_pattern(i);
-            // SAFETY: the index range provided is returned directly from the inner `Query` object
-            let pattern_content = unsafe { query_content.get_unchecked(start_idx..end_idx) };
-            // All child patterns of a non-empty `Query` object created through `Query::new` are guaranteed
-            // to create their own valid `Query` by referencing their pattern byte range.
-            let pattern_query = Query::new(grammar, pattern_content)
-                .expect("unable to create subquery of valid query, this is a bug");
-
-            let mut cursor = QueryCursor::new();
-            let pattern_has_matches = pattern_query
-                .matches(&root, source, &mut cursor)
-                .next()
-                .is_some();
-            if !pattern_has_matches {
-                let trimmed_end_idx = pattern_content
-                    .rmatch_indices('\n')
-                    .map(|(i, _)| i)
-                    .find_map(|i| {
-                        let line = pattern_content[i..].trim_start();
-                        let is_pattern_line = !line.is_empty() && !line.starts_with(';');
-                        is_pattern_line.then_some(start_idx + i + 2)
-                    })
-                    .unwrap_or(pattern_content.len());
-                return Some(LabeledSpan::new_with_span(
-                    Some("unmatched".into()),
-                    SourceSpan::from(start_idx..trimmed_end_idx),
-                ));
-            }
-            None
-        })
-        .collect();
+    // A panic in any thread is resumed here, once they are all done
+    let missing_patterns: Vec<LabeledSpan> = catch_panic("matching the query", || {
+        (0..pattern_count)
+            .into_par_iter()
+            .filter_map(|i| {
+                // The TreeSitter API doesn't support splitting a query per pattern subqueries.
+                // We do so manually here by using the `query_content` and `query` fields for the same
+                // `TopiaryQuery` object.
+
+                let start_idx = query.start_byte_for_pattern(i);
+                let end_idx = query.end_byte_for_pattern(i);
+                // SAFETY: the index range provided is returned directly from the inner `Query` object
+                let pattern_content = unsafe { query_content.get_unchecked(start_idx..end_idx) };
+                // All child patterns of a non-empty `Query` object created through `Query::new` are guaranteed
+                // to create their own valid `Query` by referencing their pattern byte range.
+                let pattern_query = Query::new(grammar, pattern_content)
+                    .expect("unable to create subquery of valid query, this is a bug");
+
+                let mut cursor = QueryCursor::new();
+                let pattern_has_matches = pattern_query
+                    .matches(&root, source, &mut cursor)
+                    .next()
+                    .is_some();
+                if !pattern_has_matches {
+                    let trimmed_end_idx = pattern_content
+                        .rmatch_indices('\n')
+                        .map(|(i, _)| i)
+                        .find_map(|i| {
+                            let line = pattern_content[i..].trim_start();
+                            let is_pattern_line = !line.is_empty() && !line.starts_with(';');
+                            is_pattern_line.then_some(start_idx + i + 2)
+                        })
+                        .unwrap_or(pattern_content.len());
+                    return Some(LabeledSpan::new_with_span(
+                        Some("unmatched".into()),
+                        SourceSpan::from(
+                            original_query.original_offset(start_idx)
+                                ..original_query.original_offset(trimmed_end_idx),
+                        ),
+                    ));
+                }
+                None
+            })
+            .collect()
+    })?;
 
     let ok_patterns = pattern_count - missing_patterns.len();
     let cover_percentage = ok_patterns as f32 / pattern_count as f32;
@@ -853,3 +1776,327 @@ pub fn check_query_coverage(
 ) -> FormatterResult<CoverageData> {
     unimplemented!();
 }
+
+/// The matches of one pattern of a query, as reported by [`query_coverage`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatternCoverage {
+    /// The index of the pattern in the query
+    pub pattern_index: usize,
+    /// The name given to the pattern with `#query_name!`, if any
+    pub query_name: Option<String>,
+    /// The position of the pattern in the query file
+    pub position: Position,
+    /// The number of times the pattern matched
+    pub match_count: usize,
+    /// The byte range of the input spanned by the captures of each match. This
+    /// refers to the input that the report was built from, so it is not
+    /// combined by [`CoverageReport::merge`].
+    pub ranges: Vec<std::ops::Range<usize>>,
+}
+
+/// How often each pattern of a query matched an input, as returned by
+/// [`query_coverage`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CoverageReport {
+    /// The coverage of each pattern, in the order of the query
+    pub patterns: Vec<PatternCoverage>,
+}
+
+impl CoverageReport {
+    /// The patterns that never matched
+    pub fn unmatched(&self) -> impl Iterator<Item = &PatternCoverage> {
+        self.patterns
+            .iter()
+            .filter(|pattern| pattern.match_count == 0)
+    }
+
+    /// Adds the match counts of another report of the same query, such as
+    /// that of another input file, so that coverage can be aggregated across
+    /// a corpus. The ranges of this report are left as they are.
+    pub fn merge(&mut self, other: &CoverageReport) {
+        for (pattern, other) in self.patterns.iter_mut().zip(&other.patterns) {
+            pattern.match_count += other.match_count;
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Counts the matches of each pattern of a query in the input, along with the
+/// byte ranges that they matched. Unlike [`check_query_coverage`], this runs
+/// the query only once, and is meant for finding dead patterns across many
+/// inputs (see [`CoverageReport::merge`]).
+///
+/// # Errors
+///
+/// This function can return an error if the input cannot be parsed by the
+/// grammar, or if a predicate of the query could not be parsed.
+pub fn query_coverage(
+    input_content: &str,
+    query: &TopiaryQuery,
+    grammar: &topiary_tree_sitter_facade::Language,
+) -> FormatterResult<CoverageReport> {
+    let tree = parse(input_content, grammar, false)?;
+    let root = tree.root_node();
+
+    let mut patterns = (0..query.query.pattern_count())
+        .map(|pattern_index| {
+            let mut predicates = QueryPredicates::default();
+            for p in query.query.general_predicates(pattern_index) {
+                predicates = handle_predicate(&p, &predicates)?;
+            }
+
+            Ok(PatternCoverage {
+                pattern_index,
+                query_name: predicates.query_name,
+                position: query.pattern_position(pattern_index),
+                match_count: 0,
+                ranges: Vec::new(),
+            })
+        })
+        .collect::<FormatterResult<Vec<_>>>()?;
+
+    catch_panic("matching the query", || {
+        let mut cursor = QueryCursor::new();
+        let mut query_matches = query
+            .query
+            .matches(&root, input_content.as_bytes(), &mut cursor);
+        #[allow(clippy::while_let_on_iterator)] // This is not a normal iterator
+        while let Some(query_match) = query_matches.next() {
+            let pattern = &mut patterns[query_match.pattern_index()];
+            pattern.match_count += 1;
+
+            let range = query_match
+                .captures()
+                .map(|capture| {
+                    let node = capture.node();
+                    node.start_byte() as usize..node.end_byte() as usize
+                })
+                .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end));
+            pattern.ranges.extend(range);
+        }
+    })?;
+
+    Ok(CoverageReport { patterns })
+}
+
+/// Patterns cannot be enumerated in the WASM build, where coverage cannot be
+/// reported.
+///
+/// # Errors
+///
+/// This function always returns a `FormatterError::Query`.
+#[cfg(target_arch = "wasm32")]
+pub fn query_coverage(
+    _input_content: &str,
+    _query: &TopiaryQuery,
+    _grammar: &topiary_tree_sitter_facade::Language,
+) -> FormatterResult<CoverageReport> {
+    rootcause::bail!(FormatterError::Query(
+        "Query coverage cannot be reported in the WASM build".into()
+    ));
+}
+
+/// A problem found in a query file by [`validate_query`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryDiagnostic {
+    pub severity: error::Severity,
+    pub message: String,
+    /// The position of the offending capture or, for predicates, of the
+    /// pattern that contains it
+    pub position: Position,
+}
+
+/// All the problems found in a query file by [`validate_query`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueryDiagnostics {
+    pub diagnostics: Vec<QueryDiagnostic>,
+}
+
+impl QueryDiagnostics {
+    /// Whether any of the diagnostics would make formatting fail
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == error::Severity::Error)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Checks a formatting query against a grammar, without formatting anything.
+/// The query's node aliases are resolved as by
+/// [`TopiaryQuery::with_node_aliases`], and positions refer to the query as it
+/// was written.
+///
+/// Unknown capture names are reported as warnings, at each place they occur,
+/// as they may be helper captures used by the query's own predicates. Unknown
+/// predicates and predicates with the wrong number of arguments are reported
+/// as errors.
+///
+/// # Errors
+///
+/// If Tree-sitter cannot compile the query, a `FormatterError::Query` is
+/// returned; no further checks can be made in that case.
+pub fn validate_query(
+    query_content: &str,
+    grammar: &topiary_tree_sitter_facade::Language,
+    node_aliases: &HashMap<String, String>,
+) -> FormatterResult<QueryDiagnostics> {
+    let query = TopiaryQuery::with_node_aliases(grammar, query_content, node_aliases)
+        .context(FormatterError::Query("Error parsing query file".into()))?;
+
+    let mut diagnostics: Vec<QueryDiagnostic> = unknown_captures(&query.query, query_content)
+        .into_iter()
+        .map(|(name, position)| QueryDiagnostic {
+            severity: error::Severity::Warning,
+            message: format!("@{name} is not a capture name that Topiary recognises"),
+            position,
+        })
+        .collect();
+
+    for pattern_index in 0..query.query.pattern_count() {
+        let position = query.pattern_position(pattern_index);
+
+        for predicate in query.query.general_predicates(pattern_index) {
+            let message = parse_predicate(&predicate.operator(), predicate.args())
+                .err()
+                .map(|err| err.to_string());
+
+            if let Some(message) = message {
+                diagnostics.push(QueryDiagnostic {
+                    severity: error::Severity::Error,
+                    message,
+                    position,
+                });
+            }
+        }
+    }
+
+    Ok(QueryDiagnostics { diagnostics })
+}
+
+/// Patterns cannot be enumerated in the WASM build, where queries cannot be
+/// validated.
+///
+/// # Errors
+///
+/// This function always returns a `FormatterError::Query`.
+#[cfg(target_arch = "wasm32")]
+pub fn validate_query(
+    _query_content: &str,
+    _grammar: &topiary_tree_sitter_facade::Language,
+    _node_aliases: &HashMap<String, String>,
+) -> FormatterResult<QueryDiagnostics> {
+    rootcause::bail!(FormatterError::Query(
+        "Queries cannot be validated in the WASM build".into()
+    ));
+}
+
+/// The query, with the named nodes whose kind is a key of `node_aliases`
+/// replaced by the kind that it maps to.
+fn resolve_node_aliases(query_content: &str, node_aliases: &HashMap<&str, &str>) -> ResolvedQuery {
+    let mut content = String::with_capacity(query_content.len());
+    let mut replacement_ends = Vec::new();
+    let mut copied = 0;
+    for (kind, offset) in node_kind_occurrences(query_content) {
+        if let Some(actual) = node_aliases.get(kind) {
+            content.push_str(&query_content[copied..offset]);
+            content.push_str(actual);
+            copied = offset + kind.len();
+            replacement_ends.push((content.len(), copied));
+        }
+    }
+    content.push_str(&query_content[copied..]);
+
+    ResolvedQuery {
+        content,
+        replacement_ends,
+    }
+}
+
+/// The byte offset of every character of a query file that `is_token`
+/// accepts, skipping over strings and comments.
+fn query_token_offsets(query_content: &str, is_token: impl Fn(char) -> bool) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut chars = query_content.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            ';' => {
+                chars.by_ref().find(|(_, c)| *c == '\n');
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            c if is_token(c) => offsets.push(offset),
+            _ => {}
+        }
+    }
+
+    offsets
+}
+
+/// The kind and byte offset of every named node in a query file (i.e., of each
+/// name that follows an opening parenthesis), skipping over strings and
+/// comments.
+fn node_kind_occurrences(query_content: &str) -> Vec<(&str, usize)> {
+    let is_kind_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    query_token_offsets(query_content, |c| c == '(')
+        .into_iter()
+        .filter_map(|offset| {
+            let rest = &query_content[offset + 1..];
+            let start = query_content.len() - rest.trim_start().len();
+            let rest = &query_content[start..];
+            let kind = &rest[..rest.find(|c| !is_kind_char(c)).unwrap_or(rest.len())];
+            (!kind.is_empty()).then_some((kind, start))
+        })
+        .collect()
+}
+
+/// The name and position of every occurrence of a capture in `query_content`
+/// that is neither private, a capture name that Topiary recognises, nor an
+/// alias defined by `#define!`.
+#[cfg(not(target_arch = "wasm32"))]
+fn unknown_captures<'a>(query: &Query, query_content: &'a str) -> Vec<(&'a str, Position)> {
+    // Aliases are known captures, wherever they are defined
+    let aliases: HashSet<String> = (0..query.pattern_count())
+        .flat_map(|pattern_index| query.general_predicates(pattern_index))
+        .filter(|predicate| predicate.operator() == "define!")
+        .filter_map(|predicate| predicate.args().into_iter().next())
+        .collect();
+
+    let capture_names = query.capture_names();
+    capture_occurrences(query_content)
+        .into_iter()
+        .filter(|(name, _)| {
+            capture_names.contains(name)
+                && !is_private_capture(name)
+                && !CAPTURE_NAMES.contains(name)
+                && !aliases.contains(*name)
+        })
+        .map(|(name, offset)| (name, Position::of_offset(query_content, offset)))
+        .collect()
+}
+
+/// The name and byte offset of every capture in a query file, skipping over
+/// strings and comments.
+#[cfg(not(target_arch = "wasm32"))]
+fn capture_occurrences(query_content: &str) -> Vec<(&str, usize)> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '.' | '-');
+
+    query_token_offsets(query_content, |c| c == '@')
+        .into_iter()
+        .map(|offset| {
+            let rest = &query_content[offset + 1..];
+            let name = &rest[..rest.find(|c| !is_name_char(c)).unwrap_or(rest.len())];
+            (name, offset)
+        })
+        .collect()
+}
@@ -2,16 +2,18 @@
 ; any which are encountered by Topiary will be forcibly collapsed on to
 ; a single line. (See Issue #172)
 
-; Don't modify strings, heredocs, comments, atomic "words", variable
-; expansions or array indices.
+; Don't modify strings, comments, atomic "words", variable expansions or
+; array indices.
 [
   (comment)
   (expansion)
-  (heredoc_redirect)
   (string)
   (word)
 ] @leaf
 
+; Keep heredocs exactly as they are, as their indentation is significant
+((heredoc_redirect) @leaf (#verbatim!))
+
 (simple_expansion
   "$"
   .
@@ -1,3 +1,6 @@
+; Indent with four spaces, unless configured otherwise or by .editorconfig
+((source_file) (#indent_string! "    "))
+
 ; Sometimes we want to indicate that certain parts of our source text should
 ; not be formatted, but taken as is. We use the leaf capture name to inform the
 ; tool of this.
@@ -106,3 +106,70 @@ pub fn tree_sitter_query() -> &'static str {
 pub fn wit() -> &'static str {
     include_str!("../queries/wit/formatting.scm")
 }
+
+/// Returns the Topiary-compatible query file for the named language, if it is
+/// bundled (i.e., its feature is enabled).
+pub fn formatting(language: &str) -> Option<&'static str> {
+    match language {
+        #[cfg(feature = "bash")]
+        "bash" => Some(bash()),
+
+        #[cfg(feature = "css")]
+        "css" => Some(css()),
+
+        #[cfg(feature = "json")]
+        "json" => Some(json()),
+
+        #[cfg(feature = "markdown")]
+        "markdown" => Some(markdown()),
+
+        #[cfg(feature = "nickel")]
+        "nickel" => Some(nickel()),
+
+        #[cfg(feature = "ocaml")]
+        "ocaml" => Some(ocaml()),
+
+        #[cfg(feature = "ocaml_interface")]
+        "ocaml_interface" => Some(ocaml_interface()),
+
+        #[cfg(feature = "ocamllex")]
+        "ocamllex" => Some(ocamllex()),
+
+        #[cfg(feature = "openscad")]
+        "openscad" => Some(openscad()),
+
+        #[cfg(feature = "rust")]
+        "rust" => Some(rust()),
+
+        #[cfg(feature = "sdml")]
+        "sdml" => Some(sdml()),
+
+        #[cfg(feature = "toml")]
+        "toml" => Some(toml()),
+
+        #[cfg(feature = "tree_sitter_query")]
+        "tree_sitter_query" => Some(tree_sitter_query()),
+
+        #[cfg(feature = "wit")]
+        "wit" => Some(wit()),
+
+        _ => None,
+    }
+}
+
+/// Returns the Topiary-compatible injection query file for the named language,
+/// if it is bundled and has one.
+pub fn injections(language: &str) -> Option<&'static str> {
+    match language {
+        #[cfg(feature = "markdown")]
+        "markdown" => Some(markdown_injections()),
+
+        #[cfg(feature = "ocamllex")]
+        "ocamllex" => Some(ocamllex_injections()),
+
+        #[cfg(feature = "rust")]
+        "rust" => Some(rust_injections()),
+
+        _ => None,
+    }
+}
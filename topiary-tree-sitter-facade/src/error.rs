@@ -1,6 +1,8 @@
 #[cfg(not(target_arch = "wasm32"))]
 mod native {
 
+    use tree_sitter::QueryErrorKind;
+
     use crate::{Point, Range};
 
     #[derive(Eq, PartialEq)]
@@ -63,6 +65,46 @@ mod native {
             let range = Range::new_linewise(source, inner.offset as u32, &start_point);
             Self { inner, range }
         }
+
+        /// A short description of the kind of error, such as "Invalid syntax"
+        pub fn kind(&self) -> &'static str {
+            match self.inner.kind {
+                QueryErrorKind::Syntax => "Invalid syntax",
+                QueryErrorKind::NodeType => "Invalid node type",
+                QueryErrorKind::Field => "Invalid field name",
+                QueryErrorKind::Capture => "Invalid capture name",
+                QueryErrorKind::Predicate => "Invalid predicate",
+                QueryErrorKind::Structure => "Impossible pattern",
+                QueryErrorKind::Language => "Incompatible language",
+            }
+        }
+
+        /// What is at fault, such as the name of an invalid node type. Syntax
+        /// errors and impossible patterns have no such detail: their position
+        /// is all there is to say.
+        pub fn detail(&self) -> Option<&str> {
+            match self.inner.kind {
+                QueryErrorKind::Syntax | QueryErrorKind::Structure => None,
+                _ => Some(&self.inner.message),
+            }
+        }
+
+        /// An error that Tree-sitter did not report itself, such as a panic
+        /// while compiling the query from `source`, described by `message`. As
+        /// it is most likely the grammar's fault, it is an "Incompatible
+        /// language" error, at the start of the query.
+        pub fn other(source: &str, message: String) -> Self {
+            Self::new(
+                source,
+                tree_sitter::QueryError {
+                    row: 0,
+                    column: 0,
+                    offset: 0,
+                    message,
+                    kind: QueryErrorKind::Language,
+                },
+            )
+        }
     }
 
     unsafe impl Send for QueryError {}
@@ -2,6 +2,13 @@
 mod native {
     use std::{borrow::Cow, convert::TryFrom};
 
+    /// The most recent grammar ABI version that can be loaded
+    pub const LANGUAGE_VERSION: u32 = tree_sitter::LANGUAGE_VERSION as u32;
+
+    /// The oldest grammar ABI version that can be loaded
+    pub const MIN_COMPATIBLE_LANGUAGE_VERSION: u32 =
+        tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION as u32;
+
     pub struct LanguageRef<'a> {
         pub(crate) inner: tree_sitter::LanguageRef<'a>,
     }
@@ -73,6 +80,22 @@ mod native {
             u32::try_from(self.inner.abi_version()).unwrap()
         }
 
+        /// Iterate over the node kinds of the grammar, with their ids and whether they are named.
+        /// Ids with no kind (such as unused ones) are skipped.
+        pub fn node_kinds(&self) -> impl Iterator<Item = (u16, Cow<'_, str>, bool)> + '_ {
+            (0..self.node_kind_count()).filter_map(|id| {
+                self.node_kind_for_id(id)
+                    .map(|kind| (id, kind, self.node_kind_is_named(id)))
+            })
+        }
+
+        /// Whether the grammar has a node kind with the given name, which is named or not
+        #[inline]
+        pub fn has_node_kind(&self, kind: &str, named: bool) -> bool {
+            // Tree-sitter maps unknown kinds to the id 0, which is that of the end of input
+            self.id_for_node_kind(kind, named) != 0
+        }
+
         #[inline]
         pub fn name(&self) -> Option<&'static str> {
             self.inner.name()
@@ -108,6 +131,34 @@ mod native {
     impl Unpin for Language {}
 
     impl std::panic::UnwindSafe for Language {}
+
+    #[cfg(test)]
+    mod tests {
+        use super::{Language, MIN_COMPATIBLE_LANGUAGE_VERSION};
+
+        #[test]
+        fn grammar_lists_its_node_kinds() {
+            let grammar = Language::from(tree_sitter_json::LANGUAGE);
+
+            assert!(grammar.version() >= MIN_COMPATIBLE_LANGUAGE_VERSION);
+            let kinds: Vec<_> = grammar.node_kinds().collect();
+            assert!(
+                kinds
+                    .iter()
+                    .any(|(_, kind, named)| kind == "pair" && *named)
+            );
+            assert!(kinds.iter().any(|(_, kind, named)| kind == "{" && !*named));
+            assert!(
+                kinds
+                    .iter()
+                    .all(|(id, kind, _)| grammar.node_kind_for_id(*id).as_ref() == Some(kind))
+            );
+
+            assert!(grammar.has_node_kind("pair", true));
+            assert!(!grammar.has_node_kind("pair", false));
+            assert!(!grammar.has_node_kind("no_such_kind", true));
+        }
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -169,6 +220,22 @@ mod wasm {
         pub fn version(&self) -> u32 {
             self.inner.version()
         }
+
+        /// Iterate over the node kinds of the grammar, with their ids and whether they are named.
+        /// Ids with no kind (such as unused ones) are skipped.
+        pub fn node_kinds(&self) -> impl Iterator<Item = (u16, Cow<str>, bool)> + '_ {
+            (0..self.node_kind_count()).filter_map(|id| {
+                self.node_kind_for_id(id)
+                    .map(|kind| (id, kind, self.node_kind_is_named(id)))
+            })
+        }
+
+        /// Whether the grammar has a node kind with the given name, which is named or not
+        #[inline]
+        pub fn has_node_kind(&self, kind: &str, named: bool) -> bool {
+            // Tree-sitter maps unknown kinds to the id 0, which is that of the end of input
+            self.id_for_node_kind(kind, named) != 0
+        }
     }
 
     impl std::fmt::Debug for Language {
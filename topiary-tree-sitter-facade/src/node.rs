@@ -118,7 +118,7 @@ mod native {
 
         #[inline]
         pub fn is_extra(&self) -> bool {
-            self.inner.is_named()
+            self.inner.is_extra()
         }
 
         #[inline]